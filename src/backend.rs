@@ -0,0 +1,71 @@
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+
+use crate::chunks::{ChunkHash, FileChunk, FileChunkDesc};
+use crate::delta::ChunkReuse;
+use crate::image::ImageName;
+use crate::retry::{RetryPolicy, UploadError};
+use crate::signing::ImageSignature;
+use crate::upload::{AttachInfo, UploadProgress, ValidateUploadResponse};
+
+/// Operations of Golem Registry used when pushing images.
+/// Implemented by [`RegistryClient`](crate::client::RegistryClient) for real registries
+/// and by [`MockRegistry`](crate::mock::MockRegistry) for offline testing.
+#[async_trait]
+pub trait RegistryBackend: Clone + Send + Sync + 'static {
+    /// Registry address, used in messages and attach info
+    fn base_url(&self) -> &str;
+
+    fn retry_policy(&self) -> &RetryPolicy;
+
+    /// Credentials used for operations that need login, like attaching to repository
+    fn with_credentials(self, user_name: &str, pat: &str) -> Self;
+
+    /// Returns false if the registry rejected credentials
+    async fn check_login(&self, user_name: &str, pat: &str) -> anyhow::Result<bool>;
+
+    /// Upload status of the descriptor and its chunks
+    async fn validate_upload(&self, descr_sha256: &str) -> anyhow::Result<ValidateUploadResponse>;
+
+    async fn push_descr(&self, descr_path: &Path) -> anyhow::Result<()>;
+
+    /// Uploads signature of the descriptor, stored by the registry next to it.
+    /// Returns false if the registry does not support signatures.
+    async fn push_signature(
+        &self,
+        descr_sha256: &str,
+        signature: &ImageSignature,
+    ) -> anyhow::Result<bool>;
+
+    /// Descriptor of the image attached to the tag
+    async fn tagged_descriptor(&self, image_name: &ImageName) -> anyhow::Result<FileChunkDesc>;
+
+    /// Asks the registry to copy chunks already stored with the base descriptor.
+    /// Returns numbers of chunks the registry accepted, None if the registry does not support reuse.
+    async fn reuse_chunks(
+        &self,
+        descr_sha256: &str,
+        base_descr_sha256: &str,
+        chunks: &[ChunkReuse],
+    ) -> anyhow::Result<Option<Vec<u64>>>;
+
+    /// Uploads single chunk of the image file, `chunk_hash` is the algorithm of `chunk.hash`.
+    /// Errors are classified for retrying.
+    async fn push_chunk(
+        &self,
+        file_path: PathBuf,
+        chunk: FileChunk,
+        chunk_hash: ChunkHash,
+        descr_sha256: String,
+        progress: UploadProgress,
+    ) -> Result<(), UploadError>;
+
+    /// Attaches uploaded image to repository tag, with `check` only verifies if it is possible
+    async fn attach_to_repo(
+        &self,
+        descr_sha256: &str,
+        image_name: &ImageName,
+        check: bool,
+    ) -> anyhow::Result<AttachInfo>;
+}
@@ -0,0 +1,124 @@
+//! Content-defined chunking (FastCDC with normalized chunking).
+//!
+//! Chunk boundaries depend on file content instead of offsets, so inserting bytes
+//! in the middle of the image changes only chunks around the change.
+
+/// Gear table, 256 pseudo random values generated with splitmix64
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x6776_6d6b_6974_2d63; //"gvmkit-c"
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Mask with `bits` highest bits set, gear hash mixes input into the high bits first
+const fn high_bits_mask(bits: u32) -> u64 {
+    if bits == 0 {
+        0
+    } else {
+        u64::MAX << (64 - bits)
+    }
+}
+
+/// Finds chunk boundaries, chunks are between `avg_size / 4` and `avg_size * 4` bytes
+#[derive(Debug, Clone)]
+pub struct ContentChunker {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    //harder to match before average size, easier after (normalized chunking)
+    mask_small: u64,
+    mask_large: u64,
+}
+
+impl ContentChunker {
+    pub fn new(avg_size: usize) -> Self {
+        let avg_size = avg_size.max(256);
+        let bits = avg_size.ilog2();
+        ContentChunker {
+            min_size: avg_size / 4,
+            avg_size,
+            max_size: avg_size * 4,
+            mask_small: high_bits_mask(bits + 2),
+            mask_large: high_bits_mask(bits - 2),
+        }
+    }
+
+    pub fn avg_size(&self) -> usize {
+        self.avg_size
+    }
+
+    /// Largest possible chunk, reader should provide at least that many bytes to [`Self::cut`]
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// Length of the chunk starting at the beginning of `data`.
+    /// `data` shorter than `max_size` is treated as the end of file.
+    pub fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min_size {
+            return data.len();
+        }
+        let end = data.len().min(self.max_size);
+        let normal = end.min(self.avg_size);
+        let mut hash = 0u64;
+        for (i, byte) in data.iter().enumerate().take(normal).skip(self.min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash & self.mask_small == 0 {
+                return i + 1;
+            }
+        }
+        for (i, byte) in data.iter().enumerate().take(end).skip(normal) {
+            hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+            if hash & self.mask_large == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+#[test]
+fn test_content_chunker() {
+    use std::iter::repeat_with;
+
+    let mut rng = fastrand::Rng::new();
+    rng.seed(1234);
+    let data: Vec<u8> = repeat_with(|| rng.u8(..)).take(200_000).collect();
+    let chunker = ContentChunker::new(4096);
+    let split = |data: &[u8]| {
+        let mut chunks = Vec::new();
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = chunker.cut(&data[pos..]);
+            chunks.push(data[pos..pos + len].to_vec());
+            pos += len;
+        }
+        chunks
+    };
+    let chunks = split(&data);
+    assert_eq!(chunks.iter().map(|c| c.len()).sum::<usize>(), data.len());
+    assert!(chunks[..chunks.len() - 1]
+        .iter()
+        .all(|c| c.len() >= 1024 && c.len() <= 16384));
+    let avg = data.len() / chunks.len();
+    assert!(avg > 2048 && avg < 8192, "average chunk size {avg}");
+
+    //insertion near the start changes only first chunks
+    let mut modified = data.clone();
+    modified.splice(100..100, [1, 2, 3, 4, 5]);
+    let modified_chunks = split(&modified);
+    let unchanged = modified_chunks
+        .iter()
+        .filter(|c| chunks.contains(c))
+        .count();
+    assert!(unchanged + 2 >= chunks.len());
+}
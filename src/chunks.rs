@@ -1,29 +1,200 @@
+use crate::cdc::ContentChunker;
+use crate::metadata::read_metadata_footer;
 use crate::progress::{create_chunk_pb, ProgressBarType};
 use sha2::{Digest, Sha256};
 use sha3::Sha3_224;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use tokio::fs;
 use tokio::fs::File;
-use tokio::io::AsyncReadExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
+/// Descriptor v1: size, chunk size, sha3, then sha256 of every (fixed size) chunk
 const VERSION_AND_HEADER: u64 = 0x333333334;
+/// Descriptor v2 with typed header, see [`FileChunkDesc::serialize_to_bytes`]
+const VERSION_V2: u64 = 0x333333336;
+const V2_HEADER_LENGTH: usize = 72;
+
+/// How image file is split into chunks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Chunking {
+    /// Chunks of equal size, understood by every registry version
+    #[default]
+    Fixed,
+    /// Boundaries chosen by content (FastCDC), `chunk_size` is the average size.
+    /// Chunks stay the same between image versions, so unchanged ones do not have to be uploaded again.
+    ContentDefined,
+}
+
+impl Chunking {
+    pub fn from_name(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "fixed" => Ok(Chunking::Fixed),
+            "cdc" | "content-defined" => Ok(Chunking::ContentDefined),
+            _ => Err(anyhow::anyhow!(
+                "Unknown chunking {name}, possible values: fixed, cdc"
+            )),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Chunking::Fixed => "fixed",
+            Chunking::ContentDefined => "content-defined",
+        }
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            Chunking::Fixed => 0,
+            Chunking::ContentDefined => 1,
+        }
+    }
+
+    fn from_id(id: u8) -> anyhow::Result<Self> {
+        match id {
+            0 => Ok(Chunking::Fixed),
+            1 => Ok(Chunking::ContentDefined),
+            _ => Err(anyhow::anyhow!("Unknown chunking id {id}")),
+        }
+    }
+}
+
+/// Hash algorithm of chunks, v1 descriptors always use sha256
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChunkHash {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl ChunkHash {
+    pub fn from_name(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "sha256" => Ok(ChunkHash::Sha256),
+            "blake3" => Ok(ChunkHash::Blake3),
+            _ => Err(anyhow::anyhow!(
+                "Unknown chunk hash {name}, possible values: sha256, blake3"
+            )),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChunkHash::Sha256 => "sha256",
+            ChunkHash::Blake3 => "blake3",
+        }
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            ChunkHash::Sha256 => 1,
+            ChunkHash::Blake3 => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> anyhow::Result<Self> {
+        match id {
+            1 => Ok(ChunkHash::Sha256),
+            2 => Ok(ChunkHash::Blake3),
+            _ => Err(anyhow::anyhow!("Unknown chunk hash id {id}")),
+        }
+    }
+
+    pub fn digest(&self, data: &[u8]) -> [u8; 32] {
+        match self {
+            ChunkHash::Sha256 => Sha256::digest(data).into(),
+            ChunkHash::Blake3 => blake3::hash(data).into(),
+        }
+    }
+
+    /// Incremental hasher, for chunks received in parts
+    pub fn hasher(&self) -> ChunkHasher {
+        match self {
+            ChunkHash::Sha256 => ChunkHasher::Sha256(Sha256::new()),
+            ChunkHash::Blake3 => ChunkHasher::Blake3(Box::default()),
+        }
+    }
+}
+
+pub enum ChunkHasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl ChunkHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            ChunkHasher::Sha256(hasher) => hasher.update(data),
+            ChunkHasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finalize(self) -> [u8; 32] {
+        match self {
+            ChunkHasher::Sha256(hasher) => hasher.finalize().into(),
+            ChunkHasher::Blake3(hasher) => hasher.finalize().into(),
+        }
+    }
+}
+
+/// Format of newly created descriptor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DescriptorFormat {
+    pub chunking: Chunking,
+    pub chunk_hash: ChunkHash,
+    /// Use v2 also for fixed sha256 chunks, other settings always need v2
+    pub v2: bool,
+}
+
+impl DescriptorFormat {
+    pub fn version(&self) -> u64 {
+        if self.v2 || self.chunking != Chunking::Fixed || self.chunk_hash != ChunkHash::Sha256 {
+            VERSION_V2
+        } else {
+            VERSION_AND_HEADER
+        }
+    }
+}
+
+/// Entry of v2 descriptor extension area
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct DescriptorExtension {
+    pub kind: u16,
+    pub data: Vec<u8>,
+}
+
+impl DescriptorExtension {
+    /// sha256 of the metadata json stored in gvmi file
+    pub const METADATA_HASH: u16 = 1;
+    /// Signature of the descriptor
+    pub const SIGNATURE: u16 = 2;
+}
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct FileChunk {
     pub chunk_no: u64,
     pub pos: u64,
     pub len: u64,
-    //use sha-256 (sha256) for chunk collision resistance and performance
-    pub sha256: [u8; 32],
+    //sha-256 (sha256) for v1, algorithm chosen in descriptor for v2
+    pub hash: [u8; 32],
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct FileChunkDesc {
     pub version: u64,
     pub size: u64,
     pub chunk_size: u64,
+    pub chunking: Chunking,
+    pub chunk_hash: ChunkHash,
     //golem uses sha-3 (sha224) for checking integrity of downloaded images
     pub sha3: [u8; 28],
     pub chunks: Vec<FileChunk>,
+    /// Only in v2 descriptors
+    pub extensions: Vec<DescriptorExtension>,
+    /// Header fields of later v2 revisions not known to this version, written back unchanged
+    pub header_extra: Vec<u8>,
     //descriptor hash of FileChunkDesc, it is used for unique identification of descriptor
     pub descr_hash: [u8; 32],
 }
@@ -33,21 +204,92 @@ impl FileChunkDesc {
         hex::encode(self.descr_hash)
     }
 
+    /// Image link (sha3 of the whole file) used in Golem SDKs
+    pub fn get_sha3_str(self: &FileChunkDesc) -> String {
+        hex::encode(self.sha3)
+    }
+
+    /// Format version number: 1 or 2
+    pub fn format_version(&self) -> u8 {
+        if self.version == VERSION_V2 {
+            2
+        } else {
+            1
+        }
+    }
+
+    pub fn format(&self) -> DescriptorFormat {
+        DescriptorFormat {
+            chunking: self.chunking,
+            chunk_hash: self.chunk_hash,
+            v2: self.version == VERSION_V2,
+        }
+    }
+
+    /// Chunks with the same content as in the other descriptor
+    pub fn shared_chunks(&self, other: &FileChunkDesc) -> usize {
+        if self.chunk_hash != other.chunk_hash {
+            return 0;
+        }
+        let other_chunks: std::collections::HashSet<[u8; 32]> =
+            other.chunks.iter().map(|chunk| chunk.hash).collect();
+        self.chunks
+            .iter()
+            .filter(|chunk| other_chunks.contains(&chunk.hash))
+            .count()
+    }
+
+    pub fn extension(&self, kind: u16) -> Option<&[u8]> {
+        self.extensions
+            .iter()
+            .find(|extension| extension.kind == kind)
+            .map(|extension| extension.data.as_slice())
+    }
+
+    /// Adds or replaces extension (v2 only), descriptor hash is updated
+    pub fn set_extension(&mut self, kind: u16, data: Vec<u8>) -> anyhow::Result<()> {
+        if self.version != VERSION_V2 {
+            return Err(anyhow::anyhow!(
+                "Extensions are supported only in v2 descriptors"
+            ));
+        }
+        match self.extensions.iter_mut().find(|e| e.kind == kind) {
+            Some(extension) => extension.data = data,
+            None => self.extensions.push(DescriptorExtension { kind, data }),
+        }
+        self.update_descr_hash();
+        Ok(())
+    }
+
+    pub fn update_descr_hash(&mut self) {
+        self.descr_hash = Sha256::digest(self.serialize_to_bytes()).into();
+    }
+
+    /// v1 layout: version, size, chunk size, sha3, sha256 of every chunk.
+    ///
+    /// v2 layout (big endian): version u64, header length u32, chunking u8, chunk hash u8,
+    /// reserved u16, size u64, chunk size u64, sha3 [28], number of chunks u64,
+    /// number of extensions u32; then length u64 and hash [32] of every chunk;
+    /// then extensions: kind u16, length u32, data.
+    /// Readers skip unknown header fields using header length (and keep them to write the same descriptor back).
     pub fn serialize_to_bytes(self: &FileChunkDesc) -> Vec<u8> {
+        if self.version == VERSION_V2 {
+            return self.serialize_v2_to_bytes();
+        }
         let expected_length = 8 + 8 + 8 + 28 + self.chunks.len() * 32;
         let mut bytes = Vec::with_capacity(expected_length);
         bytes.extend_from_slice(&self.version.to_be_bytes());
         bytes.extend_from_slice(&self.size.to_be_bytes());
         bytes.extend_from_slice(&self.chunk_size.to_be_bytes());
         bytes.extend_from_slice(&self.sha3);
-        let number_of_chunks = (self.size + self.chunk_size - 1) / self.chunk_size;
+        let number_of_chunks = self.size.div_ceil(self.chunk_size);
         if number_of_chunks != self.chunks.len() as u64 {
             //sanity check
             eprintln!("File descriptor {:?}", self);
             panic!("number of chunks is not equal to size / chunk_size. This should not happen. {} vs {}", number_of_chunks, self.chunks.len());
         }
         for chunk in &self.chunks {
-            bytes.extend_from_slice(&chunk.sha256);
+            bytes.extend_from_slice(&chunk.hash);
         }
         if bytes.len() != expected_length {
             panic!(
@@ -59,6 +301,127 @@ impl FileChunkDesc {
         bytes
     }
 
+    fn serialize_v2_to_bytes(&self) -> Vec<u8> {
+        let extensions_length: usize = self.extensions.iter().map(|e| 6 + e.data.len()).sum();
+        let header_length = V2_HEADER_LENGTH + self.header_extra.len();
+        let mut bytes =
+            Vec::with_capacity(header_length + self.chunks.len() * 40 + extensions_length);
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        bytes.extend_from_slice(&(header_length as u32).to_be_bytes());
+        bytes.push(self.chunking.id());
+        bytes.push(self.chunk_hash.id());
+        bytes.extend_from_slice(&0_u16.to_be_bytes());
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes.extend_from_slice(&self.chunk_size.to_be_bytes());
+        bytes.extend_from_slice(&self.sha3);
+        bytes.extend_from_slice(&(self.chunks.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(&(self.extensions.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&self.header_extra);
+        for chunk in &self.chunks {
+            bytes.extend_from_slice(&chunk.len.to_be_bytes());
+            bytes.extend_from_slice(&chunk.hash);
+        }
+        for extension in &self.extensions {
+            bytes.extend_from_slice(&extension.kind.to_be_bytes());
+            bytes.extend_from_slice(&(extension.data.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&extension.data);
+        }
+        bytes
+    }
+
+    fn deserialize_v2_from_bytes(bytes: &[u8]) -> Result<Self, anyhow::Error> {
+        let mut reader = ByteReader { bytes, offset: 8 };
+        let header_length = reader.u32()? as usize;
+        if header_length < V2_HEADER_LENGTH || header_length > bytes.len() {
+            return Err(anyhow::anyhow!(
+                "Invalid descriptor header length {}",
+                header_length
+            ));
+        }
+        let chunking = Chunking::from_id(reader.take(1)?[0])?;
+        let chunk_hash = ChunkHash::from_id(reader.take(1)?[0])?;
+        //written as zero, so it could not be written back if set
+        let reserved = reader.take(2)?;
+        if reserved != [0, 0] {
+            return Err(anyhow::anyhow!(
+                "Unsupported descriptor flags {}",
+                hex::encode(reserved)
+            ));
+        }
+        let size = reader.u64()?;
+        let chunk_size = reader.u64()?;
+        let mut sha3 = [0_u8; 28];
+        sha3.copy_from_slice(reader.take(28)?);
+        let number_of_chunks = reader.u64()?;
+        let number_of_extensions = reader.u32()?;
+        //fields added in later versions of the format
+        let header_extra = reader.take(header_length - V2_HEADER_LENGTH)?.to_vec();
+        if chunk_size == 0 {
+            return Err(anyhow::anyhow!("Invalid descriptor chunk size 0"));
+        }
+
+        if number_of_chunks > (bytes.len() / 40) as u64 {
+            return Err(anyhow::anyhow!(
+                "Invalid descriptor, {} chunks do not fit in {} bytes",
+                number_of_chunks,
+                bytes.len()
+            ));
+        }
+        //fixed chunk positions are also computed as chunk_no * chunk_size
+        if chunking == Chunking::Fixed && number_of_chunks != size.div_ceil(chunk_size) {
+            return Err(anyhow::anyhow!(
+                "Invalid descriptor, {} fixed chunks of {} bytes do not match size {}",
+                number_of_chunks,
+                chunk_size,
+                size
+            ));
+        }
+        let chunks = read_chunk_lengths(&mut reader, number_of_chunks, size)?;
+        if chunking == Chunking::Fixed {
+            let last_chunk_no = number_of_chunks.saturating_sub(1);
+            if let Some(chunk) = chunks.iter().find(|chunk| {
+                chunk.len > chunk_size
+                    || (chunk.chunk_no != last_chunk_no && chunk.len != chunk_size)
+            }) {
+                return Err(anyhow::anyhow!(
+                    "Invalid descriptor, fixed chunk {} has length {} with chunk size {}",
+                    chunk.chunk_no,
+                    chunk.len,
+                    chunk_size
+                ));
+            }
+        }
+        let mut extensions = Vec::new();
+        for _ in 0..number_of_extensions {
+            let kind = u16::from_be_bytes(reader.take(2)?.try_into()?);
+            let len = reader.u32()? as usize;
+            extensions.push(DescriptorExtension {
+                kind,
+                data: reader.take(len)?.to_vec(),
+            });
+        }
+        if reader.offset != bytes.len() {
+            return Err(anyhow::anyhow!(
+                "Invalid descriptor expected length {} vs {}",
+                reader.offset,
+                bytes.len()
+            ));
+        }
+        Ok(FileChunkDesc {
+            version: VERSION_V2,
+            size,
+            chunk_size,
+            chunking,
+            chunk_hash,
+            sha3,
+            chunks,
+            extensions,
+            header_extra,
+            descr_hash: Sha256::digest(bytes).into(),
+        })
+    }
+
+    /// Reads v1 and v2 descriptors
     pub fn deserialize_from_bytes(bytes: &[u8]) -> Result<Self, anyhow::Error> {
         let mut offset = 0;
         if bytes.len() < 8 {
@@ -66,26 +429,35 @@ impl FileChunkDesc {
         }
         let version_bytes = u64::from_be_bytes(bytes[offset..offset + 8].try_into()?);
         offset += 8;
+        if version_bytes == VERSION_V2 {
+            return Self::deserialize_v2_from_bytes(bytes);
+        }
         if version_bytes != VERSION_AND_HEADER {
             return Err(anyhow::anyhow!(
                 "Invalid descriptor version {}",
                 version_bytes
             ));
         }
+        if bytes.len() < 8 + 8 + 8 + 28 {
+            return Err(anyhow::anyhow!("Invalid descriptor length {}", bytes.len()));
+        }
 
         let size = u64::from_be_bytes(bytes[offset..offset + 8].try_into()?);
         offset += 8;
         let chunk_size = u64::from_be_bytes(bytes[offset..offset + 8].try_into()?);
         offset += 8;
-        let number_of_chunks = ((size + chunk_size - 1) / chunk_size) as usize;
+        if chunk_size == 0 {
+            return Err(anyhow::anyhow!("Invalid descriptor chunk size 0"));
+        }
+        let number_of_chunks = size.div_ceil(chunk_size) as usize;
         let mut sha3 = [0_u8; 28];
         sha3.copy_from_slice(&bytes[offset..offset + 28]);
         offset += 28;
 
-        if bytes.len() != offset + number_of_chunks * 32 {
+        if Some(bytes.len()) != number_of_chunks.checked_mul(32).map(|len| offset + len) {
             return Err(anyhow::anyhow!(
                 "Invalid descriptor expected length {} vs {}",
-                offset + number_of_chunks * 32,
+                offset + number_of_chunks.saturating_mul(32),
                 bytes.len()
             ));
         }
@@ -97,21 +469,25 @@ impl FileChunkDesc {
             version: version_bytes,
             size,
             chunk_size,
+            chunking: Chunking::Fixed,
+            chunk_hash: ChunkHash::Sha256,
             sha3,
             chunks: Vec::with_capacity(number_of_chunks),
+            extensions: Vec::new(),
+            header_extra: Vec::new(),
             descr_hash: sha256.into(),
         };
 
         let mut file_pos = 0;
         for chunk_no in 0..number_of_chunks {
             let chunk_length = std::cmp::min(chunk_size, size - file_pos);
-            let mut sha256 = [0_u8; 32];
-            sha256.copy_from_slice(&bytes[offset..offset + 32]);
+            let mut hash = [0_u8; 32];
+            hash.copy_from_slice(&bytes[offset..offset + 32]);
             descr.chunks.push(FileChunk {
                 chunk_no: chunk_no as u64,
                 pos: file_pos,
                 len: chunk_length,
-                sha256,
+                hash,
             });
             file_pos += chunk_size;
             offset += 32;
@@ -120,10 +496,96 @@ impl FileChunkDesc {
     }
 }
 
+/// Reads length and hash of every chunk, chunks have to cover the whole file
+fn read_chunk_lengths(
+    reader: &mut ByteReader,
+    number_of_chunks: u64,
+    size: u64,
+) -> anyhow::Result<Vec<FileChunk>> {
+    let mut chunks = Vec::with_capacity(number_of_chunks as usize);
+    let mut pos = 0_u64;
+    for chunk_no in 0..number_of_chunks {
+        let len = reader.u64()?;
+        if len == 0 || len > size {
+            return Err(anyhow::anyhow!(
+                "Invalid descriptor, chunk {} has length {}",
+                chunk_no,
+                len
+            ));
+        }
+        let mut hash = [0_u8; 32];
+        hash.copy_from_slice(reader.take(32)?);
+        chunks.push(FileChunk {
+            chunk_no,
+            pos,
+            len,
+            hash,
+        });
+        pos = pos.checked_add(len).ok_or_else(|| {
+            anyhow::anyhow!("Invalid descriptor, chunks cover more than {} bytes", size)
+        })?;
+    }
+    if pos != size {
+        return Err(anyhow::anyhow!(
+            "Invalid descriptor, chunks cover {} bytes of {}",
+            pos,
+            size
+        ));
+    }
+    Ok(chunks)
+}
+
+/// Bounds checked big endian reader
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| anyhow::anyhow!("Descriptor truncated at {}", self.offset))?;
+        let data = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(data)
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> anyhow::Result<u64> {
+        Ok(u64::from_be_bytes(self.take(8)?.try_into()?))
+    }
+}
+
+/// Creates v1 descriptor with fixed size sha256 chunks
 pub async fn create_descriptor_from_reader<AsyncReader>(
+    reader: AsyncReader,
+    file_size: u64,
+    chunk_size: usize,
+) -> anyhow::Result<FileChunkDesc>
+where
+    AsyncReader: tokio::io::AsyncRead + Unpin,
+{
+    create_descriptor_with_format_from_reader(
+        reader,
+        file_size,
+        chunk_size,
+        DescriptorFormat::default(),
+    )
+    .await
+}
+
+/// Creates descriptor in given format, for content-defined chunking `chunk_size` is the average size
+pub async fn create_descriptor_with_format_from_reader<AsyncReader>(
     mut reader: AsyncReader,
     file_size: u64,
     chunk_size: usize,
+    format: DescriptorFormat,
 ) -> anyhow::Result<FileChunkDesc>
 where
     AsyncReader: tokio::io::AsyncRead + Unpin,
@@ -131,56 +593,200 @@ where
     let pb = create_chunk_pb(file_size, ProgressBarType::CreateDescriptor);
     pb.set_message("Reading file");
 
+    let chunker = match format.chunking {
+        Chunking::Fixed => None,
+        Chunking::ContentDefined => Some(ContentChunker::new(chunk_size)),
+    };
+    //cut point of content-defined chunk may be anywhere up to max chunk size
+    let window = chunker
+        .as_ref()
+        .map(ContentChunker::max_size)
+        .unwrap_or(chunk_size);
     let mut file_chunks = Vec::new();
-    let mut offset = 0;
-    let mut sha256_chunk = Sha256::new();
-    let mut buffer = vec![0; chunk_size];
-    let mut chunk_no = 0;
     let mut sha3 = Sha3_224::new();
+    let mut buffer = Vec::with_capacity(window);
+    let mut read = 0;
+    let mut offset = 0;
     while offset < file_size {
-        let chunk_size = std::cmp::min(chunk_size, (file_size - offset) as usize);
-        buffer.resize(chunk_size, 0);
-
-        reader.read_exact(&mut buffer).await.unwrap();
-        sha256_chunk.update(&buffer);
-        sha3.update(&buffer);
-        let chunk = FileChunk {
-            chunk_no,
-            pos: offset,
-            len: chunk_size as u64,
-            sha256: sha256_chunk.finalize_reset().into(),
+        let to_read = (window - buffer.len()).min((file_size - read) as usize);
+        if to_read > 0 {
+            let start = buffer.len();
+            buffer.resize(start + to_read, 0);
+            reader.read_exact(&mut buffer[start..]).await?;
+            read += to_read as u64;
+        }
+        let len = match &chunker {
+            Some(chunker) => chunker.cut(&buffer),
+            None => buffer.len(),
         };
-        chunk_no += 1;
-        file_chunks.push(chunk);
-        offset += chunk_size as u64;
-        pb.inc(chunk_size as u64);
+        let chunk = &buffer[..len];
+        sha3.update(chunk);
+        file_chunks.push(FileChunk {
+            chunk_no: file_chunks.len() as u64,
+            pos: offset,
+            len: len as u64,
+            hash: format.chunk_hash.digest(chunk),
+        });
+        buffer.drain(..len);
+        offset += len as u64;
+        pb.inc(len as u64);
     }
 
     let mut fcd = FileChunkDesc {
-        version: VERSION_AND_HEADER,
+        version: format.version(),
         size: file_size,
-        chunk_size: chunk_size as u64,
+        chunk_size: chunker
+            .as_ref()
+            .map(|chunker| chunker.avg_size())
+            .unwrap_or(chunk_size) as u64,
+        chunking: format.chunking,
+        chunk_hash: format.chunk_hash,
         chunks: file_chunks,
         sha3: sha3.finalize().into(),
+        extensions: Vec::new(),
+        header_extra: Vec::new(),
         descr_hash: [0; 32],
     };
-
-    let bytes = fcd.serialize_to_bytes();
-    let mut sha2 = sha2::Sha256::new();
-    sha2.update(&bytes);
-    fcd.descr_hash = sha2.finalize().into();
+    fcd.update_descr_hash();
     Ok(fcd)
 }
 
 pub async fn create_descriptor(path: &Path, chunk_size: usize) -> anyhow::Result<FileChunkDesc> {
+    create_descriptor_with_format(path, chunk_size, DescriptorFormat::default()).await
+}
+
+/// Creates descriptor of gvmi file, v2 descriptor also gets hash of image metadata (if present)
+pub async fn create_descriptor_with_format(
+    path: &Path,
+    chunk_size: usize,
+    format: DescriptorFormat,
+) -> anyhow::Result<FileChunkDesc> {
     let file = File::open(path).await?;
-    let file_size = file.metadata().await.unwrap().len();
-    create_descriptor_from_reader(file, file_size, chunk_size).await
+    let file_size = file.metadata().await?.len();
+    let mut descr =
+        create_descriptor_with_format_from_reader(file, file_size, chunk_size, format).await?;
+    if descr.version == VERSION_V2 {
+        if let Ok(footer) = read_metadata_footer(path) {
+            descr.set_extension(
+                DescriptorExtension::METADATA_HASH,
+                Sha256::digest(&footer.json).to_vec(),
+            )?;
+        }
+    }
+    Ok(descr)
+}
+
+/// Chunk size used for upload when not given explicitly, depends on image size
+pub fn default_chunk_size(image_file_size: u64) -> u64 {
+    if image_file_size > 1024 * 1024 * 1024 * 5 {
+        100 * 1024 * 1024
+    } else if image_file_size > 1024 * 1024 * 1024 * 2 {
+        50 * 1024 * 1024
+    } else if image_file_size > 1024 * 1024 * 1024 {
+        20 * 1024 * 1024
+    } else if image_file_size > 1024 * 1024 * 500 {
+        10 * 1024 * 1024
+    } else if image_file_size > 1024 * 1024 * 200 {
+        5 * 1024 * 1024
+    } else {
+        2 * 1024 * 1024
+    }
+}
+
+/// Path of the descriptor file kept next to the image (`<image>.descr.bin`)
+pub fn descriptor_path(image_path: &Path) -> PathBuf {
+    PathBuf::from(image_path.display().to_string() + ".descr.bin")
+}
+
+/// Reads descriptor saved next to the image or creates a new one if it is missing,
+/// outdated or was created with different chunk size or format.
+/// Returns descriptor together with the path of the descriptor file.
+pub async fn load_or_create_descriptor(
+    path: &Path,
+    chunk_size: u64,
+    format: DescriptorFormat,
+) -> anyhow::Result<(FileChunkDesc, PathBuf)> {
+    let descr_path = descriptor_path(path);
+    let path_meta = fs::metadata(&path).await?;
+    //previous descriptor of the rebuilt image, used to report reused chunks
+    let mut previous = None;
+    let descr = if descr_path.exists() {
+        if fs::metadata(&descr_path)
+            .await?
+            .modified()
+            .expect("Modified field has to be here")
+            < path_meta.modified().expect("Modified field has to be here")
+        {
+            println!(" -- File descriptor is older than image, recreating");
+            previous = fs::read(&descr_path)
+                .await
+                .ok()
+                .and_then(|bytes| FileChunkDesc::deserialize_from_bytes(&bytes).ok());
+            None
+        } else {
+            match fs::read(&descr_path).await {
+                Ok(file_descr_bytes) => {
+                    match FileChunkDesc::deserialize_from_bytes(&file_descr_bytes) {
+                        Ok(descr) => {
+                            if descr.chunk_size != chunk_size {
+                                println!(" -- chunk size changed, recreating file descriptor");
+                                None
+                            } else if descr.version != format.version()
+                                || descr.chunking != format.chunking
+                                || descr.chunk_hash != format.chunk_hash
+                            {
+                                println!(
+                                    " -- descriptor format changed, recreating file descriptor"
+                                );
+                                None
+                            } else {
+                                println!(" -- file descriptor already exists and is newer");
+                                println!("Image link (for use in SDK): {}", descr.get_sha3_str());
+                                Some(descr)
+                            }
+                        }
+                        Err(e) => {
+                            println!(" -- failed to deserialize file descriptor: {}", e);
+                            None
+                        }
+                    }
+                }
+                Err(e) => {
+                    println!(
+                        " -- failed to read file descriptor: {} {}",
+                        descr_path.display(),
+                        e
+                    );
+                    None
+                }
+            }
+        }
+    } else {
+        None
+    };
+    if let Some(descr) = descr {
+        Ok((descr, descr_path))
+    } else {
+        println!(" * Writing file descriptor to {}", descr_path.display());
+        let mut file = File::create(&descr_path).await?;
+        let descr = create_descriptor_with_format(path, chunk_size as usize, format).await?;
+        file.write_all(&descr.serialize_to_bytes()).await?;
+        println!(" -- file descriptor created successfully");
+        if let Some(previous) = previous.filter(|previous| previous.chunking == format.chunking) {
+            println!(
+                " -- {} of {} chunks unchanged since previous descriptor",
+                descr.shared_chunks(&previous),
+                descr.chunks.len()
+            );
+        }
+        println!(" -- image link (for use in SDK): {}", descr.get_sha3_str());
+        Ok((descr, descr_path))
+    }
 }
 
 #[tokio::test]
 async fn test_descriptor_creation() {
-    let rng = fastrand::Rng::new();
+    let mut rng = fastrand::Rng::new();
     rng.seed(1234);
 
     use std::iter::repeat_with;
@@ -219,7 +825,7 @@ async fn test_descriptor_creation() {
             "Chunk: pos: {} len: {} hash: {}",
             chunk.pos,
             chunk.len,
-            hex::encode(chunk.sha256)
+            hex::encode(chunk.hash)
         );
     }
     for chunk in &descr2.chunks {
@@ -227,7 +833,7 @@ async fn test_descriptor_creation() {
             "Chunk: pos: {} len: {} hash: {}",
             chunk.pos,
             chunk.len,
-            hex::encode(chunk.sha256)
+            hex::encode(chunk.hash)
         );
     }
 
@@ -238,7 +844,7 @@ async fn test_descriptor_creation() {
     let bytes_empty = descr_empty.serialize_to_bytes();
     assert_eq!(bytes_empty.len(), 8 + 8 + 8 + 28);
     let bytes_single = descr_single.serialize_to_bytes();
-    assert_eq!(bytes_single.len(), 8 + 8 + 8 + 28 + 1 * 32);
+    assert_eq!(bytes_single.len(), 8 + 8 + 8 + 28 + 32);
 
     let descr_de1 = FileChunkDesc::deserialize_from_bytes(&bytes1).unwrap();
     assert_eq!(descr_de1, descr1);
@@ -248,4 +854,126 @@ async fn test_descriptor_creation() {
     assert_eq!(descr_de_empty, descr_empty);
     let descr_de_single = FileChunkDesc::deserialize_from_bytes(&bytes_single).unwrap();
     assert_eq!(descr_de_single, descr_single);
+
+    //content-defined chunks survive insertion of bytes near the start of file
+    let image: Vec<u8> = repeat_with(|| rng.u8(..)).take(100_000).collect();
+    let mut rebuilt = image.clone();
+    rebuilt.splice(500..500, *b"new file");
+    let cdc = DescriptorFormat {
+        chunking: Chunking::ContentDefined,
+        ..Default::default()
+    };
+    let descr_cdc =
+        create_descriptor_with_format_from_reader(&image[..], image.len() as u64, 2048, cdc)
+            .await
+            .unwrap();
+    let descr_rebuilt =
+        create_descriptor_with_format_from_reader(&rebuilt[..], rebuilt.len() as u64, 2048, cdc)
+            .await
+            .unwrap();
+    assert_eq!(descr_cdc.chunking, Chunking::ContentDefined);
+    assert_eq!(descr_cdc.version, VERSION_V2);
+    assert_eq!(
+        descr_cdc.get_sha3_str(),
+        hex::encode(Sha3_224::digest(&image))
+    );
+    assert!(descr_rebuilt.shared_chunks(&descr_cdc) + 2 >= descr_cdc.chunks.len());
+    let descr_fixed = create_descriptor_from_reader(&image[..], image.len() as u64, 2048)
+        .await
+        .unwrap();
+    let rebuilt_fixed = create_descriptor_from_reader(&rebuilt[..], rebuilt.len() as u64, 2048)
+        .await
+        .unwrap();
+    assert_eq!(rebuilt_fixed.shared_chunks(&descr_fixed), 0);
+
+    let bytes_cdc = descr_cdc.serialize_to_bytes();
+    assert_eq!(
+        bytes_cdc.len(),
+        V2_HEADER_LENGTH + descr_cdc.chunks.len() * 40
+    );
+    let descr_de_cdc = FileChunkDesc::deserialize_from_bytes(&bytes_cdc).unwrap();
+    assert_eq!(descr_de_cdc, descr_cdc);
+
+    assert!(FileChunkDesc::deserialize_from_bytes(&bytes_cdc[..bytes_cdc.len() - 1]).is_err());
+
+    //v2 with blake3 chunks and extensions
+    let blake3_format = DescriptorFormat {
+        chunk_hash: ChunkHash::Blake3,
+        ..Default::default()
+    };
+    let mut descr_blake3 = create_descriptor_with_format_from_reader(
+        &image[..],
+        image.len() as u64,
+        4096,
+        blake3_format,
+    )
+    .await
+    .unwrap();
+    assert_eq!(descr_blake3.version, VERSION_V2);
+    assert_eq!(descr_blake3.chunks.len(), 25);
+    assert_eq!(
+        descr_blake3.chunks[0].hash,
+        *blake3::hash(&image[..4096]).as_bytes()
+    );
+    assert_eq!(descr_blake3.get_sha3_str(), descr_fixed.get_sha3_str());
+    assert_eq!(descr_blake3.shared_chunks(&descr_fixed), 0);
+    let hash_before = descr_blake3.descr_hash;
+    descr_blake3
+        .set_extension(DescriptorExtension::METADATA_HASH, vec![7; 32])
+        .unwrap();
+    descr_blake3
+        .set_extension(99, b"unknown extension".to_vec())
+        .unwrap();
+    assert_ne!(descr_blake3.descr_hash, hash_before);
+    let bytes_blake3 = descr_blake3.serialize_to_bytes();
+    assert_eq!(
+        hex::encode(Sha256::digest(&bytes_blake3)),
+        descr_blake3.get_descr_hash_str()
+    );
+    let descr_de_blake3 = FileChunkDesc::deserialize_from_bytes(&bytes_blake3).unwrap();
+    assert_eq!(descr_de_blake3, descr_blake3);
+    assert_eq!(
+        descr_de_blake3.extension(DescriptorExtension::METADATA_HASH),
+        Some(&[7; 32][..])
+    );
+    assert_eq!(
+        descr_de_blake3.extension(DescriptorExtension::SIGNATURE),
+        None
+    );
+
+    //readers skip header fields added in later revisions of v2
+    let mut longer_header = bytes_blake3[..V2_HEADER_LENGTH].to_vec();
+    longer_header[8..12].copy_from_slice(&(V2_HEADER_LENGTH as u32 + 4).to_be_bytes());
+    longer_header.extend_from_slice(&[0xff; 4]);
+    longer_header.extend_from_slice(&bytes_blake3[V2_HEADER_LENGTH..]);
+    let descr_longer = FileChunkDesc::deserialize_from_bytes(&longer_header).unwrap();
+    assert_eq!(descr_longer.chunks, descr_blake3.chunks);
+    assert_eq!(descr_longer.extensions, descr_blake3.extensions);
+    assert_eq!(descr_longer.serialize_to_bytes(), longer_header);
+    assert_eq!(
+        descr_longer.get_descr_hash_str(),
+        hex::encode(Sha256::digest(&longer_header))
+    );
+    let mut flags = bytes_blake3.clone();
+    flags[14] = 1;
+    assert!(FileChunkDesc::deserialize_from_bytes(&flags).is_err());
+
+    //chunks of zero length or longer than the file are rejected
+    let chunk_len_offset = V2_HEADER_LENGTH + 40;
+    for len in [0, u64::MAX, u64::MAX - 4095] {
+        let mut damaged = bytes_blake3.clone();
+        damaged[chunk_len_offset..chunk_len_offset + 8].copy_from_slice(&len.to_be_bytes());
+        assert!(FileChunkDesc::deserialize_from_bytes(&damaged).is_err());
+    }
+    //fixed chunks must all have chunk size, except the last one, even if lengths add up
+    let mut shifted = bytes_blake3.clone();
+    shifted[V2_HEADER_LENGTH..V2_HEADER_LENGTH + 8].copy_from_slice(&4000_u64.to_be_bytes());
+    shifted[chunk_len_offset..chunk_len_offset + 8].copy_from_slice(&4192_u64.to_be_bytes());
+    assert!(FileChunkDesc::deserialize_from_bytes(&shifted).is_err());
+
+    //extensions are not possible in v1
+    let mut descr_v1 = descr1.clone();
+    assert!(descr_v1
+        .set_extension(DescriptorExtension::SIGNATURE, vec![1])
+        .is_err());
 }
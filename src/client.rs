@@ -0,0 +1,134 @@
+use std::time::Duration;
+
+use reqwest::RequestBuilder;
+
+use crate::retry::RetryPolicy;
+use crate::throttle::RateLimiter;
+
+pub const DEFAULT_REGISTRY_URL: &str = "https://registry.golem.network";
+
+/// User name and personal access token used for attaching images to repositories
+#[derive(Debug, Clone)]
+pub struct RegistryCredentials {
+    pub user_name: String,
+    pub token: String,
+}
+
+/// Connection to single Golem Registry instance.
+/// The underlying http client keeps connection pool, so clone this object instead of creating new one.
+#[derive(Debug, Clone)]
+pub struct RegistryClient {
+    http: reqwest::Client,
+    base_url: String,
+    credentials: Option<RegistryCredentials>,
+    timeout: Duration,
+    user_agent: String,
+    retry: RetryPolicy,
+    upload_limiter: Option<RateLimiter>,
+}
+
+impl RegistryClient {
+    pub fn new(base_url: &str) -> anyhow::Result<Self> {
+        let http = reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(30))
+            .pool_idle_timeout(Duration::from_secs(90))
+            .build()
+            .map_err(|e| anyhow::anyhow!("Failed to create http client: {}", e))?;
+        Ok(RegistryClient {
+            http,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            credentials: None,
+            timeout: Duration::from_secs(60),
+            user_agent: format!("gvmkit-build/{}", env!("CARGO_PKG_VERSION")),
+            retry: RetryPolicy::default(),
+            upload_limiter: None,
+        })
+    }
+
+    /// Registry given in REGISTRY_URL env variable or default Golem Registry
+    pub fn from_env() -> anyhow::Result<Self> {
+        let base_url =
+            std::env::var("REGISTRY_URL").unwrap_or_else(|_| DEFAULT_REGISTRY_URL.to_string());
+        Self::new(&base_url)
+    }
+
+    pub fn with_credentials(mut self, user_name: &str, token: &str) -> Self {
+        self.credentials = Some(RegistryCredentials {
+            user_name: user_name.to_string(),
+            token: token.to_string(),
+        });
+        self
+    }
+
+    /// Timeout of api requests (chunk transfers are limited by retry policy `chunk_timeout`)
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = user_agent.to_string();
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Limits total bandwidth of chunk uploads, shared by all clones of this client
+    pub fn with_upload_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.upload_limiter = Some(limiter);
+        self
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn credentials(&self) -> Option<&RegistryCredentials> {
+        self.credentials.as_ref()
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    pub fn upload_limiter(&self) -> Option<&RateLimiter> {
+        self.upload_limiter.as_ref()
+    }
+
+    pub(crate) fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// Api request limited by client timeout
+    pub(crate) fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        self.transfer(method, path).timeout(self.timeout)
+    }
+
+    /// Request transferring image data, not limited by client timeout
+    pub(crate) fn transfer(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        self.http
+            .request(method, self.url(path))
+            .header(reqwest::header::USER_AGENT, &self.user_agent)
+    }
+}
+
+#[test]
+fn test_registry_client() {
+    let client = RegistryClient::new("http://localhost:8080/")
+        .unwrap()
+        .with_credentials("golem", "token");
+    assert_eq!(client.base_url(), "http://localhost:8080");
+    assert_eq!(
+        client.url("/v1/image/push/descr"),
+        "http://localhost:8080/v1/image/push/descr"
+    );
+    assert_eq!(client.credentials().unwrap().user_name, "golem");
+
+    //clones share connection pool but keep own settings
+    let other = client.clone().with_retry_policy(RetryPolicy::no_retries());
+    assert_eq!(other.retry_policy().max_retries, 0);
+    assert_eq!(client.retry_policy().max_retries, 5);
+}
@@ -0,0 +1,289 @@
+//! Delta push: chunks of the new image that the registry already stores as part of a previous version.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use humansize::DECIMAL;
+use serde::Serialize;
+
+use crate::backend::RegistryBackend;
+use crate::chunks::FileChunkDesc;
+use crate::image::ImageName;
+use crate::journal::UploadJournal;
+
+/// Previous version of the image used as delta base
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeltaBase {
+    /// Image attached to the tag in the registry
+    Tag(ImageName),
+    /// Local descriptor (`.descr.bin`) of image pushed before
+    Descriptor(PathBuf),
+}
+
+impl DeltaBase {
+    /// Existing file is treated as a descriptor, anything else as a tag, e.g. `golem/my-image:v1`
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let path = Path::new(value);
+        if path.is_file() {
+            return Ok(DeltaBase::Descriptor(path.to_path_buf()));
+        }
+        Ok(DeltaBase::Tag(ImageName::from_str_name(value)?))
+    }
+
+    pub async fn load<B: RegistryBackend>(&self, registry: &B) -> anyhow::Result<FileChunkDesc> {
+        match self {
+            DeltaBase::Tag(image_name) => registry.tagged_descriptor(image_name).await,
+            DeltaBase::Descriptor(path) => {
+                FileChunkDesc::deserialize_from_bytes(&tokio::fs::read(path).await?)
+            }
+        }
+    }
+}
+
+impl fmt::Display for DeltaBase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeltaBase::Tag(image_name) => write!(f, "{}", image_name.to_normalized_name()),
+            DeltaBase::Descriptor(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Chunk of the new image with the same content as chunk of the base image
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkReuse {
+    pub chunk_no: u64,
+    pub base_chunk_no: u64,
+}
+
+/// Result of comparing descriptor with the delta base
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeltaReport {
+    pub base_descr_hash: String,
+    pub reused: Vec<ChunkReuse>,
+    pub reused_bytes: u64,
+    pub new_chunks: usize,
+    pub new_bytes: u64,
+    /// Chunks the registry copied from the base, filled by [`reuse_base_chunks`]
+    pub accepted: Vec<u64>,
+}
+
+impl DeltaReport {
+    /// Chunks are matched by hash and length, descriptors with different chunk hash share nothing
+    pub fn compare(descr: &FileChunkDesc, base: &FileChunkDesc) -> Self {
+        let mut base_chunks = HashMap::new();
+        if descr.chunk_hash == base.chunk_hash {
+            for chunk in &base.chunks {
+                base_chunks
+                    .entry((chunk.hash, chunk.len))
+                    .or_insert(chunk.chunk_no);
+            }
+        }
+        let mut report = DeltaReport {
+            base_descr_hash: base.get_descr_hash_str(),
+            reused: Vec::new(),
+            reused_bytes: 0,
+            new_chunks: 0,
+            new_bytes: 0,
+            accepted: Vec::new(),
+        };
+        for chunk in &descr.chunks {
+            match base_chunks.get(&(chunk.hash, chunk.len)) {
+                Some(base_chunk_no) => {
+                    report.reused.push(ChunkReuse {
+                        chunk_no: chunk.chunk_no,
+                        base_chunk_no: *base_chunk_no,
+                    });
+                    report.reused_bytes += chunk.len;
+                }
+                None => {
+                    report.new_chunks += 1;
+                    report.new_bytes += chunk.len;
+                }
+            }
+        }
+        report
+    }
+}
+
+impl fmt::Display for DeltaReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} new chunks ({}), {} reused ({})",
+            self.new_chunks,
+            humansize::format_size(self.new_bytes, DECIMAL),
+            self.reused.len(),
+            humansize::format_size(self.reused_bytes, DECIMAL)
+        )
+    }
+}
+
+/// Compares descriptor with the base and asks the registry to copy shared chunks.
+/// Chunks accepted by the registry are marked as uploaded in the journal,
+/// everything else (also when the registry does not support reuse) is uploaded as usual.
+pub async fn reuse_base_chunks<B: RegistryBackend>(
+    registry: &B,
+    descr: &FileChunkDesc,
+    base: &DeltaBase,
+    journal: &Arc<Mutex<UploadJournal>>,
+) -> anyhow::Result<DeltaReport> {
+    let base_descr = base.load(registry).await?;
+    let mut report = DeltaReport::compare(descr, &base_descr);
+    println!(" -- delta against {base}: {report}");
+
+    let to_reuse: Vec<ChunkReuse> = {
+        let journal = journal.lock().unwrap();
+        report
+            .reused
+            .iter()
+            .filter(|reuse| !journal.is_uploaded(reuse.chunk_no))
+            .copied()
+            .collect()
+    };
+    if to_reuse.is_empty() {
+        return Ok(report);
+    }
+    match registry
+        .reuse_chunks(
+            &descr.get_descr_hash_str(),
+            &report.base_descr_hash,
+            &to_reuse,
+        )
+        .await?
+    {
+        Some(accepted) => {
+            let mut journal = journal.lock().unwrap();
+            for chunk_no in &accepted {
+                journal.record_success(*chunk_no);
+            }
+            println!(
+                " -- {} of {} chunks reused by registry",
+                accepted.len(),
+                to_reuse.len()
+            );
+            report.accepted = accepted;
+        }
+        None => {
+            println!(" -- registry does not support chunk reuse, uploading all chunks");
+        }
+    }
+    Ok(report)
+}
+
+#[tokio::test]
+async fn test_delta_push() {
+    use crate::chunks::create_descriptor;
+    use crate::mock::MockRegistry;
+    use crate::retry::RetryPolicy;
+    use crate::upload::{push_image, PushOptions};
+    use std::iter::repeat_with;
+    use std::time::Duration;
+
+    let mut rng = fastrand::Rng::new();
+    rng.seed(1234);
+    let temp_dir = tempfile::tempdir().unwrap();
+    let content_v1: Vec<u8> = repeat_with(|| rng.u8(..)).take(40960).collect();
+    let mut content_v2 = content_v1.clone();
+    content_v2[5000..5100].fill(0);
+    content_v2.extend_from_slice(&[1; 1000]);
+    let path_v1 = temp_dir.path().join("v1.gvmi");
+    let path_v2 = temp_dir.path().join("v2.gvmi");
+    std::fs::write(&path_v1, &content_v1).unwrap();
+    std::fs::write(&path_v2, &content_v2).unwrap();
+    let descr_v1 = create_descriptor(&path_v1, 4096).await.unwrap();
+    let descr_v2 = create_descriptor(&path_v2, 4096).await.unwrap();
+    let descr_path_v1 = temp_dir.path().join("v1.descr.bin");
+    let descr_path_v2 = temp_dir.path().join("v2.descr.bin");
+    std::fs::write(&descr_path_v1, descr_v1.serialize_to_bytes()).unwrap();
+    std::fs::write(&descr_path_v2, descr_v2.serialize_to_bytes()).unwrap();
+
+    //chunk 1 changed, chunk 10 is new and shorter than the last chunk of v1
+    let report = DeltaReport::compare(&descr_v2, &descr_v1);
+    assert_eq!(report.reused.len(), 9);
+    assert_eq!(report.reused_bytes, 9 * 4096);
+    assert_eq!(report.new_chunks, 2);
+    assert_eq!(report.new_bytes, 4096 + 1000);
+    assert!(!report.reused.iter().any(|reuse| reuse.chunk_no == 1));
+    assert_eq!(
+        report.reused.last(),
+        Some(&ChunkReuse {
+            chunk_no: 9,
+            base_chunk_no: 9
+        })
+    );
+
+    let options = PushOptions {
+        upload_workers: 2,
+        journal_dir: temp_dir.path().join("journal"),
+        validate_interval: Duration::from_millis(1),
+        ..Default::default()
+    };
+    let tag_v1 = ImageName::from_str_name("golem/test:v1").unwrap();
+    for chunk_reuse in [true, false] {
+        let registry = MockRegistry::new()
+            .with_user("golem", "token")
+            .with_credentials("golem", "token")
+            .with_retry_policy(RetryPolicy {
+                initial_backoff: Duration::from_millis(1),
+                ..Default::default()
+            })
+            .with_chunk_reuse(chunk_reuse);
+        push_image(
+            &registry,
+            &path_v1,
+            &descr_v1,
+            &descr_path_v1,
+            Some(&tag_v1),
+            &options,
+        )
+        .await
+        .unwrap();
+        let uploaded_v1 = registry.uploaded_bytes();
+        assert_eq!(uploaded_v1, content_v1.len() as u64);
+
+        let options = PushOptions {
+            delta_base: Some(DeltaBase::Tag(tag_v1.clone())),
+            ..options.clone()
+        };
+        push_image(
+            &registry,
+            &path_v2,
+            &descr_v2,
+            &descr_path_v2,
+            None,
+            &options,
+        )
+        .await
+        .unwrap();
+        let expected = if chunk_reuse {
+            report.new_bytes
+        } else {
+            content_v2.len() as u64
+        };
+        assert_eq!(registry.uploaded_bytes() - uploaded_v1, expected);
+        assert_eq!(
+            registry.image(&descr_v2.get_descr_hash_str()),
+            Some(content_v2.clone())
+        );
+    }
+
+    //local descriptor works as base too, unknown tag is an error
+    assert_eq!(
+        DeltaBase::parse(descr_path_v1.to_str().unwrap()).unwrap(),
+        DeltaBase::Descriptor(descr_path_v1.clone())
+    );
+    assert_eq!(
+        DeltaBase::parse("golem/test:v1").unwrap(),
+        DeltaBase::Tag(tag_v1)
+    );
+    let registry = MockRegistry::new();
+    assert!(DeltaBase::parse("golem/test:v0")
+        .unwrap()
+        .load(&registry)
+        .await
+        .is_err());
+}
@@ -0,0 +1,458 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use futures_util::{stream, StreamExt};
+use indicatif::{MultiProgress, ProgressBar};
+use reqwest::Method;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::chunks::{descriptor_path, ChunkHash, FileChunk, FileChunkDesc};
+use crate::client::RegistryClient;
+use crate::image::ImageName;
+use crate::progress::{create_chunk_pb, ProgressBarType};
+use crate::verify::{verify_content, VerifyExpectation};
+
+/// Image to download, either tag in registry or descriptor hash
+#[derive(Debug, Clone)]
+pub enum PullSource {
+    Tag(ImageName),
+    Descriptor(String),
+}
+
+impl PullSource {
+    /// Accepts `<user>/<repository>:<tag>` or sha256 of the descriptor (64 hex characters)
+    pub fn parse(source: &str) -> anyhow::Result<Self> {
+        if source.len() == 64 && source.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Ok(PullSource::Descriptor(source.to_lowercase()));
+        }
+        let image_name = ImageName::from_str_name(source)?;
+        if image_name.user.is_none() {
+            return Err(anyhow!(
+                "Image name {} has to contain user, use format <username>/<repository>:<tag>",
+                source
+            ));
+        }
+        Ok(PullSource::Tag(image_name))
+    }
+
+    /// File name used when output is not given
+    pub fn default_output_name(&self) -> String {
+        match self {
+            PullSource::Tag(image_name) => format!(
+                "{}-{}.gvmi",
+                image_name.to_base_name().replace('/', "-"),
+                image_name.tag
+            ),
+            PullSource::Descriptor(descr_hash) => format!("{}.gvmi", &descr_hash[0..10]),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct ImageInfoResponse {
+    //download link in form {registry_url}/download/{descriptor hash}
+    http: String,
+}
+
+/// Chunks of partially downloaded file that are missing or damaged
+pub async fn missing_chunks(
+    file_path: &Path,
+    file_descr: &FileChunkDesc,
+) -> anyhow::Result<Vec<FileChunk>> {
+    let mut file = File::open(file_path).await?;
+    let file_len = file.metadata().await?.len();
+    let mut buffer = Vec::new();
+    let mut missing = Vec::new();
+    for chunk in &file_descr.chunks {
+        //chunk lengths are checked when descriptor is read, but the file can be shorter
+        if chunk.pos + chunk.len > file_len {
+            missing.push(chunk.clone());
+            continue;
+        }
+        buffer.resize(chunk.len as usize, 0);
+        file.read_exact(&mut buffer).await?;
+        if file_descr.chunk_hash.digest(&buffer) != chunk.hash {
+            missing.push(chunk.clone());
+        }
+    }
+    Ok(missing)
+}
+
+impl RegistryClient {
+    /// Finds descriptor hash of the image attached to the tag
+    pub async fn resolve_tag(&self, image_name: &ImageName) -> anyhow::Result<String> {
+        let response = self
+            .request(Method::GET, "/v1/image/info")
+            .query(&[("tag", image_name.to_normalized_name())])
+            .send()
+            .await
+            .map_err(|e| anyhow!("Repository image info failed: {}", e))?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "Image {} not found in repository, status: {}",
+                image_name.to_normalized_name(),
+                response.status()
+            ));
+        }
+        let info = response.json::<ImageInfoResponse>().await?;
+        match info.http.rsplit_once("/download/") {
+            Some((_, descr_hash)) if !descr_hash.is_empty() => Ok(descr_hash.to_string()),
+            _ => Err(anyhow!("Unexpected download link: {}", info.http)),
+        }
+    }
+
+    /// Downloads descriptor and checks if its hash matches
+    pub async fn download_descriptor(
+        &self,
+        descr_sha256: &str,
+    ) -> anyhow::Result<(FileChunkDesc, Vec<u8>)> {
+        let response = self
+            .request(
+                Method::GET,
+                &format!("/v1/image/descr/download/{descr_sha256}"),
+            )
+            .send()
+            .await
+            .map_err(|e| anyhow!("Descriptor download failed: {}", e))?;
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "Descriptor {} download failed with code {}",
+                descr_sha256,
+                response.status().as_u16()
+            ));
+        }
+        let bytes = response.bytes().await?.to_vec();
+        let descr = FileChunkDesc::deserialize_from_bytes(&bytes)?;
+        if descr.get_descr_hash_str() != descr_sha256 {
+            return Err(anyhow!(
+                "Downloaded descriptor hash {} does not match {}",
+                descr.get_descr_hash_str(),
+                descr_sha256
+            ));
+        }
+        Ok((descr, bytes))
+    }
+
+    pub async fn download_single_chunk(
+        self,
+        file_path: PathBuf,
+        chunk: FileChunk,
+        chunk_hash: ChunkHash,
+        descr_sha256: String,
+        pb_chunks: ProgressBar,
+        pb_total: ProgressBar,
+    ) -> anyhow::Result<()> {
+        let response = self
+            .transfer(Method::GET, &format!("/download/{descr_sha256}"))
+            .header(
+                reqwest::header::RANGE,
+                //chunks are never empty, see FileChunkDesc::deserialize_from_bytes
+                format!("bytes={}-{}", chunk.pos, chunk.pos + chunk.len - 1),
+            )
+            .send()
+            .await
+            .map_err(|e| anyhow!("Chunk {} download error: {}", chunk.chunk_no, e))?;
+        //200 is fine only if the server sent exactly this range (single chunk image)
+        let status = response.status();
+        if status != reqwest::StatusCode::PARTIAL_CONTENT
+            && !(status == reqwest::StatusCode::OK && chunk.pos == 0)
+        {
+            return Err(anyhow!(
+                "Chunk {} download failed with code {}",
+                chunk.chunk_no,
+                status.as_u16()
+            ));
+        }
+
+        let mut file = OpenOptions::new().write(true).open(&file_path).await?;
+        file.seek(SeekFrom::Start(chunk.pos)).await?;
+        let mut hasher = chunk_hash.hasher();
+        let mut received = 0;
+        let mut body = response.bytes_stream();
+        while let Some(bytes) = body.next().await {
+            let bytes =
+                bytes.map_err(|e| anyhow!("Chunk {} download error: {}", chunk.chunk_no, e))?;
+            if received + bytes.len() as u64 > chunk.len {
+                return Err(anyhow!(
+                    "Chunk {} download returned more than {} bytes",
+                    chunk.chunk_no,
+                    chunk.len
+                ));
+            }
+            hasher.update(&bytes);
+            file.write_all(&bytes).await?;
+            received += bytes.len() as u64;
+            pb_total.inc(bytes.len() as u64);
+        }
+        file.flush().await?;
+        if received != chunk.len || hasher.finalize() != chunk.hash {
+            pb_total.set_position(pb_total.position().saturating_sub(received));
+            return Err(anyhow!(
+                "Chunk {} downloaded with invalid checksum",
+                chunk.chunk_no
+            ));
+        }
+        pb_chunks.inc(1);
+        Ok(())
+    }
+
+    pub async fn pull_chunks(
+        &self,
+        file_path: &Path,
+        file_descr: &FileChunkDesc,
+        download_workers: usize,
+    ) -> anyhow::Result<()> {
+        let descr_sha256 = file_descr.get_descr_hash_str();
+        let resumed = tokio::fs::metadata(file_path)
+            .await
+            .map(|meta| meta.len() == file_descr.size)
+            .unwrap_or(false);
+        let chunks_to_download = if resumed {
+            println!(
+                " -- checking partially downloaded file {}",
+                file_path.display()
+            );
+            missing_chunks(file_path, file_descr).await?
+        } else {
+            let file = File::create(file_path).await?;
+            file.set_len(file_descr.size).await?;
+            file_descr.chunks.clone()
+        };
+        if resumed {
+            println!(
+                " -- resuming download, {} of {} chunks missing",
+                chunks_to_download.len(),
+                file_descr.chunks.len()
+            );
+        }
+
+        let mc = MultiProgress::new();
+        let pb_total = create_chunk_pb(file_descr.size, ProgressBarType::DownloadTotal);
+        let pb_chunks = create_chunk_pb(
+            file_descr.chunks.len() as u64,
+            ProgressBarType::DownloadChunks,
+        );
+        if !pb_total.is_hidden() {
+            mc.add(pb_total.clone());
+        }
+        if !pb_chunks.is_hidden() {
+            mc.add(pb_chunks.clone());
+        }
+        let already_downloaded = file_descr.chunks.len() - chunks_to_download.len();
+        pb_chunks.inc(already_downloaded as u64);
+        pb_total.inc(
+            file_descr.size
+                - chunks_to_download
+                    .iter()
+                    .map(|chunk| chunk.len)
+                    .sum::<u64>(),
+        );
+        pb_total.set_message("Total download");
+
+        let mut futures = stream::iter(chunks_to_download.iter().map(|chunk| {
+            tokio::spawn(self.clone().download_single_chunk(
+                PathBuf::from(file_path),
+                chunk.clone(),
+                file_descr.chunk_hash,
+                descr_sha256.clone(),
+                pb_chunks.clone(),
+                pb_total.clone(),
+            ))
+        }))
+        .buffer_unordered(download_workers);
+        while let Some(fut) = futures.next().await {
+            match fut {
+                Ok(join_res) => join_res?,
+                Err(e) => {
+                    log::error!("Image download failed: {:?}", e);
+                    return Err(anyhow!("Image download failed: {:?}", e));
+                }
+            }
+        }
+        pb_chunks.finish_and_clear();
+        pb_total.finish_and_clear();
+        mc.remove(&pb_chunks);
+        mc.remove(&pb_total);
+
+        println!(" -- chunked download finished successfully");
+        Ok(())
+    }
+
+    /// Downloads image from Golem Registry, returns path of downloaded file.
+    /// Download is resumed if partially downloaded file (`<output>.part`) is found.
+    pub async fn pull_image(
+        &self,
+        source: &PullSource,
+        output: Option<&Path>,
+        download_workers: usize,
+    ) -> anyhow::Result<PathBuf> {
+        println!("Downloading image from golem registry: {}", self.base_url());
+        let descr_sha256 = match source {
+            PullSource::Tag(image_name) => {
+                println!(
+                    " * Step1 - resolving tag {}",
+                    image_name.to_normalized_name()
+                );
+                let descr_sha256 = self.resolve_tag(image_name).await?;
+                println!(" -- descriptor: {}", descr_sha256);
+                descr_sha256
+            }
+            PullSource::Descriptor(descr_sha256) => descr_sha256.clone(),
+        };
+        println!(" * Step2 - downloading descriptor");
+        let (descr, descr_bytes) = self.download_descriptor(&descr_sha256).await?;
+        println!(
+            " -- image size: {}, chunks: {}",
+            descr.size,
+            descr.chunks.len()
+        );
+
+        let output = output
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| PathBuf::from(source.default_output_name()));
+        let part_path = PathBuf::from(output.display().to_string() + ".part");
+        let expected = VerifyExpectation::Descriptor(descr.clone());
+
+        if output.exists() {
+            let file = File::open(&output).await?;
+            let file_size = file.metadata().await?.len();
+            let report = verify_content(file, file_size, &expected).await?;
+            if report.link_valid() && report.size_valid() && report.corrupted_ranges.is_empty() {
+                println!(" -- image already downloaded: {}", output.display());
+                tokio::fs::write(descriptor_path(&output), &descr_bytes).await?;
+                return Ok(output);
+            }
+            //damaged file is repaired like partial download
+            tokio::fs::rename(&output, &part_path).await?;
+        }
+
+        println!(" * Step3 - downloading image to {}", output.display());
+        self.pull_chunks(&part_path, &descr, download_workers)
+            .await?;
+
+        println!(" * Step4 - verifying image");
+        let file = File::open(&part_path).await?;
+        let report = verify_content(file, descr.size, &expected).await?;
+        if !report.link_valid() {
+            return Err(anyhow!(
+                "Downloaded image link {} does not match expected {}",
+                report.actual_link,
+                report.expected_link
+            ));
+        }
+        tokio::fs::rename(&part_path, &output).await?;
+        tokio::fs::write(descriptor_path(&output), &descr_bytes).await?;
+        println!(" -- image downloaded successfully: {}", output.display());
+        println!(" -- image link (for use in SDK): {}", report.actual_link);
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+fn serve_test_golem_registry(
+    descr_hash: String,
+    descr_bytes: Vec<u8>,
+    image: Vec<u8>,
+) -> std::net::SocketAddr {
+    use std::io::{BufRead, BufReader, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let path = request_line
+                .split_whitespace()
+                .nth(1)
+                .unwrap_or_default()
+                .to_string();
+            let mut range = None;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some(value) = line.to_lowercase().strip_prefix("range: bytes=") {
+                    let (start, end) = value.trim().split_once('-').unwrap();
+                    range = Some((
+                        start.parse::<usize>().unwrap(),
+                        end.parse::<usize>().unwrap(),
+                    ));
+                }
+            }
+            let (status, body) = if path.starts_with("/v1/image/info?tag=golem%2Ftest%3Av1") {
+                (
+                    "200 OK",
+                    format!(r#"{{"http":"http://{addr}/download/{descr_hash}"}}"#).into_bytes(),
+                )
+            } else if path == format!("/v1/image/descr/download/{descr_hash}") {
+                ("200 OK", descr_bytes.clone())
+            } else if path == format!("/download/{descr_hash}") {
+                match range {
+                    Some((start, end)) => ("206 Partial Content", image[start..=end].to_vec()),
+                    None => ("200 OK", image.clone()),
+                }
+            } else {
+                ("404 Not Found", Vec::new())
+            };
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            let _ = stream.write_all(&body);
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_pull_image() {
+    use crate::chunks::create_descriptor_from_reader;
+    use std::iter::repeat_with;
+
+    let mut rng = fastrand::Rng::new();
+    rng.seed(1234);
+    let image: Vec<u8> = repeat_with(|| rng.u8(..)).take(10500).collect();
+    let descr = create_descriptor_from_reader(&image[..], image.len() as u64, 1000)
+        .await
+        .unwrap();
+    let descr_hash = descr.get_descr_hash_str();
+    let addr = serve_test_golem_registry(
+        descr_hash.clone(),
+        descr.serialize_to_bytes(),
+        image.clone(),
+    );
+    let client = RegistryClient::new(&format!("http://{addr}")).unwrap();
+    let temp_dir = tempfile::tempdir().unwrap();
+    let output = temp_dir.path().join("test.gvmi");
+
+    let source = PullSource::parse("golem/test:v1").unwrap();
+    assert_eq!(source.default_output_name(), "golem-test-v1.gvmi");
+    client.pull_image(&source, Some(&output), 3).await.unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), image);
+    assert_eq!(
+        std::fs::read(descriptor_path(&output)).unwrap(),
+        descr.serialize_to_bytes()
+    );
+
+    //damaged file is resumed, only broken chunks are downloaded again
+    let mut damaged = image.clone();
+    damaged[5500] ^= 1;
+    std::fs::write(&output, &damaged).unwrap();
+    let source = PullSource::parse(&descr_hash).unwrap();
+    client.pull_image(&source, Some(&output), 3).await.unwrap();
+    assert_eq!(std::fs::read(&output).unwrap(), image);
+
+    assert!(PullSource::parse("test:v1").is_err());
+    assert!(client
+        .pull_image(&PullSource::parse("golem/test:v2").unwrap(), None, 1)
+        .await
+        .is_err());
+}
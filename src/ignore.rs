@@ -0,0 +1,253 @@
+//! Exclude patterns in `.dockerignore` syntax, used for docker build context.
+//!
+//! Patterns are matched against paths relative to the root (leading `/` is ignored),
+//! `*`, `?` and `[...]` match within one path component, `**` matches any number of components.
+//! Pattern matching a directory excludes everything below it, `!pattern` includes paths again,
+//! the last matching pattern wins.
+
+use std::path::{Component, Path};
+
+use anyhow::anyhow;
+
+/// Name of the file with rules excluding paths from the image
+pub const GVMKITIGNORE: &str = ".gvmkitignore";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IgnoreRule {
+    /// Pattern as written in the file
+    pub pattern: String,
+    pub negated: bool,
+    components: Vec<String>,
+}
+
+impl IgnoreRule {
+    pub fn parse(line: &str) -> anyhow::Result<Self> {
+        let line = line.trim();
+        let (negated, pattern) = match line.strip_prefix('!') {
+            Some(pattern) => (true, pattern.trim()),
+            None => (false, line),
+        };
+        let components: Vec<String> = pattern
+            .split('/')
+            .filter(|c| !c.is_empty() && *c != ".")
+            .map(str::to_string)
+            .collect();
+        if components.is_empty() {
+            return Err(anyhow!("Invalid exclude pattern: {line}"));
+        }
+        for component in &components {
+            validate_component(component).map_err(|e| anyhow!("Invalid pattern {line}: {e}"))?;
+        }
+        Ok(IgnoreRule {
+            pattern: line.to_string(),
+            negated,
+            components,
+        })
+    }
+
+    /// True if the pattern matches the path or one of its parent directories
+    pub fn matches(&self, path: &Path) -> bool {
+        let path: Vec<String> = path
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(c) => Some(c.to_string_lossy().to_string()),
+                _ => None,
+            })
+            .collect();
+        (1..=path.len()).any(|len| match_components(&self.components, &path[..len]))
+    }
+}
+
+/// Ordered list of rules, e.g. content of `.dockerignore`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IgnoreRules {
+    pub rules: Vec<IgnoreRule>,
+}
+
+impl IgnoreRules {
+    /// One pattern per line, empty lines and lines starting with `#` are skipped
+    pub fn parse(content: &str) -> anyhow::Result<Self> {
+        let rules = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(IgnoreRule::parse)
+            .collect::<anyhow::Result<_>>()?;
+        Ok(IgnoreRules { rules })
+    }
+
+    /// Rules from file, no rules if the file does not exist
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(content) => Self::parse(&content)
+                .map_err(|e| anyhow!("Failed to parse {}: {}", path.display(), e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(anyhow!("Failed to read {}: {}", path.display(), e)),
+        }
+    }
+
+    /// Adds rule after existing ones, so it takes precedence
+    pub fn push(&mut self, pattern: &str) -> anyhow::Result<()> {
+        self.rules.push(IgnoreRule::parse(pattern)?);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// True if some rule can include path excluded by previous rules,
+    /// so excluded directories still have to be visited
+    pub fn has_exceptions(&self) -> bool {
+        self.rules.iter().any(|rule| rule.negated)
+    }
+
+    /// Index of the rule excluding the path, None if the path is kept
+    pub fn excluded_by(&self, path: &Path) -> Option<usize> {
+        self.rules
+            .iter()
+            .rposition(|rule| rule.matches(path))
+            .filter(|pos| !self.rules[*pos].negated)
+    }
+
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.excluded_by(path).is_some()
+    }
+}
+
+/// What single rule removed from the image
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExcludeStats {
+    pub entries: u64,
+    /// Size of removed regular files
+    pub bytes: u64,
+}
+
+fn match_components(pattern: &[String], path: &[String]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skip| match_components(rest, &path[skip..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((name, path_rest)) => {
+                match_component(first, name) && match_components(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Checks syntax of single component pattern: classes have to be closed, escape needs a character
+fn validate_component(pattern: &str) -> anyhow::Result<()> {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars
+                    .next()
+                    .ok_or_else(|| anyhow!("pattern ends with escape character"))?;
+            }
+            '[' => {
+                //first character of the class can be ']'
+                chars.next();
+                if !chars.any(|c| c == ']') {
+                    return Err(anyhow!("unclosed character class"));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Matches single path component against pattern with `*`, `?`, `[...]` and `\` escapes
+fn match_component(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    match_chars(&pattern, &name)
+}
+
+fn match_chars(pattern: &[char], name: &[char]) -> bool {
+    let Some((first, rest)) = pattern.split_first() else {
+        return name.is_empty();
+    };
+    match first {
+        //consecutive stars are the same as one
+        '*' if rest.first() == Some(&'*') => match_chars(rest, name),
+        '*' => (0..=name.len()).any(|skip| match_chars(rest, &name[skip..])),
+        '?' => !name.is_empty() && match_chars(rest, &name[1..]),
+        '[' => {
+            let Some(end) = rest.iter().skip(1).position(|c| *c == ']') else {
+                return false;
+            };
+            let (class, after) = (&rest[..end + 1], &rest[end + 2..]);
+            let (negated, class) = match class.first() {
+                Some('^') | Some('!') => (true, &class[1..]),
+                _ => (false, class),
+            };
+            let Some((c, name_rest)) = name.split_first() else {
+                return false;
+            };
+            let mut in_class = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == '-' {
+                    in_class |= class[i] <= *c && *c <= class[i + 2];
+                    i += 3;
+                } else {
+                    in_class |= class[i] == *c;
+                    i += 1;
+                }
+            }
+            in_class != negated && match_chars(after, name_rest)
+        }
+        '\\' => match (rest.split_first(), name.split_first()) {
+            (Some((escaped, rest)), Some((c, name_rest))) => {
+                escaped == c && match_chars(rest, name_rest)
+            }
+            _ => false,
+        },
+        c => name.first() == Some(c) && match_chars(rest, &name[1..]),
+    }
+}
+
+#[test]
+fn test_ignore_rules() {
+    let rules = IgnoreRules::parse(
+        "# comment\n\n/var/cache/apt\n**/__pycache__\n*.log\n!keep.log\nusr/share/doc/*\n!usr/share/doc/copyright\ndata-[0-9]?\n",
+    )
+    .unwrap();
+    assert_eq!(rules.rules.len(), 7);
+    assert!(rules.has_exceptions());
+
+    let excluded = |path: &str| rules.is_excluded(Path::new(path));
+    assert!(excluded("/var/cache/apt"));
+    assert!(excluded("var/cache/apt/archives/bash.deb"));
+    assert!(!excluded("/var/cache"));
+    assert!(excluded("/__pycache__/a.pyc"));
+    assert!(excluded("/app/lib/__pycache__/a.pyc"));
+    assert!(excluded("/error.log"));
+    assert!(!excluded("/app/error.log"));
+    assert!(!excluded("/keep.log"));
+    assert!(excluded("/usr/share/doc/bash"));
+    assert!(!excluded("/usr/share/doc/copyright"));
+    assert!(excluded("/data-12"));
+    assert!(!excluded("/data-x2"));
+    assert!(!excluded("/data-1"));
+    assert_eq!(rules.excluded_by(Path::new("/app/__pycache__")), Some(1));
+    assert_eq!(rules.excluded_by(Path::new("/var/cache/apt/x")), Some(0));
+
+    assert!(IgnoreRule::parse("data[0-9").is_err());
+    assert!(IgnoreRule::parse("/").is_err());
+    assert!(IgnoreRule::parse("a\\").is_err());
+    assert!(IgnoreRule::parse("\\*.txt")
+        .unwrap()
+        .matches(Path::new("*.txt")));
+    assert!(!IgnoreRule::parse("\\*.txt")
+        .unwrap()
+        .matches(Path::new("a.txt")));
+    assert!(IgnoreRule::parse("[!a]*")
+        .unwrap()
+        .matches(Path::new("bcd")));
+}
@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::image::config::ImageConfig;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ManifestEntry {
+    config: String,
+    #[serde(default)]
+    repo_tags: Option<Vec<String>>,
+    layers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct EntryLocation {
+    offset: u64,
+    size: u64,
+}
+
+/// Image selected from `docker save` archive
+#[derive(Debug, Clone)]
+pub struct ArchiveImage {
+    /// Tag used to select the image (or first tag found in the archive)
+    pub name: Option<String>,
+    /// Image id (sha256 of the config without prefix), the same as reported by docker
+    pub id: String,
+    pub config: ImageConfig,
+    /// Layer paths inside the archive, from the bottom one
+    pub layers: Vec<String>,
+}
+
+/// Archive created by `docker save`, both legacy and OCI based (docker 25+) layouts are supported.
+/// Archive is indexed once, entries are read directly from the file afterwards.
+pub struct DockerArchive {
+    path: PathBuf,
+    entries: HashMap<String, EntryLocation>,
+    links: HashMap<String, String>,
+}
+
+/// Normalizes image tag so `ubuntu`, `ubuntu:latest` and `docker.io/library/ubuntu:latest` match
+pub fn normalize_tag(tag: &str) -> String {
+    let tag = tag
+        .strip_prefix("docker.io/library/")
+        .or_else(|| tag.strip_prefix("docker.io/"))
+        .unwrap_or(tag);
+    match tag.rsplit_once(':') {
+        Some((_, t)) if !t.contains('/') => tag.to_string(),
+        _ => format!("{tag}:latest"),
+    }
+}
+
+fn normalize_entry_name(name: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in name.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            p => parts.push(p),
+        }
+    }
+    parts.join("/")
+}
+
+impl DockerArchive {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path)
+            .map_err(|e| anyhow!("Failed to open archive {}: {}", path.display(), e))?;
+        let mut archive = tar::Archive::new(file);
+        let mut entries = HashMap::new();
+        let mut links = HashMap::new();
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let name = normalize_entry_name(&entry.path()?.to_string_lossy());
+            match entry.header().entry_type() {
+                tar::EntryType::Symlink | tar::EntryType::Link => {
+                    if let Some(target) = entry.link_name()? {
+                        let target = target.to_string_lossy();
+                        //symlinks are relative to the entry, hard links to the archive root
+                        let target = if entry.header().entry_type() == tar::EntryType::Symlink {
+                            let dir = Path::new(&name)
+                                .parent()
+                                .map(|p| p.to_string_lossy().to_string())
+                                .unwrap_or_default();
+                            normalize_entry_name(&format!("{dir}/{target}"))
+                        } else {
+                            normalize_entry_name(&target)
+                        };
+                        links.insert(name, target);
+                    }
+                }
+                _ => {
+                    entries.insert(
+                        name,
+                        EntryLocation {
+                            offset: entry.raw_file_position(),
+                            size: entry.size(),
+                        },
+                    );
+                }
+            }
+        }
+        Ok(DockerArchive {
+            path: path.to_path_buf(),
+            entries,
+            links,
+        })
+    }
+
+    fn locate(&self, name: &str) -> anyhow::Result<EntryLocation> {
+        let mut name = normalize_entry_name(name);
+        //limit number of followed links to avoid cycles
+        for _ in 0..10 {
+            if let Some(location) = self.entries.get(&name) {
+                return Ok(*location);
+            }
+            match self.links.get(&name) {
+                Some(target) => name = target.clone(),
+                None => break,
+            }
+        }
+        Err(anyhow!("Entry {} not found in archive", name))
+    }
+
+    /// Size of the entry in bytes
+    pub fn entry_size(&self, name: &str) -> anyhow::Result<u64> {
+        Ok(self.locate(name)?.size)
+    }
+
+    /// Opens reader of a single file stored in the archive
+    pub fn open_entry(&self, name: &str) -> anyhow::Result<impl Read> {
+        let location = self.locate(name)?;
+        let mut file = File::open(&self.path)?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        Ok(file.take(location.size))
+    }
+
+    fn read_entry(&self, name: &str) -> anyhow::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.open_entry(name)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// Selects image by tag, tag can be omitted if archive contains only one image
+    pub fn select_image(&self, tag: Option<&str>) -> anyhow::Result<ArchiveImage> {
+        let manifest: Vec<ManifestEntry> = serde_json::from_slice(
+            &self
+                .read_entry("manifest.json")
+                .map_err(|_| anyhow!("manifest.json not found, is it docker save archive?"))?,
+        )?;
+        let (name, entry) = match tag {
+            Some(tag) => {
+                let wanted = normalize_tag(tag);
+                manifest
+                    .iter()
+                    .find(|m| {
+                        m.repo_tags
+                            .iter()
+                            .flatten()
+                            .any(|t| normalize_tag(t) == wanted)
+                    })
+                    .map(|m| (Some(tag.to_string()), m))
+                    .ok_or_else(|| {
+                        let available: Vec<String> = manifest
+                            .iter()
+                            .flat_map(|m| m.repo_tags.iter().flatten().cloned())
+                            .collect();
+                        anyhow!(
+                            "Image {} not found in archive, available tags: {}",
+                            tag,
+                            available.join(", ")
+                        )
+                    })?
+            }
+            None => match manifest.as_slice() {
+                [entry] => (entry.repo_tags.iter().flatten().next().cloned(), entry),
+                [] => return Err(anyhow!("Archive does not contain any image")),
+                _ => {
+                    return Err(anyhow!(
+                        "Archive contains {} images, select one by tag",
+                        manifest.len()
+                    ))
+                }
+            },
+        };
+        let config_bytes = self.read_entry(&entry.config)?;
+        let id = hex::encode(Sha256::digest(&config_bytes));
+        Ok(ArchiveImage {
+            name,
+            id,
+            config: ImageConfig::from_slice(&config_bytes)?,
+            layers: entry.layers.clone(),
+        })
+    }
+}
+
+#[test]
+fn test_docker_archive() {
+    use crate::rootfs::{EntryKind, RootfsTree};
+    use std::io::Write;
+
+    fn append_file(builder: &mut tar::Builder<Vec<u8>>, path: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(1700000000);
+        header.set_size(data.len() as u64);
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    let mut layer1 = tar::Builder::new(Vec::new());
+    append_file(&mut layer1, "etc/hostname", b"golem");
+    append_file(&mut layer1, "etc/removed", b"x");
+    append_file(&mut layer1, "var/cache/a", b"aaa");
+    append_file(&mut layer1, "var/cache/b", b"bbb");
+    let layer1 = layer1.into_inner().unwrap();
+
+    let mut layer2 = tar::Builder::new(Vec::new());
+    append_file(&mut layer2, "etc/.wh.removed", b"");
+    append_file(&mut layer2, "var/cache/c", b"ccc");
+    append_file(&mut layer2, "var/cache/.wh..wh..opq", b"");
+    let layer2 = layer2.into_inner().unwrap();
+    //second layer is stored compressed as in docker 25+ archives
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(&layer2).unwrap();
+    let layer2 = encoder.finish().unwrap();
+
+    let config = br#"{"architecture":"amd64","os":"linux","config":{"Env":["PATH=/bin"],"Entrypoint":["/bin/sh"],"WorkingDir":"/work"}}"#;
+    let config_id = hex::encode(Sha256::digest(config));
+    let manifest = format!(
+        r#"[{{"Config":"blobs/sha256/{config_id}","RepoTags":["golem/test:v1"],"Layers":["l1/layer.tar","blobs/sha256/l2"]}}]"#
+    );
+
+    let mut archive = tar::Builder::new(Vec::new());
+    append_file(&mut archive, &format!("blobs/sha256/{config_id}"), config);
+    append_file(&mut archive, "blobs/sha256/l1", &layer1);
+    append_file(&mut archive, "blobs/sha256/l2", &layer2);
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Symlink);
+    header.set_mode(0o777);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(1700000000);
+    header.set_size(0);
+    archive
+        .append_link(&mut header, "l1/layer.tar", "../blobs/sha256/l1")
+        .unwrap();
+    append_file(&mut archive, "manifest.json", manifest.as_bytes());
+    let temp_dir = tempfile::tempdir().unwrap();
+    let archive_path = temp_dir.path().join("image.tar");
+    std::fs::write(&archive_path, archive.into_inner().unwrap()).unwrap();
+
+    let archive = DockerArchive::open(&archive_path).unwrap();
+    assert!(archive.select_image(Some("golem/test:v2")).is_err());
+    let image = archive.select_image(Some("golem/test:v1")).unwrap();
+    assert_eq!(image.id, config_id);
+    assert_eq!(image.config.architecture.as_deref(), Some("amd64"));
+    let cfg = image.config.to_container_config(&image.id);
+    assert_eq!(cfg.entrypoint, Some(vec!["/bin/sh".to_string()]));
+    assert_eq!(cfg.working_dir.as_deref(), Some("/work"));
+    assert_eq!(cfg.image, Some(config_id));
+    assert_eq!(
+        archive.select_image(None).unwrap().name.as_deref(),
+        Some("golem/test:v1")
+    );
+
+    let mut tree = RootfsTree::new(temp_dir.path()).unwrap();
+    for layer in &image.layers {
+        let reader = crate::image::config::open_layer(archive.open_entry(layer).unwrap()).unwrap();
+        tree.append_layer(reader).unwrap();
+    }
+    let paths: Vec<_> = tree
+        .entries()
+        .map(|(p, _)| p.display().to_string())
+        .collect();
+    assert_eq!(
+        paths,
+        vec![
+            "/etc",
+            "/etc/hostname",
+            "/var",
+            "/var/cache",
+            "/var/cache/c"
+        ]
+    );
+    let EntryKind::File { source, .. } = &tree.get(Path::new("/etc/hostname")).unwrap().kind else {
+        panic!("Expected file");
+    };
+    assert_eq!(std::fs::read(source).unwrap(), b"golem");
+}
@@ -1,37 +1,138 @@
 use std::collections::HashMap;
 
+use std::io::Read;
 use std::{
     env, fs,
     path::{Path, PathBuf},
 };
 
 use bollard::container;
-use bollard::container::{
-    DownloadFromContainerOptions, LogOutput, LogsOptions, UploadToContainerOptions,
-};
-
-use crate::wrapper::{stream_with_progress, ProgressContext};
+use bollard::container::DownloadFromContainerOptions;
+use bollard::service::ContainerConfig;
+use bollard::Docker;
+use chrono::{TimeZone, Utc};
+use sha2::{Digest, Sha256};
+use tokio_util::io::{StreamReader, SyncIoBridge};
+
+use crate::wrapper::{stream_with_progress, ProgressContext, ProgressReader};
 use anyhow::anyhow;
 
 use futures_util::TryStreamExt;
 use humansize::DECIMAL;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 
+use crate::ignore::IgnoreRules;
+use crate::image::archive::DockerArchive;
+use crate::image::config::{default_output_name, load_config_file, open_layer, DigestReader};
+use crate::image::dockerfile::{build_dockerfile, DockerfileBuild};
 use crate::image::name::ImageName;
+use crate::image::oci::{check_config_platform, OciLayout};
+use crate::image::platform::{Platform, PLATFORM_LABEL};
+use crate::image::registry::{DistributionClient, ImageReference};
+use crate::layer_cache::LayerCache;
 use crate::metadata::{add_metadata_outside, read_metadata_outside};
 use crate::progress::{create_chunk_pb, ProgressBarType};
+use crate::reproducible::strip_volatile_config;
+use crate::rootfs::RootfsTree;
+use crate::sbom::{
+    collect_packages, sbom_document, sbom_path, PackageManager, SbomFormat, SBOM_FORMAT_LABEL,
+    SBOM_SHA256_LABEL, SBOM_SOURCES,
+};
+use crate::squashfs::{write_squashfs, SquashfsOptions};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
+/// Where the image filesystem and configuration are taken from
+#[derive(Debug, Clone, Default)]
+pub enum ImageSource {
+    /// Image from local docker engine, pulled if missing
+    #[default]
+    Docker,
+    /// Image built by local docker engine from Dockerfile, tagged with image name if given
+    Dockerfile(DockerfileBuild),
+    /// Archive created by `docker save`, image selected by tag if given
+    DockerArchive { path: PathBuf, tag: Option<String> },
+    /// OCI image layout directory, image selected by tag (ref name) and platform
+    OciLayout { path: PathBuf, tag: Option<String> },
+    /// Image pulled directly from OCI/Docker distribution registry (no docker engine needed)
+    Registry { reference: String },
+    /// Root filesystem directory or tarball (optionally compressed) with container config json file
+    Rootfs {
+        path: PathBuf,
+        config: Option<PathBuf>,
+    },
+}
+
 pub struct ImageBuilder {
     image_name: String,
+    source: ImageSource,
+    platform: Option<Platform>,
     output: Option<String>,
     force_overwrite: bool,
+    //legacy options, accepted for compatibility but not applied to the image
+    #[allow(dead_code)]
     env: Vec<String>,
+    #[allow(dead_code)]
     volumes: Vec<String>,
+    #[allow(dead_code)]
     entrypoint: Option<String>,
     compression_method: String,
     compression_level: Option<u32>,
+    sbom: Option<SbomFormat>,
+    sbom_in_metadata: bool,
+    layer_cache: Option<PathBuf>,
+    exclude: IgnoreRules,
+    source_date_epoch: Option<u32>,
+}
+
+/// Platform of image inspected in docker engine
+fn docker_image_platform(image: &bollard::service::ImageInspect) -> Option<Platform> {
+    match (&image.os, &image.architecture) {
+        (Some(os), Some(arch)) => Some(Platform::new(os, arch, image.variant.as_deref())),
+        _ => None,
+    }
+}
+
+/// Connects to local docker engine, DOCKER_TIMEOUT sets request timeout in seconds
+async fn connect_docker() -> anyhow::Result<Docker> {
+    let docker = match Docker::connect_with_local_defaults() {
+        Ok(docker) => docker,
+        Err(err) => {
+            log::error!("Failed to connect to docker: {}", err);
+            return Err(anyhow::anyhow!("Failed to connect to docker: {}", err));
+        }
+    };
+    let docker = docker.with_timeout(std::time::Duration::from_secs(
+        env::var("DOCKER_TIMEOUT")
+            .unwrap_or("3600".to_string())
+            .parse::<u64>()
+            .expect("Failed to parse DOCKER_TIMEOUT env variable"),
+    ));
+
+    match docker.version().await {
+        Ok(version) => {
+            println!(
+                " -- connected to docker engine platform: {} version: {}",
+                version.platform.map(|pv| pv.name).unwrap_or("".to_string()),
+                version.version.unwrap_or_default()
+            );
+        }
+        Err(err) => {
+            log::error!("Failed to get docker service version: {}", err);
+            return Err(anyhow::anyhow!(
+                "Cannot connect to docker engine, please check if docker is running"
+            ));
+        }
+    };
+    Ok(docker)
+}
+
+/// File contents are staged next to the output file
+fn staging_dir(output_path: &str) -> PathBuf {
+    Path::new(output_path)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(env::temp_dir)
 }
 
 impl ImageBuilder {
@@ -48,6 +149,8 @@ impl ImageBuilder {
     ) -> Self {
         ImageBuilder {
             image_name: image_name.to_string(),
+            source: ImageSource::Docker,
+            platform: None,
             output,
             force_overwrite,
             env,
@@ -55,59 +158,673 @@ impl ImageBuilder {
             entrypoint,
             compression_method,
             compression_level,
+            sbom: None,
+            sbom_in_metadata: false,
+            source_date_epoch: None,
+            layer_cache: None,
+            exclude: IgnoreRules::default(),
         }
     }
 
+    /// Selects source of the image, docker engine is used by default
+    pub fn with_source(mut self, source: ImageSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Requested image platform, by default linux/amd64 is selected from multi-platform images
+    pub fn with_platform(mut self, platform: Option<Platform>) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    /// Writes SBOM of packages installed in the image next to the output file
+    pub fn with_sbom(mut self, sbom: Option<SbomFormat>) -> Self {
+        self.sbom = sbom;
+        self
+    }
+
+    /// Adds format and hash of the SBOM file to labels of the image metadata
+    pub fn with_sbom_in_metadata(mut self, sbom_in_metadata: bool) -> Self {
+        self.sbom_in_metadata = sbom_in_metadata;
+        self
+    }
+
+    /// Reproducible build: file times are clamped to the given timestamp (see SOURCE_DATE_EPOCH)
+    /// and container config fields which change with every build are removed
+    pub fn with_source_date_epoch(mut self, source_date_epoch: Option<u32>) -> Self {
+        self.source_date_epoch = source_date_epoch;
+        self
+    }
+
+    /// Paths matching the rules are removed from the image before it is written
+    pub fn with_exclude(mut self, exclude: IgnoreRules) -> Self {
+        self.exclude = exclude;
+        self
+    }
+
+    /// Extracted layers are cached in the directory, so only new layers are fetched in next builds
+    pub fn with_layer_cache(mut self, layer_cache: Option<PathBuf>) -> Self {
+        self.layer_cache = layer_cache;
+        self
+    }
+
     pub async fn build(&self) -> anyhow::Result<PathBuf> {
-        use bollard::{
-            image,
-            service::{HostConfig, Mount, MountTypeEnum},
-            Docker,
-        };
-        println!("Building image: {}", self.image_name);
-        let docker = match Docker::connect_with_local_defaults() {
-            Ok(docker) => docker,
-            Err(err) => {
-                log::error!("Failed to connect to docker: {}", err);
-                return Err(anyhow::anyhow!("Failed to connect to docker: {}", err));
+        match &self.source {
+            ImageSource::Registry { reference } => self.build_from_registry(reference).await,
+            ImageSource::Rootfs { path, config } => {
+                self.build_from_rootfs(path, config.as_deref()).await
+            }
+            ImageSource::OciLayout { path, tag } => {
+                self.build_from_oci_layout(path, tag.as_deref()).await
             }
+            ImageSource::Docker => {
+                println!("Building image: {}", self.image_name);
+                let docker = connect_docker().await?;
+                self.build_from_docker(&docker, &self.image_name).await
+            }
+            ImageSource::Dockerfile(build) => self.build_from_dockerfile(build).await,
+            ImageSource::DockerArchive { path, tag } => {
+                self.build_from_archive(path, tag.as_deref()).await
+            }
+        }
+    }
+
+    /// Resolves output path, returns true if up to date image already exists.
+    /// Image without id is always rebuilt.
+    async fn prepare_output(
+        &self,
+        default_name: String,
+        image_id: Option<&str>,
+    ) -> anyhow::Result<(String, bool)> {
+        let path = if let Some(path) = &self.output {
+            path.clone()
+        } else {
+            default_name
         };
-        let docker = docker.with_timeout(std::time::Duration::from_secs(
-            env::var("DOCKER_TIMEOUT")
-                .unwrap_or("3600".to_string())
-                .parse::<u64>()
-                .expect("Failed to parse DOCKER_TIMEOUT env variable"),
-        ));
-
-        match docker.version().await {
-            Ok(version) => {
+
+        let path = Path::new(&path);
+        if path.exists() {
+            let meta_out = read_metadata_outside(path).await;
+            match meta_out {
+                Ok(meta_out) => {
+                    if let Some(image_left) = meta_out.image {
+                        if Some(image_left.as_str()) == image_id {
+                            if self.force_overwrite {
+                                println!(
+                                    " -- GVMI image already exists - overwriting: {}",
+                                    path.display()
+                                );
+                            } else {
+                                println!(" -- GVMI image already exists: {}", path.display());
+                                return Ok((path.display().to_string(), true));
+                            }
+                        } else {
+                            println!(" -- GVMI image id mismatch: {}", path.display());
+                        }
+                    }
+                }
+                Err(_err) => {
+                    println!(
+                        " -- Failed to read metadata from GVMI image: {}",
+                        path.display()
+                    );
+                }
+            }
+        }
+        if let Err(err) = fs::write(path, "") {
+            log::error!("Failed to create output file: {} {}", path.display(), err);
+            return Err(anyhow::anyhow!("Failed to create output file: {}", err));
+        }
+
+        let path = path
+            .canonicalize()?
+            .display()
+            .to_string()
+            .replace(r"\\?\", ""); // strip \\?\ prefix on windows
+        println!(" -- GVMI image output path: {}", path);
+        Ok((path, false))
+    }
+
+    /// Writes squashfs image of the tree and appends metadata,
+    /// platform of the image (if known) is recorded in metadata labels
+    async fn write_image(
+        &self,
+        mut tree: RootfsTree,
+        path: &str,
+        meta_cfg: &ContainerConfig,
+        platform: Option<Platform>,
+        step: u32,
+    ) -> anyhow::Result<PathBuf> {
+        let mut meta_cfg = meta_cfg.clone();
+        if let Some(platform) = platform {
+            meta_cfg
+                .labels
+                .get_or_insert_with(HashMap::new)
+                .insert(PLATFORM_LABEL.to_string(), platform.to_string());
+        }
+        //packages are listed before exclude rules remove package databases
+        if let Some(format) = self.sbom {
+            let sbom_hash = self.write_sbom(&tree, path, format)?;
+            if self.sbom_in_metadata {
+                let labels = meta_cfg.labels.get_or_insert_with(HashMap::new);
+                labels.insert(SBOM_FORMAT_LABEL.to_string(), format.to_string());
+                labels.insert(SBOM_SHA256_LABEL.to_string(), sbom_hash);
+            }
+        }
+        if !self.exclude.is_empty() {
+            let stats = tree.apply_exclude();
+            println!(
+                " -- excluded {} entries ({}):",
+                stats.iter().map(|s| s.entries).sum::<u64>(),
+                humansize::format_size(stats.iter().map(|s| s.bytes).sum::<u64>(), DECIMAL)
+            );
+            for (rule, stats) in self.exclude.rules.iter().zip(stats) {
+                if rule.negated {
+                    continue;
+                }
                 println!(
-                    " -- connected to docker engine platform: {} version: {}",
-                    version.platform.map(|pv| pv.name).unwrap_or("".to_string()),
-                    version.version.unwrap_or_default()
+                    "    {}: {} entries ({})",
+                    rule.pattern,
+                    stats.entries,
+                    humansize::format_size(stats.bytes, DECIMAL)
                 );
             }
-            Err(err) => {
-                log::error!("Failed to get docker service version: {}", err);
-                return Err(anyhow::anyhow!(
-                    "Cannot connect to docker engine, please check if docker is running"
-                ));
+        }
+        if let Some(epoch) = self.source_date_epoch {
+            println!(" -- reproducible build, file times clamped to {epoch} (SOURCE_DATE_EPOCH)");
+            tree.clamp_mtimes(epoch);
+            strip_volatile_config(&mut meta_cfg);
+        }
+        println!(
+            " * Step{} - writing squashfs image, compression: {} ...",
+            step, self.compression_method
+        );
+        let pb = create_chunk_pb(tree.files_size(), ProgressBarType::WriteSquashfs);
+        pb.set_message("Writing image");
+        let options = SquashfsOptions {
+            compression_method: self.compression_method.clone(),
+            compression_level: self.compression_level,
+            mod_time: self.source_date_epoch,
+        };
+        let output_path = PathBuf::from(path);
+        let squashfs_size = {
+            let pb = pb.clone();
+            tokio::task::spawn_blocking(move || write_squashfs(&tree, &output_path, &options, &pb))
+                .await??
+        };
+        pb.finish_and_clear();
+        println!(
+            " -- Squashfs image written: {} ({} bytes)",
+            humansize::format_size(squashfs_size, DECIMAL),
+            squashfs_size
+        );
+
+        println!(" * Step{} - Adding metadata...", step + 1);
+        let bytes = add_metadata_outside(&PathBuf::from(path), &meta_cfg).await?;
+        let conf = read_metadata_outside(&PathBuf::from(path)).await?;
+        log::debug!("conf :: {:?}", conf);
+        println!(" -- container metadata ({} bytes) added", bytes);
+        let file_length = fs::metadata(path)?.len();
+        if file_length == 0 {
+            return Err(anyhow!("Output gvmi image is empty"));
+        }
+        println!(
+            " -- Output gvmi image size: {} ({} bytes), path: {}",
+            humansize::format_size(file_length, DECIMAL),
+            file_length,
+            path
+        );
+        Ok(PathBuf::from(path))
+    }
+
+    /// Writes SBOM sidecar file of the image, returns sha256 of its content
+    fn write_sbom(
+        &self,
+        tree: &RootfsTree,
+        path: &str,
+        format: SbomFormat,
+    ) -> anyhow::Result<String> {
+        let inventory = collect_packages(tree)?;
+        for warning in &inventory.warnings {
+            println!(" -- warning: {warning}");
+        }
+        let name = if self.image_name.is_empty() {
+            Path::new(path)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default()
+        } else {
+            self.image_name.clone()
+        };
+        let created = match self.source_date_epoch {
+            Some(epoch) => Utc
+                .timestamp_opt(epoch as i64, 0)
+                .single()
+                .ok_or_else(|| anyhow!("Invalid source date epoch {epoch}"))?,
+            None => Utc::now(),
+        };
+        let document = sbom_document(&inventory, format, &name, created);
+        let content = serde_json::to_vec_pretty(&document)?;
+        let sbom_path = sbom_path(Path::new(path), format);
+        fs::write(&sbom_path, &content)
+            .map_err(|e| anyhow!("Failed to write SBOM {}: {}", sbom_path.display(), e))?;
+        println!(
+            " -- SBOM ({}): {} packages (dpkg {}, apk {}, rpm {}) written to {}",
+            format,
+            inventory.packages.len(),
+            inventory.count(PackageManager::Dpkg),
+            inventory.count(PackageManager::Apk),
+            inventory.count(PackageManager::Rpm),
+            sbom_path.display()
+        );
+        Ok(hex::encode(Sha256::digest(&content)))
+    }
+
+    /// Empty tree, files matching exclude rules are skipped while tar streams are appended to it
+    fn new_tree(&self, staging_dir: &Path) -> anyhow::Result<RootfsTree> {
+        let mut tree = RootfsTree::new(staging_dir)?;
+        tree.set_exclude(self.exclude.clone());
+        if self.sbom.is_some() {
+            tree.defer_exclude(SBOM_SOURCES);
+        }
+        Ok(tree)
+    }
+
+    /// Applies layers (bottom first) on top of empty tree, layers are decompressed if needed.
+    /// With layer cache only layers missing in the cache are read, they are matched by `diff_ids` from image config
+    async fn extract_layers<F>(
+        &self,
+        output_path: &str,
+        layers: Vec<(String, u64)>,
+        diff_ids: &[String],
+        open: F,
+    ) -> anyhow::Result<RootfsTree>
+    where
+        F: Fn(&str) -> anyhow::Result<Box<dyn Read>> + Send + 'static,
+    {
+        println!(" * Step2 - extracting {} layers ...", layers.len());
+        let cache = match &self.layer_cache {
+            Some(dir) if diff_ids.len() == layers.len() => Some(LayerCache::open(dir)?),
+            Some(_) => {
+                log::warn!("Image config does not list layer diff ids, layer cache is not used");
+                None
             }
+            None => None,
+        };
+        let missing: Vec<usize> = (0..layers.len())
+            .filter(|no| match &cache {
+                Some(cache) => !cache.contains(&diff_ids[*no]),
+                None => true,
+            })
+            .collect();
+        let missing_size = missing.iter().map(|no| layers[*no].1).sum();
+        if cache.is_some() {
+            println!(
+                " -- layer cache: {} of {} layers cached, fetching {} ({})",
+                layers.len() - missing.len(),
+                layers.len(),
+                missing.len(),
+                humansize::format_size(missing_size, DECIMAL)
+            );
+        }
+        let pb = create_chunk_pb(missing_size, ProgressBarType::CopyingFiles);
+        pb.set_message("Extracting layers");
+        let mut tree = self.new_tree(&staging_dir(output_path))?;
+        let diff_ids = diff_ids.to_vec();
+        let tree = {
+            let pb = pb.clone();
+            tokio::task::spawn_blocking(move || {
+                for no in missing {
+                    let layer = &layers[no].0;
+                    let mut reader = open_layer(ProgressReader::new(open(layer)?, &pb))?;
+                    match &cache {
+                        Some(cache) => {
+                            let diff_id = cache
+                                .store(&mut reader)
+                                .map_err(|e| anyhow!("Failed to extract layer {}: {}", layer, e))?;
+                            if diff_id != diff_ids[no] {
+                                return Err(anyhow!(
+                                    "Layer {} content {} does not match image config diff id {}",
+                                    layer,
+                                    diff_id,
+                                    diff_ids[no]
+                                ));
+                            }
+                        }
+                        None => tree
+                            .append_layer(&mut reader)
+                            .map_err(|e| anyhow!("Failed to extract layer {}: {}", layer, e))?,
+                    }
+                    //read till the end, so digest of the whole blob is checked
+                    std::io::copy(&mut reader, &mut std::io::sink())
+                        .map_err(|e| anyhow!("Failed to extract layer {}: {}", layer, e))?;
+                }
+                if let Some(cache) = &cache {
+                    cache.assemble(&mut tree, &diff_ids)?;
+                }
+                Ok::<_, anyhow::Error>(tree)
+            })
+            .await??
+        };
+        pb.finish_and_clear();
+        println!(" -- Extracted {} entries", tree.len());
+        Ok(tree)
+    }
+
+    /// Assembles rootfs from layer cache, layers missing in the cache are taken from `docker save` export of the image
+    async fn extract_docker_layers(
+        &self,
+        docker: &Docker,
+        cache_dir: &Path,
+        image_id: &str,
+        image_size: u64,
+        diff_ids: Vec<String>,
+        output_path: &str,
+    ) -> anyhow::Result<RootfsTree> {
+        let cache = LayerCache::open(cache_dir)?;
+        let missing = diff_ids.iter().filter(|id| !cache.contains(id)).count();
+        println!(
+            " * Step3 - assembling rootfs from layer cache, {} of {} layers cached ...",
+            diff_ids.len() - missing,
+            diff_ids.len()
+        );
+        if missing > 0 {
+            //docker engine has no api for single layers
+            println!(
+                " -- exporting whole image to extract {} new layers",
+                missing
+            );
+            let pb = create_chunk_pb(image_size, ProgressBarType::CopyingFiles);
+            pb.set_message("Exporting layers");
+            let pc = ProgressContext::new();
+            let input = stream_with_progress(Box::pin(docker.export_image(image_id)), &pb, pc)
+                .map_err(std::io::Error::other);
+            let reader = SyncIoBridge::new(StreamReader::new(Box::pin(input)));
+            let cache_dir = cache_dir.to_path_buf();
+            let stored = tokio::task::spawn_blocking(move || {
+                LayerCache::open(&cache_dir)?.store_from_archive(reader)
+            })
+            .await??;
+            pb.finish_and_clear();
+            println!(" -- stored {} layers in cache", stored.len());
+            if let Some(diff_id) = diff_ids.iter().find(|id| !cache.contains(id)) {
+                return Err(anyhow!("Layer {} not found in exported image", diff_id));
+            }
+        }
+        let mut tree = self.new_tree(&staging_dir(output_path))?;
+        let tree = tokio::task::spawn_blocking(move || {
+            cache.assemble(&mut tree, &diff_ids)?;
+            Ok::<_, anyhow::Error>(tree)
+        })
+        .await??;
+        println!(
+            " -- Assembled {} entries ({})",
+            tree.len(),
+            humansize::format_size(tree.files_size(), DECIMAL)
+        );
+        Ok(tree)
+    }
+
+    async fn build_from_registry(&self, image_name: &str) -> anyhow::Result<PathBuf> {
+        let reference = ImageReference::parse(image_name)?;
+        println!(" * Step1 - fetching image manifest: {} ...", reference);
+        let mut client = DistributionClient::new();
+        if let (Ok(user), Ok(password)) = (
+            env::var("SOURCE_REGISTRY_USER"),
+            env::var("SOURCE_REGISTRY_PASSWORD"),
+        ) {
+            println!(" -- Using credentials from environment variables (SOURCE_REGISTRY_USER and SOURCE_REGISTRY_PASSWORD)");
+            client = client.with_credentials(&user, &password);
+        }
+        let image = client
+            .resolve_image(&reference, self.platform.as_ref())
+            .await?;
+        let layers_size: u64 = image.layers.iter().map(|l| l.size).sum();
+        println!(
+            " -- Image name: {}\n -- Image id: {}\n -- Layers: {} ({})",
+            reference,
+            image.id,
+            image.layers.len(),
+            humansize::format_size(layers_size, DECIMAL)
+        );
+
+        let (path, up_to_date) = self
+            .prepare_output(
+                default_output_name(image_name.split('@').next().unwrap_or_default(), &image.id),
+                Some(&image.id),
+            )
+            .await?;
+        if up_to_date {
+            return Ok(PathBuf::from(path));
+        }
+
+        //layers are streamed directly from registry into the tree
+        let client = Arc::new(client);
+        let handle = tokio::runtime::Handle::current();
+        let layers = image
+            .layers
+            .iter()
+            .map(|l| (l.digest.clone(), l.size))
+            .collect();
+        let tree = self
+            .extract_layers(&path, layers, image.config.diff_ids(), move |digest| {
+                let stream = handle.block_on(client.blob_stream(&reference, digest))?;
+                let reader = SyncIoBridge::new_with_handle(
+                    StreamReader::new(Box::pin(stream)),
+                    handle.clone(),
+                );
+                Ok(Box::new(DigestReader::new(reader, digest)?))
+            })
+            .await?;
+
+        let meta_cfg = image.config.to_container_config(&image.id);
+        self.write_image(tree, &path, &meta_cfg, image.config.platform(), 3)
+            .await
+    }
+
+    async fn build_from_rootfs(
+        &self,
+        rootfs_path: &Path,
+        config_path: Option<&Path>,
+    ) -> anyhow::Result<PathBuf> {
+        println!(" * Step1 - reading rootfs: {} ...", rootfs_path.display());
+        let meta_cfg = match config_path {
+            Some(config_path) => load_config_file(config_path)?,
+            None => {
+                log::warn!("No config file given, image will have empty metadata (no entrypoint)");
+                ContainerConfig {
+                    domainname: Some("".to_string()),
+                    ..Default::default()
+                }
+            }
+        };
+        let base_name = if self.image_name.is_empty() {
+            rootfs_path
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string())
+                .unwrap_or("rootfs".to_string())
+                .split('.')
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        } else {
+            self.image_name.replace(['/', ':'], "-")
+        };
+        let (path, _) = self
+            .prepare_output(format!("{}.gvmi", base_name), None)
+            .await?;
+
+        println!(" * Step2 - collecting files ...");
+        let mut tree = self.new_tree(&staging_dir(&path))?;
+        let rootfs_path = rootfs_path.to_path_buf();
+        let tree = tokio::task::spawn_blocking(move || {
+            if rootfs_path.is_dir() {
+                tree.append_dir(&rootfs_path)?;
+            } else {
+                let file = fs::File::open(&rootfs_path).map_err(|e| {
+                    anyhow!("Failed to open rootfs {}: {}", rootfs_path.display(), e)
+                })?;
+                tree.append_tar(open_layer(file)?)?;
+            }
+            Ok::<_, anyhow::Error>(tree)
+        })
+        .await??;
+        println!(
+            " -- Collected {} entries ({})",
+            tree.len(),
+            humansize::format_size(tree.files_size(), DECIMAL)
+        );
+
+        self.write_image(tree, &path, &meta_cfg, None, 3).await
+    }
+
+    async fn build_from_oci_layout(
+        &self,
+        layout_path: &Path,
+        tag: Option<&str>,
+    ) -> anyhow::Result<PathBuf> {
+        println!(
+            " * Step1 - reading OCI layout: {} ...",
+            layout_path.display()
+        );
+        let layout = OciLayout::open(layout_path)?;
+        let image = layout.select_image(tag, self.platform.as_ref())?;
+        let image_name = image.name.clone().unwrap_or("image".to_string());
+        let layers_size: u64 = image.layers.iter().map(|l| l.size).sum();
+        println!(
+            " -- Image name: {}\n -- Image id: {}\n -- Layers: {} ({})",
+            image_name,
+            image.id,
+            image.layers.len(),
+            humansize::format_size(layers_size, DECIMAL)
+        );
+
+        let (path, up_to_date) = self
+            .prepare_output(default_output_name(&image_name, &image.id), Some(&image.id))
+            .await?;
+        if up_to_date {
+            return Ok(PathBuf::from(path));
+        }
+
+        let layers = image
+            .layers
+            .iter()
+            .map(|l| (l.digest.clone(), l.size))
+            .collect();
+        let tree = self
+            .extract_layers(&path, layers, image.config.diff_ids(), move |digest| {
+                Ok(Box::new(layout.open_blob(digest)?))
+            })
+            .await?;
+
+        let meta_cfg = image.config.to_container_config(&image.id);
+        self.write_image(tree, &path, &meta_cfg, image.config.platform(), 3)
+            .await
+    }
+
+    async fn build_from_archive(
+        &self,
+        archive_path: &Path,
+        tag: Option<&str>,
+    ) -> anyhow::Result<PathBuf> {
+        println!(
+            " * Step1 - reading docker archive: {} ...",
+            archive_path.display()
+        );
+        let archive = {
+            let archive_path = archive_path.to_path_buf();
+            tokio::task::spawn_blocking(move || DockerArchive::open(&archive_path)).await??
         };
+        let image = archive.select_image(tag)?;
+        if let Some(platform) = &self.platform {
+            check_config_platform(&image.config, platform)?;
+        }
+        let image_name = image.name.clone().unwrap_or("image".to_string());
+        let mut layers = Vec::new();
+        for layer in &image.layers {
+            layers.push((layer.clone(), archive.entry_size(layer)?));
+        }
+        let layers_size: u64 = layers.iter().map(|(_, size)| size).sum();
+        println!(
+            " -- Image name: {}\n -- Image id: {}\n -- Layers: {} ({})",
+            image_name,
+            image.id,
+            image.layers.len(),
+            humansize::format_size(layers_size, DECIMAL)
+        );
 
-        let parsed_name = ImageName::from_str_name(&self.image_name)?;
+        let (path, up_to_date) = self
+            .prepare_output(default_output_name(&image_name, &image.id), Some(&image.id))
+            .await?;
+        if up_to_date {
+            return Ok(PathBuf::from(path));
+        }
+
+        let tree = self
+            .extract_layers(&path, layers, image.config.diff_ids(), move |layer| {
+                Ok(Box::new(archive.open_entry(layer)?))
+            })
+            .await?;
+
+        let meta_cfg = image.config.to_container_config(&image.id);
+        self.write_image(tree, &path, &meta_cfg, image.config.platform(), 3)
+            .await
+    }
+
+    async fn build_from_dockerfile(&self, build: &DockerfileBuild) -> anyhow::Result<PathBuf> {
+        println!(
+            "Building image from Dockerfile: {}",
+            build.dockerfile.display()
+        );
+        let docker = connect_docker().await?;
+        println!(" -- build context: {}", build.context.display());
+        let tag = (!self.image_name.is_empty()).then_some(self.image_name.as_str());
+        let image_id = build_dockerfile(&docker, build, tag, self.platform.as_ref()).await?;
+        //untagged image is referred by its short id
+        let image_name = match tag {
+            Some(tag) => tag.to_string(),
+            None => image_id[..12].to_string(),
+        };
+        println!(" -- built image {} ({})", image_name, &image_id[..12]);
+        self.build_from_docker(&docker, &image_name).await
+    }
+
+    async fn build_from_docker(
+        &self,
+        docker: &Docker,
+        image_name: &str,
+    ) -> anyhow::Result<PathBuf> {
+        use bollard::{image, service::HostConfig};
+        let parsed_name = ImageName::from_str_name(image_name)?;
 
         let image_base_name = parsed_name.to_base_name();
         let tag_from_image_name = parsed_name.tag;
 
-        let inspect_image_result = docker.inspect_image(&self.image_name).await;
-        if inspect_image_result.is_err() {
+        let platform_name = self
+            .platform
+            .as_ref()
+            .map(|p| p.to_string())
+            .unwrap_or_default();
+        let mut local_image = docker.inspect_image(image_name).await.ok();
+        //local image of other platform is replaced by pulled one, like docker pull --platform does
+        if let (Some(platform), Some(image)) = (&self.platform, &local_image) {
+            let local_platform = docker_image_platform(image);
+            if !local_platform.map(|p| platform.matches(&p)).unwrap_or(true) {
+                println!(
+                    " -- local image {} is not for platform {}, pulling it",
+                    image_name, platform
+                );
+                local_image = None;
+            }
+        }
+        if local_image.is_none() {
             let mp = MultiProgress::new();
             let layers = Arc::new(Mutex::new(HashMap::<String, ProgressBar>::new()));
 
             println!(
                 " * Step1 - create image from given name: {} ...",
-                self.image_name
+                image_name
             );
 
             println!(
@@ -117,8 +834,9 @@ impl ImageBuilder {
             match docker
                 .create_image(
                     Some(image::CreateImageOptions {
-                        from_image: self.image_name.as_str(),
+                        from_image: image_name,
                         tag: &tag_from_image_name,
+                        platform: &platform_name,
                         ..Default::default()
                     }),
                     None,
@@ -179,18 +897,23 @@ impl ImageBuilder {
         } else {
             println!(
                 " * Step1 - image for given name {} found, image creation skipped",
-                self.image_name
+                image_name
             );
         }
 
         //pb.finish_and_clear();
 
-        println!(" * Step2 - inspect docker image: {} ...", self.image_name);
-        let image = if let Ok(inspect_image_result) = inspect_image_result {
-            inspect_image_result
-        } else {
-            docker.inspect_image(&self.image_name).await?
+        println!(" * Step2 - inspect docker image: {} ...", image_name);
+        let image = match local_image {
+            Some(image) => image,
+            None => docker.inspect_image(image_name).await?,
         };
+        let image_platform = docker_image_platform(&image);
+        let diff_ids = image
+            .root_fs
+            .as_ref()
+            .and_then(|root_fs| root_fs.layers.clone())
+            .unwrap_or_default();
         let image_id = image.id.unwrap();
         let image_id = if image_id.starts_with("sha256:") {
             image_id.replace("sha256:", "")
@@ -199,75 +922,83 @@ impl ImageBuilder {
             return Err(anyhow::anyhow!("Image id is not sha256: {}", image_id));
         };
 
+        if let (Some(platform), Some(image_platform)) = (&self.platform, &image_platform) {
+            if !platform.matches(image_platform) {
+                return Err(anyhow!(
+                    "Image {} platform {} does not match requested platform {}",
+                    image_name,
+                    image_platform,
+                    platform
+                ));
+            }
+        }
         let image_size = image.size.unwrap_or(0);
 
         println!(
-            " -- Image name: {}\n -- Image id: {}\n -- Image size: {}",
-            self.image_name,
+            " -- Image name: {}\n -- Image id: {}\n -- Image size: {}\n -- Image platform: {}",
+            image_name,
             image_id,
-            humansize::format_size(image_size as u64, DECIMAL)
+            humansize::format_size(image_size as u64, DECIMAL),
+            image_platform
+                .as_ref()
+                .map(|p| p.to_string())
+                .unwrap_or("unknown".to_string())
         );
 
-        let path = if let Some(path) = &self.output {
-            path.clone()
-        } else {
-            format!(
-                "{}-{}-{}.gvmi",
-                image_base_name.replace('/', "-"),
-                tag_from_image_name,
-                &image_id[0..10]
-            )
+        //architecture is part of the name only when platform is requested explicitly
+        let platform_suffix = match &self.platform {
+            Some(platform) => format!("-{}", platform.to_string().replace('/', "-")),
+            None => String::new(),
         };
-
-        let path = Path::new(&path);
-        if path.exists() {
-            let meta_out = read_metadata_outside(path).await;
-            match meta_out {
-                Ok(meta_out) => {
-                    if let Some(image_left) = meta_out.image {
-                        if image_left == image_id {
-                            if self.force_overwrite {
-                                println!(
-                                    " -- GVMI image already exists - overwriting: {}",
-                                    path.display()
-                                );
-                            } else {
-                                println!(" -- GVMI image already exists: {}", path.display());
-                                return Ok(PathBuf::from(path));
-                            }
-                        } else {
-                            println!(" -- GVMI image id mismatch: {}", path.display());
-                        }
-                    }
-                }
-                Err(_err) => {
-                    println!(
-                        " -- Failed to read metadata from GVMI image: {}",
-                        path.display()
-                    );
-                }
-            }
-        }
-        if let Err(err) = fs::write(path, "") {
-            log::error!("Failed to create output file: {} {}", path.display(), err);
-            return Err(anyhow::anyhow!("Failed to create output file: {}", err));
+        let (path, up_to_date) = self
+            .prepare_output(
+                format!(
+                    "{}-{}{}-{}.gvmi",
+                    image_base_name.replace('/', "-"),
+                    tag_from_image_name,
+                    platform_suffix,
+                    &image_id[0..10]
+                ),
+                Some(&image_id),
+            )
+            .await?;
+        if up_to_date {
+            return Ok(PathBuf::from(path));
         }
 
-        let path = path
-            .canonicalize()?
-            .display()
-            .to_string()
-            .replace(r"\\?\", ""); // strip \\?\ prefix on windows
-        println!(" -- GVMI image output path: {}", path);
+        //files are taken from cached layers, container is not needed
+        if let Some(cache_dir) = self.layer_cache.as_ref().filter(|_| !diff_ids.is_empty()) {
+            let mut tree = self
+                .extract_docker_layers(
+                    docker,
+                    cache_dir,
+                    &image_id,
+                    image_size as u64,
+                    diff_ids,
+                    &path,
+                )
+                .await?;
+            tree.add_docker_init_entries(Utc::now().timestamp().clamp(0, u32::MAX as i64) as u32)?;
+            let mut meta_cfg = image.config.ok_or(anyhow!("Image has no config"))?;
+            //the same as in config of container created from the image
+            meta_cfg.image = Some(image_id.clone());
+            meta_cfg.domainname = Some("".to_string());
+            meta_cfg.user = None;
+            return self
+                .write_image(tree, &path, &meta_cfg, image_platform, 4)
+                .await;
+        }
 
-        println!(
-            " * Step3 - create container from image: {} ...",
-            self.image_name
-        );
+        println!(" * Step3 - create container from image: {} ...", image_name);
 
         let container = docker
             .create_container::<String, String>(
-                None,
+                self.platform
+                    .as_ref()
+                    .map(|platform| container::CreateContainerOptions {
+                        name: String::new(),
+                        platform: Some(platform.to_string()),
+                    }),
                 container::Config {
                     image: Some(image_id.clone()),
                     host_config: Some(HostConfig {
@@ -285,95 +1016,11 @@ impl ImageBuilder {
 
         println!(" -- Container id: {}", &container_id[0..12]);
 
-        let tool_image_name =
-            env::var("SQUASHFS_IMAGE_NAME").unwrap_or("scx1332/squashfs".to_string());
-
-        if docker.inspect_image(&tool_image_name).await.is_err() {
-            println!(
-                " * Step4 - pulling tool image used for gvmi generation: {} ...",
-                tool_image_name
-            );
-
-            match docker
-                .create_image(
-                    Some(image::CreateImageOptions {
-                        from_image: tool_image_name.as_str(),
-                        tag: "latest",
-                        ..Default::default()
-                    }),
-                    None,
-                    None,
-                )
-                .try_for_each(|ev| async move {
-                    log::debug!("{:?}", ev);
-                    Ok(())
-                })
-                .await
-            {
-                Ok(_) => {}
-                Err(err) => {
-                    log::error!("Failed to create image: {} {}", tool_image_name, err);
-                    return Err(anyhow::anyhow!("Failed to create image: {}", err));
-                }
-            };
-        } else {
-            println!(
-                " * Step4 - tool image used for gvmi generation already exists: {} ...",
-                tool_image_name
-            );
-        }
-
         let copy_result: anyhow::Result<_> = async {
-            let mut mksquash_command = vec![
-                "mksquashfs".to_string(),
-                "/work/in".to_string(),
-                "/work/out/image.squashfs".to_string(),
-                "-info".to_string(),
-                "-comp".to_string(),
-                self.compression_method.clone(),
-                "-noappend".to_string(),
-            ];
-            if let Some(compression_level) = self.compression_level {
-                mksquash_command.push("-Xcompression-level".to_string());
-                mksquash_command.push(compression_level.to_string());
-            }
-            if self.compression_method == "lz4" {
-                //force Xhc compression for lz4, otherwise this compression type has no benefits over lzo
-                mksquash_command.push("-Xhc".to_string());
-            }
-            println!(" -- Image command: {}", mksquash_command.join(" "));
-
-            let tool_container = docker
-                .create_container::<String, String>(
-                    None,
-                    container::Config {
-                        image: Some(tool_image_name.to_string()),
-                        cmd: Some(mksquash_command),
-                        attach_stderr: Some(true),
-                        attach_stdout: Some(true),
-                        host_config: Some(HostConfig {
-                            auto_remove: Some(true),
-                            mounts: Some(vec![Mount {
-                                target: Some("/work/out/image.squashfs".to_string()),
-                                source: Some(path.clone()),
-                                typ: Some(MountTypeEnum::BIND),
-                                ..Default::default()
-                            }]),
-                            ..Default::default()
-                        }),
-                        ..Default::default()
-                    },
-                )
-                .await?;
-
-            println!(" -- Tool container id: {}", &tool_container.id[0..12]);
-
             println!(
-                " * Step5 - copy data between containers {} and {} ...",
-                &container_id[0..12],
-                &tool_container.id[0..12]
+                " * Step4 - copy files from container {} ...",
+                &container_id[0..12]
             );
-
             let input = docker.download_from_container(
                 &container_id,
                 Some(DownloadFromContainerOptions { path: "/" }),
@@ -388,136 +1035,27 @@ impl ImageBuilder {
             let pb = create_chunk_pb(image_size as u64, ProgressBarType::CopyingFiles);
             pb.set_style(sty.clone());
             pb.set_message("Copying files from /");
-            let input = stream_with_progress(input, &pb, pc.clone());
+            let input = stream_with_progress(input, &pb, pc.clone()).map_err(std::io::Error::other);
+            let reader = SyncIoBridge::new(StreamReader::new(Box::pin(input)));
 
-            docker
-                .upload_to_container::<String>(
-                    &tool_container.id,
-                    Some(UploadToContainerOptions {
-                        path: "/work/in".to_owned(),
-                        ..Default::default()
-                    }),
-                    hyper::Body::wrap_stream(input),
-                )
-                .await?;
+            let mut tree = self.new_tree(&staging_dir(&path))?;
+            let tree = tokio::task::spawn_blocking(move || {
+                tree.append_tar(reader)?;
+                Ok::<_, anyhow::Error>(tree)
+            })
+            .await??;
             pb.finish_and_clear();
             println!(
                 " -- Copying data finished. Copied {} bytes vs {} bytes image",
                 pc.total_bytes(),
                 image_size
             );
-            Ok(tool_container)
+            Ok(tree)
         }
         .await;
 
         docker.remove_container(&container_id, None).await?;
-        let tool_container = copy_result?;
-
-        docker
-            .start_container::<String>(&tool_container.id, None)
-            .await?;
-        println!(
-            " * Step6 - Starting tool container to create image: {}",
-            &tool_container.id[0..12]
-        );
-
-        let mp = MultiProgress::new();
-
-        let pg1 = create_chunk_pb(image_size as u64, ProgressBarType::PullLine1);
-        let pg2 = create_chunk_pb(image_size as u64, ProgressBarType::PullLine2);
-
-        if !pg1.is_hidden() {
-            mp.add(pg1.clone());
-        }
-        if !pg2.is_hidden() {
-            mp.add(pg2.clone());
-        }
-
-        docker
-            .logs::<String>(
-                &tool_container.id,
-                Some(LogsOptions {
-                    follow: true,
-                    stderr: true,
-                    stdout: true,
-                    ..Default::default()
-                }),
-            )
-            .try_for_each(|ev| {
-                let pg1 = pg1.clone();
-                let pg2 = pg2.clone();
-
-                async move {
-                    match ev {
-                        LogOutput::StdOut { message } => {
-                            let message = String::from_utf8(message.to_vec()).unwrap_or_default();
-                            if message.contains("uncompressed size") {
-                                let mut spl = message.split("uncompressed size");
-                                let filename = spl.next();
-                                let value = spl
-                                    .next()
-                                    .unwrap_or("0 bytes")
-                                    .split("bytes")
-                                    .next()
-                                    .unwrap_or("0")
-                                    .trim()
-                                    .parse::<u64>()
-                                    .unwrap_or(0);
-                                //log::info!("uncompressed size :: {}", value);
-                                if value != 0 {
-                                    pg1.inc(value);
-                                    pg2.inc(value);
-                                    let part = filename.unwrap_or_default();
-                                    if part.starts_with("file") {
-                                        let mut split = part.split("file");
-                                        split.next();
-                                        let part2 = split.next().unwrap_or_default();
-                                        let part2 = part2.split(',').next().unwrap_or_default();
-                                        pg2.set_message(part2.trim().to_string());
-                                    }
-                                }
-                            }
-                            //parse to int
-
-                            //log::info!("stdout :: {}", message.trim());
-                        }
-                        LogOutput::StdErr { message } => {
-                            let message = String::from_utf8(message.to_vec()).unwrap_or_default();
-                            log::info!("stderr :: {}", message.trim());
-                        }
-                        _ => {}
-                    }
-                    Ok(())
-                }
-            })
-            .await?;
-
-        pg1.finish_and_clear();
-        pg2.finish_and_clear();
-        println!(" * Step7 - Waiting for tool container to finish...");
-        //tokio::time::sleep(Duration::from_secs(1)).await;
-        match docker
-            .wait_container::<String>(&tool_container.id, None)
-            .try_for_each(|ev| async move {
-                log::debug!("end :: {:?}", ev);
-                Ok(())
-            })
-            .await
-        {
-            Ok(_) => {
-                println!(" -- Tool container finished");
-            }
-            Err(err) => {
-                if err.to_string().contains("No such container") {
-                    println!(" -- Tool container already removed");
-                } else {
-                    log::warn!("Failed to wait for tool container: {}", err)
-                }
-            }
-        }
-
-        print!(" * Step8 - Adding metadata...");
-        //        let hash = cont.id.ok_or(anyhow!("Container has no id"))?;
+        let tree = copy_result?;
 
         let cfg = cont.config.ok_or(anyhow!("Container has no config"))?;
 
@@ -526,21 +1064,8 @@ impl ImageBuilder {
         meta_cfg.domainname = Some("".to_string());
         meta_cfg.user = None;
 
-        let bytes = add_metadata_outside(&PathBuf::from(&path), &meta_cfg).await?;
-        let conf = read_metadata_outside(&PathBuf::from(&path)).await?;
-        log::debug!("conf :: {:?}", conf);
-        println!(" -- container metadata ({} bytes) added", bytes);
-        let file_length = fs::metadata(&path)?.len();
-        if file_length == 0 {
-            return Err(anyhow!("Output gvmi image is empty"));
-        }
-        println!(
-            " -- Output gvmi image size: {} ({} bytes), path: {}",
-            humansize::format_size(file_length, DECIMAL),
-            file_length,
-            &path
-        );
-        Ok(PathBuf::from(&path))
+        self.write_image(tree, &path, &meta_cfg, image_platform, 5)
+            .await
     }
 }
 
@@ -0,0 +1,164 @@
+use std::io::Read;
+
+use bollard::service::ContainerConfig;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::image::platform::Platform;
+
+/// Image configuration json shared by docker archives, OCI layouts and registries
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ImageConfig {
+    #[serde(default)]
+    pub architecture: Option<String>,
+    #[serde(default)]
+    pub os: Option<String>,
+    #[serde(default)]
+    pub variant: Option<String>,
+    #[serde(default)]
+    pub config: Option<ContainerConfig>,
+    #[serde(default)]
+    pub rootfs: Option<ImageRootfs>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ImageRootfs {
+    /// Digests of uncompressed layers, bottom first
+    #[serde(default)]
+    pub diff_ids: Vec<String>,
+}
+
+impl ImageConfig {
+    pub fn from_slice(bytes: &[u8]) -> anyhow::Result<Self> {
+        serde_json::from_slice(bytes)
+            .map_err(|e| anyhow::anyhow!("Failed to parse image config: {}", e))
+    }
+
+    /// Platform the image is built for, None if os or architecture is missing
+    pub fn platform(&self) -> Option<Platform> {
+        match (&self.os, &self.architecture) {
+            (Some(os), Some(arch)) => Some(Platform::new(os, arch, self.variant.as_deref())),
+            _ => None,
+        }
+    }
+
+    /// Diff ids of layers (sha256 of uncompressed layer tar), empty if not given
+    pub fn diff_ids(&self) -> &[String] {
+        self.rootfs
+            .as_ref()
+            .map(|rootfs| rootfs.diff_ids.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Container config stored in gvmi metadata, the same fields are kept as for docker built images
+    pub fn to_container_config(&self, image_id: &str) -> ContainerConfig {
+        let mut cfg = self.config.clone().unwrap_or_default();
+        cfg.image = Some(image_id.to_string());
+        cfg.domainname = Some("".to_string());
+        cfg.user = None;
+        cfg
+    }
+}
+
+/// Loads container config from json file, both gvmi metadata format (docker `Config` section)
+/// and full image config (with `config` field) are accepted
+pub fn load_config_file(path: &std::path::Path) -> anyhow::Result<ContainerConfig> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read config file {}: {}", path.display(), e))?;
+    let value: serde_json::Value = serde_json::from_slice(&bytes)
+        .map_err(|e| anyhow::anyhow!("Failed to parse config file {}: {}", path.display(), e))?;
+    let mut cfg = if value.get("config").is_some() {
+        serde_json::from_value::<ImageConfig>(value)?
+            .config
+            .unwrap_or_default()
+    } else {
+        serde_json::from_value::<ContainerConfig>(value)?
+    };
+    cfg.domainname = Some("".to_string());
+    Ok(cfg)
+}
+
+/// Wraps layer stream with decompressor detected from the first bytes (gzip, zstd or plain tar)
+pub fn open_layer<'a>(reader: impl Read + 'a) -> anyhow::Result<Box<dyn Read + 'a>> {
+    let mut reader = std::io::BufReader::new(reader);
+    let magic = std::io::BufRead::fill_buf(&mut reader)?;
+    if magic.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(flate2::read::MultiGzDecoder::new(reader)))
+    } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+        Ok(Box::new(zstd::stream::read::Decoder::with_buffer(reader)?))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+/// Output file name derived from image name and id, e.g. `user-repo-tag-0123456789.gvmi`
+pub fn default_output_name(image_name: &str, image_id: &str) -> String {
+    let (base, tag) = match image_name.rsplit_once(':') {
+        Some((base, tag)) if !tag.contains('/') => (base, tag),
+        _ => (image_name, "latest"),
+    };
+    format!(
+        "{}-{}-{}.gvmi",
+        base.replace(['/', ':'], "-"),
+        tag,
+        &image_id[0..std::cmp::min(10, image_id.len())]
+    )
+}
+
+fn parse_sha256_digest(digest: &str) -> anyhow::Result<&str> {
+    match digest.split_once(':') {
+        Some(("sha256", hex)) if hex.len() == 64 => Ok(hex),
+        _ => Err(anyhow::anyhow!("Unsupported digest: {}", digest)),
+    }
+}
+
+/// Checks if content matches `sha256:<hex>` digest
+pub fn verify_digest(digest: &str, bytes: &[u8]) -> anyhow::Result<()> {
+    let expected = parse_sha256_digest(digest)?;
+    let actual = hex::encode(Sha256::digest(bytes));
+    if actual != expected {
+        return Err(anyhow::anyhow!(
+            "Digest mismatch, expected {} got sha256:{}",
+            digest,
+            actual
+        ));
+    }
+    Ok(())
+}
+
+/// Reader computing sha256 of the content, returns error at the end of stream if digest does not match
+pub struct DigestReader<R> {
+    inner: R,
+    hasher: Sha256,
+    expected: String,
+}
+
+impl<R> DigestReader<R> {
+    pub fn new(inner: R, digest: &str) -> anyhow::Result<Self> {
+        Ok(DigestReader {
+            inner,
+            hasher: Sha256::new(),
+            expected: parse_sha256_digest(digest)?.to_string(),
+        })
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            let actual = hex::encode(self.hasher.clone().finalize());
+            if actual != self.expected {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Digest mismatch, expected sha256:{} got sha256:{}",
+                        self.expected, actual
+                    ),
+                ));
+            }
+        }
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
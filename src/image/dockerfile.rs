@@ -0,0 +1,340 @@
+//! Building image from Dockerfile and context directory in docker engine.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{Seek, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use bollard::image::BuildImageOptions;
+use bollard::Docker;
+use futures_util::TryStreamExt;
+use humansize::DECIMAL;
+use tokio_util::io::ReaderStream;
+
+use crate::ignore::IgnoreRules;
+use crate::image::platform::Platform;
+
+pub const DOCKERIGNORE: &str = ".dockerignore";
+/// Dockerfile (truncated to target stage) is sent in the context under this name
+const CONTEXT_DOCKERFILE: &str = ".gvmkit-build.Dockerfile";
+
+/// Dockerfile build parameters, the same as for `docker build`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DockerfileBuild {
+    pub dockerfile: PathBuf,
+    pub context: PathBuf,
+    pub build_args: Vec<(String, String)>,
+    /// Stage to build in multi-stage Dockerfile, the last one by default
+    pub target: Option<String>,
+}
+
+/// Parses `KEY=VALUE` build argument, `KEY` alone takes the value from environment like docker does
+pub fn parse_build_arg(arg: &str) -> anyhow::Result<(String, String)> {
+    let (key, value) = match arg.split_once('=') {
+        Some((key, value)) => (key, value.to_string()),
+        None => (
+            arg,
+            std::env::var(arg).map_err(|_| {
+                anyhow!("Build argument {arg} has no value and is not set in environment")
+            })?,
+        ),
+    };
+    if key.is_empty() {
+        return Err(anyhow!("Invalid build argument {arg}, expected KEY=VALUE"));
+    }
+    Ok((key.to_string(), value))
+}
+
+/// Terminators of heredocs (`<<EOF`, `<<-"EOF"`) started in the line,
+/// the flag is set when leading tabs are stripped from the body (`<<-`)
+fn heredoc_terminators(line: &str) -> Vec<(String, bool)> {
+    let mut terminators = Vec::new();
+    let mut rest = line;
+    while let Some(pos) = rest.find("<<") {
+        rest = &rest[pos + 2..];
+        //<<< is a here-string, not a heredoc
+        if rest.starts_with('<') {
+            rest = rest.trim_start_matches('<');
+            continue;
+        }
+        let strip_tabs = rest.starts_with('-');
+        if strip_tabs {
+            rest = &rest[1..];
+        }
+        let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'');
+        if let Some(quote) = quote {
+            rest = &rest[1..];
+            if let Some(end) = rest.find(quote) {
+                terminators.push((rest[..end].to_string(), strip_tabs));
+                rest = &rest[end + 1..];
+            }
+            continue;
+        }
+        let end = rest
+            .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+            .unwrap_or(rest.len());
+        if end > 0 {
+            terminators.push((rest[..end].to_string(), strip_tabs));
+        }
+        rest = &rest[end..];
+    }
+    terminators
+}
+
+/// Removes stages following the target stage, so the target is built as the last one
+/// (the same stages are built as by the classic builder with --target)
+pub fn truncate_to_target(dockerfile: &str, target: &str) -> anyhow::Result<String> {
+    //offsets of FROM instructions and names of stages they start
+    let mut stages: Vec<(usize, Option<String>)> = Vec::new();
+    let mut offset = 0;
+    let mut continued = false;
+    //heredocs waiting for their terminator line, in order of appearance
+    let mut heredocs: VecDeque<(String, bool)> = VecDeque::new();
+    for line in dockerfile.split_inclusive('\n') {
+        let trimmed = line.trim();
+        if let Some((terminator, strip_tabs)) = heredocs.front() {
+            let body_line = line.trim_end_matches(['\n', '\r']);
+            let body_line = if *strip_tabs {
+                body_line.trim_start_matches('\t')
+            } else {
+                body_line
+            };
+            if body_line == terminator {
+                heredocs.pop_front();
+            }
+            offset += line.len();
+            continue;
+        }
+        //comments and empty lines do not end instruction continued with backslash
+        if !trimmed.is_empty() && !trimmed.starts_with('#') {
+            if !continued {
+                let mut words = trimmed.split_whitespace();
+                if words.next().map(|w| w.eq_ignore_ascii_case("FROM")) == Some(true) {
+                    let words: Vec<&str> = words.filter(|w| !w.starts_with("--")).collect();
+                    let name = match words.as_slice() {
+                        [_, as_keyword, name, ..] if as_keyword.eq_ignore_ascii_case("AS") => {
+                            Some(name.to_string())
+                        }
+                        _ => None,
+                    };
+                    stages.push((offset, name));
+                }
+            }
+            heredocs.extend(heredoc_terminators(trimmed));
+            continued = trimmed.ends_with('\\');
+        }
+        offset += line.len();
+    }
+    let target_no = stages
+        .iter()
+        .position(|(_, name)| {
+            name.as_ref()
+                .map(|name| name.eq_ignore_ascii_case(target))
+                .unwrap_or(false)
+        })
+        .ok_or_else(|| anyhow!("Target stage {target} not found in Dockerfile"))?;
+    let end = stages
+        .get(target_no + 1)
+        .map(|(offset, _)| *offset)
+        .unwrap_or(dockerfile.len());
+    Ok(dockerfile[..end].to_string())
+}
+
+/// Size of the build context sent to docker
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ContextStats {
+    pub files: u64,
+    pub bytes: u64,
+    pub excluded: u64,
+}
+
+/// Writes tar of the context directory, paths excluded by `.dockerignore` are skipped.
+/// Dockerfile content is added as `.gvmkit-build.Dockerfile`, so it can be outside of the context.
+pub fn write_context_tar(
+    context: &Path,
+    dockerfile: &[u8],
+    output: impl Write,
+) -> anyhow::Result<ContextStats> {
+    let rules = IgnoreRules::load(&context.join(DOCKERIGNORE))?;
+    let mut builder = tar::Builder::new(output);
+    builder.follow_symlinks(false);
+    let mut stats = ContextStats::default();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = fs::read_dir(context.join(&dir))
+            .map_err(|e| anyhow!("Failed to read context directory {}: {}", dir.display(), e))?
+            .collect::<Result<Vec<_>, _>>()?;
+        //sorted, so the same context gives the same tar
+        entries.sort_by_key(|e| e.file_name());
+        let mut subdirs = Vec::new();
+        for entry in entries {
+            let rel_path = dir.join(entry.file_name());
+            if rel_path == Path::new(CONTEXT_DOCKERFILE) {
+                continue;
+            }
+            let is_dir = entry.file_type()?.is_dir();
+            //.dockerignore is always sent, like docker cli does
+            if rel_path != Path::new(DOCKERIGNORE) && rules.is_excluded(&rel_path) {
+                //directory has to be visited if some files inside can be included again
+                if is_dir && rules.has_exceptions() {
+                    subdirs.push(rel_path);
+                }
+                stats.excluded += 1;
+                continue;
+            }
+            builder
+                .append_path_with_name(entry.path(), &rel_path)
+                .map_err(|e| anyhow!("Failed to add {} to context: {}", rel_path.display(), e))?;
+            if is_dir {
+                subdirs.push(rel_path);
+            } else {
+                stats.files += 1;
+                stats.bytes += entry.metadata()?.len();
+            }
+        }
+        dirs.extend(subdirs.into_iter().rev());
+    }
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_size(dockerfile.len() as u64);
+    header.set_cksum();
+    builder.append_data(&mut header, CONTEXT_DOCKERFILE, dockerfile)?;
+    builder.into_inner()?.flush()?;
+    Ok(stats)
+}
+
+/// Builds image in docker engine printing build log, returns id of the image
+pub async fn build_dockerfile(
+    docker: &Docker,
+    build: &DockerfileBuild,
+    tag: Option<&str>,
+    platform: Option<&Platform>,
+) -> anyhow::Result<String> {
+    let dockerfile = fs::read_to_string(&build.dockerfile).map_err(|e| {
+        anyhow!(
+            "Failed to read Dockerfile {}: {}",
+            build.dockerfile.display(),
+            e
+        )
+    })?;
+    let dockerfile = match &build.target {
+        Some(target) => truncate_to_target(&dockerfile, target)?,
+        None => dockerfile,
+    };
+    //walking and reading the whole context is blocking file I/O
+    let context = build.context.clone();
+    let (context_file, stats) = tokio::task::spawn_blocking(move || {
+        let mut context_file = tempfile::tempfile()?;
+        let stats = write_context_tar(&context, dockerfile.as_bytes(), &mut context_file)?;
+        context_file.rewind()?;
+        anyhow::Ok((context_file, stats))
+    })
+    .await??;
+    println!(
+        " -- sending context: {} files ({}), {} paths excluded by {}",
+        stats.files,
+        humansize::format_size(stats.bytes, DECIMAL),
+        stats.excluded,
+        DOCKERIGNORE
+    );
+
+    let options = BuildImageOptions {
+        dockerfile: CONTEXT_DOCKERFILE.to_string(),
+        t: tag.unwrap_or_default().to_string(),
+        buildargs: build.build_args.iter().cloned().collect::<HashMap<_, _>>(),
+        platform: platform.map(|p| p.to_string()).unwrap_or_default(),
+        rm: true,
+        ..Default::default()
+    };
+    let body = hyper::Body::wrap_stream(ReaderStream::new(tokio::fs::File::from_std(context_file)));
+    let mut stream = docker.build_image(options, None, Some(body));
+    let mut image_id = None;
+    while let Some(info) = stream
+        .try_next()
+        .await
+        .map_err(|e| anyhow!("Docker build failed: {}", e))?
+    {
+        if let Some(error) = info.error {
+            return Err(anyhow!("Docker build failed: {}", error.trim()));
+        }
+        if let Some(line) = info.stream {
+            print!("{line}");
+        } else if let (Some(status), None) = (info.status, info.progress) {
+            println!("{status}");
+        }
+        if let Some(id) = info.aux.and_then(|aux| aux.id) {
+            image_id = Some(id);
+        }
+    }
+    let image_id = image_id.ok_or_else(|| anyhow!("Docker build did not return image id"))?;
+    Ok(image_id.trim_start_matches("sha256:").to_string())
+}
+
+#[test]
+fn test_dockerfile_context() {
+    let dockerfile = "# syntax=docker/dockerfile:1\nFROM rust:1.72 AS builder\nRUN cargo build \\\n  --release\n\nfrom --platform=linux/amd64 debian:12 as runtime\nCOPY --from=builder /app /app\n\nFROM runtime AS test\nRUN /app --test\n";
+    let truncated = truncate_to_target(dockerfile, "Runtime").unwrap();
+    assert!(truncated.ends_with("COPY --from=builder /app /app\n\n"));
+    assert_eq!(
+        truncate_to_target(dockerfile, "builder").unwrap(),
+        "# syntax=docker/dockerfile:1\nFROM rust:1.72 AS builder\nRUN cargo build \\\n  --release\n\n"
+    );
+    assert_eq!(truncate_to_target(dockerfile, "test").unwrap(), dockerfile);
+    assert!(truncate_to_target(dockerfile, "release").is_err());
+    //FROM inside heredoc body does not start a stage
+    let dockerfile = "FROM alpine AS base\nRUN <<EOF\nFROM inside heredoc\nEOF\nCOPY <<-\"END\" /etc/motd\n\tFROM motd\n\tEND\nFROM base AS final\n";
+    assert_eq!(
+        truncate_to_target(dockerfile, "base").unwrap(),
+        "FROM alpine AS base\nRUN <<EOF\nFROM inside heredoc\nEOF\nCOPY <<-\"END\" /etc/motd\n\tFROM motd\n\tEND\n"
+    );
+    assert_eq!(truncate_to_target(dockerfile, "final").unwrap(), dockerfile);
+
+    assert_eq!(
+        parse_build_arg("VERSION=1.0=rc").unwrap(),
+        ("VERSION".to_string(), "1.0=rc".to_string())
+    );
+    assert!(parse_build_arg("=1").is_err());
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let context = temp_dir.path();
+    fs::create_dir_all(context.join("src/__pycache__")).unwrap();
+    fs::create_dir_all(context.join("target/release")).unwrap();
+    fs::write(context.join("src/main.py"), "print(1)").unwrap();
+    fs::write(context.join("src/__pycache__/main.pyc"), "x").unwrap();
+    fs::write(context.join("target/release/app"), "binary").unwrap();
+    fs::write(context.join("target/release/keep"), "k").unwrap();
+    fs::write(context.join(CONTEXT_DOCKERFILE), "stale").unwrap();
+    fs::write(
+        context.join(DOCKERIGNORE),
+        "target\n**/__pycache__\n!target/release/keep\n",
+    )
+    .unwrap();
+
+    let mut tar_bytes = Vec::new();
+    let stats = write_context_tar(context, b"FROM scratch\n", &mut tar_bytes).unwrap();
+    let mut archive = tar::Archive::new(&tar_bytes[..]);
+    let mut paths = Vec::new();
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().display().to_string();
+        if path == CONTEXT_DOCKERFILE {
+            let mut content = String::new();
+            std::io::Read::read_to_string(&mut entry, &mut content).unwrap();
+            assert_eq!(content, "FROM scratch\n");
+        }
+        paths.push(path);
+    }
+    assert_eq!(
+        paths,
+        [
+            ".dockerignore",
+            "src",
+            "src/main.py",
+            "target/release/keep",
+            CONTEXT_DOCKERFILE
+        ]
+    );
+    assert_eq!(stats.files, 3);
+    assert_eq!(stats.excluded, 5);
+}
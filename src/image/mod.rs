@@ -1,5 +1,22 @@
+mod archive;
 mod builder;
+mod config;
+mod dockerfile;
 mod name;
+mod oci;
+mod platform;
+mod registry;
 
-pub use builder::ImageBuilder;
+pub use archive::{normalize_tag, ArchiveImage, DockerArchive};
+pub use builder::{ImageBuilder, ImageSource};
+pub use config::{
+    default_output_name, load_config_file, open_layer, verify_digest, DigestReader, ImageConfig,
+};
+pub use dockerfile::{parse_build_arg, DockerfileBuild};
 pub use name::ImageName;
+pub use oci::{
+    check_config_platform, select_platform_manifest, OciDescriptor, OciImage, OciLayout,
+    OciManifest,
+};
+pub use platform::{Platform, PLATFORM_LABEL};
+pub use registry::{DistributionClient, ImageReference};
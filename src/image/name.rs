@@ -1,3 +1,4 @@
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ImageName {
     pub user: Option<String>,
     pub repository: String,
@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use serde::Deserialize;
+
+use crate::image::archive::normalize_tag;
+use crate::image::config::{verify_digest, DigestReader, ImageConfig};
+use crate::image::platform::Platform;
+
+const REF_NAME_ANNOTATION: &str = "org.opencontainers.image.ref.name";
+
+/// Content descriptor (OCI and docker distribution formats share the same fields)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OciDescriptor {
+    #[serde(default)]
+    pub media_type: Option<String>,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default)]
+    pub platform: Option<Platform>,
+    #[serde(default)]
+    pub annotations: Option<HashMap<String, String>>,
+}
+
+impl OciDescriptor {
+    fn ref_name(&self) -> Option<&str> {
+        self.annotations
+            .as_ref()
+            .and_then(|a| a.get(REF_NAME_ANNOTATION))
+            .map(|s| s.as_str())
+    }
+}
+
+/// Image manifest or image index (manifest list)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum OciManifest {
+    Image {
+        config: OciDescriptor,
+        layers: Vec<OciDescriptor>,
+    },
+    Index {
+        manifests: Vec<OciDescriptor>,
+    },
+}
+
+impl OciManifest {
+    pub fn from_slice(bytes: &[u8]) -> anyhow::Result<Self> {
+        serde_json::from_slice(bytes).map_err(|e| anyhow!("Failed to parse image manifest: {}", e))
+    }
+}
+
+/// Selects manifest for given platform from image index
+pub fn select_platform_manifest<'a>(
+    manifests: &'a [OciDescriptor],
+    platform: &Platform,
+) -> anyhow::Result<&'a OciDescriptor> {
+    manifests
+        .iter()
+        .find(|m| m.platform.as_ref().map(|p| platform.matches(p)) == Some(true))
+        .ok_or_else(|| {
+            let available: Vec<String> = manifests
+                .iter()
+                .filter_map(|m| m.platform.as_ref().map(|p| p.to_string()))
+                .collect();
+            anyhow!(
+                "Image for platform {} not found, available platforms: {}",
+                platform,
+                available.join(", ")
+            )
+        })
+}
+
+/// Checks if platform of the image config matches requested one
+pub fn check_config_platform(config: &ImageConfig, platform: &Platform) -> anyhow::Result<()> {
+    if let Some(image_platform) = config.platform() {
+        if !platform.matches(&image_platform) {
+            return Err(anyhow!(
+                "Image platform {} does not match requested platform {}",
+                image_platform,
+                platform
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Image selected from OCI layout
+#[derive(Debug, Clone)]
+pub struct OciImage {
+    pub name: Option<String>,
+    /// Image id (config digest without algorithm prefix)
+    pub id: String,
+    pub config: ImageConfig,
+    pub layers: Vec<OciDescriptor>,
+}
+
+/// OCI image layout directory (`oci-layout`, `index.json` and `blobs`)
+pub struct OciLayout {
+    root: PathBuf,
+}
+
+impl OciLayout {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let layout = fs::read(path.join("oci-layout")).map_err(|_| {
+            anyhow!(
+                "{} is not OCI image layout, oci-layout file not found",
+                path.display()
+            )
+        })?;
+        let layout: serde_json::Value = serde_json::from_slice(&layout)?;
+        if layout["imageLayoutVersion"].as_str() != Some("1.0.0") {
+            log::warn!(
+                "Unknown OCI layout version: {}",
+                layout["imageLayoutVersion"]
+            );
+        }
+        Ok(OciLayout {
+            root: path.to_path_buf(),
+        })
+    }
+
+    fn blob_path(&self, digest: &str) -> anyhow::Result<PathBuf> {
+        let (algorithm, hex) = digest
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Invalid digest: {}", digest))?;
+        if !algorithm.chars().all(|c| c.is_ascii_alphanumeric())
+            || !hex.chars().all(|c| c.is_ascii_hexdigit())
+        {
+            return Err(anyhow!("Invalid digest: {}", digest));
+        }
+        Ok(self.root.join("blobs").join(algorithm).join(hex))
+    }
+
+    /// Reads whole blob and checks its digest
+    pub fn read_blob(&self, digest: &str) -> anyhow::Result<Vec<u8>> {
+        let bytes = fs::read(self.blob_path(digest)?)
+            .map_err(|e| anyhow!("Failed to read blob {}: {}", digest, e))?;
+        verify_digest(digest, &bytes)?;
+        Ok(bytes)
+    }
+
+    /// Opens blob for streaming, digest is checked when whole blob is read
+    pub fn open_blob(&self, digest: &str) -> anyhow::Result<impl Read> {
+        let file = File::open(self.blob_path(digest)?)
+            .map_err(|e| anyhow!("Failed to open blob {}: {}", digest, e))?;
+        DigestReader::new(file, digest)
+    }
+
+    /// Selects image by tag (ref name annotation) and platform.
+    /// Tag can be omitted if layout contains only one image.
+    /// Without requested platform linux/amd64 is selected from indexes and platform of single image is not checked.
+    pub fn select_image(
+        &self,
+        tag: Option<&str>,
+        requested_platform: Option<&Platform>,
+    ) -> anyhow::Result<OciImage> {
+        let default_platform = Platform::default();
+        let platform = requested_platform.unwrap_or(&default_platform);
+        let index = OciManifest::from_slice(&fs::read(self.root.join("index.json"))?)?;
+        let OciManifest::Index { manifests } = index else {
+            return Err(anyhow!("index.json is not an image index"));
+        };
+        let candidates: Vec<OciDescriptor> = match tag {
+            Some(tag) => manifests
+                .iter()
+                .filter(|m| match m.ref_name() {
+                    Some(ref_name) => {
+                        ref_name == tag || normalize_tag(ref_name) == normalize_tag(tag)
+                    }
+                    None => false,
+                })
+                .cloned()
+                .collect(),
+            None => manifests.clone(),
+        };
+        let name = tag
+            .or_else(|| candidates.iter().find_map(|m| m.ref_name()))
+            .map(|name| {
+                //skopeo and buildah store only tag as ref name, layout directory is used as image name then
+                if name.contains(':') || name.contains('/') {
+                    name.to_string()
+                } else {
+                    let dir_name = self
+                        .root
+                        .canonicalize()
+                        .ok()
+                        .and_then(|p| p.file_name().map(|n| n.to_string_lossy().to_string()))
+                        .unwrap_or("image".to_string());
+                    format!("{dir_name}:{name}")
+                }
+            });
+        let mut descriptor = match candidates.as_slice() {
+            [] => {
+                let available: Vec<&str> = manifests.iter().filter_map(|m| m.ref_name()).collect();
+                return Err(anyhow!(
+                    "Image {} not found in OCI layout, available: {}",
+                    tag.unwrap_or_default(),
+                    available.join(", ")
+                ));
+            }
+            [single] => single.clone(),
+            many if many.iter().all(|m| m.platform.is_some()) => {
+                select_platform_manifest(many, platform)?.clone()
+            }
+            many => {
+                return Err(anyhow!(
+                    "OCI layout contains {} images, select one by tag",
+                    many.len()
+                ))
+            }
+        };
+        //nested indexes are resolved until image manifest for the platform is found
+        for _ in 0..5 {
+            match OciManifest::from_slice(&self.read_blob(&descriptor.digest)?)? {
+                OciManifest::Index { manifests } => {
+                    descriptor = select_platform_manifest(&manifests, platform)?.clone();
+                }
+                OciManifest::Image { config, layers } => {
+                    let config_bytes = self.read_blob(&config.digest)?;
+                    let image_config = ImageConfig::from_slice(&config_bytes)?;
+                    if let Some(platform) = requested_platform {
+                        check_config_platform(&image_config, platform)?;
+                    }
+                    let id = config
+                        .digest
+                        .split_once(':')
+                        .map(|(_, hex)| hex.to_string())
+                        .unwrap_or(config.digest.clone());
+                    return Ok(OciImage {
+                        name,
+                        id,
+                        config: image_config,
+                        layers,
+                    });
+                }
+            }
+        }
+        Err(anyhow!("Too many nested image indexes"))
+    }
+}
+
+#[test]
+fn test_oci_layout() {
+    use sha2::{Digest, Sha256};
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let root = temp_dir.path();
+    fs::create_dir_all(root.join("blobs/sha256")).unwrap();
+    let add_blob = |data: &[u8]| {
+        let hex = hex::encode(Sha256::digest(data));
+        fs::write(root.join("blobs/sha256").join(&hex), data).unwrap();
+        (format!("sha256:{hex}"), data.len())
+    };
+
+    let mut layer = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header.set_size(5);
+    layer
+        .append_data(&mut header, "etc/hostname", &b"golem"[..])
+        .unwrap();
+    let (layer_digest, layer_size) = add_blob(&layer.into_inner().unwrap());
+
+    let mut manifests = Vec::new();
+    for arch in ["amd64", "arm64"] {
+        let config = format!(
+            r#"{{"architecture":"{arch}","os":"linux","config":{{"Env":["ARCH={arch}"],"Cmd":["/bin/sh"],"WorkingDir":"/","Volumes":{{"/data":{{}}}}}}}}"#
+        );
+        let (config_digest, config_size) = add_blob(config.as_bytes());
+        let manifest = format!(
+            r#"{{"schemaVersion":2,"mediaType":"application/vnd.oci.image.manifest.v1+json","config":{{"mediaType":"application/vnd.oci.image.config.v1+json","digest":"{config_digest}","size":{config_size}}},"layers":[{{"mediaType":"application/vnd.oci.image.layer.v1.tar","digest":"{layer_digest}","size":{layer_size}}}]}}"#
+        );
+        let (digest, size) = add_blob(manifest.as_bytes());
+        manifests.push(format!(
+            r#"{{"mediaType":"application/vnd.oci.image.manifest.v1+json","digest":"{digest}","size":{size},"platform":{{"architecture":"{arch}","os":"linux"}}}}"#
+        ));
+    }
+    let nested = format!(
+        r#"{{"schemaVersion":2,"manifests":[{}]}}"#,
+        manifests.join(",")
+    );
+    let (nested_digest, nested_size) = add_blob(nested.as_bytes());
+    fs::write(
+        root.join("index.json"),
+        format!(
+            r#"{{"schemaVersion":2,"manifests":[{{"mediaType":"application/vnd.oci.image.index.v1+json","digest":"{nested_digest}","size":{nested_size},"annotations":{{"org.opencontainers.image.ref.name":"golem/test:v1"}}}}]}}"#
+        ),
+    )
+    .unwrap();
+    assert!(OciLayout::open(root).is_err());
+    fs::write(root.join("oci-layout"), r#"{"imageLayoutVersion":"1.0.0"}"#).unwrap();
+
+    let layout = OciLayout::open(root).unwrap();
+    let arm = Platform::from_str_name("linux/arm64").unwrap();
+    let image = layout
+        .select_image(Some("golem/test:v1"), Some(&arm))
+        .unwrap();
+    assert_eq!(image.config.platform(), Some(arm.clone()));
+    let cfg = image.config.to_container_config(&image.id);
+    assert_eq!(cfg.env, Some(vec!["ARCH=arm64".to_string()]));
+    assert_eq!(cfg.cmd, Some(vec!["/bin/sh".to_string()]));
+    assert!(cfg.volumes.unwrap().contains_key("/data"));
+    assert_eq!(image.layers.len(), 1);
+    let mut content = Vec::new();
+    layout
+        .open_blob(&image.layers[0].digest)
+        .unwrap()
+        .read_to_end(&mut content)
+        .unwrap();
+    assert_eq!(content.len(), layer_size);
+
+    assert!(layout
+        .select_image(Some("golem/test:v2"), Some(&arm))
+        .is_err());
+    assert_eq!(
+        layout.select_image(None, None).unwrap().name.as_deref(),
+        Some("golem/test:v1")
+    );
+    let riscv = Platform::from_str_name("linux/riscv64").unwrap();
+    assert!(layout.select_image(None, Some(&riscv)).is_err());
+
+    //corrupted blob is detected
+    fs::write(
+        root.join("blobs/sha256").join(&layer_digest[7..]),
+        b"corrupted",
+    )
+    .unwrap();
+    let mut content = Vec::new();
+    assert!(layout
+        .open_blob(&image.layers[0].digest)
+        .unwrap()
+        .read_to_end(&mut content)
+        .is_err());
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Label added to image metadata with the platform the image was built for
+pub const PLATFORM_LABEL: &str = "network.golem.image.platform";
+
+/// Image platform as used in OCI image indexes, e.g. `linux/amd64` or `linux/arm/v7`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+/// Maps rust/uname architecture names to names used by OCI
+fn normalize_architecture(arch: &str) -> &str {
+    match arch {
+        "x86_64" | "x86-64" => "amd64",
+        "aarch64" => "arm64",
+        "i386" | "i686" | "x86" => "386",
+        other => other,
+    }
+}
+
+impl Platform {
+    pub fn new(os: &str, architecture: &str, variant: Option<&str>) -> Self {
+        Platform {
+            os: os.to_string(),
+            architecture: normalize_architecture(architecture).to_string(),
+            variant: variant.map(|v| v.to_string()),
+        }
+    }
+
+    pub fn from_str_name(name: &str) -> anyhow::Result<Self> {
+        let parts: Vec<&str> = name.split('/').collect();
+        match parts.as_slice() {
+            [os, arch] if !os.is_empty() && !arch.is_empty() => Ok(Platform::new(os, arch, None)),
+            [os, arch, variant] if !os.is_empty() && !arch.is_empty() && !variant.is_empty() => {
+                Ok(Platform::new(os, arch, Some(variant)))
+            }
+            _ => Err(anyhow::anyhow!(
+                "Invalid platform: {}. Use format <os>/<architecture>[/<variant>], e.g. linux/amd64",
+                name
+            )),
+        }
+    }
+
+    /// Checks if image built for `other` can be used when this platform is requested.
+    /// Variant is compared only if requested (arm64 images usually do not specify it).
+    pub fn matches(&self, other: &Platform) -> bool {
+        self.os == other.os
+            && self.architecture == normalize_architecture(&other.architecture)
+            && match (&self.variant, &other.variant) {
+                (Some(v), Some(o)) => v == o,
+                (Some(v), None) => self.architecture == "arm64" && v == "v8",
+                (None, _) => true,
+            }
+    }
+}
+
+impl Default for Platform {
+    /// Platform used by Golem providers when none is requested
+    fn default() -> Self {
+        Platform::new("linux", "amd64", None)
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_platform() {
+    let platform = Platform::from_str_name("linux/amd64").unwrap();
+    assert_eq!(platform.to_string(), "linux/amd64");
+    assert!(platform.matches(&Platform::new("linux", "x86_64", None)));
+    assert!(!platform.matches(&Platform::new("linux", "arm64", None)));
+
+    let arm = Platform::from_str_name("linux/arm64/v8").unwrap();
+    assert!(arm.matches(&Platform::new("linux", "arm64", None)));
+    assert!(arm.matches(&Platform::new("linux", "aarch64", Some("v8"))));
+    assert!(!Platform::from_str_name("linux/arm/v7")
+        .unwrap()
+        .matches(&Platform::new("linux", "arm", Some("v6"))));
+
+    assert!(Platform::from_str_name("linux").is_err());
+    assert!(Platform::from_str_name("linux/").is_err());
+    assert!(Platform::from_str_name("linux/arm/v7/x").is_err());
+}
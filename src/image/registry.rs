@@ -0,0 +1,503 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use anyhow::anyhow;
+use bytes::Bytes;
+use futures_util::{Stream, TryStreamExt};
+use reqwest::{header, StatusCode};
+
+use crate::image::config::{verify_digest, ImageConfig};
+use crate::image::oci::{check_config_platform, select_platform_manifest, OciImage, OciManifest};
+use crate::image::platform::Platform;
+
+const DOCKER_HUB_REGISTRY: &str = "registry-1.docker.io";
+
+const MANIFEST_ACCEPT: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+/// Reference to image in OCI/Docker distribution registry, e.g. `ubuntu:22.04`,
+/// `ghcr.io/org/image:tag` or `localhost:5000/image@sha256:...`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageReference {
+    pub registry: String,
+    pub repository: String,
+    pub tag: Option<String>,
+    pub digest: Option<String>,
+}
+
+impl ImageReference {
+    pub fn parse(name: &str) -> anyhow::Result<Self> {
+        let (name, digest) = match name.split_once('@') {
+            Some((name, digest)) => (name, Some(digest.to_string())),
+            None => (name, None),
+        };
+        let (registry, rest) = match name.split_once('/') {
+            Some((first, rest))
+                if first.contains('.') || first.contains(':') || first == "localhost" =>
+            {
+                (first.to_string(), rest)
+            }
+            _ => ("docker.io".to_string(), name),
+        };
+        let (repository, tag) = match rest.rsplit_once(':') {
+            Some((repository, tag)) if !tag.contains('/') => {
+                (repository.to_string(), Some(tag.to_string()))
+            }
+            _ => (rest.to_string(), None),
+        };
+        if repository.is_empty()
+            || !repository
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "._-/".contains(c))
+        {
+            return Err(anyhow!("Invalid image reference: {}", name));
+        }
+        let repository = if registry == "docker.io" && !repository.contains('/') {
+            format!("library/{repository}")
+        } else {
+            repository
+        };
+        let tag = if tag.is_none() && digest.is_none() {
+            Some("latest".to_string())
+        } else {
+            tag
+        };
+        Ok(ImageReference {
+            registry,
+            repository,
+            tag,
+            digest,
+        })
+    }
+
+    /// Tag or digest used to fetch manifest
+    fn reference(&self) -> &str {
+        self.digest
+            .as_deref()
+            .or(self.tag.as_deref())
+            .unwrap_or("latest")
+    }
+
+    fn base_url(&self) -> String {
+        let host = if self.registry == "docker.io" {
+            DOCKER_HUB_REGISTRY
+        } else {
+            &self.registry
+        };
+        //local registries are usually served without tls
+        if host.starts_with("localhost") || host.starts_with("127.0.0.1") {
+            format!("http://{host}")
+        } else {
+            format!("https://{host}")
+        }
+    }
+}
+
+impl fmt::Display for ImageReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.registry, self.repository)?;
+        if let Some(tag) = &self.tag {
+            write!(f, ":{}", tag)?;
+        }
+        if let Some(digest) = &self.digest {
+            write!(f, "@{}", digest)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `Bearer realm="...",service="...",scope="..."` challenge parameters
+fn parse_challenge(value: &str) -> Option<(String, HashMap<String, String>)> {
+    let (scheme, params) = value.trim().split_once(' ').unwrap_or((value.trim(), ""));
+    let mut res = HashMap::new();
+    let mut rest = params.trim();
+    while !rest.is_empty() {
+        let (key, after_key) = rest.split_once('=')?;
+        let after_key = after_key.trim_start();
+        let (val, after_val) = if let Some(quoted) = after_key.strip_prefix('"') {
+            let end = quoted.find('"')?;
+            (&quoted[..end], &quoted[end + 1..])
+        } else {
+            let end = after_key.find(',').unwrap_or(after_key.len());
+            (&after_key[..end], &after_key[end..])
+        };
+        res.insert(key.trim().to_lowercase(), val.to_string());
+        rest = after_val.trim_start_matches([',', ' ']);
+    }
+    Some((scheme.to_lowercase(), res))
+}
+
+#[derive(Debug, Clone)]
+enum Authorization {
+    Basic,
+    Bearer(String),
+}
+
+/// Client of OCI/Docker distribution (registry v2) API used to pull images without docker engine.
+/// Supports anonymous and basic/token authentication, all fetched content is verified against digests.
+pub struct DistributionClient {
+    client: reqwest::Client,
+    credentials: Option<(String, String)>,
+    authorization: Mutex<Option<Authorization>>,
+}
+
+impl Default for DistributionClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DistributionClient {
+    pub fn new() -> Self {
+        DistributionClient {
+            client: reqwest::Client::new(),
+            credentials: None,
+            authorization: Mutex::new(None),
+        }
+    }
+
+    pub fn with_credentials(mut self, user: &str, password: &str) -> Self {
+        self.credentials = Some((user.to_string(), password.to_string()));
+        self
+    }
+
+    fn authorize(&self, request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match (&*self.authorization.lock().unwrap(), &self.credentials) {
+            (Some(Authorization::Bearer(token)), _) => request.bearer_auth(token),
+            (Some(Authorization::Basic), Some((user, password))) => {
+                request.basic_auth(user, Some(password))
+            }
+            _ => request,
+        }
+    }
+
+    async fn authenticate(&self, challenge: &str) -> anyhow::Result<()> {
+        let (scheme, params) = parse_challenge(challenge)
+            .ok_or_else(|| anyhow!("Invalid auth challenge: {}", challenge))?;
+        match scheme.as_str() {
+            "basic" => {
+                if self.credentials.is_none() {
+                    return Err(anyhow!("Registry requires credentials"));
+                }
+                *self.authorization.lock().unwrap() = Some(Authorization::Basic);
+            }
+            "bearer" => {
+                let realm = params
+                    .get("realm")
+                    .ok_or_else(|| anyhow!("Auth challenge without realm: {}", challenge))?;
+                let query: Vec<(&str, &str)> = ["service", "scope"]
+                    .iter()
+                    .filter_map(|k| params.get(*k).map(|v| (*k, v.as_str())))
+                    .collect();
+                let mut request = self.client.get(realm).query(&query);
+                if let Some((user, password)) = &self.credentials {
+                    request = request.basic_auth(user, Some(password));
+                }
+                let response = request
+                    .send()
+                    .await
+                    .map_err(|e| anyhow!("Failed to get registry token: {}", e))?;
+                if !response.status().is_success() {
+                    return Err(anyhow!(
+                        "Failed to get registry token: {}",
+                        response.status()
+                    ));
+                }
+                let body: serde_json::Value = response.json().await?;
+                let token = body["token"]
+                    .as_str()
+                    .or(body["access_token"].as_str())
+                    .ok_or_else(|| anyhow!("Token response does not contain token"))?;
+                *self.authorization.lock().unwrap() =
+                    Some(Authorization::Bearer(token.to_string()));
+            }
+            other => return Err(anyhow!("Unsupported auth scheme: {}", other)),
+        }
+        Ok(())
+    }
+
+    /// GET request retried once after authentication if registry responds with 401
+    async fn get(
+        &self,
+        image: &ImageReference,
+        path: &str,
+        accept: Option<&str>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let url = format!("{}/v2/{}/{}", image.base_url(), image.repository, path);
+        for attempt in 0..2 {
+            let mut request = self.authorize(self.client.get(&url));
+            if let Some(accept) = accept {
+                request = request.header(header::ACCEPT, accept);
+            }
+            let response = request
+                .send()
+                .await
+                .map_err(|e| anyhow!("Request to {} failed: {}", url, e))?;
+            match response.status() {
+                StatusCode::UNAUTHORIZED if attempt == 0 => {
+                    let challenge = response
+                        .headers()
+                        .get(header::WWW_AUTHENTICATE)
+                        .and_then(|v| v.to_str().ok())
+                        .ok_or_else(|| anyhow!("Registry requires authentication: {}", url))?
+                        .to_string();
+                    self.authenticate(&challenge).await?;
+                }
+                status if status.is_success() => return Ok(response),
+                status => {
+                    let text = response.text().await.unwrap_or_default();
+                    return Err(anyhow!("Request to {} failed: {} {}", url, status, text));
+                }
+            }
+        }
+        Err(anyhow!("Not authorized to access {}", url))
+    }
+
+    /// Fetches manifest (or index) by tag or digest
+    pub async fn fetch_manifest(
+        &self,
+        image: &ImageReference,
+        reference: &str,
+    ) -> anyhow::Result<Vec<u8>> {
+        let response = self
+            .get(
+                image,
+                &format!("manifests/{reference}"),
+                Some(MANIFEST_ACCEPT),
+            )
+            .await?;
+        let content_digest = response
+            .headers()
+            .get("Docker-Content-Digest")
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string());
+        let bytes = response.bytes().await?.to_vec();
+        if reference.contains(':') {
+            verify_digest(reference, &bytes)?;
+        } else if let Some(digest) = content_digest {
+            verify_digest(&digest, &bytes)?;
+        }
+        Ok(bytes)
+    }
+
+    /// Fetches whole blob and checks its digest
+    pub async fn fetch_blob(
+        &self,
+        image: &ImageReference,
+        digest: &str,
+    ) -> anyhow::Result<Vec<u8>> {
+        let response = self.get(image, &format!("blobs/{digest}"), None).await?;
+        let bytes = response.bytes().await?.to_vec();
+        verify_digest(digest, &bytes)?;
+        Ok(bytes)
+    }
+
+    /// Opens blob for streaming, caller is responsible for checking digest (see [crate::image::DigestReader])
+    pub async fn blob_stream(
+        &self,
+        image: &ImageReference,
+        digest: &str,
+    ) -> anyhow::Result<impl Stream<Item = std::io::Result<Bytes>> + Send + 'static> {
+        let response = self.get(image, &format!("blobs/{digest}"), None).await?;
+        Ok(response.bytes_stream().map_err(std::io::Error::other))
+    }
+
+    /// Resolves image manifest for the platform and fetches image config.
+    /// Without requested platform linux/amd64 is selected from indexes and platform of single image is not checked.
+    pub async fn resolve_image(
+        &self,
+        image: &ImageReference,
+        requested_platform: Option<&Platform>,
+    ) -> anyhow::Result<OciImage> {
+        let default_platform = Platform::default();
+        let platform = requested_platform.unwrap_or(&default_platform);
+        let mut reference = image.reference().to_string();
+        //nested indexes are resolved until image manifest for the platform is found
+        for _ in 0..5 {
+            match OciManifest::from_slice(&self.fetch_manifest(image, &reference).await?)? {
+                OciManifest::Index { manifests } => {
+                    reference = select_platform_manifest(&manifests, platform)?
+                        .digest
+                        .clone();
+                }
+                OciManifest::Image { config, layers } => {
+                    let image_config =
+                        ImageConfig::from_slice(&self.fetch_blob(image, &config.digest).await?)?;
+                    if let Some(platform) = requested_platform {
+                        check_config_platform(&image_config, platform)?;
+                    }
+                    let id = config
+                        .digest
+                        .split_once(':')
+                        .map(|(_, hex)| hex.to_string())
+                        .unwrap_or(config.digest.clone());
+                    return Ok(OciImage {
+                        name: Some(image.to_string()),
+                        id,
+                        config: image_config,
+                        layers,
+                    });
+                }
+            }
+        }
+        Err(anyhow!("Too many nested image indexes"))
+    }
+}
+
+#[cfg(test)]
+fn serve_test_registry(
+    files: HashMap<String, Vec<u8>>,
+    token: &'static str,
+) -> std::net::SocketAddr {
+    use std::io::{BufRead, BufReader, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let path = request_line
+                .split_whitespace()
+                .nth(1)
+                .unwrap_or_default()
+                .to_string();
+            let mut authorized = false;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if line.to_lowercase().starts_with("authorization: bearer ") {
+                    authorized = line.trim().ends_with(token);
+                }
+            }
+            let (status, extra_header, body) = if path.starts_with("/token") {
+                (
+                    "200 OK",
+                    String::new(),
+                    format!(r#"{{"token":"{token}"}}"#).into_bytes(),
+                )
+            } else if !authorized {
+                (
+                    "401 Unauthorized",
+                    format!(
+                        "WWW-Authenticate: Bearer realm=\"http://{addr}/token\",service=\"test\",scope=\"repository:golem/test:pull\"\r\n"
+                    ),
+                    Vec::new(),
+                )
+            } else if let Some(body) = files.get(&path) {
+                ("200 OK", String::new(), body.clone())
+            } else {
+                ("404 Not Found", String::new(), Vec::new())
+            };
+            let _ = write!(
+                stream,
+                "HTTP/1.1 {status}\r\n{extra_header}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            let _ = stream.write_all(&body);
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn test_registry_pull() {
+    use sha2::{Digest, Sha256};
+
+    assert_eq!(
+        ImageReference::parse("ubuntu").unwrap().to_string(),
+        "docker.io/library/ubuntu:latest"
+    );
+    let reference = ImageReference::parse("ghcr.io/org/image@sha256:abc").unwrap();
+    assert_eq!(reference.registry, "ghcr.io");
+    assert_eq!(reference.repository, "org/image");
+    assert_eq!(reference.reference(), "sha256:abc");
+    assert!(ImageReference::parse("Invalid/Name").is_err());
+
+    let digest = |data: &[u8]| format!("sha256:{}", hex::encode(Sha256::digest(data)));
+    let mut files = HashMap::new();
+    let layer = b"layer content".to_vec();
+    let layer_digest = digest(&layer);
+    let mut manifests = Vec::new();
+    for arch in ["amd64", "arm64"] {
+        let config = format!(
+            r#"{{"architecture":"{arch}","os":"linux","config":{{"Cmd":["/bin/{arch}"]}}}}"#
+        );
+        let config_digest = digest(config.as_bytes());
+        files.insert(
+            format!("/v2/golem/test/blobs/{config_digest}"),
+            config.into_bytes(),
+        );
+        let manifest = format!(
+            r#"{{"schemaVersion":2,"config":{{"digest":"{config_digest}","size":1}},"layers":[{{"digest":"{layer_digest}","size":{}}}]}}"#,
+            layer.len()
+        );
+        let manifest_digest = digest(manifest.as_bytes());
+        files.insert(
+            format!("/v2/golem/test/manifests/{manifest_digest}"),
+            manifest.into_bytes(),
+        );
+        manifests.push(format!(
+            r#"{{"digest":"{manifest_digest}","size":1,"platform":{{"os":"linux","architecture":"{arch}"}}}}"#
+        ));
+    }
+    files.insert(
+        "/v2/golem/test/manifests/v1".to_string(),
+        format!(
+            r#"{{"schemaVersion":2,"manifests":[{}]}}"#,
+            manifests.join(",")
+        )
+        .into_bytes(),
+    );
+    files.insert(
+        format!("/v2/golem/test/blobs/{layer_digest}"),
+        layer.clone(),
+    );
+    let bad_digest = digest(b"other");
+    files.insert(format!("/v2/golem/test/blobs/{bad_digest}"), layer.clone());
+    let addr = serve_test_registry(files, "secret-token");
+
+    let client = DistributionClient::new();
+    let reference = ImageReference::parse(&format!("{addr}/golem/test:v1")).unwrap();
+    let image = client
+        .resolve_image(
+            &reference,
+            Some(&Platform::from_str_name("linux/arm64").unwrap()),
+        )
+        .await
+        .unwrap();
+    assert_eq!(
+        image.config.config.unwrap().cmd,
+        Some(vec!["/bin/arm64".to_string()])
+    );
+    let image = client.resolve_image(&reference, None).await.unwrap();
+    assert_eq!(image.layers[0].digest, layer_digest);
+    assert_eq!(
+        client.fetch_blob(&reference, &layer_digest).await.unwrap(),
+        layer
+    );
+    assert!(client.fetch_blob(&reference, &bad_digest).await.is_err());
+    let streamed: Vec<Bytes> = client
+        .blob_stream(&reference, &layer_digest)
+        .await
+        .unwrap()
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(streamed.concat(), layer);
+    assert!(client
+        .resolve_image(
+            &ImageReference::parse(&format!("{addr}/golem/test:v2")).unwrap(),
+            None
+        )
+        .await
+        .is_err());
+}
@@ -0,0 +1,240 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use bollard::service::ContainerConfig;
+use serde::Serialize;
+
+use crate::chunks::{descriptor_path, FileChunkDesc};
+use crate::metadata::read_metadata_footer;
+
+const SQUASHFS_MAGIC: u32 = 0x73717368;
+const SUPERBLOCK_SIZE: usize = 96;
+
+/// Fields of squashfs 4.0 superblock
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SquashfsInfo {
+    pub version: String,
+    pub compressor: String,
+    pub block_size: u32,
+    pub inode_count: u32,
+    pub fragment_count: u32,
+    pub bytes_used: u64,
+    pub modification_time: u32,
+}
+
+fn compressor_name(id: u16) -> String {
+    match id {
+        1 => "gzip".to_string(),
+        2 => "lzma".to_string(),
+        3 => "lzo".to_string(),
+        4 => "xz".to_string(),
+        5 => "lz4".to_string(),
+        6 => "zstd".to_string(),
+        other => format!("unknown ({other})"),
+    }
+}
+
+/// Reads squashfs superblock from the beginning of gvmi file
+pub fn read_squashfs_info(path: &Path) -> anyhow::Result<SquashfsInfo> {
+    let mut buf = [0u8; SUPERBLOCK_SIZE];
+    File::open(path)?
+        .read_exact(&mut buf)
+        .map_err(|_| anyhow!("File is too small to contain squashfs image"))?;
+    let u16_at = |pos: usize| u16::from_le_bytes([buf[pos], buf[pos + 1]]);
+    let u32_at = |pos: usize| u32::from_le_bytes(buf[pos..pos + 4].try_into().unwrap());
+    let u64_at = |pos: usize| u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap());
+    if u32_at(0) != SQUASHFS_MAGIC {
+        return Err(anyhow!("Squashfs magic not found, file is not gvmi image"));
+    }
+    Ok(SquashfsInfo {
+        version: format!("{}.{}", u16_at(28), u16_at(30)),
+        compressor: compressor_name(u16_at(20)),
+        block_size: u32_at(12),
+        inode_count: u32_at(4),
+        fragment_count: u32_at(16),
+        bytes_used: u64_at(40),
+        modification_time: u32_at(8),
+    })
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetadataInfo {
+    /// Position of metadata footer in the file
+    pub offset: u64,
+    pub size: u64,
+    pub crc_stored: String,
+    pub crc_computed: String,
+    pub crc_valid: bool,
+    pub config: Option<ContainerConfig>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DescriptorInfo {
+    pub path: PathBuf,
+    /// Image link (sha3-224 of the whole file) used in Golem SDKs
+    pub image_link: String,
+    pub descriptor_hash: String,
+    pub file_size: u64,
+    /// Descriptor format version, 1 or 2
+    pub version: u8,
+    /// sha256 or blake3
+    pub chunk_hash: String,
+    /// fixed or content-defined, for content-defined chunk size is the average
+    pub chunking: String,
+    pub chunk_size: u64,
+    pub chunk_count: usize,
+}
+
+impl DescriptorInfo {
+    pub fn from_descriptor(path: &Path, descr: &FileChunkDesc) -> Self {
+        DescriptorInfo {
+            path: path.to_path_buf(),
+            image_link: descr.get_sha3_str(),
+            descriptor_hash: descr.get_descr_hash_str(),
+            file_size: descr.size,
+            version: descr.format_version(),
+            chunk_hash: descr.chunk_hash.name().to_string(),
+            chunking: descr.chunking.name().to_string(),
+            chunk_size: descr.chunk_size,
+            chunk_count: descr.chunks.len(),
+        }
+    }
+}
+
+/// Information about gvmi file gathered without rebuilding it
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InspectReport {
+    pub path: PathBuf,
+    pub file_size: u64,
+    pub squashfs: Option<SquashfsInfo>,
+    pub squashfs_error: Option<String>,
+    pub metadata: Option<MetadataInfo>,
+    pub metadata_error: Option<String>,
+    pub descriptor: Option<DescriptorInfo>,
+}
+
+/// Inspects gvmi file, descriptor is loaded from `descriptor` or default `<file>.descr.bin` if exists.
+/// Damaged parts of the image are reported as errors in the report instead of failing.
+pub fn inspect_image(path: &Path, descriptor: Option<&Path>) -> anyhow::Result<InspectReport> {
+    let file_size = std::fs::metadata(path)
+        .map_err(|e| anyhow!("Failed to read {}: {}", path.display(), e))?
+        .len();
+    let (squashfs, squashfs_error) = match read_squashfs_info(path) {
+        Ok(info) => (Some(info), None),
+        Err(err) => (None, Some(err.to_string())),
+    };
+    let (metadata, metadata_error) = match read_metadata_footer(path) {
+        Ok(footer) => {
+            let (config, error) = if footer.crc_valid() {
+                match serde_json::from_slice::<ContainerConfig>(&footer.json) {
+                    Ok(config) => (Some(config), None),
+                    Err(err) => (None, Some(format!("Invalid metadata json: {err}"))),
+                }
+            } else {
+                (None, Some("CRC mismatch".to_string()))
+            };
+            (
+                Some(MetadataInfo {
+                    offset: footer.offset,
+                    size: footer.json.len() as u64,
+                    crc_stored: format!("{:08x}", footer.crc_stored),
+                    crc_computed: format!("{:08x}", footer.crc_computed),
+                    crc_valid: footer.crc_valid(),
+                    config,
+                    error,
+                }),
+                None,
+            )
+        }
+        Err(err) => (None, Some(err.to_string())),
+    };
+    let descr_path = descriptor
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| descriptor_path(path));
+    let descriptor = if descr_path.exists() {
+        let descr = FileChunkDesc::deserialize_from_bytes(&std::fs::read(&descr_path)?)
+            .map_err(|e| anyhow!("Invalid descriptor {}: {}", descr_path.display(), e))?;
+        Some(DescriptorInfo::from_descriptor(&descr_path, &descr))
+    } else if descriptor.is_some() {
+        return Err(anyhow!("Descriptor {} not found", descr_path.display()));
+    } else {
+        None
+    };
+    Ok(InspectReport {
+        path: path.to_path_buf(),
+        file_size,
+        squashfs,
+        squashfs_error,
+        metadata,
+        metadata_error,
+        descriptor,
+    })
+}
+
+#[test]
+fn test_inspect_image() {
+    use crate::metadata::add_metadata_outside;
+    use crate::rootfs::RootfsTree;
+    use crate::squashfs::{write_squashfs, SquashfsOptions};
+    use indicatif::ProgressBar;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut tree = RootfsTree::new(temp_dir.path()).unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header.set_size(5);
+    let mut builder = tar::Builder::new(Vec::new());
+    builder
+        .append_data(&mut header, "etc/hostname", &b"golem"[..])
+        .unwrap();
+    tree.append_tar(&builder.into_inner().unwrap()[..]).unwrap();
+    let image = temp_dir.path().join("image.gvmi");
+    let options = SquashfsOptions {
+        compression_method: "gzip".to_string(),
+        ..Default::default()
+    };
+    let squashfs_size = write_squashfs(&tree, &image, &options, &ProgressBar::hidden()).unwrap();
+    let config = ContainerConfig {
+        entrypoint: Some(vec!["/bin/sh".to_string()]),
+        ..Default::default()
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    runtime
+        .block_on(add_metadata_outside(&image, &config))
+        .unwrap();
+
+    let report = inspect_image(&image, None).unwrap();
+    let squashfs = report.squashfs.unwrap();
+    assert_eq!(squashfs.compressor, "gzip");
+    assert_eq!(squashfs.version, "4.0");
+    assert_eq!(squashfs.block_size, 128 * 1024);
+    //root, /etc and /etc/hostname
+    assert_eq!(squashfs.inode_count, 3);
+    assert!(squashfs.bytes_used <= squashfs_size);
+    let metadata = report.metadata.unwrap();
+    assert!(metadata.crc_valid);
+    assert_eq!(metadata.offset, squashfs_size);
+    assert_eq!(metadata.config, Some(config));
+    assert!(report.descriptor.is_none());
+
+    //damaged metadata json is reported, not returned as error
+    let mut bytes = std::fs::read(&image).unwrap();
+    let pos = bytes.len() - 12;
+    bytes[pos] ^= 0xff;
+    std::fs::write(&image, &bytes).unwrap();
+    let report = inspect_image(&image, None).unwrap();
+    assert!(!report.metadata.unwrap().crc_valid);
+    assert!(inspect_image(&image, Some(&temp_dir.path().join("missing"))).is_err());
+}
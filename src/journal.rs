@@ -0,0 +1,345 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::chunks::FileChunkDesc;
+
+/// Minimum time between journal writes during upload
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Directory where upload journals are kept, can be changed with UPLOAD_JOURNAL_DIR env variable
+pub fn default_journal_dir() -> PathBuf {
+    if let Ok(dir) = std::env::var("UPLOAD_JOURNAL_DIR") {
+        return PathBuf::from(dir);
+    }
+    user_cache_dir().join("uploads")
+}
+
+/// Per user cache directory of gvmkit-build (under XDG_CACHE_HOME or ~/.cache)
+pub(crate) fn user_cache_dir() -> PathBuf {
+    let cache_dir = std::env::var("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|_| std::env::var("HOME").map(|home| PathBuf::from(home).join(".cache")))
+        .or_else(|_| std::env::var("LOCALAPPDATA").map(PathBuf::from))
+        .unwrap_or_else(|_| std::env::temp_dir());
+    cache_dir.join("gvmkit-build")
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ChunkStatus {
+    Pending,
+    InProgress,
+    Uploaded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChunkJournalEntry {
+    pub status: ChunkStatus,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub updated: u64,
+}
+
+/// Local record of chunk upload progress, one file per descriptor hash.
+/// Changes are saved at most once per [`SAVE_INTERVAL`] and when the journal is dropped,
+/// so it survives process restarts. Chunks finished just before a crash may be uploaded again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UploadJournal {
+    pub descr_hash: String,
+    pub image_path: PathBuf,
+    pub file_size: u64,
+    pub chunk_size: u64,
+    pub created: u64,
+    pub chunks: BTreeMap<u64, ChunkJournalEntry>,
+    #[serde(skip)]
+    path: PathBuf,
+    #[serde(skip)]
+    dirty: bool,
+    #[serde(skip)]
+    last_save: Option<Instant>,
+}
+
+impl UploadJournal {
+    pub fn journal_path(dir: &Path, descr_hash: &str) -> PathBuf {
+        dir.join(format!("{descr_hash}.json"))
+    }
+
+    pub fn new(dir: &Path, image_path: &Path, descr: &FileChunkDesc) -> Self {
+        let now = unix_time();
+        UploadJournal {
+            descr_hash: descr.get_descr_hash_str(),
+            image_path: image_path.to_path_buf(),
+            file_size: descr.size,
+            chunk_size: descr.chunk_size,
+            created: now,
+            chunks: descr
+                .chunks
+                .iter()
+                .map(|chunk| {
+                    (
+                        chunk.chunk_no,
+                        ChunkJournalEntry {
+                            status: ChunkStatus::Pending,
+                            attempts: 0,
+                            last_error: None,
+                            updated: now,
+                        },
+                    )
+                })
+                .collect(),
+            path: Self::journal_path(dir, &descr.get_descr_hash_str()),
+            dirty: false,
+            last_save: None,
+        }
+    }
+
+    /// Loads journal of the previous upload of this descriptor, if any
+    pub fn load(dir: &Path, descr: &FileChunkDesc) -> anyhow::Result<Option<Self>> {
+        let path = Self::journal_path(dir, &descr.get_descr_hash_str());
+        let bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(anyhow!("Failed to read {}: {}", path.display(), err)),
+        };
+        let mut journal: UploadJournal = serde_json::from_slice(&bytes)
+            .map_err(|e| anyhow!("Invalid upload journal {}: {}", path.display(), e))?;
+        if journal.file_size != descr.size
+            || journal.chunk_size != descr.chunk_size
+            || journal.chunks.len() != descr.chunks.len()
+        {
+            return Err(anyhow!(
+                "Upload journal {} does not match descriptor",
+                path.display()
+            ));
+        }
+        journal.path = path;
+        Ok(Some(journal))
+    }
+
+    /// Continues previous journal when resuming, otherwise starts a new one
+    pub fn open(
+        dir: &Path,
+        image_path: &Path,
+        descr: &FileChunkDesc,
+        resume: bool,
+    ) -> anyhow::Result<Self> {
+        if resume {
+            if let Some(mut journal) = Self::load(dir, descr)? {
+                //chunks interrupted in the middle have to be sent again
+                for entry in journal.chunks.values_mut() {
+                    if entry.status == ChunkStatus::InProgress {
+                        entry.status = ChunkStatus::Failed;
+                        entry.last_error = Some("interrupted".to_string());
+                    }
+                }
+                return Ok(journal);
+            }
+            println!(" -- no upload journal found, starting new upload");
+        }
+        let mut journal = Self::new(dir, image_path, descr);
+        journal.save()?;
+        Ok(journal)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        //write to temporary file first, so the journal is never left half written
+        let tmp_path = self.path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, serde_json::to_vec(self)?)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.dirty = false;
+        self.last_save = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Saves changes not written yet
+    pub fn flush(&mut self) {
+        if self.dirty {
+            if let Err(err) = self.save() {
+                log::warn!("Failed to save upload journal: {}", err);
+            }
+        }
+    }
+
+    pub fn remove(&mut self) -> anyhow::Result<()> {
+        //nothing to save when dropped
+        self.dirty = false;
+        match std::fs::remove_file(&self.path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn update(&mut self, chunk_no: u64, f: impl FnOnce(&mut ChunkJournalEntry)) {
+        let entry = self
+            .chunks
+            .entry(chunk_no)
+            .or_insert_with(|| ChunkJournalEntry {
+                status: ChunkStatus::Pending,
+                attempts: 0,
+                last_error: None,
+                updated: 0,
+            });
+        f(entry);
+        entry.updated = unix_time();
+        //called by every upload worker under lock, so writes are limited
+        self.dirty = true;
+        if self
+            .last_save
+            .is_none_or(|last_save| last_save.elapsed() >= SAVE_INTERVAL)
+        {
+            self.flush();
+        }
+    }
+
+    pub fn start_attempt(&mut self, chunk_no: u64) {
+        self.update(chunk_no, |entry| {
+            entry.status = ChunkStatus::InProgress;
+            entry.attempts += 1;
+        });
+    }
+
+    pub fn record_success(&mut self, chunk_no: u64) {
+        self.update(chunk_no, |entry| {
+            entry.status = ChunkStatus::Uploaded;
+            entry.last_error = None;
+        });
+    }
+
+    pub fn record_failure(&mut self, chunk_no: u64, error: &str) {
+        self.update(chunk_no, |entry| {
+            entry.status = ChunkStatus::Failed;
+            entry.last_error = Some(error.to_string());
+        });
+    }
+
+    pub fn is_uploaded(&self, chunk_no: u64) -> bool {
+        self.chunks
+            .get(&chunk_no)
+            .map(|entry| entry.status == ChunkStatus::Uploaded)
+            .unwrap_or(false)
+    }
+
+    fn chunks_where(&self, f: impl Fn(&ChunkJournalEntry) -> bool) -> Vec<u64> {
+        self.chunks
+            .iter()
+            .filter(|(_, entry)| f(entry))
+            .map(|(chunk_no, _)| *chunk_no)
+            .collect()
+    }
+
+    pub fn uploaded_chunks(&self) -> Vec<u64> {
+        self.chunks_where(|entry| entry.status == ChunkStatus::Uploaded)
+    }
+
+    pub fn failed_chunks(&self) -> Vec<u64> {
+        self.chunks_where(|entry| entry.status == ChunkStatus::Failed)
+    }
+
+    /// Chunks that needed more than one attempt
+    pub fn retried_chunks(&self) -> Vec<u64> {
+        self.chunks_where(|entry| entry.attempts > 1)
+    }
+}
+
+impl Drop for UploadJournal {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+/// Formats chunk numbers as ranges, e.g. `1-3, 7`
+pub fn format_chunk_list(chunks: &[u64]) -> String {
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for &chunk_no in chunks {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == chunk_no => *end = chunk_no,
+            _ => ranges.push((chunk_no, chunk_no)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{start}-{end}")
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[tokio::test]
+async fn test_upload_journal() {
+    use crate::chunks::create_descriptor_from_reader;
+
+    let bytes = vec![7u8; 5500];
+    let descr = create_descriptor_from_reader(&bytes[..], bytes.len() as u64, 1000)
+        .await
+        .unwrap();
+    let dir = tempfile::tempdir().unwrap();
+    let image_path = dir.path().join("image.gvmi");
+
+    let mut journal = UploadJournal::open(dir.path(), &image_path, &descr, false).unwrap();
+    assert!(journal.path().exists());
+    for chunk_no in 0..6 {
+        journal.start_attempt(chunk_no);
+    }
+    journal.record_failure(1, "connection reset");
+    journal.record_success(0);
+    journal.record_success(2);
+    //changes right after open are not written yet
+    let loaded = UploadJournal::load(dir.path(), &descr).unwrap().unwrap();
+    assert!(loaded.uploaded_chunks().is_empty());
+    drop(loaded);
+    drop(journal);
+    assert!(!std::fs::read(UploadJournal::journal_path(
+        dir.path(),
+        &descr.get_descr_hash_str()
+    ))
+    .unwrap()
+    .contains(&b'\n'));
+
+    //process restarted
+    let mut journal = UploadJournal::open(dir.path(), &image_path, &descr, true).unwrap();
+    assert_eq!(journal.uploaded_chunks(), vec![0, 2]);
+    assert_eq!(journal.failed_chunks(), vec![1, 3, 4, 5]);
+    assert_eq!(
+        journal.chunks[&3].last_error.as_deref(),
+        Some("interrupted")
+    );
+    journal.start_attempt(1);
+    journal.record_success(1);
+    assert_eq!(journal.retried_chunks(), vec![1]);
+    assert_eq!(format_chunk_list(&journal.failed_chunks()), "3-5");
+    assert_eq!(format_chunk_list(&[0, 2, 3, 4, 9]), "0, 2-4, 9");
+
+    //without resume journal starts from scratch
+    let mut journal = UploadJournal::open(dir.path(), &image_path, &descr, false).unwrap();
+    assert!(journal.uploaded_chunks().is_empty());
+    journal.record_success(0);
+    journal.remove().unwrap();
+    drop(journal);
+    assert!(!UploadJournal::journal_path(dir.path(), &descr.get_descr_hash_str()).exists());
+}
@@ -0,0 +1,425 @@
+//! Local cache of extracted image layers, keyed by layer diff id (sha256 of uncompressed layer tar).
+//!
+//! Every layer is kept in its own directory: `changes.json` lists changes the layer makes to the filesystem,
+//! file contents are stored next to it and are referenced directly by the assembled tree,
+//! so when only the top layer of the image changes only that layer is extracted
+//! (and fetched, except for docker engine which exports the whole image).
+
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use std::{fs, mem};
+
+use anyhow::anyhow;
+use sha2::{Digest, Sha256};
+use tar::EntryType;
+
+use crate::image::open_layer;
+use crate::journal::user_cache_dir;
+use crate::rootfs::{read_change, EntryKind, LayerChange, RootfsTree};
+
+const CHANGES_FILE: &str = "changes.json";
+
+/// Directory where extracted layers are cached, can be changed with LAYER_CACHE_DIR env variable
+pub fn default_layer_cache_dir() -> PathBuf {
+    if let Ok(dir) = std::env::var("LAYER_CACHE_DIR") {
+        return PathBuf::from(dir);
+    }
+    user_cache_dir().join("layers")
+}
+
+/// Parses size like 500MB, 10GB or 2GiB
+pub fn parse_size(value: &str) -> anyhow::Result<u64> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid size: {value}, expected e.g. 500MB, 10GB"))?;
+    let multiplier = match unit.trim() {
+        "" | "B" => 1.0,
+        "k" | "K" | "kB" | "KB" => 1e3,
+        "KiB" => 1024.0,
+        "M" | "MB" => 1e6,
+        "MiB" => 1024.0 * 1024.0,
+        "G" | "GB" => 1e9,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        unit => return Err(anyhow!("Unknown size unit: {unit}")),
+    };
+    Ok((number * multiplier) as u64)
+}
+
+/// Result of `LayerCache::prune`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneStats {
+    pub removed: u64,
+    pub removed_bytes: u64,
+    pub kept: u64,
+    pub kept_bytes: u64,
+}
+
+pub struct LayerCache {
+    dir: PathBuf,
+}
+
+impl LayerCache {
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir).map_err(|e| {
+            anyhow!(
+                "Failed to create layer cache directory {}: {}",
+                dir.display(),
+                e
+            )
+        })?;
+        Ok(LayerCache {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn layer_dir(&self, diff_id: &str) -> anyhow::Result<PathBuf> {
+        match diff_id.split_once(':') {
+            Some(("sha256", hex))
+                if hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()) =>
+            {
+                Ok(self.dir.join(hex))
+            }
+            _ => Err(anyhow!("Unsupported layer diff id: {}", diff_id)),
+        }
+    }
+
+    pub fn contains(&self, diff_id: &str) -> bool {
+        self.layer_dir(diff_id)
+            .map(|dir| dir.join(CHANGES_FILE).exists())
+            .unwrap_or(false)
+    }
+
+    /// Extracts uncompressed layer tar into the cache, returns diff id of the layer
+    pub fn store(&self, reader: impl Read) -> anyhow::Result<String> {
+        let staging = tempfile::Builder::new()
+            .prefix(".tmp-")
+            .tempdir_in(&self.dir)?;
+        let mut reader = HashReader {
+            inner: reader,
+            hasher: Sha256::new(),
+        };
+        let mut changes = Vec::new();
+        let mut files = 0_u64;
+        let mut archive = tar::Archive::new(&mut reader);
+        for entry in archive.entries()? {
+            let change = read_change(entry?, true, |content| {
+                let name = files.to_string();
+                files += 1;
+                let mut file = fs::File::create(staging.path().join(&name))?;
+                let size = io::copy(content, &mut file)?;
+                Ok((PathBuf::from(name), size))
+            })?;
+            changes.extend(change);
+        }
+        //padding after the end of archive is part of the digest too
+        io::copy(&mut reader, &mut io::sink())?;
+        let diff_id = format!("sha256:{}", hex::encode(reader.hasher.finalize()));
+
+        let changes = serde_json::to_vec(&changes)
+            .map_err(|e| anyhow!("Failed to store layer {} in cache: {}", diff_id, e))?;
+        fs::write(staging.path().join(CHANGES_FILE), changes)?;
+        let layer_dir = self.layer_dir(&diff_id)?;
+        if !self.contains(&diff_id) {
+            //leftover of interrupted cleanup
+            if layer_dir.exists() {
+                fs::remove_dir_all(&layer_dir)?;
+            }
+            //the same layer can be stored by other build in the meantime
+            if let Err(e) = fs::rename(staging.path(), &layer_dir) {
+                if !self.contains(&diff_id) {
+                    return Err(anyhow!("Failed to store layer {} in cache: {}", diff_id, e));
+                }
+            }
+        }
+        Ok(diff_id)
+    }
+
+    /// Stores layers from `docker save` stream which are not cached yet, returns their diff ids.
+    /// Layers are recognized by content, so both legacy (`<id>/layer.tar`) and OCI (`blobs/sha256/<digest>`)
+    /// archive formats are supported.
+    pub fn store_from_archive(&self, reader: impl Read) -> anyhow::Result<Vec<String>> {
+        let mut stored = Vec::new();
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            if entry.header().entry_type() != EntryType::Regular {
+                continue;
+            }
+            let path = entry.path()?.to_path_buf();
+            if path.starts_with("blobs/sha256") {
+                //uncompressed layer blobs are named by diff id
+                let digest = format!(
+                    "sha256:{}",
+                    path.file_name().unwrap_or_default().to_string_lossy()
+                );
+                if self.contains(&digest) {
+                    continue;
+                }
+            } else if !path.ends_with("layer.tar") {
+                continue;
+            }
+            let mut reader = BufReader::new(entry);
+            //image configs and manifests are blobs too
+            if reader.fill_buf()?.first() == Some(&b'{') {
+                continue;
+            }
+            let diff_id = self
+                .store(open_layer(reader)?)
+                .map_err(|e| anyhow!("Failed to extract layer {}: {}", path.display(), e))?;
+            stored.push(diff_id);
+        }
+        Ok(stored)
+    }
+
+    /// Changes made by cached layer, file contents are referenced in the cache
+    pub fn load(&self, diff_id: &str) -> anyhow::Result<Vec<LayerChange>> {
+        let layer_dir = self.layer_dir(diff_id)?;
+        let changes_path = layer_dir.join(CHANGES_FILE);
+        let bytes = fs::read(&changes_path)
+            .map_err(|e| anyhow!("Layer {} is not in cache: {}", diff_id, e))?;
+        //modification time of the changes file is the last use of the layer, see `prune`
+        if let Err(e) = fs::File::options()
+            .write(true)
+            .open(&changes_path)
+            .and_then(|file| file.set_modified(SystemTime::now()))
+        {
+            log::debug!("Failed to mark cached layer {} as used: {}", diff_id, e);
+        }
+        let mut changes: Vec<LayerChange> = serde_json::from_slice(&bytes)
+            .map_err(|e| anyhow!("Cached layer {} is corrupted: {}", diff_id, e))?;
+        for change in &mut changes {
+            if let LayerChange::Entry {
+                kind: EntryKind::File { source, .. },
+                ..
+            } = change
+            {
+                *source = layer_dir.join(mem::take(source));
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Removes least recently used layers until the cache is not bigger than `max_size`.
+    /// Must not be run when a build using the cache is in progress.
+    pub fn prune(&self, max_size: u64) -> anyhow::Result<PruneStats> {
+        let mut layers = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if !entry.file_type()?.is_dir() || name.starts_with(".tmp-") {
+                continue;
+            }
+            let mut size = 0;
+            for file in fs::read_dir(entry.path())? {
+                size += file?.metadata()?.len();
+            }
+            //leftovers of interrupted cleanup (without changes file) are always removed
+            let last_used = fs::metadata(entry.path().join(CHANGES_FILE))
+                .and_then(|m| m.modified())
+                .ok();
+            layers.push((last_used, entry.path(), size));
+        }
+        layers.sort_by_key(|(last_used, _, _)| std::cmp::Reverse(*last_used));
+        let mut stats = PruneStats::default();
+        for (last_used, dir, size) in layers {
+            if last_used.is_some() && stats.kept_bytes + size <= max_size {
+                stats.kept += 1;
+                stats.kept_bytes += size;
+                continue;
+            }
+            //layer without changes file is not used anymore, even if removing the rest fails
+            let _ = fs::remove_file(dir.join(CHANGES_FILE));
+            fs::remove_dir_all(&dir)
+                .map_err(|e| anyhow!("Failed to remove cached layer {}: {}", dir.display(), e))?;
+            stats.removed += 1;
+            stats.removed_bytes += size;
+        }
+        Ok(stats)
+    }
+
+    /// Applies cached layers (bottom first) on top of the tree
+    pub fn assemble(&self, tree: &mut RootfsTree, diff_ids: &[String]) -> anyhow::Result<()> {
+        for diff_id in diff_ids {
+            tree.apply_layer_changes(self.load(diff_id)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reader computing sha256 of the content read through it
+struct HashReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+#[test]
+fn test_layer_cache() {
+    let new_header = |size: usize| {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(1700000000);
+        header.set_size(size as u64);
+        header
+    };
+    let layer = |files: &[(&str, &[u8])], links: &[(&str, &str)]| {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, content) in files {
+            let mut header = new_header(content.len());
+            builder.append_data(&mut header, path, *content).unwrap();
+        }
+        for (path, target) in links {
+            let mut header = new_header(0);
+            header.set_entry_type(EntryType::Link);
+            builder.append_link(&mut header, path, target).unwrap();
+        }
+        builder.into_inner().unwrap()
+    };
+    let layers = [
+        layer(
+            &[
+                ("etc/os-release", b"ID=debian"),
+                ("var/cache/apt/pkgcache.bin", b"cache"),
+            ],
+            &[],
+        ),
+        layer(
+            &[("etc/.wh.os-release", b""), ("app/main", b"binary")],
+            &[("app/main-link", "app/main")],
+        ),
+        layer(&[("var/cache/.wh..wh..opq", b"")], &[]),
+    ];
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let cache = LayerCache::open(&temp_dir.path().join("layers")).unwrap();
+    let mut diff_ids = Vec::new();
+    for layer in &layers {
+        let diff_id = cache.store(&layer[..]).unwrap();
+        assert_eq!(
+            diff_id,
+            format!("sha256:{}", hex::encode(Sha256::digest(layer)))
+        );
+        assert!(cache.contains(&diff_id));
+        diff_ids.push(diff_id);
+    }
+    //storing again keeps the cached layer
+    assert_eq!(cache.store(&layers[0][..]).unwrap(), diff_ids[0]);
+    assert!(!cache.contains("sha256:1234"));
+
+    let mut extracted = RootfsTree::new(temp_dir.path()).unwrap();
+    for layer in &layers {
+        extracted.append_layer(&layer[..]).unwrap();
+    }
+    let mut assembled = RootfsTree::new(temp_dir.path()).unwrap();
+    cache.assemble(&mut assembled, &diff_ids).unwrap();
+    let paths = |tree: &RootfsTree| {
+        tree.entries()
+            .map(|(p, e)| (p.clone(), e.meta))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(paths(&assembled), paths(&extracted));
+    assert_eq!(
+        assembled.read_file(Path::new("/app/main-link")).unwrap(),
+        Some(b"binary".to_vec())
+    );
+    assert!(assembled.get(Path::new("/etc/os-release")).is_none());
+    assert!(assembled.get(Path::new("/var/cache/apt")).is_none());
+    match &assembled.get(Path::new("/app/main")).unwrap().kind {
+        EntryKind::File { source, .. } => assert!(source.starts_with(cache.dir())),
+        kind => panic!("unexpected entry {kind:?}"),
+    }
+
+    //docker save archive, the first layer is already cached
+    let mut builder = tar::Builder::new(Vec::new());
+    let blobs = [
+        (diff_ids[0].replace(':', "/"), layers[0].clone()),
+        ("sha256/aa".to_string(), b"{\"config\":{}}".to_vec()),
+    ];
+    for (name, content) in &blobs {
+        let mut header = new_header(content.len());
+        builder
+            .append_data(&mut header, format!("blobs/{name}"), &content[..])
+            .unwrap();
+    }
+    let new_layer = layer(&[("app/config", b"{}")], &[]);
+    let mut header = new_header(new_layer.len());
+    builder
+        .append_data(&mut header, "0123abcd/layer.tar", &new_layer[..])
+        .unwrap();
+    let archive = builder.into_inner().unwrap();
+    let stored = cache.store_from_archive(&archive[..]).unwrap();
+    assert_eq!(
+        stored,
+        [format!(
+            "sha256:{}",
+            hex::encode(Sha256::digest(&new_layer))
+        )]
+    );
+}
+
+#[test]
+fn test_layer_cache_prune() {
+    assert_eq!(parse_size("10GB").unwrap(), 10_000_000_000);
+    assert_eq!(parse_size("1.5 KiB").unwrap(), 1536);
+    assert!(parse_size("10 parsecs").is_err());
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let cache = LayerCache::open(temp_dir.path()).unwrap();
+    let layer = |path: &str, content: &[u8]| {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_size(content.len() as u64);
+        builder.append_data(&mut header, path, content).unwrap();
+        cache.store(&builder.into_inner().unwrap()[..]).unwrap()
+    };
+    let diff_ids = [
+        layer("a", &[1; 1000]),
+        layer("b", &[2; 1000]),
+        layer("c", &[3; 1000]),
+    ];
+    //the first layer is the least recently used one, unless it is loaded
+    let hour_ago = SystemTime::now() - std::time::Duration::from_secs(3600);
+    for diff_id in &diff_ids {
+        fs::File::options()
+            .write(true)
+            .open(cache.layer_dir(diff_id).unwrap().join(CHANGES_FILE))
+            .unwrap()
+            .set_modified(hour_ago)
+            .unwrap();
+    }
+    cache.load(&diff_ids[0]).unwrap();
+    fs::create_dir(temp_dir.path().join("0".repeat(64))).unwrap();
+
+    let stats = cache.prune(u64::MAX).unwrap();
+    assert_eq!(stats.removed, 1);
+    assert_eq!(stats.kept, 3);
+    let stats = cache.prune(stats.kept_bytes / 2).unwrap();
+    assert_eq!(stats.kept, 1);
+    assert_eq!(stats.removed, 2);
+    assert!(cache.contains(&diff_ids[0]));
+    assert!(!cache.contains(&diff_ids[1]));
+    assert_eq!(cache.prune(0).unwrap().removed, 1);
+    assert!(!cache.contains(&diff_ids[0]));
+}
@@ -0,0 +1,47 @@
+//! Library part of gvmkit-build.
+//!
+//! Everything the `gvmkit-build` binary does is available here, so images can be
+//! built, described and pushed to Golem Registry directly from Rust code.
+
+extern crate core;
+
+pub mod backend;
+pub mod cdc;
+pub mod chunks;
+pub mod client;
+pub mod delta;
+pub mod docker;
+pub mod download;
+pub mod ignore;
+pub mod image;
+pub mod inspect;
+pub mod journal;
+pub mod layer_cache;
+pub mod login;
+pub mod metadata;
+pub mod mock;
+pub mod progress;
+pub mod publish;
+pub mod reproducible;
+pub mod retry;
+pub mod rootfs;
+pub mod sbom;
+pub mod signing;
+pub mod squashfs;
+pub mod throttle;
+pub mod upload;
+pub mod verify;
+pub mod wrapper;
+
+pub use backend::RegistryBackend;
+pub use chunks::{
+    create_descriptor, create_descriptor_from_reader, default_chunk_size,
+    load_or_create_descriptor, ChunkHash, Chunking, DescriptorExtension, DescriptorFormat,
+    FileChunk, FileChunkDesc,
+};
+pub use client::{RegistryClient, RegistryCredentials, DEFAULT_REGISTRY_URL};
+pub use image::{ImageBuilder, ImageName};
+pub use metadata::{add_metadata_outside, read_metadata_outside};
+pub use publish::{publish, PublishOptions, PushTarget};
+pub use retry::RetryPolicy;
+pub use upload::{push_image, AttachInfo, PushOptions};
@@ -1,4 +1,4 @@
-use crate::upload::check_login;
+use crate::backend::RegistryBackend;
 use keyring::{Entry, Error};
 use std::io;
 use std::io::{stdout, Write};
@@ -6,10 +6,10 @@ use std::io::{stdout, Write};
 const CREDENTIAL_SERVICE_FIELD: &str = "gvmkit-build-rs";
 const CREDENTIAL_USER_FIELD: &str = "default";
 
-pub async fn check_if_valid_login() -> anyhow::Result<bool> {
+pub async fn check_if_valid_login(registry: &impl RegistryBackend) -> anyhow::Result<bool> {
     if let Some((user_name, pat)) = get_credentials().await? {
         println!(" -- credentials found {}", user_name);
-        let logged_in = check_login(&user_name, &pat).await?;
+        let logged_in = registry.check_login(&user_name, &pat).await?;
         if logged_in {
             return Ok(true);
         }
@@ -19,11 +19,15 @@ pub async fn check_if_valid_login() -> anyhow::Result<bool> {
     Ok(false)
 }
 
-pub async fn login(user_name: Option<&str>, force: bool) -> anyhow::Result<(String, String)> {
+pub async fn login(
+    registry: &impl RegistryBackend,
+    user_name: Option<&str>,
+    force: bool,
+) -> anyhow::Result<(String, String)> {
     if !force {
         if let Some((user_name, pat)) = get_credentials().await? {
             println!(" -- credentials already found {}", user_name);
-            let logged_in = check_login(&user_name, &pat).await?;
+            let logged_in = registry.check_login(&user_name, &pat).await?;
             if logged_in {
                 return Ok((user_name, pat));
             }
@@ -51,7 +55,7 @@ pub async fn login(user_name: Option<&str>, force: bool) -> anyhow::Result<(Stri
     println!(" -- provide access token:");
     let pat = rpassword::read_password().unwrap();
     let pat = pat.trim().to_string();
-    let can_log_in = check_login(&user_name, &pat).await?;
+    let can_log_in = registry.check_login(&user_name, &pat).await?;
     if can_log_in {
         save_credentials(&user_name, &pat).await?;
     } else {
@@ -1,27 +1,450 @@
-extern crate core;
+use gvmkit_build::client::RegistryClient;
+use gvmkit_build::download::PullSource;
+use gvmkit_build::ignore::{IgnoreRules, GVMKITIGNORE};
+use gvmkit_build::image::{
+    parse_build_arg, DockerfileBuild, ImageBuilder, ImageName, ImageSource, Platform,
+};
+use gvmkit_build::inspect::inspect_image;
+use gvmkit_build::layer_cache::{default_layer_cache_dir, parse_size, LayerCache};
+use gvmkit_build::reproducible::{source_date_epoch, RebuildDiff};
+use gvmkit_build::retry::RetryPolicy;
+use gvmkit_build::sbom::{sbom_path, SbomFormat};
+use gvmkit_build::signing::{
+    generate_signing_key, get_signing_key, load_signing_key, parse_public_key,
+    read_signing_key_file, remove_signing_key, save_signing_key, signature_path, trust_key,
+    trusted_keys, untrust_key, write_signing_key_file, ImageSignature, DEFAULT_KEY_NAME,
+};
+use gvmkit_build::squashfs::COMPRESSION_POSSIBLE_VALUES;
+use gvmkit_build::throttle::{parse_rate, RateLimiter, UploadSchedule};
+use gvmkit_build::verify::{verify_image, VerifyExpectation};
+use gvmkit_build::{login, progress};
 
-mod chunks;
-mod docker;
-mod image;
-mod login;
-mod metadata;
-mod progress;
-mod upload;
-mod wrapper;
+use clap::{Args, Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use crate::image::{ImageBuilder, ImageName};
-
-use clap::Parser;
-use std::path::PathBuf;
-
-use serde_json::json;
 use std::env;
 
-const COMPRESSION_POSSIBLE_VALUES: &[&str] = &["lzo", "gzip", "lz4", "zstd", "xz"];
-
 #[derive(Parser, Debug)]
-#[command(author, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct CmdArgs {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    legacy: LegacyArgs,
+    /// Hide progress bars during operation
+    #[arg(help_heading = Some("Extra options"), long, global = true)]
+    hide_progress: bool,
+    /// Write json info to specified file
+    #[arg(help_heading = Some("Extra options"), long, global = true)]
+    extra_json_info_path: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)] //parsed once, no need to box
+enum Command {
+    /// Build gvmi image from Docker image (or docker save archive, OCI layout, rootfs) and optionally upload it to registry
+    Build(BuildArgs),
+    /// Build image twice in reproducible mode and check that both builds are identical
+    RebuildCheck(RebuildCheckArgs),
+    /// Upload ready gvmi file to registry
+    Push(PushArgs),
+    /// Download gvmi file from registry
+    Pull(PullArgs),
+    /// Show information about gvmi file
+    Inspect(InspectArgs),
+    /// Check gvmi file against its descriptor
+    Verify(VerifyArgs),
+    /// Sign descriptor of gvmi file with ed25519 key
+    Sign(SignArgs),
+    /// Manage signing keys
+    Key {
+        #[command(subcommand)]
+        command: KeyCommand,
+    },
+    /// Manage local layer cache used with --layer-cache
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Log in to registry (or check saved login)
+    Login(LoginArgs),
+    /// Forget saved credentials
+    Logout,
+}
+
+#[derive(Args, Debug, Clone)]
+struct ImageOptions {
+    /// Output image name
+    #[arg(help_heading = Some("Image creation"), short, long)]
+    output: Option<String>,
+    /// Force overwriting existing image, even if it matches image
+    #[arg(help_heading = Some("Image creation"), short, long)]
+    force: bool,
+    /// Compression method used for squashfs.
+    /// lzo is lzo1x_1 and lz4 has no high compression mode, both give larger images than mksquashfs did
+    #[arg(help_heading = Some("Image creation"), long, default_value = "lzo", value_parser = clap::builder::PossibleValuesParser::new(COMPRESSION_POSSIBLE_VALUES))]
+    compression_method: String,
+    /// Possible values: gzip [1-9] (default 9), zstd [1-22] (default 15)
+    /// lzo, lz4 and xz do not support this option
+    #[arg(help_heading = Some("Image creation"), long)]
+    compression_level: Option<u32>,
+    /// Write SBOM of installed packages (dpkg, apk, rpm) next to the image: spdx or cyclonedx
+    #[arg(help_heading = Some("Image creation"), long, value_parser = SbomFormat::from_name)]
+    sbom: Option<SbomFormat>,
+    /// Reference the SBOM from image metadata (labels with its format and sha256)
+    #[arg(help_heading = Some("Image creation"), long, requires = "sbom")]
+    sbom_in_metadata: bool,
+    /// Bit-identical build: file times clamped to SOURCE_DATE_EPOCH (0 if not set),
+    /// volatile container config fields (hostname) removed
+    #[arg(help_heading = Some("Image creation"), long)]
+    reproducible: bool,
+    /// Cache extracted layers (in LAYER_CACHE_DIR, ~/.cache/gvmkit-build/layers by default),
+    /// next builds extract only new layers (and fetch only them from registry, OCI layout or archive)
+    #[arg(help_heading = Some("Image creation"), long)]
+    layer_cache: bool,
+    /// Remove paths matching the pattern from the image (.dockerignore syntax, e.g. /usr/share/doc,
+    /// **/__pycache__), can be repeated
+    #[arg(help_heading = Some("Image creation"), long)]
+    exclude: Vec<String>,
+    /// Read exclude patterns from file, one per line (.gvmkitignore in current directory if no file is given)
+    #[arg(help_heading = Some("Image creation"), long, num_args = 0..=1, require_equals = true,
+        default_missing_value = GVMKITIGNORE, value_name = "FILE")]
+    ignore_file: Option<PathBuf>,
+    /// Specify additional image environment variable
+    #[arg(help_heading = Some("Legacy/unused image options"), long)]
+    env: Vec<String>,
+    /// Specify additional image volume
+    #[arg(help_heading = Some("Legacy/unused image options"), long)]
+    vol: Vec<String>,
+    /// Specify image entrypoint
+    #[arg(help_heading = Some("Legacy/unused image options"), long)]
+    entrypoint: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct UploadOptions {
+    /// Specify chunk size (default 2MB, set this value in bytes)
+    #[arg(help_heading = Some("Portal"), long)]
+    upload_chunk_size: Option<u64>,
+    /// How image is split into chunks: fixed or cdc (content-defined, chunk size is the average,
+    /// unchanged parts of rebuilt image give the same chunks)
+    #[arg(help_heading = Some("Portal"), long, default_value = "fixed", value_parser = Chunking::from_name)]
+    chunking: Chunking,
+    /// Hash of chunks: sha256 or blake3 (needs descriptor version 2)
+    #[arg(help_heading = Some("Portal"), long, default_value = "sha256", value_parser = ChunkHash::from_name)]
+    chunk_hash: ChunkHash,
+    /// Descriptor format version: 1 or 2, by default 2 is used only when other options need it
+    #[arg(help_heading = Some("Portal"), long, value_parser = clap::value_parser!(u8).range(1..=2))]
+    descriptor_version: Option<u8>,
+    /// Specify number of upload workers (default 4)
+    #[arg(help_heading = Some("Portal"), long, default_value = "4")]
+    upload_workers: usize,
+    /// Continue interrupted upload using local upload journal
+    #[arg(help_heading = Some("Portal"), long)]
+    resume: bool,
+    /// Number of retries of failed chunk upload (network errors, 5xx, 429)
+    #[arg(help_heading = Some("Portal"), long, default_value = "5")]
+    upload_retries: u32,
+    /// Delay before first retry, doubled with every next one (e.g. 500ms, 2s)
+    #[arg(help_heading = Some("Portal"), long, default_value = "1s", value_parser = humantime::parse_duration)]
+    retry_backoff: Duration,
+    /// Maximum delay between retries (Retry-After sent by the registry may be longer)
+    #[arg(help_heading = Some("Portal"), long, default_value = "60s", value_parser = humantime::parse_duration)]
+    retry_max_backoff: Duration,
+    /// Time limit for uploading single chunk (e.g. 5m), no limit by default
+    #[arg(help_heading = Some("Portal"), long, value_parser = humantime::parse_duration)]
+    chunk_timeout: Option<Duration>,
+    /// Limit of total upload speed of all workers, e.g. 500KB, 2MB, 20Mbit (per second)
+    #[arg(help_heading = Some("Portal"), long, value_parser = parse_rate)]
+    max_upload_rate: Option<u64>,
+    /// Upload limits depending on local time, e.g. "09:00-17:00=1MB,17:00-09:00=unlimited",
+    /// --max-upload-rate is used in hours not covered by the schedule
+    #[arg(help_heading = Some("Portal"), long, value_parser = UploadSchedule::parse)]
+    upload_schedule: Option<UploadSchedule>,
+    /// Previous version of the image: tag in the registry (e.g. golem/my-image:v1) or local .descr.bin file.
+    /// Only chunks that differ from it are uploaded, if the registry supports chunk reuse
+    #[arg(help_heading = Some("Portal"), long, value_parser = DeltaBase::parse)]
+    delta_from: Option<DeltaBase>,
+    /// Sign the descriptor with key from the keyring before upload and upload the signature
+    #[arg(help_heading = Some("Portal"), long)]
+    sign_with: Option<String>,
+    /// Upload existing signature (<file>.descr.sig) together with the image
+    #[arg(help_heading = Some("Portal"), long, conflicts_with = "sign_with")]
+    upload_signature: bool,
+}
+
+impl UploadOptions {
+    fn descriptor_format(&self) -> anyhow::Result<DescriptorFormat> {
+        let format = DescriptorFormat {
+            chunking: self.chunking,
+            chunk_hash: self.chunk_hash,
+            v2: self.descriptor_version == Some(2),
+        };
+        if self.descriptor_version == Some(1)
+            && format.version() != DescriptorFormat::default().version()
+        {
+            return Err(anyhow::anyhow!(
+                "Chunking {} with chunk hash {} needs descriptor version 2",
+                self.chunking.name(),
+                self.chunk_hash.name()
+            ));
+        }
+        Ok(format)
+    }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.upload_retries,
+            initial_backoff: self.retry_backoff,
+            max_backoff: self.retry_max_backoff,
+            chunk_timeout: self.chunk_timeout,
+            ..Default::default()
+        }
+    }
+
+    fn upload_limiter(&self) -> Option<RateLimiter> {
+        if self.max_upload_rate.is_none() && self.upload_schedule.is_none() {
+            return None;
+        }
+        let limiter = RateLimiter::new(self.max_upload_rate);
+        Some(match &self.upload_schedule {
+            Some(schedule) => limiter.with_schedule(schedule.clone()),
+            None => limiter,
+        })
+    }
+
+    /// Registry client with retry policy and upload limits
+    fn registry_client(&self) -> anyhow::Result<RegistryClient> {
+        let mut client = RegistryClient::from_env()?.with_retry_policy(self.retry_policy());
+        if let Some(limiter) = self.upload_limiter() {
+            println!(" -- upload rate limit: {}", limiter);
+            client = client.with_upload_limiter(limiter);
+        }
+        Ok(client)
+    }
+
+    fn publish_options(&self) -> anyhow::Result<PublishOptions> {
+        Ok(PublishOptions {
+            chunk_size: self.upload_chunk_size,
+            descriptor_format: self.descriptor_format()?,
+            sign_with: self.sign_with.clone(),
+            upload_signature: self.upload_signature,
+            push: PushOptions {
+                upload_workers: self.upload_workers,
+                resume: self.resume,
+                delta_base: self.delta_from.clone(),
+                ..Default::default()
+            },
+        })
+    }
+}
+
+/// Where the image is built from, shared by build and rebuild-check
+#[derive(Args, Debug, Clone)]
+#[command(group(clap::ArgGroup::new("dockerfile_build").multiple(true).args(["dockerfile", "context"])))]
+struct SourceArgs {
+    /// Input Docker image name (with --docker-archive or --oci-layout: tag of the image to select,
+    /// with --rootfs: name used for output file, with --dockerfile: tag of the built image)
+    #[arg(required_unless_present_any = ["docker_archive", "oci_layout", "rootfs", "dockerfile", "context"])]
+    image_name: Option<String>,
+    /// Read image from archive created by `docker save` instead of docker engine
+    #[arg(help_heading = Some("Image source"), long, conflicts_with = "oci_layout")]
+    docker_archive: Option<PathBuf>,
+    /// Read image from OCI image layout directory (e.g. created by skopeo or buildah)
+    #[arg(help_heading = Some("Image source"), long, conflicts_with = "rootfs")]
+    oci_layout: Option<PathBuf>,
+    /// Build image from root filesystem directory or tarball (.tar, .tar.gz, .tar.zst)
+    #[arg(help_heading = Some("Image source"), long, conflicts_with = "docker_archive")]
+    rootfs: Option<PathBuf>,
+    /// Container config json used with --rootfs (Entrypoint, Cmd, Env, WorkingDir, Volumes)
+    #[arg(help_heading = Some("Image source"), long, requires = "rootfs")]
+    config: Option<PathBuf>,
+    /// Pull image directly from container registry (e.g. Docker Hub, ghcr.io) instead of using docker engine.
+    /// Credentials can be given in SOURCE_REGISTRY_USER and SOURCE_REGISTRY_PASSWORD env variables
+    #[arg(help_heading = Some("Image source"), long, requires = "image_name", conflicts_with_all = ["docker_archive", "oci_layout", "rootfs", "dockerfile_build"])]
+    from_registry: bool,
+    /// Build image in docker engine from Dockerfile (<context>/Dockerfile if only --context is given)
+    #[arg(help_heading = Some("Image source"), long, conflicts_with_all = ["docker_archive", "oci_layout", "rootfs"])]
+    dockerfile: Option<PathBuf>,
+    /// Build context directory sent to docker engine, paths listed in .dockerignore are skipped
+    /// (current directory by default)
+    #[arg(help_heading = Some("Image source"), long, conflicts_with_all = ["docker_archive", "oci_layout", "rootfs"])]
+    context: Option<PathBuf>,
+    /// Dockerfile build argument KEY=VALUE (KEY alone takes value from environment), can be repeated
+    #[arg(help_heading = Some("Image source"), long = "build-arg", requires = "dockerfile_build", value_parser = parse_build_arg)]
+    build_args: Vec<(String, String)>,
+    /// Stage of multi-stage Dockerfile to build
+    #[arg(help_heading = Some("Image source"), long, requires = "dockerfile_build")]
+    target: Option<String>,
+    /// Image platform, e.g. linux/amd64 or linux/arm64. Docker image is pulled and run for this platform,
+    /// from multi-platform OCI layout or registry image matching one is selected (linux/amd64 by default)
+    #[arg(help_heading = Some("Image source"), long, value_parser = Platform::from_str_name)]
+    platform: Option<Platform>,
+}
+
+#[derive(Args, Debug)]
+struct BuildArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    #[command(flatten)]
+    image: ImageOptions,
+    /// Upload image to repository, repository and tag is taken from image name <username>/<repository>:<tag>
+    #[arg(help_heading = Some("Image upload"), long, conflicts_with = "push_to")]
+    push: bool,
+    /// Alternative to --push: Upload image to repository, use format <username>/<repository>:<tag>
+    #[arg(help_heading = Some("Image upload"), long)]
+    push_to: Option<String>,
+    /// Skip login to repository (anonymous upload)
+    #[arg(help_heading = Some("Image upload"), long, conflicts_with = "push_to", requires = "push")]
+    nologin: bool,
+    #[command(flatten)]
+    upload: UploadOptions,
+}
+
+#[derive(Args, Debug)]
+struct RebuildCheckArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    #[command(flatten)]
+    image: ImageOptions,
+}
+
+#[derive(Args, Debug)]
+#[command(group(clap::ArgGroup::new("target").required(true).args(["push_to", "nologin"])))]
+struct PushArgs {
+    /// Ready gvmi file to upload
+    file: PathBuf,
+    /// Repository and tag to attach image to, use format <username>/<repository>:<tag>
+    #[arg(help_heading = Some("Image upload"), long)]
+    push_to: Option<String>,
+    /// Skip login to repository (anonymous upload)
+    #[arg(help_heading = Some("Image upload"), long)]
+    nologin: bool,
+    #[command(flatten)]
+    upload: UploadOptions,
+}
+
+#[derive(Args, Debug)]
+struct PullArgs {
+    /// Image to download, use format <username>/<repository>:<tag> or descriptor hash
+    image: String,
+    /// Output file name (default <username>-<repository>-<tag>.gvmi)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Specify number of download workers
+    #[arg(long, default_value = "4")]
+    download_workers: usize,
+}
+
+#[derive(Args, Debug)]
+struct InspectArgs {
+    /// gvmi file to inspect
+    file: PathBuf,
+    /// Descriptor to show (default <file>.descr.bin if exists)
+    #[arg(long)]
+    descriptor: Option<PathBuf>,
+    /// Print report as json
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct VerifyArgs {
+    /// gvmi file to verify
+    file: PathBuf,
+    /// Descriptor to check against (default <file>.descr.bin)
+    #[arg(long)]
+    descriptor: Option<PathBuf>,
+    /// Check only against image link (sha3 of the whole file) instead of descriptor
+    #[arg(long, conflicts_with = "descriptor")]
+    image_link: Option<String>,
+    /// Also check signature of the descriptor (default <file>.descr.sig)
+    #[arg(long, conflicts_with = "image_link")]
+    signature: Option<Option<PathBuf>>,
+    /// Public key (hex) the signature has to be made with (keys added with `key trust` by default)
+    #[arg(long, requires = "signature")]
+    public_key: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct SignArgs {
+    /// gvmi file to sign
+    file: PathBuf,
+    /// Descriptor to sign (default <file>.descr.bin, created if missing)
+    #[arg(long)]
+    descriptor: Option<PathBuf>,
+    /// Name of the signing key in the keyring
+    #[arg(long, default_value = DEFAULT_KEY_NAME)]
+    key: String,
+    /// Read signing key (hex encoded) from file instead of the keyring
+    #[arg(long, conflicts_with = "key")]
+    key_file: Option<PathBuf>,
+    /// Signature file (default <file>.descr.sig)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum KeyCommand {
+    /// Generate new signing key and store it in the keyring
+    Generate {
+        #[arg(long, default_value = DEFAULT_KEY_NAME)]
+        name: String,
+        /// Replace existing key with the same name
+        #[arg(long)]
+        force: bool,
+        /// Write the key to file instead of the keyring (e.g. for CI)
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Import signing key (hex encoded) from file into the keyring
+    Import {
+        file: PathBuf,
+        #[arg(long, default_value = DEFAULT_KEY_NAME)]
+        name: String,
+        /// Replace existing key with the same name
+        #[arg(long)]
+        force: bool,
+    },
+    /// Show public key of the signing key
+    Show {
+        #[arg(long, default_value = DEFAULT_KEY_NAME)]
+        name: String,
+    },
+    /// Remove signing key from the keyring
+    Remove {
+        #[arg(long, default_value = DEFAULT_KEY_NAME)]
+        name: String,
+    },
+    /// Trust public key (hex) of a publisher when verifying signatures
+    Trust { public_key: String },
+    /// Stop trusting public key (hex) of a publisher
+    Untrust { public_key: String },
+    /// List trusted public keys
+    Trusted,
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// Remove least recently used layers (all layers if no size is given), do not run while building
+    Prune {
+        /// Keep the cache up to this size, e.g. 10GB
+        #[arg(long, value_parser = parse_size)]
+        max_size: Option<u64>,
+    },
+}
+
+#[derive(Args, Debug)]
+struct LoginArgs {
+    /// Only check if saved login is valid
+    #[arg(long)]
+    check: bool,
+}
+
+/// Flat options used before subcommands were introduced, kept for compatibility
+#[derive(Args, Debug)]
+struct LegacyArgs {
     /// Input Docker image name
     image_name: Option<String>,
     /// Output image name
@@ -31,26 +454,26 @@ struct CmdArgs {
     #[arg(help_heading = Some("Image creation"), short, long)]
     force: bool,
     /// Upload image to repository, repository and tag is taken from image name <username>/<repository>:<tag>
-    #[arg(help_heading = Some("Image upload"), long)]
+    #[arg(help_heading = Some("Image upload"), long, conflicts_with = "push_to")]
     push: bool,
     /// Alternative to --push: Upload image to repository, use format <username>/<repository>:<tag>
     #[arg(help_heading = Some("Image upload"), long)]
     push_to: Option<String>,
     /// Force login action and do not build or push image
     /// Use if you want to change username or personal access token
-    #[arg(help_heading = Some("Portal"), long)]
+    #[arg(help_heading = Some("Portal"), long, conflicts_with_all = ["login_check", "logout", "image_name", "push", "push_to", "nologin", "direct_file_upload"])]
     login: bool,
     ///check if saved login is valid
-    #[arg(help_heading = Some("Portal"), long)]
+    #[arg(help_heading = Some("Portal"), long, conflicts_with_all = ["logout", "image_name", "push", "push_to", "nologin", "direct_file_upload"])]
     login_check: bool,
     /// Force logout action (forget saved credentials)
-    #[arg(help_heading = Some("Portal"), long)]
+    #[arg(help_heading = Some("Portal"), long, conflicts_with_all = ["image_name", "push", "push_to", "nologin", "direct_file_upload"])]
     logout: bool,
     /// Skip login to repository (anonymous upload)
-    #[arg(help_heading = Some("Portal"), long)]
+    #[arg(help_heading = Some("Portal"), long, conflicts_with = "push_to")]
     nologin: bool,
     /// Specify ready gvmi file to upload to registry, do not use until you now what are you doing
-    #[arg(help_heading = Some("Maintenance options"), short, long)]
+    #[arg(help_heading = Some("Maintenance options"), short, long, conflicts_with = "image_name")]
     direct_file_upload: Option<String>,
     /// Specify additional image environment variable
     #[arg(help_heading = Some("Legacy/unused image options"), long)]
@@ -61,305 +484,661 @@ struct CmdArgs {
     /// Specify image entrypoint
     #[arg(help_heading = Some("Legacy/unused image options"), long)]
     entrypoint: Option<String>,
-    /// Possible values: lzo, gzip, lz4, zstd, xz
-    #[arg(help_heading = Some("Image creation"), long, default_value = "lzo")]
+    /// Compression method used for squashfs.
+    /// lzo is lzo1x_1 and lz4 has no high compression mode, both give larger images than mksquashfs did
+    #[arg(help_heading = Some("Image creation"), long, default_value = "lzo", value_parser = clap::builder::PossibleValuesParser::new(COMPRESSION_POSSIBLE_VALUES))]
     compression_method: String,
-    /// Possible values: lzo [1-9] (default 8), gzip [1-9] (default 9), zstd [1-22] (default 15)
-    /// lz4 and xz do not support this option
+    /// Possible values: gzip [1-9] (default 9), zstd [1-22] (default 15)
+    /// lzo, lz4 and xz do not support this option
     #[arg(help_heading = Some("Image creation"), long)]
     compression_level: Option<u32>,
     /// Specify chunk size (default 2MB, set this value in bytes)
     #[arg(help_heading = Some("Portal"), long)]
     upload_chunk_size: Option<u64>,
+    /// How image is split into chunks: fixed or cdc (content-defined, chunk size is the average,
+    /// unchanged parts of rebuilt image give the same chunks)
+    #[arg(help_heading = Some("Portal"), long, default_value = "fixed", value_parser = Chunking::from_name)]
+    chunking: Chunking,
     /// Specify number of upload workers (default 4)
     #[arg(help_heading = Some("Portal"), long, default_value = "4")]
     upload_workers: usize,
-    /// Hide progress bars during operation
-    #[arg(help_heading = Some("Extra options"), long)]
-    hide_progress: bool,
-    #[arg(help_heading = Some("Write json info to specified file"), long)]
-    extra_json_info_path: Option<String>,
 }
-use tokio::fs;
-
-use crate::chunks::FileChunkDesc;
-use crate::login::remove_credentials;
-use crate::progress::set_progress_bar_settings;
-use crate::upload::{attach_to_repo, check_login, full_upload, upload_descriptor, REGISTRY_URL};
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
 
-#[tokio::main()]
-async fn main() -> anyhow::Result<()> {
-    dotenv::dotenv().ok();
-    let log_level = env::var("RUST_LOG").unwrap_or("info,bollard=warn,hyper=warn".to_string());
-    env::set_var("RUST_LOG", log_level);
-    env_logger::init();
-
-    println!("Golem Image Builder v{}", env!("CARGO_PKG_VERSION"));
+impl LegacyArgs {
+    /// Translate flat options into one of the subcommands
+    fn into_command(self) -> anyhow::Result<Command> {
+        if self.login || self.login_check {
+            return Ok(Command::Login(LoginArgs {
+                check: self.login_check,
+            }));
+        }
+        if self.logout {
+            return Ok(Command::Logout);
+        }
+        let upload = UploadOptions {
+            upload_chunk_size: self.upload_chunk_size,
+            chunking: self.chunking,
+            chunk_hash: ChunkHash::Sha256,
+            descriptor_version: None,
+            upload_workers: self.upload_workers,
+            resume: false,
+            upload_retries: 5,
+            retry_backoff: Duration::from_secs(1),
+            retry_max_backoff: Duration::from_secs(60),
+            chunk_timeout: None,
+            max_upload_rate: None,
+            upload_schedule: None,
+            delta_from: None,
+            sign_with: None,
+            upload_signature: false,
+        };
+        if let Some(direct_file_upload) = self.direct_file_upload {
+            if self.push && !self.nologin {
+                return Err(anyhow::anyhow!(
+                    "You have to specify username.\nInstead of --push you can use --push-to <username>/<repository>:<tag>\nYou can also add --nologin to upload image anonymously"
+                ));
+            }
+            //without --push or --push-to only descriptor is created for given file
+            return Ok(Command::Push(PushArgs {
+                file: PathBuf::from(direct_file_upload),
+                push_to: self.push_to,
+                nologin: self.push && self.nologin,
+                upload,
+            }));
+        }
+        let Some(image_name) = self.image_name else {
+            return Err(anyhow::anyhow!("You have to specify image name to build"));
+        };
+        Ok(Command::Build(BuildArgs {
+            source: SourceArgs {
+                image_name: Some(image_name),
+                docker_archive: None,
+                oci_layout: None,
+                from_registry: false,
+                rootfs: None,
+                config: None,
+                dockerfile: None,
+                context: None,
+                build_args: Vec::new(),
+                target: None,
+                platform: None,
+            },
+            image: ImageOptions {
+                output: self.output,
+                force: self.force,
+                compression_method: self.compression_method,
+                compression_level: self.compression_level,
+                env: self.env,
+                vol: self.vol,
+                entrypoint: self.entrypoint,
+                sbom: None,
+                sbom_in_metadata: false,
+                reproducible: false,
+                layer_cache: false,
+                exclude: Vec::new(),
+                ignore_file: None,
+            },
+            push: self.push,
+            push_to: self.push_to,
+            nologin: self.nologin,
+            upload,
+        }))
+    }
+}
 
-    let cmdargs = <CmdArgs as Parser>::parse();
+use tokio::fs;
 
-    set_progress_bar_settings(progress::ProgressBarSettings {
-        hidden: cmdargs.hide_progress,
-    });
+use gvmkit_build::chunks::{
+    create_descriptor, default_chunk_size, descriptor_path, load_or_create_descriptor, ChunkHash,
+    Chunking, DescriptorFormat, FileChunkDesc,
+};
+use gvmkit_build::delta::DeltaBase;
+use gvmkit_build::login::remove_credentials;
+use gvmkit_build::metadata::read_metadata_footer;
+use gvmkit_build::progress::set_progress_bar_settings;
+use gvmkit_build::publish::{publish, PublishOptions, PushTarget};
+use gvmkit_build::upload::PushOptions;
 
-    if cmdargs.nologin && cmdargs.push_to.is_some() {
+fn parse_push_to(push_to: &str) -> anyhow::Result<ImageName> {
+    //pushing to user/repository:tag given by the user
+    let push_image_name = ImageName::from_str_name(push_to)?;
+    if push_image_name.user.is_none() {
         return Err(anyhow::anyhow!(
-            "Options --push-to and --nologin are incompatible"
+            "You have to specify username in push-to argument"
         ));
     }
-    if !COMPRESSION_POSSIBLE_VALUES.contains(&cmdargs.compression_method.as_str()) {
-        return Err(anyhow::anyhow!(
-            "Not supported compression method: {}, possible values {}",
-            cmdargs.compression_method,
-            COMPRESSION_POSSIBLE_VALUES.join(", ")
-        ));
-    }
-    if cmdargs.login {
-        println!("Logging in to golem registry: {}", REGISTRY_URL.as_str());
-        login::login(None, true).await?;
-        return Ok(());
-    }
-    if cmdargs.logout {
-        remove_credentials().await?;
-        return Ok(());
-    }
-    if cmdargs.login_check {
-        println!(
-            "Checking login to golem registry: {}",
-            REGISTRY_URL.as_str()
-        );
-        return if login::check_if_valid_login().await? {
-            Ok(())
-        } else {
-            Err(anyhow::anyhow!("Login is not valid"))
-        };
-    }
-    let push_image_name = if !cmdargs.nologin {
-        if let Some(push_to) = &cmdargs.push_to {
-            //pushing to user/repository:tag given by the user
-            let push_image_name = ImageName::from_str_name(push_to)?;
-            if push_image_name.user.is_none() {
-                return Err(anyhow::anyhow!(
-                    "You have to specify username in push-to argument"
-                ));
-            }
-            Some(push_image_name)
-        } else if cmdargs.push {
-            let cmd_image_name = match cmdargs.image_name.clone() {
-                Some(image_name) => image_name,
-                None => return Err(anyhow::anyhow!("You have to specify image name to build")),
-            };
-            //pushing to user/repository:tag from image name
-            let push_image_name = ImageName::from_str_name(&cmd_image_name)?;
-            if push_image_name.user.is_none() {
-                return Err(anyhow::anyhow!(
-                "You have to specify username.\nInstead of --push you can use --push-to <username>/<repository>:<tag>\nAlternatively you add --nologin option to upload image anonymously"
-            ));
-            }
-            Some(push_image_name)
-        } else {
-            //not pushing at all
-            None
+    Ok(push_image_name)
+}
+
+/// Builder configured from source and image creation options
+fn image_builder(source: SourceArgs, image: ImageOptions) -> anyhow::Result<ImageBuilder> {
+    let image_source = if let Some(path) = source.docker_archive {
+        ImageSource::DockerArchive {
+            path,
+            tag: source.image_name.clone(),
         }
+    } else if source.from_registry {
+        ImageSource::Registry {
+            reference: source.image_name.clone().unwrap_or_default(),
+        }
+    } else if let Some(path) = source.rootfs {
+        ImageSource::Rootfs {
+            path,
+            config: source.config,
+        }
+    } else if let Some(path) = source.oci_layout {
+        ImageSource::OciLayout {
+            path,
+            tag: source.image_name.clone(),
+        }
+    } else if source.dockerfile.is_some() || source.context.is_some() {
+        if let Some(image_name) = &source.image_name {
+            let _ = ImageName::from_str_name(image_name)?;
+        }
+        let context = source.context.unwrap_or_else(|| PathBuf::from("."));
+        ImageSource::Dockerfile(DockerfileBuild {
+            dockerfile: source
+                .dockerfile
+                .unwrap_or_else(|| context.join("Dockerfile")),
+            context,
+            build_args: source.build_args,
+            target: source.target,
+        })
+    } else {
+        //parse image name to check if proper name is provided
+        let _ = ImageName::from_str_name(source.image_name.as_deref().unwrap_or_default())?;
+        ImageSource::Docker
+    };
+    let mut exclude = match &image.ignore_file {
+        Some(path) if !path.exists() => {
+            return Err(anyhow::anyhow!("Ignore file {} not found", path.display()));
+        }
+        Some(path) => {
+            let rules = IgnoreRules::load(path)?;
+            println!(
+                " -- {} exclude patterns loaded from {}",
+                rules.rules.len(),
+                path.display()
+            );
+            rules
+        }
+        None => IgnoreRules::default(),
+    };
+    for pattern in &image.exclude {
+        exclude.push(pattern)?;
+    }
+    let source_date_epoch = if image.reproducible {
+        Some(source_date_epoch()?)
     } else {
         None
     };
 
-    let (user_name, pat) = if !cmdargs.nologin && (cmdargs.push_to.is_some() || cmdargs.push) {
-        println!("Logging in to golem registry: {}", REGISTRY_URL.as_str());
+    Ok(ImageBuilder::new(
+        source.image_name.as_deref().unwrap_or_default(),
+        image.output,
+        image.force,
+        image.env,
+        image.vol,
+        image.entrypoint,
+        image.compression_method,
+        image.compression_level,
+    )
+    .with_source(image_source)
+    .with_platform(source.platform)
+    .with_sbom(image.sbom)
+    .with_sbom_in_metadata(image.sbom_in_metadata)
+    .with_source_date_epoch(source_date_epoch)
+    .with_layer_cache(image.layer_cache.then(default_layer_cache_dir))
+    .with_exclude(exclude))
+}
 
-        if let (Ok(registry_user), Ok(registry_token)) =
-            (env::var("REGISTRY_USER"), env::var("REGISTRY_TOKEN"))
-        {
-            println!(" -- Using credentials from environment variables (REGISTRY_USER and REGISTRY_TOKEN)");
-            let res = check_login(&registry_user, &registry_token).await?;
-            if !res {
-                return Err(anyhow::anyhow!(
-                    "Login to golem registry: {} failed",
-                    REGISTRY_URL.as_str()
-                ));
-            }
-            (registry_user, registry_token)
-        } else if let Some(user_name) = &push_image_name.as_ref().map(|x| &x.user).unwrap_or(&None)
-        {
-            login::login(Some(user_name), false).await?
-        } else {
+async fn run_build(args: BuildArgs, extra_json_info_path: Option<&str>) -> anyhow::Result<()> {
+    let push_target = if let Some(push_to) = &args.push_to {
+        PushTarget::Tag(parse_push_to(push_to)?)
+    } else if args.push && args.nologin {
+        PushTarget::Anonymous
+    } else if args.push {
+        //pushing to user/repository:tag from image name
+        let Some(image_name) = &args.source.image_name else {
             return Err(anyhow::anyhow!(
-                    "You have to specify username.\nInstead of --push you can use --push-to <username>/<repository>:<tag>\nYou can also add --nologin to upload image anonymously"
-                ));
+                "You have to specify image name or use --push-to <username>/<repository>:<tag>"
+            ));
+        };
+        let push_image_name = ImageName::from_str_name(image_name)?;
+        if push_image_name.user.is_none() {
+            return Err(anyhow::anyhow!(
+                "You have to specify username.\nInstead of --push you can use --push-to <username>/<repository>:<tag>\nAlternatively you add --nologin option to upload image anonymously"
+            ));
         }
+        PushTarget::Tag(push_image_name)
     } else {
-        (String::new(), String::new())
+        PushTarget::None
     };
+    let builder = image_builder(args.source, args.image)?;
+    let path = builder.build().await?;
+    publish(
+        args.upload.registry_client()?,
+        path,
+        push_target,
+        &args.upload.publish_options()?,
+        extra_json_info_path,
+    )
+    .await
+}
 
-    let path = if let Some(direct_file_upload) = cmdargs.direct_file_upload {
-        //skip build and upload file directly
-        let path_buf = PathBuf::from(&direct_file_upload);
-        if path_buf.exists() {
-            path_buf
-        } else {
-            return Err(anyhow::anyhow!(
-                "File {} does not exist",
-                direct_file_upload
-            ));
+/// Builds image twice (second build next to the first one) and compares descriptors
+async fn run_rebuild_check(args: RebuildCheckArgs) -> anyhow::Result<()> {
+    let image = ImageOptions {
+        force: true,
+        reproducible: true,
+        ..args.image
+    };
+    println!(" * Rebuild check - first build ...");
+    let first = image_builder(args.source.clone(), image.clone())?
+        .build()
+        .await?;
+    let second_output = format!(
+        "{}.rebuild.gvmi",
+        first.display().to_string().trim_end_matches(".gvmi")
+    );
+    println!(" * Rebuild check - second build ...");
+    let second = image_builder(
+        args.source,
+        ImageOptions {
+            output: Some(second_output),
+            ..image.clone()
+        },
+    )?
+    .build()
+    .await?;
+
+    println!(" * Rebuild check - comparing descriptors ...");
+    //the same chunk size for both builds, so chunks can be compared
+    let chunk_size = default_chunk_size(fs::metadata(&first).await?.len()) as usize;
+    let diff = RebuildDiff::compare(
+        &create_descriptor(&first, chunk_size).await?,
+        &create_descriptor(&second, chunk_size).await?,
+    )?;
+    println!(" -- {diff}");
+    if diff.is_identical() {
+        fs::remove_file(&second).await?;
+        if let Some(format) = image.sbom {
+            fs::remove_file(sbom_path(&second, format)).await.ok();
         }
+        return Ok(());
+    }
+    if read_metadata_footer(&first)?.json != read_metadata_footer(&second)?.json {
+        println!(" -- metadata differs");
+    }
+    Err(anyhow::anyhow!(
+        "Image build is not reproducible, second build kept at {}",
+        second.display()
+    ))
+}
+
+fn check_file_exists(path: &Path) -> anyhow::Result<()> {
+    if path.exists() {
+        Ok(())
     } else {
-        let cmd_image_name = match cmdargs.image_name {
-            Some(image_name) => image_name,
-            None => return Err(anyhow::anyhow!("You have to specify image name to build")),
-        };
-        //parse image name to check if proper name is provided
-        let _ = ImageName::from_str_name(&cmd_image_name)?;
-
-        let builder = ImageBuilder::new(
-            &cmd_image_name,
-            cmdargs.output,
-            cmdargs.force,
-            cmdargs.env,
-            cmdargs.vol,
-            cmdargs.entrypoint,
-            cmdargs.compression_method,
-            cmdargs.compression_level,
-        );
+        Err(anyhow::anyhow!("File {} does not exist", path.display()))
+    }
+}
 
-        builder.build().await?
+async fn run_push(args: PushArgs, extra_json_info_path: Option<&str>) -> anyhow::Result<()> {
+    check_file_exists(&args.file)?;
+    let push_target = if let Some(push_to) = &args.push_to {
+        PushTarget::Tag(parse_push_to(push_to)?)
+    } else if args.nologin {
+        PushTarget::Anonymous
+    } else {
+        PushTarget::None
     };
+    publish(
+        args.upload.registry_client()?,
+        args.file,
+        push_target,
+        &args.upload.publish_options()?,
+        extra_json_info_path,
+    )
+    .await
+}
+
+async fn run_pull(args: PullArgs) -> anyhow::Result<()> {
+    let source = PullSource::parse(&args.image)?;
+    RegistryClient::from_env()?
+        .pull_image(&source, args.output.as_deref(), args.download_workers)
+        .await?;
+    Ok(())
+}
 
-    let image_file_size = fs::metadata(&path).await?.len();
-    let chunk_size = if let Some(chunk_size) = cmdargs.upload_chunk_size {
-        chunk_size
-    } else if image_file_size > 1024 * 1024 * 1024 * 5 {
-        100 * 1024 * 1024
-    } else if image_file_size > 1024 * 1024 * 1024 * 2 {
-        50 * 1024 * 1024
-    } else if image_file_size > 1024 * 1024 * 1024 {
-        20 * 1024 * 1024
-    } else if image_file_size > 1024 * 1024 * 500 {
-        10 * 1024 * 1024
-    } else if image_file_size > 1024 * 1024 * 200 {
-        5 * 1024 * 1024
+async fn run_inspect(args: InspectArgs) -> anyhow::Result<()> {
+    check_file_exists(&args.file)?;
+    let report = inspect_image(&args.file, args.descriptor.as_deref())?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+    println!("Image: {}", report.path.display());
+    println!(" -- file size: {}", report.file_size);
+    match (&report.squashfs, &report.squashfs_error) {
+        (Some(squashfs), _) => {
+            println!("Squashfs:");
+            println!(" -- version: {}", squashfs.version);
+            println!(" -- compressor: {}", squashfs.compressor);
+            println!(" -- block size: {}", squashfs.block_size);
+            println!(" -- inode count: {}", squashfs.inode_count);
+            println!(" -- bytes used: {}", squashfs.bytes_used);
+        }
+        (None, err) => println!("Squashfs: {}", err.as_deref().unwrap_or("unknown error")),
+    }
+    match (&report.metadata, &report.metadata_error) {
+        (Some(metadata), _) => {
+            println!("Metadata:");
+            println!(" -- offset: {}", metadata.offset);
+            println!(" -- size: {}", metadata.size);
+            if metadata.crc_valid {
+                println!(" -- CRC: {} (valid)", metadata.crc_stored);
+            } else {
+                println!(
+                    " -- CRC: {} (INVALID, computed {})",
+                    metadata.crc_stored, metadata.crc_computed
+                );
+            }
+            if let Some(error) = &metadata.error {
+                println!(" -- error: {}", error);
+            }
+            if let Some(config) = &metadata.config {
+                println!("{}", serde_json::to_string_pretty(config)?);
+            }
+        }
+        (None, err) => println!("Metadata: {}", err.as_deref().unwrap_or("unknown error")),
+    }
+    if let Some(descr) = &report.descriptor {
+        println!("Descriptor: {}", descr.path.display());
+        println!(" -- image link (for use in SDK): {}", descr.image_link);
+        println!(" -- descriptor hash: {}", descr.descriptor_hash);
+        println!(" -- descriptor version: {}", descr.version);
+        println!(" -- chunking: {}", descr.chunking);
+        println!(" -- chunk hash: {}", descr.chunk_hash);
+        println!(" -- chunk size: {}", descr.chunk_size);
+        println!(" -- chunk count: {}", descr.chunk_count);
+        if descr.file_size != report.file_size {
+            println!(
+                " -- WARNING: descriptor file size {} does not match image",
+                descr.file_size
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn run_sign(args: SignArgs) -> anyhow::Result<()> {
+    check_file_exists(&args.file)?;
+    let descr_path = args
+        .descriptor
+        .unwrap_or_else(|| descriptor_path(&args.file));
+    let descr = if descr_path.exists() {
+        FileChunkDesc::deserialize_from_bytes(&fs::read(&descr_path).await?)?
     } else {
-        2 * 1024 * 1024
+        let file_size = fs::metadata(&args.file).await?.len();
+        load_or_create_descriptor(
+            &args.file,
+            default_chunk_size(file_size),
+            DescriptorFormat::default(),
+        )
+        .await?
+        .0
+    };
+    let key = match &args.key_file {
+        Some(key_file) => read_signing_key_file(key_file)?,
+        None => load_signing_key(&args.key)?,
     };
+    println!(
+        " * Signing descriptor {} of {}",
+        descr_path.display(),
+        args.file.display()
+    );
+    let signature = ImageSignature::sign(&descr, &key);
+    let output = args.output.unwrap_or_else(|| signature_path(&args.file));
+    signature.save(&output)?;
+    println!(" -- descriptor hash: {}", signature.descriptor_hash);
+    println!(" -- public key: {}", signature.public_key);
+    println!(" -- signature saved to {}", output.display());
+    Ok(())
+}
+
+fn run_cache(command: CacheCommand) -> anyhow::Result<()> {
+    match command {
+        CacheCommand::Prune { max_size } => {
+            let cache = LayerCache::open(&default_layer_cache_dir())?;
+            let stats = cache.prune(max_size.unwrap_or(0))?;
+            println!(
+                " -- layer cache {}: removed {} layers ({}), kept {} layers ({})",
+                cache.dir().display(),
+                stats.removed,
+                humansize::format_size(stats.removed_bytes, humansize::DECIMAL),
+                stats.kept,
+                humansize::format_size(stats.kept_bytes, humansize::DECIMAL)
+            );
+        }
+    }
+    Ok(())
+}
 
-    let descr_path = PathBuf::from(path.display().to_string() + ".descr.bin");
-    let descr = {
-        let path_meta = fs::metadata(&path).await?;
-        let descr = if descr_path.exists() {
-            if fs::metadata(&descr_path)
-                .await?
-                .modified()
-                .expect("Modified field has to be here")
-                < path_meta.modified().expect("Modified field has to be here")
-            {
-                println!(" -- File descriptor is older than image, recreating");
-                None
+fn run_key(command: KeyCommand) -> anyhow::Result<()> {
+    match command {
+        KeyCommand::Generate {
+            name,
+            force,
+            output,
+        } => {
+            let key = generate_signing_key();
+            if let Some(output) = output {
+                write_signing_key_file(&output, &key)?;
+                println!(" -- signing key written to {}", output.display());
             } else {
-                match tokio::fs::read(&descr_path).await {
-                    Ok(file_descr_bytes) => {
-                        match FileChunkDesc::deserialize_from_bytes(&file_descr_bytes) {
-                            Ok(descr) => {
-                                if descr.chunk_size != chunk_size {
-                                    println!(" -- chunk size changed, recreating file descriptor");
-                                    None
-                                } else {
-                                    println!(" -- file descriptor already exists and is newer");
-                                    println!(
-                                        "Image link (for use in SDK): {}",
-                                        hex::encode(descr.sha3)
-                                    );
-                                    Some(descr)
-                                }
-                            }
-                            Err(e) => {
-                                println!(" -- failed to deserialize file descriptor: {}", e);
-                                None
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        println!(
-                            " -- failed to read file descriptor: {} {}",
-                            descr_path.display(),
-                            e
-                        );
-                        None
-                    }
+                if !force && get_signing_key(&name)?.is_some() {
+                    return Err(anyhow::anyhow!(
+                        "Signing key {name} already exists, use --force to replace it"
+                    ));
                 }
+                save_signing_key(&name, &key)?;
+                println!(" -- signing key {name} saved in keyring");
             }
-        } else {
-            None
-        };
-        if let Some(descr) = descr {
-            descr
-        } else {
-            println!(" * Writing file descriptor to {}", descr_path.display());
-            let mut file = File::create(&descr_path).await?;
-            let descr = chunks::create_descriptor(&path, chunk_size as usize).await?;
-            file.write_all(&descr.serialize_to_bytes()).await?;
-            println!(" -- file descriptor created successfully");
             println!(
-                " -- image link (for use in SDK): {}",
-                hex::encode(descr.sha3)
+                " -- public key: {}",
+                hex::encode(key.verifying_key().as_bytes())
+            );
+        }
+        KeyCommand::Import { file, name, force } => {
+            let key = read_signing_key_file(&file)?;
+            if !force && get_signing_key(&name)?.is_some() {
+                return Err(anyhow::anyhow!(
+                    "Signing key {name} already exists, use --force to replace it"
+                ));
+            }
+            save_signing_key(&name, &key)?;
+            println!(" -- signing key {name} imported from {}", file.display());
+            println!(
+                " -- public key: {}",
+                hex::encode(key.verifying_key().as_bytes())
+            );
+        }
+        KeyCommand::Show { name } => {
+            let key = load_signing_key(&name)?;
+            println!(
+                " -- public key: {}",
+                hex::encode(key.verifying_key().as_bytes())
             );
-            descr
         }
+        KeyCommand::Remove { name } => {
+            if remove_signing_key(&name)? {
+                println!(" -- signing key {name} removed");
+            } else {
+                println!(" -- signing key {name} not found");
+            }
+        }
+        KeyCommand::Trust { public_key } => {
+            if trust_key(&parse_public_key(&public_key)?)? {
+                println!(" -- public key {public_key} is trusted now");
+            } else {
+                println!(" -- public key {public_key} is already trusted");
+            }
+        }
+        KeyCommand::Untrust { public_key } => {
+            if untrust_key(&parse_public_key(&public_key)?)? {
+                println!(" -- public key {public_key} is not trusted anymore");
+            } else {
+                println!(" -- public key {public_key} was not trusted");
+            }
+        }
+        KeyCommand::Trusted => {
+            for key in trusted_keys()? {
+                println!("{}", hex::encode(key.as_bytes()));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_verify(args: VerifyArgs) -> anyhow::Result<()> {
+    check_file_exists(&args.file)?;
+    let mut signed_descr = None;
+    let expected = if let Some(image_link) = args.image_link {
+        println!(" * Verifying {} against image link", args.file.display());
+        VerifyExpectation::ImageLink(image_link)
+    } else {
+        let descr_path = args
+            .descriptor
+            .unwrap_or_else(|| descriptor_path(&args.file));
+        println!(
+            " * Verifying {} against descriptor {}",
+            args.file.display(),
+            descr_path.display()
+        );
+        let descr_bytes = fs::read(&descr_path).await.map_err(|e| {
+            anyhow::anyhow!("Failed to read descriptor {}: {}", descr_path.display(), e)
+        })?;
+        let descr = FileChunkDesc::deserialize_from_bytes(&descr_bytes)?;
+        if args.signature.is_some() {
+            signed_descr = Some(descr.clone());
+        }
+        VerifyExpectation::Descriptor(descr)
     };
+    let report = verify_image(&args.file, &expected).await?;
 
-    let repo_info = if cmdargs.push || cmdargs.push_to.is_some() {
+    match &report.metadata_error {
+        None => println!(" -- metadata CRC valid"),
+        Some(err) => println!(" -- metadata invalid: {}", err),
+    }
+    if !report.size_valid() {
         println!(
-            "Uploading image to golem registry: {}",
-            REGISTRY_URL.as_str()
+            " -- file size {} does not match descriptor size {}",
+            report.file_size,
+            report.expected_size.unwrap_or_default()
         );
-        let full_upload_needed = upload_descriptor(&descr_path).await?;
-
-        if full_upload_needed {
-            if let Some(push_image_name) = &push_image_name {
-                //check if we can attach to the repo before uploading the file
-                let _repo_info = attach_to_repo(
-                    &descr.get_descr_hash_str(),
-                    push_image_name,
-                    &user_name,
-                    &pat,
-                    true,
-                )
-                .await?;
-            };
-            full_upload(&path, &descr, cmdargs.upload_workers).await?;
-        }
-
-        if let Some(push_image_name) = &push_image_name {
-            //attach to repo after upload
-            Some(
-                attach_to_repo(
-                    &descr.get_descr_hash_str(),
-                    push_image_name,
-                    &user_name,
-                    &pat,
-                    false,
-                )
-                .await?,
-            )
-        } else {
-            None
+    }
+    if report.chunks_checked > 0 {
+        if report.corrupted_ranges.is_empty() {
+            println!(" -- all {} chunks valid", report.chunks_checked);
+        }
+        for range in &report.corrupted_ranges {
+            println!(
+                " -- corrupted chunks {}-{} (bytes {}-{})",
+                range.first_chunk, range.last_chunk, range.start, range.end
+            );
         }
+    }
+    if report.link_valid() {
+        println!(" -- image link valid: {}", report.actual_link);
     } else {
-        None
-    };
-    // write info to file
-    if let Some(json_path) = &cmdargs.extra_json_info_path {
-        println!(" * Writing info to {}", json_path);
-        let repo_info_path = PathBuf::from(json_path);
-        let mut file = File::create(&repo_info_path).await?;
-        file.write_all(&serde_json::to_vec_pretty(&json!({
-            "repoInfo": &repo_info,
-            "imagePath": path.display().to_string(),
-            "imageDescrPath": descr_path.display().to_string(),
-            "hash": &descr.get_descr_hash_str(),
-        }))?)
-        .await?;
+        println!(
+            " -- image link mismatch, expected {} got {}",
+            report.expected_link, report.actual_link
+        );
+    }
+    if !report.is_valid() {
+        return Err(anyhow::anyhow!(
+            "Image {} verification failed",
+            args.file.display()
+        ));
+    }
+    println!(" -- image verified successfully");
+
+    if let Some(descr) = signed_descr {
+        let sig_path = args
+            .signature
+            .flatten()
+            .unwrap_or_else(|| signature_path(&args.file));
+        let signature = ImageSignature::load(&sig_path)?;
+        let trusted = match &args.public_key {
+            Some(public_key) => vec![parse_public_key(public_key)?],
+            None => trusted_keys()?,
+        };
+        if trusted.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No trusted keys to check signature (made with key {}), use --public-key <hex> or add publisher key with: gvmkit-build key trust <hex>",
+                signature.public_key
+            ));
+        }
+        signature.verify_trusted(&descr, &trusted)?;
+        println!(
+            " -- signature valid, signed with trusted key {}",
+            signature.public_key
+        );
     }
     Ok(())
 }
+
+#[tokio::main()]
+async fn main() -> anyhow::Result<()> {
+    dotenv::dotenv().ok();
+    let log_level = env::var("RUST_LOG").unwrap_or("info,bollard=warn,hyper=warn".to_string());
+    env::set_var("RUST_LOG", log_level);
+    env_logger::init();
+
+    let cmdargs = <CmdArgs as Parser>::parse();
+
+    //keep stdout clean for machine readable output
+    if !matches!(&cmdargs.command, Some(Command::Inspect(args)) if args.json) {
+        println!("Golem Image Builder v{}", env!("CARGO_PKG_VERSION"));
+    }
+
+    set_progress_bar_settings(progress::ProgressBarSettings {
+        hidden: cmdargs.hide_progress,
+    });
+    let extra_json_info_path = cmdargs.extra_json_info_path.as_deref();
+
+    let command = match cmdargs.command {
+        Some(command) => command,
+        None => cmdargs.legacy.into_command()?,
+    };
+
+    match command {
+        Command::Build(args) => run_build(args, extra_json_info_path).await,
+        Command::RebuildCheck(args) => run_rebuild_check(args).await,
+        Command::Push(args) => run_push(args, extra_json_info_path).await,
+        Command::Pull(args) => run_pull(args).await,
+        Command::Inspect(args) => run_inspect(args).await,
+        Command::Verify(args) => run_verify(args).await,
+        Command::Sign(args) => run_sign(args).await,
+        Command::Key { command } => run_key(command),
+        Command::Cache { command } => run_cache(command),
+        Command::Login(args) => {
+            let client = RegistryClient::from_env()?;
+            if args.check {
+                println!("Checking login to golem registry: {}", client.base_url());
+                if login::check_if_valid_login(&client).await? {
+                    Ok(())
+                } else {
+                    Err(anyhow::anyhow!("Login is not valid"))
+                }
+            } else {
+                println!("Logging in to golem registry: {}", client.base_url());
+                login::login(&client, None, true).await?;
+                Ok(())
+            }
+        }
+        Command::Logout => remove_credentials().await,
+    }
+}
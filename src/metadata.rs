@@ -17,7 +17,8 @@ pub async fn add_metadata_outside(
     image_path: &Path,
     config: &ContainerConfig,
 ) -> anyhow::Result<usize> {
-    let json_buf = serde_json::to_vec(config)?;
+    //serialized through Value to sort keys, so maps (labels, volumes) always give the same bytes
+    let json_buf = serde_json::to_vec(&serde_json::to_value(config)?)?;
     let mut file = fs::OpenOptions::new().append(true).open(image_path)?;
     let meta_size = json_buf.len();
     let crc = compute_crc(&json_buf);
@@ -27,7 +28,22 @@ pub async fn add_metadata_outside(
     Ok(bytes_written)
 }
 
-pub async fn read_metadata_outside(image_path: &Path) -> anyhow::Result<ContainerConfig> {
+/// Raw metadata footer stored at the end of gvmi file: crc, json and its length
+pub struct MetadataFooter {
+    /// Position of the footer in the file (size of the squashfs part)
+    pub offset: u64,
+    pub crc_stored: u32,
+    pub crc_computed: u32,
+    pub json: Vec<u8>,
+}
+
+impl MetadataFooter {
+    pub fn crc_valid(&self) -> bool {
+        self.crc_stored == self.crc_computed
+    }
+}
+
+pub fn read_metadata_footer(image_path: &Path) -> anyhow::Result<MetadataFooter> {
     const META_SIZE_BYTES: usize = 8;
     const CRC_BYTES: usize = 4;
 
@@ -48,19 +64,27 @@ pub async fn read_metadata_outside(image_path: &Path) -> anyhow::Result<Containe
     if meta_size + (META_SIZE_BYTES + CRC_BYTES) as u64 > file_size {
         return Err(anyhow!("File is too small"));
     }
-    file.seek(SeekFrom::End(
+    let offset = file.seek(SeekFrom::End(
         -((META_SIZE_BYTES + CRC_BYTES + meta_size as usize) as i64),
     ))?;
     let mut crc_buf = [0; CRC_BYTES];
     file.read_exact(&mut crc_buf)?;
     let mut json_buf = vec![0; meta_size as usize];
     file.read_exact(&mut json_buf)?;
-    let crc = compute_crc(&json_buf);
-    let crc_read = u32::from_le_bytes(crc_buf);
-    if crc != crc_read {
+    Ok(MetadataFooter {
+        offset,
+        crc_stored: u32::from_le_bytes(crc_buf),
+        crc_computed: compute_crc(&json_buf),
+        json: json_buf,
+    })
+}
+
+pub async fn read_metadata_outside(image_path: &Path) -> anyhow::Result<ContainerConfig> {
+    let footer = read_metadata_footer(image_path)?;
+    if !footer.crc_valid() {
         return Err(anyhow!("CRC mismatch"));
     }
-    let cfg = serde_json::from_slice::<ContainerConfig>(&json_buf)?;
+    let cfg = serde_json::from_slice::<ContainerConfig>(&footer.json)?;
     Ok(cfg)
 }
 
@@ -69,7 +93,7 @@ async fn test_descriptor_creation() {
     use std::collections::HashMap;
     use std::path::PathBuf;
 
-    let rng = fastrand::Rng::new();
+    let mut rng = fastrand::Rng::new();
 
     let test_file_name = &PathBuf::from("test_descriptor_creation.tst");
     rng.seed(1234);
@@ -80,18 +104,20 @@ async fn test_descriptor_creation() {
     fs::write(test_file_name, &bytes).unwrap();
 
     println!("Read metadata outside");
-    assert_eq!(read_metadata_outside(test_file_name).await.is_err(), true);
-    let mut cfg_write = ContainerConfig::default();
-    cfg_write.image = Some("test".to_string());
-    cfg_write.cmd = Some(vec!["test".to_string()]);
-    cfg_write.entrypoint = Some(vec!["test".to_string()]);
-    cfg_write.env = Some(vec!["test".to_string()]);
-    cfg_write.working_dir = Some("test".to_string());
-    cfg_write.user = Some("test".to_string());
-    cfg_write.volumes = Some(HashMap::from([
-        ("foo".to_string(), HashMap::new()),
-        ("foo2".to_string(), HashMap::new()),
-    ]));
+    assert!(read_metadata_outside(test_file_name).await.is_err());
+    let cfg_write = ContainerConfig {
+        image: Some("test".to_string()),
+        cmd: Some(vec!["test".to_string()]),
+        entrypoint: Some(vec!["test".to_string()]),
+        env: Some(vec!["test".to_string()]),
+        working_dir: Some("test".to_string()),
+        user: Some("test".to_string()),
+        volumes: Some(HashMap::from([
+            ("foo".to_string(), HashMap::new()),
+            ("foo2".to_string(), HashMap::new()),
+        ])),
+        ..Default::default()
+    };
 
     let bytes_written = add_metadata_outside(test_file_name, &cfg_write)
         .await
@@ -0,0 +1,615 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::backend::RegistryBackend;
+use crate::chunks::{ChunkHash, FileChunk, FileChunkDesc};
+use crate::delta::ChunkReuse;
+use crate::image::ImageName;
+use crate::retry::{RetryPolicy, UploadError};
+use crate::signing::ImageSignature;
+use crate::upload::{AttachInfo, UploadProgress, ValidateUploadResponse};
+
+/// Failure injected into the next upload of given chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockFault {
+    /// Connection dropped after part of the chunk was sent, nothing is stored
+    Partial,
+    /// Registry answers with given status code
+    Status(u16),
+}
+
+struct MockChunk {
+    data: Vec<u8>,
+    //number of status checks before the chunk becomes visible (NFS sync on server side)
+    sync_left: u32,
+}
+
+#[derive(Default)]
+struct MockState {
+    users: HashMap<String, String>,
+    descriptors: HashMap<String, FileChunkDesc>,
+    chunks: HashMap<String, BTreeMap<u64, MockChunk>>,
+    tags: HashMap<String, String>,
+    faults: HashMap<u64, VecDeque<MockFault>>,
+    chunk_attempts: HashMap<u64, u32>,
+    uploaded_bytes: u64,
+    signatures: HashMap<String, ImageSignature>,
+}
+
+/// In-memory Golem Registry for testing push flow offline.
+/// Clones share the same state, so the test can inspect what was uploaded.
+#[derive(Clone)]
+pub struct MockRegistry {
+    state: Arc<Mutex<MockState>>,
+    credentials: Option<(String, String)>,
+    retry: RetryPolicy,
+    nfs_delay: u32,
+    chunk_reuse: bool,
+    signatures: bool,
+}
+
+impl Default for MockRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockRegistry {
+    pub fn new() -> Self {
+        MockRegistry {
+            state: Arc::new(Mutex::new(MockState::default())),
+            credentials: None,
+            retry: RetryPolicy::default(),
+            nfs_delay: 0,
+            chunk_reuse: false,
+            signatures: true,
+        }
+    }
+
+    /// Registers account accepted by the registry
+    pub fn with_user(self, user_name: &str, token: &str) -> Self {
+        self.state
+            .lock()
+            .unwrap()
+            .users
+            .insert(user_name.to_string(), token.to_string());
+        self
+    }
+
+    /// Credentials sent by the client when attaching images
+    pub fn with_credentials(mut self, user_name: &str, token: &str) -> Self {
+        self.credentials = Some((user_name.to_string(), token.to_string()));
+        self
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Uploaded chunks are reported as missing by the next `nfs_delay` status checks
+    pub fn with_nfs_delay(mut self, nfs_delay: u32) -> Self {
+        self.nfs_delay = nfs_delay;
+        self
+    }
+
+    /// Registry copies chunks of previous image versions instead of requiring upload
+    pub fn with_chunk_reuse(mut self, chunk_reuse: bool) -> Self {
+        self.chunk_reuse = chunk_reuse;
+        self
+    }
+
+    /// Registry stores descriptor signatures (older registries do not)
+    pub fn with_signatures(mut self, signatures: bool) -> Self {
+        self.signatures = signatures;
+        self
+    }
+
+    /// Makes next upload of the chunk fail, faults are used in order they were added
+    pub fn fail_chunk(&self, chunk_no: u64, fault: MockFault) {
+        self.state
+            .lock()
+            .unwrap()
+            .faults
+            .entry(chunk_no)
+            .or_default()
+            .push_back(fault);
+    }
+
+    /// Number of upload attempts of the chunk, including failed ones
+    pub fn chunk_attempts(&self, chunk_no: u64) -> u32 {
+        self.state
+            .lock()
+            .unwrap()
+            .chunk_attempts
+            .get(&chunk_no)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Total size of chunks received, reused chunks are not counted
+    pub fn uploaded_bytes(&self) -> u64 {
+        self.state.lock().unwrap().uploaded_bytes
+    }
+
+    /// Signature uploaded for the descriptor
+    pub fn signature(&self, descr_sha256: &str) -> Option<ImageSignature> {
+        self.state
+            .lock()
+            .unwrap()
+            .signatures
+            .get(descr_sha256)
+            .cloned()
+    }
+
+    /// Descriptor hash attached to the tag
+    pub fn tag(&self, image_name: &str) -> Option<String> {
+        self.state.lock().unwrap().tags.get(image_name).cloned()
+    }
+
+    /// Image assembled from received chunks, if all of them were uploaded
+    pub fn image(&self, descr_sha256: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let descr = state.descriptors.get(descr_sha256)?;
+        let chunks = state.chunks.get(descr_sha256)?;
+        let mut image = Vec::with_capacity(descr.size as usize);
+        for chunk in &descr.chunks {
+            image.extend_from_slice(&chunks.get(&chunk.chunk_no)?.data);
+        }
+        Some(image)
+    }
+}
+
+fn is_visible(chunks: Option<&BTreeMap<u64, MockChunk>>, chunk_no: u64) -> bool {
+    chunks
+        .and_then(|chunks| chunks.get(&chunk_no))
+        .map(|chunk| chunk.sync_left == 0)
+        .unwrap_or(false)
+}
+
+async fn read_chunk(file_path: &Path, chunk: &FileChunk) -> std::io::Result<Vec<u8>> {
+    let mut file = tokio::fs::File::open(file_path).await?;
+    file.seek(std::io::SeekFrom::Start(chunk.pos)).await?;
+    let mut data = vec![0; chunk.len as usize];
+    file.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+#[async_trait]
+impl RegistryBackend for MockRegistry {
+    fn base_url(&self) -> &str {
+        "mock://registry"
+    }
+
+    fn retry_policy(&self) -> &RetryPolicy {
+        &self.retry
+    }
+
+    fn with_credentials(self, user_name: &str, pat: &str) -> Self {
+        MockRegistry::with_credentials(self, user_name, pat)
+    }
+
+    async fn check_login(&self, user_name: &str, pat: &str) -> anyhow::Result<bool> {
+        let state = self.state.lock().unwrap();
+        Ok(state.users.get(user_name).map(String::as_str) == Some(pat))
+    }
+
+    async fn validate_upload(&self, descr_sha256: &str) -> anyhow::Result<ValidateUploadResponse> {
+        let mut state = self.state.lock().unwrap();
+        let Some(descr) = state.descriptors.get(descr_sha256).cloned() else {
+            return Ok(ValidateUploadResponse {
+                descriptor: "missing".to_string(),
+                version: None,
+                status: None,
+                chunks: None,
+            });
+        };
+        let chunks = state.chunks.entry(descr_sha256.to_string()).or_default();
+        for chunk in chunks.values_mut() {
+            chunk.sync_left = chunk.sync_left.saturating_sub(1);
+        }
+        let visible: Vec<u64> = descr
+            .chunks
+            .iter()
+            .map(|chunk| is_visible(Some(chunks), chunk.chunk_no) as u64)
+            .collect();
+        let full = visible.iter().all(|uploaded| *uploaded == 1);
+        Ok(ValidateUploadResponse {
+            descriptor: "ok".to_string(),
+            version: Some(descr.version),
+            status: Some(if full { "full" } else { "partial" }.to_string()),
+            chunks: Some(visible),
+        })
+    }
+
+    async fn push_descr(&self, descr_path: &Path) -> anyhow::Result<()> {
+        let bytes = tokio::fs::read(descr_path).await?;
+        let descr = FileChunkDesc::deserialize_from_bytes(&bytes)?;
+        if hex::encode(Sha256::digest(&bytes)) != descr.get_descr_hash_str() {
+            return Err(anyhow!("Descriptor hash does not match its content"));
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .descriptors
+            .insert(descr.get_descr_hash_str(), descr);
+        Ok(())
+    }
+
+    async fn push_chunk(
+        &self,
+        file_path: PathBuf,
+        chunk: FileChunk,
+        chunk_hash: ChunkHash,
+        descr_sha256: String,
+        progress: UploadProgress,
+    ) -> Result<(), UploadError> {
+        let fault = {
+            let mut state = self.state.lock().unwrap();
+            *state.chunk_attempts.entry(chunk.chunk_no).or_default() += 1;
+            let Some(descr_chunk_hash) = state.descriptors.get(&descr_sha256).map(|d| d.chunk_hash)
+            else {
+                return Err(UploadError::fatal(format!(
+                    "Descriptor {descr_sha256} not found"
+                )));
+            };
+            if descr_chunk_hash != chunk_hash {
+                return Err(UploadError::fatal(format!(
+                    "Chunk hash {} does not match descriptor chunk hash {}",
+                    chunk_hash.name(),
+                    descr_chunk_hash.name()
+                )));
+            }
+            state
+                .faults
+                .get_mut(&chunk.chunk_no)
+                .and_then(VecDeque::pop_front)
+        };
+        match fault {
+            Some(MockFault::Partial) => {
+                return Err(UploadError::retryable(format!(
+                    "Image upload error: connection reset after {} bytes",
+                    chunk.len / 2
+                )))
+            }
+            Some(MockFault::Status(code)) => {
+                let status = StatusCode::from_u16(code).unwrap_or(StatusCode::BAD_REQUEST);
+                return Err(UploadError::from_status(
+                    status,
+                    &HeaderMap::new(),
+                    format!("Image upload failed with code {code}"),
+                ));
+            }
+            None => {}
+        }
+        let data = read_chunk(&file_path, &chunk)
+            .await
+            .map_err(|e| UploadError::fatal(e.to_string()))?;
+        if chunk_hash.digest(&data) != chunk.hash {
+            return Err(UploadError::fatal(format!(
+                "Chunk {} checksum mismatch",
+                chunk.chunk_no
+            )));
+        }
+        let mut state = self.state.lock().unwrap();
+        state.uploaded_bytes += chunk.len;
+        state.chunks.entry(descr_sha256).or_default().insert(
+            chunk.chunk_no,
+            MockChunk {
+                data,
+                sync_left: self.nfs_delay,
+            },
+        );
+        progress.pb_details.inc(chunk.len);
+        progress.pb_total.inc(chunk.len);
+        progress.pb_chunks.inc(1);
+        Ok(())
+    }
+
+    async fn push_signature(
+        &self,
+        descr_sha256: &str,
+        signature: &ImageSignature,
+    ) -> anyhow::Result<bool> {
+        if !self.signatures {
+            return Ok(false);
+        }
+        let mut state = self.state.lock().unwrap();
+        let Some(descr) = state.descriptors.get(descr_sha256) else {
+            return Err(anyhow!("Signature upload failed: descriptor not found"));
+        };
+        signature.verify(descr)?;
+        state
+            .signatures
+            .insert(descr_sha256.to_string(), signature.clone());
+        Ok(true)
+    }
+
+    async fn tagged_descriptor(&self, image_name: &ImageName) -> anyhow::Result<FileChunkDesc> {
+        let state = self.state.lock().unwrap();
+        state
+            .tags
+            .get(&image_name.to_normalized_name())
+            .and_then(|descr_sha256| state.descriptors.get(descr_sha256))
+            .cloned()
+            .ok_or_else(|| {
+                anyhow!(
+                    "Image {} not found in repository",
+                    image_name.to_normalized_name()
+                )
+            })
+    }
+
+    async fn reuse_chunks(
+        &self,
+        descr_sha256: &str,
+        base_descr_sha256: &str,
+        chunks: &[ChunkReuse],
+    ) -> anyhow::Result<Option<Vec<u64>>> {
+        if !self.chunk_reuse {
+            return Ok(None);
+        }
+        let mut state = self.state.lock().unwrap();
+        let (Some(descr), Some(base)) = (
+            state.descriptors.get(descr_sha256),
+            state.descriptors.get(base_descr_sha256),
+        ) else {
+            return Err(anyhow!("Chunk reuse failed: descriptor not found"));
+        };
+        //only chunks with the same hash, which the registry has data for, are copied
+        let copied: Vec<(u64, Vec<u8>)> = chunks
+            .iter()
+            .filter_map(|reuse| {
+                let chunk = descr.chunks.get(reuse.chunk_no as usize)?;
+                let base_chunk = base.chunks.get(reuse.base_chunk_no as usize)?;
+                if chunk.hash != base_chunk.hash || descr.chunk_hash != base.chunk_hash {
+                    return None;
+                }
+                let data = &state
+                    .chunks
+                    .get(base_descr_sha256)?
+                    .get(&base_chunk.chunk_no)?
+                    .data;
+                Some((chunk.chunk_no, data.clone()))
+            })
+            .collect();
+        let stored = state.chunks.entry(descr_sha256.to_string()).or_default();
+        let mut reused = Vec::with_capacity(copied.len());
+        for (chunk_no, data) in copied {
+            stored.insert(
+                chunk_no,
+                MockChunk {
+                    data,
+                    sync_left: self.nfs_delay,
+                },
+            );
+            reused.push(chunk_no);
+        }
+        Ok(Some(reused))
+    }
+
+    async fn attach_to_repo(
+        &self,
+        descr_sha256: &str,
+        image_name: &ImageName,
+        check: bool,
+    ) -> anyhow::Result<AttachInfo> {
+        let Some(image_user_name) = image_name.user.clone() else {
+            return Err(anyhow!("Image name must contain user"));
+        };
+        let mut state = self.state.lock().unwrap();
+        let authorized = self
+            .credentials
+            .as_ref()
+            .map(|(login, token)| {
+                login == &image_user_name && state.users.get(login) == Some(token)
+            })
+            .unwrap_or(false);
+        if !authorized {
+            return Err(anyhow!("Not possible to add to repository: unauthorized"));
+        }
+        let Some(descr) = state.descriptors.get(descr_sha256) else {
+            return Err(anyhow!(
+                "Not possible to add to repository: descriptor not found"
+            ));
+        };
+        if !check {
+            let chunks = state.chunks.get(descr_sha256);
+            if !descr
+                .chunks
+                .iter()
+                .all(|chunk| is_visible(chunks, chunk.chunk_no))
+            {
+                return Err(anyhow!(
+                    "Not possible to add to repository: image not uploaded"
+                ));
+            }
+            state
+                .tags
+                .insert(image_name.to_normalized_name(), descr_sha256.to_string());
+        }
+        Ok(AttachInfo {
+            url: self.base_url().to_string(),
+            user: image_user_name,
+            repo: image_name.repository.clone(),
+            tag: image_name.tag.clone(),
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_push_image_to_mock_registry() {
+    use crate::chunks::{load_or_create_descriptor, DescriptorFormat};
+    use crate::rootfs::RootfsTree;
+    use crate::squashfs::{write_squashfs, SquashfsOptions};
+    use crate::upload::{push_image, PushOptions};
+    use indicatif::ProgressBar;
+    use std::iter::repeat_with;
+    use std::time::Duration;
+
+    //build small image from random file, so it is split into several chunks
+    let mut rng = fastrand::Rng::new();
+    rng.seed(1234);
+    let content: Vec<u8> = repeat_with(|| rng.u8(..)).take(20000).collect();
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut tree = RootfsTree::new(temp_dir.path()).unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(0);
+    header.set_size(content.len() as u64);
+    let mut builder = tar::Builder::new(Vec::new());
+    builder
+        .append_data(&mut header, "data/random.bin", &content[..])
+        .unwrap();
+    tree.append_tar(&builder.into_inner().unwrap()[..]).unwrap();
+    let path = temp_dir.path().join("image.gvmi");
+    let options = SquashfsOptions {
+        compression_method: "gzip".to_string(),
+        ..Default::default()
+    };
+    write_squashfs(&tree, &path, &options, &ProgressBar::hidden()).unwrap();
+    let (descr, descr_path) = load_or_create_descriptor(&path, 4096, DescriptorFormat::default())
+        .await
+        .unwrap();
+    assert!(descr.chunks.len() > 4);
+    let descr_hash = descr.get_descr_hash_str();
+
+    let policy = RetryPolicy {
+        initial_backoff: Duration::from_millis(1),
+        ..Default::default()
+    };
+    let options = PushOptions {
+        upload_workers: 2,
+        journal_dir: temp_dir.path().join("journal"),
+        validate_interval: Duration::from_millis(1),
+        ..Default::default()
+    };
+    let tag = ImageName::from_str_name("golem/test:v1").unwrap();
+
+    //wrong token, attach is checked before chunks are sent
+    let registry = MockRegistry::new()
+        .with_user("golem", "token")
+        .with_credentials("golem", "wrong")
+        .with_retry_policy(policy.clone());
+    assert!(
+        push_image(&registry, &path, &descr, &descr_path, Some(&tag), &options)
+            .await
+            .is_err()
+    );
+    assert_eq!(registry.chunk_attempts(0), 0);
+
+    //partial chunk and server errors are retried, registry sees chunks with delay
+    let registry = MockRegistry::new()
+        .with_user("golem", "token")
+        .with_credentials("golem", "token")
+        .with_retry_policy(policy.clone())
+        .with_nfs_delay(2);
+    registry.fail_chunk(1, MockFault::Partial);
+    registry.fail_chunk(2, MockFault::Status(503));
+    registry.fail_chunk(2, MockFault::Status(500));
+    let signature = ImageSignature::sign(&descr, &crate::signing::generate_signing_key());
+    let signed_options = PushOptions {
+        signature: Some(signature.clone()),
+        ..options.clone()
+    };
+    let attach_info = push_image(
+        &registry,
+        &path,
+        &descr,
+        &descr_path,
+        Some(&tag),
+        &signed_options,
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(registry.signature(&descr_hash), Some(signature.clone()));
+    assert_eq!(attach_info.repo, "test");
+    assert_eq!(registry.tag("golem/test:v1"), Some(descr_hash.clone()));
+    assert_eq!(
+        registry.image(&descr_hash),
+        Some(std::fs::read(&path).unwrap())
+    );
+    assert_eq!(registry.chunk_attempts(0), 1);
+    assert_eq!(registry.chunk_attempts(1), 2);
+    assert_eq!(registry.chunk_attempts(2), 3);
+
+    //rejected chunk stops upload, resume sends only chunks that are missing
+    let registry = MockRegistry::new().with_retry_policy(policy.clone());
+    registry.fail_chunk(3, MockFault::Status(403));
+    let options = PushOptions {
+        upload_workers: 1,
+        ..signed_options
+    };
+    assert!(
+        push_image(&registry, &path, &descr, &descr_path, None, &options)
+            .await
+            .is_err()
+    );
+    assert!(registry.image(&descr_hash).is_none());
+    //signature is uploaded only with complete image
+    assert!(registry.signature(&descr_hash).is_none());
+    let uploaded_before: Vec<u32> = (0..3).map(|no| registry.chunk_attempts(no)).collect();
+    assert_eq!(uploaded_before, vec![1, 1, 1]);
+    let options = PushOptions {
+        resume: true,
+        ..options
+    };
+    assert!(
+        push_image(&registry, &path, &descr, &descr_path, None, &options)
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert_eq!(registry.chunk_attempts(0), 1);
+    assert_eq!(registry.chunk_attempts(3), 2);
+    assert_eq!(
+        registry.image(&descr_hash),
+        Some(std::fs::read(&path).unwrap())
+    );
+    assert_eq!(registry.signature(&descr_hash), Some(signature));
+
+    //registry without signature support still gets the image
+    let registry = MockRegistry::new()
+        .with_retry_policy(policy)
+        .with_signatures(false);
+    push_image(&registry, &path, &descr, &descr_path, None, &options)
+        .await
+        .unwrap();
+    assert!(registry.image(&descr_hash).is_some());
+    assert!(registry.signature(&descr_hash).is_none());
+
+    //chunks recorded in journal are skipped also when registry does not report chunk status
+    let registry = MockRegistry::new();
+    registry.push_descr(&descr_path).await.unwrap();
+    let mut journal =
+        crate::journal::UploadJournal::open(&temp_dir.path().join("journal"), &path, &descr, false)
+            .unwrap();
+    journal.record_success(0);
+    journal.record_success(1);
+    let journal = Arc::new(Mutex::new(journal));
+    crate::upload::push_chunks(&registry, &path, &descr, None, &[], 1, journal.clone())
+        .await
+        .unwrap();
+    assert_eq!(registry.chunk_attempts(0), 0);
+    assert_eq!(registry.chunk_attempts(1), 0);
+    assert_eq!(registry.chunk_attempts(2), 1);
+    //registry reporting a chunk as missing overrides the journal, reused chunks are skipped
+    let missing = vec![0; descr.chunks.len()];
+    crate::upload::push_chunks(&registry, &path, &descr, Some(missing), &[1], 1, journal)
+        .await
+        .unwrap();
+    assert_eq!(registry.chunk_attempts(0), 1);
+    assert_eq!(registry.chunk_attempts(1), 0);
+    assert_eq!(registry.chunk_attempts(2), 2);
+}
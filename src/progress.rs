@@ -15,16 +15,17 @@ pub fn set_progress_bar_settings(pbs: ProgressBarSettings) {
 }
 
 pub enum ProgressBarType {
-    PullLine1,
-    PullLine2,
     PullLayer,
     CreateDescriptor,
     CopyingFiles,
+    WriteSquashfs,
     SingleChunk,
     DescriptorUpload,
     UploadTotal,
     UploadDetails,
     UploadChunks,
+    DownloadTotal,
+    DownloadChunks,
 }
 
 fn create_internal_style(template: &str) -> ProgressStyle {
@@ -40,12 +41,6 @@ pub fn create_chunk_pb(len: u64, pbt: ProgressBarType) -> ProgressBar {
         let pb_chunk = ProgressBar::new(len);
         #[rustfmt::skip]
         let sty_single_chunk = match pbt {
-            ProgressBarType::PullLine1 => create_internal_style(
-                "{wide_bar:.cyan/blue}"
-            ),
-            ProgressBarType::PullLine2 => create_internal_style(
-                "{bytes:9}/(est. {total_bytes:9} [{wide_msg}]"
-            ),
             ProgressBarType::PullLayer => create_internal_style(
                 "[{msg:20}] {wide_bar:.cyan/blue} {bytes:10}/{total_bytes:10}",
             ),
@@ -55,6 +50,9 @@ pub fn create_chunk_pb(len: u64, pbt: ProgressBarType) -> ProgressBar {
             ProgressBarType::CopyingFiles => create_internal_style(
                 "[{msg:20}] {wide_bar:.cyan/blue} {bytes:10}/{total_bytes:10}",
             ),
+            ProgressBarType::WriteSquashfs => create_internal_style(
+                "[{msg:20}] {wide_bar:.cyan/blue} {bytes:10}/(estimated){total_bytes:10}",
+            ),
             ProgressBarType::SingleChunk => create_internal_style(
                 "[{msg:10}] {elapsed} {wide_bar:.cyan/blue} {bytes:10}/{total_bytes:10}",
             ),
@@ -70,6 +68,12 @@ pub fn create_chunk_pb(len: u64, pbt: ProgressBarType) -> ProgressBar {
             ProgressBarType::UploadChunks => create_internal_style(
                 "Chunks finished: {pos}/{len}"
             ),
+            ProgressBarType::DownloadTotal => create_internal_style(
+                "[{msg:20}] {wide_bar:.cyan/blue} {bytes:10}/{total_bytes:10}",
+            ),
+            ProgressBarType::DownloadChunks => create_internal_style(
+                "Chunks downloaded: {pos}/{len}"
+            ),
         };
         pb_chunk.set_style(sty_single_chunk);
         pb_chunk
@@ -0,0 +1,253 @@
+//! Publishing of built image, as done by `build --push` and `push` commands:
+//! registry login, descriptor, signature, upload and json info file.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use serde_json::json;
+use tokio::fs;
+
+use crate::backend::RegistryBackend;
+use crate::chunks::{
+    default_chunk_size, load_or_create_descriptor, DescriptorFormat, FileChunkDesc,
+};
+use crate::image::ImageName;
+use crate::login;
+use crate::signing::{load_signing_key, signature_path, ImageSignature};
+use crate::upload::{push_image, PushOptions};
+
+/// Where (and if) the image should be uploaded
+#[derive(Debug, Clone)]
+pub enum PushTarget {
+    None,
+    Anonymous,
+    Tag(ImageName),
+}
+
+/// How the image is described, signed and uploaded
+#[derive(Debug, Clone, Default)]
+pub struct PublishOptions {
+    /// Chunk size of the descriptor, by default depends on image size
+    pub chunk_size: Option<u64>,
+    pub descriptor_format: DescriptorFormat,
+    /// Sign the descriptor with key from the keyring
+    pub sign_with: Option<String>,
+    /// Upload signature saved next to the image (`<file>.descr.sig`)
+    pub upload_signature: bool,
+    /// Upload options, signature is filled in by [`publish`]
+    pub push: PushOptions,
+}
+
+/// Adds registry credentials to the client when pushing to tag.
+/// Credentials are taken from REGISTRY_USER and REGISTRY_TOKEN env variables or from login.
+pub async fn get_credentials<R: RegistryBackend>(
+    registry: R,
+    push_target: &PushTarget,
+) -> anyhow::Result<R> {
+    let PushTarget::Tag(push_image_name) = push_target else {
+        return Ok(registry);
+    };
+    println!("Logging in to golem registry: {}", registry.base_url());
+
+    if let (Ok(registry_user), Ok(registry_token)) =
+        (env::var("REGISTRY_USER"), env::var("REGISTRY_TOKEN"))
+    {
+        println!(
+            " -- Using credentials from environment variables (REGISTRY_USER and REGISTRY_TOKEN)"
+        );
+        let res = registry
+            .check_login(&registry_user, &registry_token)
+            .await?;
+        if !res {
+            return Err(anyhow!(
+                "Login to golem registry: {} failed",
+                registry.base_url()
+            ));
+        }
+        Ok(registry.with_credentials(&registry_user, &registry_token))
+    } else if let Some(user_name) = &push_image_name.user {
+        let (user_name, pat) = login::login(&registry, Some(user_name), false).await?;
+        Ok(registry.with_credentials(&user_name, &pat))
+    } else {
+        Err(anyhow!(
+            "You have to specify username.\nInstead of --push you can use --push-to <username>/<repository>:<tag>\nYou can also add --nologin to upload image anonymously"
+        ))
+    }
+}
+
+/// Signs the descriptor or uses signature saved next to the image, if requested
+pub fn image_signature(
+    path: &Path,
+    descr: &FileChunkDesc,
+    options: &PublishOptions,
+) -> anyhow::Result<Option<ImageSignature>> {
+    let sig_path = signature_path(path);
+    if let Some(key_name) = &options.sign_with {
+        let signature = ImageSignature::sign(descr, &load_signing_key(key_name)?);
+        signature.save(&sig_path)?;
+        println!(
+            " -- descriptor signed with key {}: {}",
+            key_name,
+            sig_path.display()
+        );
+        return Ok(Some(signature));
+    }
+    if !options.upload_signature {
+        if sig_path.exists() {
+            println!(
+                " -- signature {} not uploaded, use --upload-signature to upload it",
+                sig_path.display()
+            );
+        }
+        return Ok(None);
+    }
+    let signature = ImageSignature::load(&sig_path)?;
+    signature
+        .verify(descr)
+        .map_err(|e| anyhow!("Signature {} not valid: {}", sig_path.display(), e))?;
+    println!(" -- signature found: {}", sig_path.display());
+    Ok(Some(signature))
+}
+
+/// Creates descriptor for the image, uploads it if requested and writes json info
+pub async fn publish<R: RegistryBackend>(
+    registry: R,
+    path: PathBuf,
+    push_target: PushTarget,
+    options: &PublishOptions,
+    extra_json_info_path: Option<&str>,
+) -> anyhow::Result<()> {
+    let registry = get_credentials(registry, &push_target).await?;
+
+    let image_file_size = fs::metadata(&path).await?.len();
+    let chunk_size = options
+        .chunk_size
+        .unwrap_or_else(|| default_chunk_size(image_file_size));
+
+    let (descr, descr_path) =
+        load_or_create_descriptor(&path, chunk_size, options.descriptor_format).await?;
+    let signature = image_signature(&path, &descr, options)?;
+
+    let repo_info = match &push_target {
+        PushTarget::None => None,
+        PushTarget::Anonymous | PushTarget::Tag(_) => {
+            let push_image_name = match &push_target {
+                PushTarget::Tag(name) => Some(name),
+                _ => None,
+            };
+            push_image(
+                &registry,
+                &path,
+                &descr,
+                &descr_path,
+                push_image_name,
+                &PushOptions {
+                    signature,
+                    ..options.push.clone()
+                },
+            )
+            .await?
+        }
+    };
+    // write info to file
+    if let Some(json_path) = extra_json_info_path {
+        println!(" * Writing info to {}", json_path);
+        fs::write(
+            json_path,
+            serde_json::to_vec_pretty(&json!({
+                "repoInfo": &repo_info,
+                "imagePath": path.display().to_string(),
+                "imageDescrPath": descr_path.display().to_string(),
+                "hash": &descr.get_descr_hash_str(),
+            }))?,
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_publish_to_mock_registry() {
+    use crate::mock::MockRegistry;
+    use crate::retry::RetryPolicy;
+    use crate::signing::generate_signing_key;
+    use std::time::Duration;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = temp_dir.path().join("image.gvmi");
+    let content: Vec<u8> = (0..30000_u32).map(|i| (i * 7 % 251) as u8).collect();
+    std::fs::write(&path, &content).unwrap();
+    let options = PublishOptions {
+        chunk_size: Some(4096),
+        upload_signature: true,
+        push: PushOptions {
+            upload_workers: 2,
+            journal_dir: temp_dir.path().join("journal"),
+            validate_interval: Duration::from_millis(1),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let registry = MockRegistry::new()
+        .with_user("golem", "token")
+        .with_retry_policy(RetryPolicy {
+            initial_backoff: Duration::from_millis(1),
+            ..Default::default()
+        });
+    let tag = PushTarget::Tag(ImageName::from_str_name("golem/test:v1").unwrap());
+    let info_path = temp_dir.path().join("info.json");
+    let info_path = info_path.to_str().unwrap();
+
+    //only this test sets registry credentials in environment
+    env::set_var("REGISTRY_USER", "golem");
+    env::set_var("REGISTRY_TOKEN", "wrong");
+    let res = publish(
+        registry.clone(),
+        path.clone(),
+        tag.clone(),
+        &options,
+        Some(info_path),
+    )
+    .await;
+    assert!(res.unwrap_err().to_string().contains("failed"));
+
+    //signature is requested but missing
+    env::set_var("REGISTRY_TOKEN", "token");
+    let res = publish(
+        registry.clone(),
+        path.clone(),
+        tag.clone(),
+        &options,
+        Some(info_path),
+    )
+    .await;
+    assert!(res.is_err());
+    assert_eq!(registry.tag("golem/test:v1"), None);
+
+    let (descr, _) = load_or_create_descriptor(&path, 4096, DescriptorFormat::default())
+        .await
+        .unwrap();
+    let descr_hash = descr.get_descr_hash_str();
+    let signature = ImageSignature::sign(&descr, &generate_signing_key());
+    signature.save(&signature_path(&path)).unwrap();
+    publish(
+        registry.clone(),
+        path.clone(),
+        tag.clone(),
+        &options,
+        Some(info_path),
+    )
+    .await
+    .unwrap();
+    env::remove_var("REGISTRY_USER");
+    env::remove_var("REGISTRY_TOKEN");
+
+    assert_eq!(registry.tag("golem/test:v1"), Some(descr_hash.clone()));
+    assert_eq!(registry.image(&descr_hash), Some(content));
+    assert_eq!(registry.signature(&descr_hash), Some(signature));
+    let info: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(info_path).unwrap()).unwrap();
+    assert_eq!(info["hash"], descr_hash.as_str());
+    assert_eq!(info["repoInfo"]["repo"], "test");
+}
@@ -0,0 +1,184 @@
+//! Reproducible builds: the same source gives bit-identical gvmi file, so the image link does not change.
+
+use std::fmt;
+
+use anyhow::anyhow;
+use bollard::service::ContainerConfig;
+use humansize::DECIMAL;
+
+use crate::chunks::{Chunking, FileChunkDesc};
+
+pub const SOURCE_DATE_EPOCH_VAR: &str = "SOURCE_DATE_EPOCH";
+
+/// Latest modification time of files in reproducible build, taken from SOURCE_DATE_EPOCH (0 if not set)
+pub fn source_date_epoch() -> anyhow::Result<u32> {
+    match std::env::var(SOURCE_DATE_EPOCH_VAR) {
+        Ok(value) => value.trim().parse::<u32>().map_err(|e| {
+            anyhow!("Invalid {SOURCE_DATE_EPOCH_VAR} value {value}, expected unix timestamp: {e}")
+        }),
+        Err(std::env::VarError::NotPresent) => Ok(0),
+        Err(e) => Err(anyhow!("Invalid {SOURCE_DATE_EPOCH_VAR} value: {e}")),
+    }
+}
+
+/// Clears fields of container config which differ between containers created from the same image
+pub fn strip_volatile_config(cfg: &mut ContainerConfig) {
+    //docker uses beginning of the container id as hostname
+    cfg.hostname = None;
+    cfg.domainname = Some("".to_string());
+    cfg.mac_address = None;
+}
+
+/// Differences between descriptors of two builds of the same source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RebuildDiff {
+    pub first_link: String,
+    pub second_link: String,
+    pub first_size: u64,
+    pub second_size: u64,
+    pub chunk_size: u64,
+    /// Numbers of chunks with different content or present only in one of the builds
+    pub differing_chunks: Vec<u64>,
+}
+
+impl RebuildDiff {
+    /// Descriptors have to be created with the same chunk size and fixed chunking to compare chunks,
+    /// chunk numbers of content-defined descriptors do not correspond to the same bytes
+    pub fn compare(first: &FileChunkDesc, second: &FileChunkDesc) -> anyhow::Result<Self> {
+        if first.chunking != Chunking::Fixed || second.chunking != Chunking::Fixed {
+            return Err(anyhow!(
+                "Only descriptors with fixed chunking can be compared"
+            ));
+        }
+        if first.chunk_size != second.chunk_size {
+            return Err(anyhow!("Descriptors are created with different chunk size"));
+        }
+        let chunk_count = first.chunks.len().max(second.chunks.len());
+        let differing_chunks = (0..chunk_count)
+            .filter(|no| match (first.chunks.get(*no), second.chunks.get(*no)) {
+                (Some(a), Some(b)) => a.hash != b.hash || a.len != b.len,
+                _ => true,
+            })
+            .map(|no| no as u64)
+            .collect();
+        Ok(RebuildDiff {
+            first_link: first.get_sha3_str(),
+            second_link: second.get_sha3_str(),
+            first_size: first.size,
+            second_size: second.size,
+            chunk_size: first.chunk_size,
+            differing_chunks,
+        })
+    }
+
+    pub fn is_identical(&self) -> bool {
+        self.first_link == self.second_link
+    }
+}
+
+impl fmt::Display for RebuildDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_identical() {
+            return write!(f, "builds are identical, image link: {}", self.first_link);
+        }
+        write!(
+            f,
+            "builds differ: {} vs {}, sizes {} vs {}, {} differing chunks",
+            self.first_link,
+            self.second_link,
+            humansize::format_size(self.first_size, DECIMAL),
+            humansize::format_size(self.second_size, DECIMAL),
+            self.differing_chunks.len()
+        )?;
+        //last chunk of the longer image may be shorter than chunk size
+        let max_size = self.first_size.max(self.second_size);
+        for chunk_no in self.differing_chunks.iter().take(10) {
+            let start = chunk_no * self.chunk_size;
+            write!(
+                f,
+                "\n   chunk {}: bytes {}..{}",
+                chunk_no,
+                start,
+                (start + self.chunk_size).min(max_size)
+            )?;
+        }
+        if self.differing_chunks.len() > 10 {
+            write!(f, "\n   ...")?;
+        }
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_reproducible_build() {
+    use crate::chunks::create_descriptor;
+    use crate::rootfs::{EntryKind, EntryMeta, RootfsEntry, RootfsTree};
+    use crate::squashfs::{write_squashfs, SquashfsOptions};
+    use indicatif::ProgressBar;
+    use std::path::PathBuf;
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let options = SquashfsOptions {
+        mod_time: Some(1700000000),
+        ..Default::default()
+    };
+    //the same content extracted at different times
+    let mut images = Vec::new();
+    for (no, mtime) in [1800000000, 1900000000].into_iter().enumerate() {
+        let mut tree = RootfsTree::new(temp_dir.path()).unwrap();
+        let (source, size) = tree.stage_content(&[7_u8; 10000][..]).unwrap();
+        tree.insert(
+            PathBuf::from("/etc/hostname"),
+            RootfsEntry {
+                kind: EntryKind::File { source, size },
+                meta: EntryMeta {
+                    mtime,
+                    ..Default::default()
+                },
+            },
+        )
+        .unwrap();
+        tree.clamp_mtimes(1700000000);
+        assert_eq!(tree.get(&PathBuf::from("/etc")).unwrap().meta.mtime, 0);
+        let path = temp_dir.path().join(format!("image{no}.gvmi"));
+        write_squashfs(&tree, &path, &options, &ProgressBar::hidden()).unwrap();
+        images.push(create_descriptor(&path, 4096).await.unwrap());
+    }
+    let diff = RebuildDiff::compare(&images[0], &images[1]).unwrap();
+    assert!(diff.is_identical(), "{diff}");
+    assert!(diff.differing_chunks.is_empty());
+
+    //changed content is reported by chunk
+    let path = temp_dir.path().join("image0.gvmi");
+    let mut content = std::fs::read(&path).unwrap();
+    content[1000] ^= 1;
+    std::fs::write(&path, &content).unwrap();
+    let changed = create_descriptor(&path, 4096).await.unwrap();
+    let diff = RebuildDiff::compare(&changed, &images[1]).unwrap();
+    assert!(!diff.is_identical());
+    assert_eq!(diff.differing_chunks, [0]);
+    assert!(diff.to_string().contains("chunk 0: bytes 0..4096"));
+    let other_chunks = create_descriptor(&path, 8192).await.unwrap();
+    assert!(RebuildDiff::compare(&other_chunks, &images[1]).is_err());
+    let cdc = crate::chunks::create_descriptor_with_format_from_reader(
+        &content[..],
+        content.len() as u64,
+        4096,
+        crate::chunks::DescriptorFormat {
+            chunking: Chunking::ContentDefined,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert!(RebuildDiff::compare(&cdc, &cdc).is_err());
+
+    let mut cfg = ContainerConfig {
+        hostname: Some("3f2a9c1b7d4e".to_string()),
+        image: Some("sha256:1234".to_string()),
+        ..Default::default()
+    };
+    strip_volatile_config(&mut cfg);
+    assert_eq!(cfg.hostname, None);
+    assert_eq!(cfg.image.as_deref(), Some("sha256:1234"));
+}
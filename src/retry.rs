@@ -0,0 +1,215 @@
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use rand::Rng;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+
+/// How failed chunk uploads are repeated
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Number of retries after the first attempt (0 disables retrying)
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled with every next one
+    pub initial_backoff: Duration,
+    /// Limit of exponential backoff, Retry-After sent by the server is not limited by it
+    pub max_backoff: Duration,
+    /// Sanity limit of Retry-After, protects against broken or hostile server values
+    pub max_retry_after: Duration,
+    /// Part of the delay that is randomized, 0.0 - 1.0
+    pub jitter: f64,
+    /// Time limit for single attempt of chunk upload
+    pub chunk_timeout: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 5,
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_retry_after: Duration::from_secs(15 * 60),
+            jitter: 0.5,
+            chunk_timeout: None,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn no_retries() -> Self {
+        RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    /// Exponential backoff for given retry (starting from 1) without jitter
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+
+    /// Delay before given retry. Retry-After sent by the server takes precedence
+    /// (up to `max_retry_after`), otherwise backoff is randomly shortened by up to `jitter` part.
+    pub fn delay(&self, retry: u32, retry_after: Option<Duration>) -> Duration {
+        if let Some(retry_after) = retry_after {
+            return retry_after.min(self.max_retry_after);
+        }
+        let backoff = self.backoff(retry);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return backoff;
+        }
+        backoff.mul_f64(1.0 - jitter * rand::thread_rng().gen::<f64>())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UploadErrorKind {
+    /// Network problem, timeout or server side error, worth trying again
+    Retryable { retry_after: Option<Duration> },
+    /// Request rejected by the registry (4xx) or local problem, retrying will not help
+    Fatal,
+}
+
+/// Error of single upload attempt, classified for the retry loop
+#[derive(Debug, Clone)]
+pub struct UploadError {
+    pub kind: UploadErrorKind,
+    pub message: String,
+}
+
+impl UploadError {
+    pub fn fatal(message: impl Into<String>) -> Self {
+        UploadError {
+            kind: UploadErrorKind::Fatal,
+            message: message.into(),
+        }
+    }
+
+    pub fn retryable(message: impl Into<String>) -> Self {
+        UploadError {
+            kind: UploadErrorKind::Retryable { retry_after: None },
+            message: message.into(),
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        matches!(self.kind, UploadErrorKind::Retryable { .. })
+    }
+
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self.kind {
+            UploadErrorKind::Retryable { retry_after } => retry_after,
+            UploadErrorKind::Fatal => None,
+        }
+    }
+
+    /// Transport errors (connection, timeout, broken body) are retryable
+    pub fn from_reqwest(err: &reqwest::Error) -> Self {
+        if err.is_builder() {
+            UploadError::fatal(err.to_string())
+        } else {
+            UploadError::retryable(err.to_string())
+        }
+    }
+
+    pub fn from_status(status: StatusCode, headers: &HeaderMap, message: String) -> Self {
+        UploadError {
+            kind: classify_status(status, headers),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+/// 5xx, 408 and 429 are retryable, other 4xx codes are fatal
+pub fn classify_status(status: StatusCode, headers: &HeaderMap) -> UploadErrorKind {
+    if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+        UploadErrorKind::Retryable {
+            retry_after: headers
+                .get(RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after),
+        }
+    } else if status.is_server_error() || status == StatusCode::REQUEST_TIMEOUT {
+        UploadErrorKind::Retryable { retry_after: None }
+    } else {
+        UploadErrorKind::Fatal
+    }
+}
+
+/// Parses Retry-After header, both delay in seconds and HTTP date are allowed
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[test]
+fn test_retry_policy() {
+    use reqwest::header::HeaderValue;
+
+    let policy = RetryPolicy {
+        jitter: 0.0,
+        ..Default::default()
+    };
+    assert_eq!(policy.delay(1, None), Duration::from_secs(1));
+    assert_eq!(policy.delay(3, None), Duration::from_secs(4));
+    assert_eq!(policy.delay(30, None), Duration::from_secs(60));
+    assert_eq!(
+        policy.delay(1, Some(Duration::from_secs(7))),
+        Duration::from_secs(7)
+    );
+    //server asked for longer pause than max backoff
+    assert_eq!(
+        policy.delay(1, Some(Duration::from_secs(120))),
+        Duration::from_secs(120)
+    );
+    assert_eq!(
+        policy.delay(1, Some(Duration::from_secs(24 * 3600))),
+        Duration::from_secs(15 * 60)
+    );
+    let policy = RetryPolicy::default();
+    for _ in 0..100 {
+        let delay = policy.delay(2, None);
+        assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(2));
+    }
+
+    let mut headers = HeaderMap::new();
+    headers.insert(RETRY_AFTER, HeaderValue::from_static("12"));
+    assert_eq!(
+        classify_status(StatusCode::TOO_MANY_REQUESTS, &headers),
+        UploadErrorKind::Retryable {
+            retry_after: Some(Duration::from_secs(12))
+        }
+    );
+    assert_eq!(
+        classify_status(StatusCode::BAD_GATEWAY, &headers),
+        UploadErrorKind::Retryable { retry_after: None }
+    );
+    assert_eq!(
+        classify_status(StatusCode::UNAUTHORIZED, &headers),
+        UploadErrorKind::Fatal
+    );
+    assert_eq!(
+        parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+        Some(Duration::ZERO)
+    );
+    assert_eq!(parse_retry_after("soon"), None);
+}
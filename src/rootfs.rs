@@ -0,0 +1,1068 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use tar::EntryType;
+use tempfile::TempDir;
+
+use crate::ignore::{ExcludeStats, IgnoreRules};
+
+/// Ownership, permissions and modification time of a single filesystem entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EntryMeta {
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: u32,
+}
+
+impl Default for EntryMeta {
+    fn default() -> Self {
+        EntryMeta {
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
+            mtime: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    Dir,
+    /// Regular file, content is read from given path when image is written
+    File {
+        source: PathBuf,
+        size: u64,
+    },
+    Symlink(PathBuf),
+    CharDevice(u32),
+    BlockDevice(u32),
+    Fifo,
+}
+
+#[derive(Debug, Clone)]
+pub struct RootfsEntry {
+    pub kind: EntryKind,
+    pub meta: EntryMeta,
+}
+
+/// Root filesystem assembled from tar streams before it is written as squashfs.
+///
+/// Entries are kept sorted by path (parents always before children).
+/// Content of regular files is staged on disk in a temporary directory,
+/// which is removed when the tree is dropped.
+pub struct RootfsTree {
+    root: EntryMeta,
+    entries: BTreeMap<PathBuf, RootfsEntry>,
+    staging: TempDir,
+    staged_files: u64,
+    /// Rules applied while tar streams are appended, see `set_exclude`
+    exclude: IgnoreRules,
+    /// What was skipped while appending, by rule
+    excluded: Vec<ExcludeStats>,
+    /// Paths not skipped while appending, they are excluded only by `apply_exclude`
+    deferred: Vec<PathBuf>,
+}
+
+/// Converts path from archive into absolute path inside image.
+/// Returns None for the root directory itself.
+pub fn normalize_path(path: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let mut res = PathBuf::from("/");
+    for component in path.components() {
+        match component {
+            Component::Normal(c) => res.push(c),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(anyhow!("Invalid path in archive: {}", path.display()));
+            }
+        }
+    }
+    if res == Path::new("/") {
+        Ok(None)
+    } else {
+        Ok(Some(res))
+    }
+}
+
+/// Absolute path the symlink at `link` points to, resolved lexically
+fn resolve_link(link: &Path, target: &Path) -> PathBuf {
+    let mut res = if target.is_absolute() {
+        PathBuf::from("/")
+    } else {
+        link.parent().unwrap_or(Path::new("/")).to_path_buf()
+    };
+    for component in target.components() {
+        match component {
+            Component::Normal(c) => res.push(c),
+            Component::ParentDir => {
+                res.pop();
+            }
+            _ => {}
+        }
+    }
+    res
+}
+
+//limit of followed links, like ELOOP
+const MAX_LINKS: usize = 16;
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const WHITEOUT_OPAQUE: &str = ".wh..opq";
+
+/// Device number encoded the same way as linux new_encode_dev (used by squashfs)
+fn encode_device(major: u32, minor: u32) -> u32 {
+    (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)
+}
+
+#[cfg(unix)]
+fn entry_meta(metadata: &fs::Metadata) -> EntryMeta {
+    use std::os::unix::fs::MetadataExt;
+    EntryMeta {
+        mode: (metadata.mode() & 0o7777) as u16,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        mtime: metadata.mtime().clamp(0, u32::MAX as i64) as u32,
+    }
+}
+
+#[cfg(not(unix))]
+fn entry_meta(metadata: &fs::Metadata) -> EntryMeta {
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs().min(u32::MAX as u64) as u32)
+        .unwrap_or(0);
+    EntryMeta {
+        mode: if metadata.is_dir() { 0o755 } else { 0o644 },
+        mtime,
+        ..Default::default()
+    }
+}
+
+#[cfg(unix)]
+fn special_file_kind(metadata: &fs::Metadata) -> Option<EntryKind> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+    let file_type = metadata.file_type();
+    //decode glibc dev_t
+    let rdev = metadata.rdev();
+    let major = (((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff)) as u32;
+    let minor = ((rdev & 0xff) | ((rdev >> 12) & !0xff)) as u32;
+    if file_type.is_char_device() {
+        Some(EntryKind::CharDevice(encode_device(major, minor)))
+    } else if file_type.is_block_device() {
+        Some(EntryKind::BlockDevice(encode_device(major, minor)))
+    } else if file_type.is_fifo() {
+        Some(EntryKind::Fifo)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn special_file_kind(_metadata: &fs::Metadata) -> Option<EntryKind> {
+    None
+}
+
+impl RootfsTree {
+    /// Creates empty tree, file contents are staged in temporary directory created in `staging_parent`
+    pub fn new(staging_parent: &Path) -> anyhow::Result<Self> {
+        let staging = tempfile::Builder::new()
+            .prefix(".gvmkit-staging")
+            .tempdir_in(staging_parent)
+            .map_err(|e| {
+                anyhow!(
+                    "Failed to create staging directory in {}: {}",
+                    staging_parent.display(),
+                    e
+                )
+            })?;
+        Ok(RootfsTree {
+            root: EntryMeta::default(),
+            entries: BTreeMap::new(),
+            staging,
+            staged_files: 0,
+            exclude: IgnoreRules::default(),
+            excluded: Vec::new(),
+            deferred: Vec::new(),
+        })
+    }
+
+    /// Files matching the rules are skipped while tar streams are appended, so their content is not staged.
+    /// Directories (and entries added other way) are removed by `apply_exclude`.
+    pub fn set_exclude(&mut self, rules: IgnoreRules) {
+        self.excluded = vec![ExcludeStats::default(); rules.rules.len()];
+        self.exclude = rules;
+    }
+
+    /// Entries under these paths are appended even if excluded and removed later by `apply_exclude`,
+    /// so they can be read before (e.g. package databases for SBOM)
+    pub fn defer_exclude<P: Into<PathBuf>>(&mut self, paths: impl IntoIterator<Item = P>) {
+        self.deferred.extend(paths.into_iter().map(Into::into));
+    }
+
+    /// Removes the rest of entries excluded by rules given in `set_exclude`,
+    /// returns what was removed by each rule including files skipped while appending
+    pub fn apply_exclude(&mut self) -> Vec<ExcludeStats> {
+        let rules = std::mem::take(&mut self.exclude);
+        let mut stats = self.exclude(&rules);
+        for (total, skipped) in stats.iter_mut().zip(self.excluded.drain(..)) {
+            total.entries += skipped.entries;
+            total.bytes += skipped.bytes;
+        }
+        stats
+    }
+
+    pub fn root_meta(&self) -> EntryMeta {
+        self.root
+    }
+
+    pub fn set_root_meta(&mut self, meta: EntryMeta) {
+        self.root = meta;
+    }
+
+    /// All entries sorted by path, parents before children
+    pub fn entries(&self) -> impl Iterator<Item = (&PathBuf, &RootfsEntry)> {
+        self.entries.iter()
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&RootfsEntry> {
+        self.entries.get(path)
+    }
+
+    /// Content of regular file, symlinks are followed. None if there is no such file
+    pub fn read_file(&self, path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut path = path.to_path_buf();
+        for _ in 0..MAX_LINKS {
+            match self.entries.get(&path).map(|e| &e.kind) {
+                Some(EntryKind::File { source, .. }) => {
+                    return Ok(Some(fs::read(source).map_err(|e| {
+                        anyhow!("Failed to read staged file {}: {}", path.display(), e)
+                    })?))
+                }
+                Some(EntryKind::Symlink(target)) => {
+                    path = resolve_link(&path, target);
+                }
+                _ => return Ok(None),
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Total size of regular files in the tree
+    pub fn files_size(&self) -> u64 {
+        self.entries
+            .values()
+            .map(|e| match &e.kind {
+                EntryKind::File { size, .. } => *size,
+                _ => 0,
+            })
+            .sum()
+    }
+
+    /// Sets modification time of entries newer than `max_mtime` to `max_mtime`
+    pub fn clamp_mtimes(&mut self, max_mtime: u32) {
+        self.root.mtime = self.root.mtime.min(max_mtime);
+        for entry in self.entries.values_mut() {
+            entry.meta.mtime = entry.meta.mtime.min(max_mtime);
+        }
+    }
+
+    /// Removes entries excluded by rules, directory is kept if something inside is included again.
+    /// Returns what was removed by each rule.
+    pub fn exclude(&mut self, rules: &IgnoreRules) -> Vec<ExcludeStats> {
+        let mut stats = vec![ExcludeStats::default(); rules.rules.len()];
+        if rules.is_empty() {
+            return stats;
+        }
+        //parents of kept entries, children are visited before parents
+        let mut kept_parents = HashSet::new();
+        let paths: Vec<PathBuf> = self.entries.keys().rev().cloned().collect();
+        for path in paths {
+            match rules.excluded_by(&path) {
+                Some(rule_no) if !kept_parents.contains(&path) => {
+                    if let Some(entry) = self.entries.remove(&path) {
+                        stats[rule_no].entries += 1;
+                        if let EntryKind::File { size, .. } = entry.kind {
+                            stats[rule_no].bytes += size;
+                        }
+                    }
+                }
+                _ => {
+                    if let Some(parent) = path.parent() {
+                        kept_parents.insert(parent.to_path_buf());
+                    }
+                }
+            }
+        }
+        stats
+    }
+
+    /// Removes entry and everything below it
+    pub fn remove(&mut self, path: &Path) {
+        if self.entries.remove(path).is_some() {
+            self.remove_children(path);
+        }
+    }
+
+    fn remove_children(&mut self, path: &Path) {
+        let children: Vec<PathBuf> = self
+            .entries
+            .range(path.to_path_buf()..)
+            .skip_while(|(p, _)| *p == path)
+            .take_while(|(p, _)| p.starts_with(path))
+            .map(|(p, _)| p.clone())
+            .collect();
+        for child in children {
+            self.entries.remove(&child);
+        }
+    }
+
+    /// Path with symlinks among its ancestors followed, like creating file under `/lib -> usr/lib` does
+    fn resolve_parents(&self, path: &Path) -> anyhow::Result<PathBuf> {
+        let mut resolved = path.to_path_buf();
+        for _ in 0..MAX_LINKS {
+            //top most link first, paths below it are not in the tree
+            let link = resolved
+                .ancestors()
+                .skip(1)
+                .filter_map(
+                    |ancestor| match self.entries.get(ancestor).map(|e| &e.kind) {
+                        Some(EntryKind::Symlink(target)) => Some((ancestor, target)),
+                        _ => None,
+                    },
+                )
+                .last();
+            let Some((link, target)) = link else {
+                return Ok(resolved);
+            };
+            let rest = resolved.strip_prefix(link)?;
+            resolved = resolve_link(link, target).join(rest);
+        }
+        Err(anyhow!(
+            "Too many levels of symbolic links in path {}",
+            path.display()
+        ))
+    }
+
+    fn ensure_parents(&mut self, path: &Path) {
+        let mut missing = Vec::new();
+        for ancestor in path.ancestors().skip(1) {
+            if ancestor == Path::new("/") {
+                break;
+            }
+            match self.entries.get(ancestor) {
+                Some(RootfsEntry {
+                    kind: EntryKind::Dir,
+                    ..
+                }) => break,
+                Some(_) => {
+                    log::warn!(
+                        "Replacing non directory entry {} with directory",
+                        ancestor.display()
+                    );
+                    missing.push(ancestor.to_path_buf());
+                }
+                None => missing.push(ancestor.to_path_buf()),
+            }
+        }
+        for dir in missing {
+            self.entries.insert(
+                dir,
+                RootfsEntry {
+                    kind: EntryKind::Dir,
+                    meta: EntryMeta::default(),
+                },
+            );
+        }
+    }
+
+    /// Inserts entry, replacing existing one.
+    /// Directory replacing directory keeps its children.
+    /// Symlinks among parents are followed, so e.g. `/lib/libc.so` goes to `/usr/lib` when `/lib -> usr/lib`.
+    pub fn insert(&mut self, path: PathBuf, entry: RootfsEntry) -> anyhow::Result<()> {
+        let path = self.resolve_parents(&path)?;
+        self.ensure_parents(&path);
+        if let Some(old) = self.entries.get(&path) {
+            if old.kind == EntryKind::Dir && entry.kind != EntryKind::Dir {
+                self.remove_children(&path);
+            }
+        }
+        self.entries.insert(path, entry);
+        Ok(())
+    }
+
+    /// Adds entries docker creates in init layer of every container (mount points and files bind mounted at runtime),
+    /// so rootfs assembled from image layers is the same as copied from a container
+    pub fn add_docker_init_entries(&mut self, mtime: u32) -> anyhow::Result<()> {
+        let meta = EntryMeta {
+            mtime,
+            ..Default::default()
+        };
+        let (empty_file, _) = self.stage_content(io::empty())?;
+        for (path, kind) in [
+            ("/dev/pts", EntryKind::Dir),
+            ("/dev/shm", EntryKind::Dir),
+            ("/proc", EntryKind::Dir),
+            ("/sys", EntryKind::Dir),
+            (
+                "/.dockerenv",
+                EntryKind::File {
+                    source: empty_file.clone(),
+                    size: 0,
+                },
+            ),
+            (
+                "/etc/resolv.conf",
+                EntryKind::File {
+                    source: empty_file.clone(),
+                    size: 0,
+                },
+            ),
+            (
+                "/etc/hosts",
+                EntryKind::File {
+                    source: empty_file.clone(),
+                    size: 0,
+                },
+            ),
+            (
+                "/etc/hostname",
+                EntryKind::File {
+                    source: empty_file.clone(),
+                    size: 0,
+                },
+            ),
+            (
+                "/dev/console",
+                EntryKind::File {
+                    source: empty_file.clone(),
+                    size: 0,
+                },
+            ),
+            (
+                "/etc/mtab",
+                EntryKind::Symlink(PathBuf::from("/proc/mounts")),
+            ),
+        ] {
+            //init layer is on top of image layers, existing directories keep their content
+            let path = PathBuf::from(path);
+            if kind == EntryKind::Dir && self.entries.contains_key(&path) {
+                continue;
+            }
+            self.insert(path, RootfsEntry { kind, meta })?;
+        }
+        Ok(())
+    }
+
+    /// Copies file content into staging directory and returns path of the staged copy
+    pub fn stage_content(&mut self, mut reader: impl Read) -> anyhow::Result<(PathBuf, u64)> {
+        let staged_path = self.staging.path().join(self.staged_files.to_string());
+        self.staged_files += 1;
+        let mut file = fs::File::create(&staged_path)?;
+        let size = io::copy(&mut reader, &mut file)?;
+        Ok((staged_path, size))
+    }
+
+    /// Removes children of the directory which are not listed in `keep`
+    fn remove_children_except(&mut self, path: &Path, keep: &HashSet<PathBuf>) {
+        let children: Vec<PathBuf> = self
+            .entries
+            .range(path.to_path_buf()..)
+            .skip_while(|(p, _)| *p == path)
+            .take_while(|(p, _)| p.starts_with(path))
+            .filter(|(p, _)| !keep.contains(*p))
+            .map(|(p, _)| p.clone())
+            .collect();
+        for child in children {
+            self.entries.remove(&child);
+        }
+    }
+
+    /// Adds contents of the directory on disk (recursively), file contents are not copied
+    pub fn append_dir(&mut self, dir: &Path) -> anyhow::Result<()> {
+        let metadata = fs::symlink_metadata(dir)
+            .map_err(|e| anyhow!("Failed to read directory {}: {}", dir.display(), e))?;
+        if !metadata.is_dir() {
+            return Err(anyhow!("{} is not a directory", dir.display()));
+        }
+        self.root = entry_meta(&metadata);
+        self.append_dir_entries(dir, Path::new("/"))
+    }
+
+    fn append_dir_entries(&mut self, dir: &Path, target: &Path) -> anyhow::Result<()> {
+        let mut children = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        children.sort_by_key(|e| e.file_name());
+        for child in children {
+            let source = child.path();
+            let path = target.join(child.file_name());
+            let metadata = fs::symlink_metadata(&source)?;
+            let file_type = metadata.file_type();
+            let kind = if file_type.is_dir() {
+                EntryKind::Dir
+            } else if file_type.is_file() {
+                EntryKind::File {
+                    source: source.clone(),
+                    size: metadata.len(),
+                }
+            } else if file_type.is_symlink() {
+                EntryKind::Symlink(fs::read_link(&source)?)
+            } else {
+                match special_file_kind(&metadata) {
+                    Some(kind) => kind,
+                    None => {
+                        log::warn!("Skipping unsupported file type: {}", source.display());
+                        continue;
+                    }
+                }
+            };
+            let is_dir = kind == EntryKind::Dir;
+            self.insert(
+                path.clone(),
+                RootfsEntry {
+                    kind,
+                    meta: entry_meta(&metadata),
+                },
+            )?;
+            if is_dir {
+                self.append_dir_entries(&source, &path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds all entries from tar stream
+    pub fn append_tar(&mut self, reader: impl Read) -> anyhow::Result<()> {
+        self.append_entries(reader, false)
+    }
+
+    /// Applies image layer on top of the tree.
+    /// Handles whiteout files (`.wh.<name>`) and opaque directories (`.wh..wh..opq`)
+    pub fn append_layer(&mut self, reader: impl Read) -> anyhow::Result<()> {
+        self.append_entries(reader, true)
+    }
+
+    fn append_entries(&mut self, reader: impl Read, whiteouts: bool) -> anyhow::Result<()> {
+        //paths added by this layer, opaque directory keeps only them
+        let mut layer_paths = HashSet::new();
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            let entry = entry?;
+            if self.skip_excluded(&entry, whiteouts)? {
+                continue;
+            }
+            let change = read_change(entry, whiteouts, |content| self.stage_content(content))?;
+            if let Some(change) = change {
+                self.apply_change(change, &mut layer_paths)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks if tar entry is excluded by rules given in `set_exclude`.
+    /// Directories are not skipped, something inside can be included again.
+    fn skip_excluded<R: Read>(
+        &mut self,
+        entry: &tar::Entry<R>,
+        whiteouts: bool,
+    ) -> anyhow::Result<bool> {
+        let header = entry.header();
+        if self.exclude.is_empty() || header.entry_type() == EntryType::Directory {
+            return Ok(false);
+        }
+        let entry_path = entry.path()?;
+        if whiteouts
+            && entry_path
+                .file_name()
+                .map(|n| n.to_string_lossy().starts_with(WHITEOUT_PREFIX))
+                .unwrap_or(false)
+        {
+            return Ok(false);
+        }
+        let Some(path) = normalize_path(&entry_path)? else {
+            return Ok(false);
+        };
+        if self
+            .deferred
+            .iter()
+            .any(|deferred| path.starts_with(deferred))
+        {
+            return Ok(false);
+        }
+        let Some(rule_no) = self.exclude.excluded_by(&path) else {
+            return Ok(false);
+        };
+        self.excluded[rule_no].entries += 1;
+        if matches!(
+            header.entry_type(),
+            EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse
+        ) {
+            self.excluded[rule_no].bytes += header.size()?;
+        }
+        Ok(true)
+    }
+
+    /// Applies changes of single layer (e.g. read from layer cache) on top of the tree
+    pub fn apply_layer_changes(
+        &mut self,
+        changes: impl IntoIterator<Item = LayerChange>,
+    ) -> anyhow::Result<()> {
+        let mut layer_paths = HashSet::new();
+        for change in changes {
+            self.apply_change(change, &mut layer_paths)?;
+        }
+        Ok(())
+    }
+
+    fn apply_change(
+        &mut self,
+        change: LayerChange,
+        layer_paths: &mut HashSet<PathBuf>,
+    ) -> anyhow::Result<()> {
+        match change {
+            LayerChange::Root(meta) => self.root = meta,
+            LayerChange::Whiteout(path) => self.remove(&self.resolve_parents(&path)?),
+            LayerChange::Opaque(dir) => {
+                self.remove_children_except(&self.resolve_parents(&dir)?, layer_paths)
+            }
+            LayerChange::Entry { path, kind, meta } => {
+                let path = self.resolve_parents(&path)?;
+                layer_paths.insert(path.clone());
+                self.insert(path, RootfsEntry { kind, meta })?;
+            }
+            LayerChange::HardLink { path, target, meta } => {
+                match self.entries.get(&self.resolve_parents(&target)?) {
+                    Some(RootfsEntry {
+                        kind: kind @ EntryKind::File { .. },
+                        ..
+                    }) => {
+                        let kind = kind.clone();
+                        let path = self.resolve_parents(&path)?;
+                        layer_paths.insert(path.clone());
+                        self.insert(path, RootfsEntry { kind, meta })?;
+                    }
+                    _ => {
+                        log::warn!(
+                            "Hard link {} points to missing file {}, skipping",
+                            path.display(),
+                            target.display()
+                        );
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Single change made by a layer (or tar stream) to the tree
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LayerChange {
+    /// Metadata of the root directory
+    Root(EntryMeta),
+    /// Entry (and everything below it) removed by whiteout file
+    Whiteout(PathBuf),
+    /// Directory content from lower layers removed by opaque whiteout
+    Opaque(PathBuf),
+    Entry {
+        path: PathBuf,
+        kind: EntryKind,
+        meta: EntryMeta,
+    },
+    /// Hard link to regular file added earlier
+    HardLink {
+        path: PathBuf,
+        target: PathBuf,
+        meta: EntryMeta,
+    },
+}
+
+/// Converts tar entry into change, content of regular files is saved by `stage`.
+/// Returns None for unsupported entries.
+pub fn read_change<R: Read>(
+    mut entry: tar::Entry<R>,
+    whiteouts: bool,
+    stage: impl FnOnce(&mut tar::Entry<R>) -> anyhow::Result<(PathBuf, u64)>,
+) -> anyhow::Result<Option<LayerChange>> {
+    let entry_path = entry.path()?.to_path_buf();
+    if whiteouts {
+        let file_name = entry_path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if let Some(hidden) = file_name.strip_prefix(WHITEOUT_PREFIX) {
+            let parent = normalize_path(entry_path.parent().unwrap_or(Path::new("")))?
+                .unwrap_or_else(|| PathBuf::from("/"));
+            return Ok(Some(if hidden == WHITEOUT_OPAQUE {
+                LayerChange::Opaque(parent)
+            } else {
+                LayerChange::Whiteout(parent.join(hidden))
+            }));
+        }
+    }
+    let header = entry.header();
+    let meta = EntryMeta {
+        mode: (header.mode()? & 0o7777) as u16,
+        uid: header.uid()? as u32,
+        gid: header.gid()? as u32,
+        mtime: header.mtime()?.min(u32::MAX as u64) as u32,
+    };
+    let entry_type = header.entry_type();
+    let Some(path) = normalize_path(&entry_path)? else {
+        if entry_type == EntryType::Directory {
+            return Ok(Some(LayerChange::Root(meta)));
+        }
+        return Ok(None);
+    };
+    let kind = match entry_type {
+        EntryType::Regular | EntryType::Continuous | EntryType::GNUSparse => {
+            let (source, size) = stage(&mut entry)?;
+            EntryKind::File { source, size }
+        }
+        EntryType::Directory => EntryKind::Dir,
+        EntryType::Symlink => {
+            let link = entry
+                .link_name()?
+                .ok_or(anyhow!("Symlink without target: {}", path.display()))?;
+            EntryKind::Symlink(link.to_path_buf())
+        }
+        EntryType::Link => {
+            let link = entry
+                .link_name()?
+                .ok_or(anyhow!("Hard link without target: {}", path.display()))?;
+            let Some(target) = normalize_path(&link)? else {
+                log::warn!("Hard link {} points to root, skipping", path.display());
+                return Ok(None);
+            };
+            return Ok(Some(LayerChange::HardLink { path, target, meta }));
+        }
+        EntryType::Char | EntryType::Block => {
+            let device = encode_device(
+                header.device_major()?.unwrap_or(0),
+                header.device_minor()?.unwrap_or(0),
+            );
+            if entry_type == EntryType::Char {
+                EntryKind::CharDevice(device)
+            } else {
+                EntryKind::BlockDevice(device)
+            }
+        }
+        EntryType::Fifo => EntryKind::Fifo,
+        other => {
+            log::debug!(
+                "Skipping unsupported tar entry {:?}: {}",
+                other,
+                path.display()
+            );
+            return Ok(None);
+        }
+    };
+    Ok(Some(LayerChange::Entry { path, kind, meta }))
+}
+
+#[test]
+fn test_append_tar() {
+    let mut rng = fastrand::Rng::new();
+    rng.seed(1234);
+    let content: Vec<u8> = std::iter::repeat_with(|| rng.u8(..)).take(3000).collect();
+
+    let new_header = || {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o777);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(1700000000);
+        header
+    };
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = new_header();
+    header.set_entry_type(EntryType::Directory);
+    header.set_mode(0o700);
+    header.set_size(0);
+    builder
+        .append_data(&mut header, "./etc/", io::empty())
+        .unwrap();
+    let mut header = new_header();
+    header.set_mode(0o644);
+    header.set_uid(1000);
+    header.set_size(content.len() as u64);
+    builder
+        .append_data(&mut header, "./etc/file.bin", &content[..])
+        .unwrap();
+    //parent directory of this file is not in archive
+    let mut header = new_header();
+    header.set_mode(0o600);
+    header.set_size(3);
+    builder
+        .append_data(&mut header, "usr/bin/tool", &b"abc"[..])
+        .unwrap();
+    let mut header = new_header();
+    header.set_entry_type(EntryType::Link);
+    header.set_size(0);
+    builder
+        .append_link(&mut header, "usr/bin/tool2", "usr/bin/tool")
+        .unwrap();
+    let mut header = new_header();
+    header.set_entry_type(EntryType::Symlink);
+    header.set_size(0);
+    builder.append_link(&mut header, "bin", "usr/bin").unwrap();
+    let bytes = builder.into_inner().unwrap();
+
+    let mut tree = RootfsTree::new(&std::env::temp_dir()).unwrap();
+    tree.append_tar(&bytes[..]).unwrap();
+    let paths: Vec<_> = tree
+        .entries()
+        .map(|(p, _)| p.display().to_string())
+        .collect();
+    assert_eq!(
+        paths,
+        vec![
+            "/bin",
+            "/etc",
+            "/etc/file.bin",
+            "/usr",
+            "/usr/bin",
+            "/usr/bin/tool",
+            "/usr/bin/tool2"
+        ]
+    );
+    assert_eq!(tree.get(Path::new("/etc")).unwrap().meta.mode, 0o700);
+    let file = tree.get(Path::new("/etc/file.bin")).unwrap();
+    assert_eq!(file.meta.uid, 1000);
+    let EntryKind::File { source, size } = &file.kind else {
+        panic!("Expected file");
+    };
+    assert_eq!(*size, 3000);
+    assert_eq!(fs::read(source).unwrap(), content);
+    assert_eq!(
+        tree.get(Path::new("/usr/bin/tool")).unwrap().kind,
+        tree.get(Path::new("/usr/bin/tool2")).unwrap().kind
+    );
+    assert_eq!(
+        tree.get(Path::new("/bin")).unwrap().kind,
+        EntryKind::Symlink(PathBuf::from("usr/bin"))
+    );
+    assert_eq!(tree.files_size(), 3006);
+
+    tree.remove(Path::new("/usr"));
+    assert_eq!(tree.len(), 3);
+
+    //next layer writes through symlinked directory, the link is not replaced
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = new_header();
+    header.set_size(3);
+    builder
+        .append_data(&mut header, "bin/tool3", &b"abc"[..])
+        .unwrap();
+    let mut header = new_header();
+    header.set_size(0);
+    builder
+        .append_data(&mut header, "bin/.wh.tool3", io::empty())
+        .unwrap();
+    builder
+        .append_data(&mut header, "bin/tool4", io::empty())
+        .unwrap();
+    let mut header = new_header();
+    header.set_entry_type(EntryType::Symlink);
+    header.set_size(0);
+    builder.append_link(&mut header, "loop", "loop").unwrap();
+    let bytes = builder.into_inner().unwrap();
+    tree.append_layer(&bytes[..]).unwrap();
+    assert_eq!(
+        tree.get(Path::new("/bin")).unwrap().kind,
+        EntryKind::Symlink(PathBuf::from("usr/bin"))
+    );
+    assert!(tree.get(Path::new("/usr/bin/tool3")).is_none());
+    assert!(tree.get(Path::new("/usr/bin/tool4")).is_some());
+    assert!(tree.get(Path::new("/bin/tool4")).is_none());
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = new_header();
+    header.set_size(0);
+    builder
+        .append_data(&mut header, "loop/file", io::empty())
+        .unwrap();
+    assert!(tree.append_tar(&builder.into_inner().unwrap()[..]).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_append_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let source = tempfile::tempdir().unwrap();
+    fs::create_dir_all(source.path().join("usr/bin")).unwrap();
+    fs::write(source.path().join("usr/bin/tool"), b"#!/bin/sh").unwrap();
+    fs::set_permissions(
+        source.path().join("usr/bin/tool"),
+        fs::Permissions::from_mode(0o750),
+    )
+    .unwrap();
+    std::os::unix::fs::symlink("usr/bin", source.path().join("bin")).unwrap();
+
+    let staging = tempfile::tempdir().unwrap();
+    let mut tree = RootfsTree::new(staging.path()).unwrap();
+    tree.append_dir(source.path()).unwrap();
+    let paths: Vec<_> = tree
+        .entries()
+        .map(|(p, _)| p.display().to_string())
+        .collect();
+    assert_eq!(paths, vec!["/bin", "/usr", "/usr/bin", "/usr/bin/tool"]);
+    let tool = tree.get(Path::new("/usr/bin/tool")).unwrap();
+    assert_eq!(tool.meta.mode, 0o750);
+    assert_eq!(
+        tool.kind,
+        EntryKind::File {
+            source: source.path().join("usr/bin/tool"),
+            size: 9
+        }
+    );
+    assert_eq!(
+        tree.get(Path::new("/bin")).unwrap().kind,
+        EntryKind::Symlink(PathBuf::from("usr/bin"))
+    );
+    assert!(RootfsTree::new(staging.path())
+        .unwrap()
+        .append_dir(&source.path().join("bin"))
+        .is_err());
+}
+
+#[test]
+fn test_exclude() {
+    let mut builder = tar::Builder::new(Vec::new());
+    for (path, content) in [
+        ("usr/share/doc/bash/README", &b"readme"[..]),
+        ("usr/share/doc/bash/copyright", b"GPL"),
+        ("usr/bin/bash", b"binary"),
+        ("app/__pycache__/main.pyc", b"pyc"),
+        ("var/cache/apt/pkgcache.bin", b"cache"),
+    ] {
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(1700000000);
+        header.set_size(content.len() as u64);
+        builder.append_data(&mut header, path, content).unwrap();
+    }
+    let bytes = builder.into_inner().unwrap();
+
+    let mut tree = RootfsTree::new(&std::env::temp_dir()).unwrap();
+    let rules =
+        IgnoreRules::parse("/usr/share/doc\n!**/copyright\n**/__pycache__\n/var/cache").unwrap();
+    tree.set_exclude(rules);
+    tree.defer_exclude(["/var/cache/apt"]);
+    tree.append_tar(&bytes[..]).unwrap();
+    //content of excluded files is not staged, unless exclusion is deferred
+    assert_eq!(tree.staged_files, 3);
+    assert!(tree.get(Path::new("/var/cache/apt/pkgcache.bin")).is_some());
+    let stats = tree.apply_exclude();
+    assert_eq!(
+        stats,
+        [
+            ExcludeStats {
+                entries: 1,
+                bytes: 6
+            },
+            ExcludeStats::default(),
+            ExcludeStats {
+                entries: 1,
+                bytes: 3
+            },
+            ExcludeStats {
+                entries: 3,
+                bytes: 5
+            },
+        ]
+    );
+    let paths: Vec<_> = tree
+        .entries()
+        .map(|(p, _)| p.display().to_string())
+        .collect();
+    assert_eq!(
+        paths,
+        [
+            "/usr",
+            "/usr/bin",
+            "/usr/bin/bash",
+            "/usr/share",
+            "/usr/share/doc",
+            "/usr/share/doc/bash",
+            "/usr/share/doc/bash/copyright",
+            "/var"
+        ]
+    );
+}
+
+#[test]
+fn test_docker_init_entries() {
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(EntryType::Directory);
+    header.set_mode(0o555);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(1700000000);
+    header.set_size(0);
+    builder.append_data(&mut header, "proc", &b""[..]).unwrap();
+    let mut header = tar::Header::new_gnu();
+    header.set_mode(0o644);
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(1700000000);
+    header.set_size(9);
+    builder
+        .append_data(&mut header, "etc/hosts", &b"127.0.0.1"[..])
+        .unwrap();
+    let bytes = builder.into_inner().unwrap();
+
+    let mut tree = RootfsTree::new(&std::env::temp_dir()).unwrap();
+    tree.append_tar(&bytes[..]).unwrap();
+    tree.add_docker_init_entries(1800000000).unwrap();
+    assert_eq!(
+        tree.get(Path::new("/proc")).unwrap().meta.mode,
+        0o555,
+        "existing directory is kept"
+    );
+    assert_eq!(
+        tree.read_file(Path::new("/etc/hosts")).unwrap(),
+        Some(Vec::new())
+    );
+    assert_eq!(
+        tree.get(Path::new("/etc/mtab")).unwrap().kind,
+        EntryKind::Symlink(PathBuf::from("/proc/mounts"))
+    );
+    let paths: Vec<_> = tree
+        .entries()
+        .map(|(p, _)| p.display().to_string())
+        .collect();
+    assert_eq!(
+        paths,
+        [
+            "/.dockerenv",
+            "/dev",
+            "/dev/console",
+            "/dev/pts",
+            "/dev/shm",
+            "/etc",
+            "/etc/hostname",
+            "/etc/hosts",
+            "/etc/mtab",
+            "/etc/resolv.conf",
+            "/proc",
+            "/sys"
+        ]
+    );
+}
@@ -0,0 +1,538 @@
+//! Software bill of materials of the image, created from package databases found in the rootfs.
+
+mod rpm;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::rootfs::RootfsTree;
+
+const DPKG_STATUS: &str = "/var/lib/dpkg/status";
+//distroless images keep one file per package instead of single status file
+const DPKG_STATUS_DIR: &str = "/var/lib/dpkg/status.d";
+const APK_INSTALLED: &str = "/lib/apk/db/installed";
+const RPM_SQLITE_DBS: [&str; 2] = [
+    "/var/lib/rpm/rpmdb.sqlite",
+    "/usr/lib/sysimage/rpm/rpmdb.sqlite",
+];
+const RPM_LEGACY_DBS: [&str; 2] = ["/var/lib/rpm/Packages", "/var/lib/rpm/Packages.db"];
+
+/// Paths read by `collect_packages`, they have to be in the tree when SBOM is created
+pub const SBOM_SOURCES: [&str; 6] = [
+    "/etc/os-release",
+    "/usr/lib/os-release",
+    "/var/lib/dpkg",
+    "/lib/apk/db",
+    "/var/lib/rpm",
+    "/usr/lib/sysimage/rpm",
+];
+
+/// Labels added to image metadata when SBOM is referenced from it
+pub const SBOM_FORMAT_LABEL: &str = "network.golem.sbom.format";
+pub const SBOM_SHA256_LABEL: &str = "network.golem.sbom.sha256";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackageManager {
+    Dpkg,
+    Apk,
+    Rpm,
+}
+
+impl PackageManager {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PackageManager::Dpkg => "dpkg",
+            PackageManager::Apk => "apk",
+            PackageManager::Rpm => "rpm",
+        }
+    }
+
+    /// Package type used in package URLs
+    fn purl_type(&self) -> &'static str {
+        match self {
+            PackageManager::Dpkg => "deb",
+            PackageManager::Apk => "apk",
+            PackageManager::Rpm => "rpm",
+        }
+    }
+
+    /// Namespace used when os-release is missing
+    fn default_distro(&self) -> &'static str {
+        match self {
+            PackageManager::Dpkg => "debian",
+            PackageManager::Apk => "alpine",
+            PackageManager::Rpm => "redhat",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Package {
+    pub manager: PackageManager,
+    pub name: String,
+    pub version: String,
+    pub arch: Option<String>,
+    pub license: Option<String>,
+}
+
+/// Distribution from /etc/os-release
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OsRelease {
+    pub id: String,
+    pub version_id: Option<String>,
+    pub pretty_name: Option<String>,
+}
+
+impl OsRelease {
+    pub fn parse(content: &str) -> Option<Self> {
+        let mut os = OsRelease::default();
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+            match key.trim() {
+                "ID" => os.id = value.to_string(),
+                "VERSION_ID" => os.version_id = Some(value.to_string()),
+                "PRETTY_NAME" => os.pretty_name = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        (!os.id.is_empty()).then_some(os)
+    }
+}
+
+/// Packages installed in the image
+#[derive(Debug, Clone, Default)]
+pub struct PackageInventory {
+    pub os: Option<OsRelease>,
+    pub packages: Vec<Package>,
+    /// Package databases found but not possible to read
+    pub warnings: Vec<String>,
+}
+
+impl PackageInventory {
+    pub fn count(&self, manager: PackageManager) -> usize {
+        self.packages
+            .iter()
+            .filter(|p| p.manager == manager)
+            .count()
+    }
+}
+
+/// Splits control file format (dpkg status, apk installed) into paragraphs of fields
+fn paragraphs(content: &str) -> Vec<Vec<(&str, String)>> {
+    let mut result = Vec::new();
+    let mut current: Vec<(&str, String)> = Vec::new();
+    for line in content.lines() {
+        if line.trim().is_empty() {
+            if !current.is_empty() {
+                result.push(std::mem::take(&mut current));
+            }
+        } else if line.starts_with([' ', '\t']) {
+            //continuation of multiline field
+            if let Some((_, value)) = current.last_mut() {
+                value.push('\n');
+                value.push_str(line.trim());
+            }
+        } else if let Some((key, value)) = line.split_once(':') {
+            current.push((key, value.trim().to_string()));
+        }
+    }
+    if !current.is_empty() {
+        result.push(current);
+    }
+    result
+}
+
+fn field<'a>(paragraph: &'a [(&str, String)], key: &str) -> Option<&'a str> {
+    paragraph
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v.as_str())
+}
+
+/// Installed packages from dpkg status file
+pub fn parse_dpkg_status(content: &str) -> Vec<Package> {
+    paragraphs(content)
+        .iter()
+        .filter(|p| {
+            //status.d files of distroless images have no status field
+            field(p, "Status")
+                .map(|status| status.ends_with(" installed"))
+                .unwrap_or(true)
+        })
+        .filter_map(|p| {
+            Some(Package {
+                manager: PackageManager::Dpkg,
+                name: field(p, "Package")?.to_string(),
+                version: field(p, "Version")?.to_string(),
+                arch: field(p, "Architecture").map(str::to_string),
+                license: None,
+            })
+        })
+        .collect()
+}
+
+/// Installed packages from apk database, fields are single letters
+pub fn parse_apk_installed(content: &str) -> Vec<Package> {
+    paragraphs(content)
+        .iter()
+        .filter_map(|p| {
+            Some(Package {
+                manager: PackageManager::Apk,
+                name: field(p, "P")?.to_string(),
+                version: field(p, "V")?.to_string(),
+                arch: field(p, "A").map(str::to_string),
+                license: field(p, "L").map(str::to_string),
+            })
+        })
+        .collect()
+}
+
+/// Reads package databases of dpkg, apk and rpm from the tree
+pub fn collect_packages(tree: &RootfsTree) -> anyhow::Result<PackageInventory> {
+    let mut inventory = PackageInventory::default();
+    for os_release in ["/etc/os-release", "/usr/lib/os-release"] {
+        if let Some(content) = tree.read_file(Path::new(os_release))? {
+            inventory.os = OsRelease::parse(&String::from_utf8_lossy(&content));
+            break;
+        }
+    }
+
+    if let Some(content) = tree.read_file(Path::new(DPKG_STATUS))? {
+        inventory
+            .packages
+            .extend(parse_dpkg_status(&String::from_utf8_lossy(&content)));
+    }
+    let status_files: Vec<PathBuf> = tree
+        .entries()
+        .filter(|(path, _)| path.parent() == Some(Path::new(DPKG_STATUS_DIR)))
+        .map(|(path, _)| path.clone())
+        .collect();
+    for path in status_files {
+        if let Some(content) = tree.read_file(&path)? {
+            inventory
+                .packages
+                .extend(parse_dpkg_status(&String::from_utf8_lossy(&content)));
+        }
+    }
+
+    if let Some(content) = tree.read_file(Path::new(APK_INSTALLED))? {
+        inventory
+            .packages
+            .extend(parse_apk_installed(&String::from_utf8_lossy(&content)));
+    }
+
+    let mut rpm_found = false;
+    for db in RPM_SQLITE_DBS {
+        if let Some(content) = tree.read_file(Path::new(db))? {
+            let wal = tree.read_file(Path::new(&format!("{db}-wal")))?;
+            match rpm::read_sqlite_packages(&content, wal.as_deref()) {
+                Ok(packages) => inventory.packages.extend(packages),
+                Err(e) => inventory
+                    .warnings
+                    .push(format!("Failed to read rpm database {db}: {e}")),
+            }
+            rpm_found = true;
+            break;
+        }
+    }
+    if !rpm_found {
+        for db in RPM_LEGACY_DBS {
+            if tree.get(Path::new(db)).is_some() {
+                inventory.warnings.push(format!(
+                    "rpm database {db} is in legacy format, only rpmdb.sqlite is supported"
+                ));
+            }
+        }
+    }
+
+    inventory.packages.sort_by(|a, b| {
+        (a.manager.name(), &a.name, &a.version).cmp(&(b.manager.name(), &b.name, &b.version))
+    });
+    inventory.packages.dedup();
+    Ok(inventory)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SbomFormat {
+    Spdx,
+    CycloneDx,
+}
+
+impl SbomFormat {
+    pub fn from_name(name: &str) -> anyhow::Result<Self> {
+        match name {
+            "spdx" => Ok(SbomFormat::Spdx),
+            "cyclonedx" | "cdx" => Ok(SbomFormat::CycloneDx),
+            _ => Err(anyhow::anyhow!(
+                "Unknown SBOM format {name}, possible values: spdx, cyclonedx"
+            )),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            SbomFormat::Spdx => "spdx.json",
+            SbomFormat::CycloneDx => "cdx.json",
+        }
+    }
+}
+
+impl fmt::Display for SbomFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SbomFormat::Spdx => write!(f, "spdx-2.3"),
+            SbomFormat::CycloneDx => write!(f, "cyclonedx-1.5"),
+        }
+    }
+}
+
+/// SBOM file saved next to the image, e.g. `image.gvmi.sbom.spdx.json`
+pub fn sbom_path(image_path: &Path, format: SbomFormat) -> PathBuf {
+    PathBuf::from(format!(
+        "{}.sbom.{}",
+        image_path.display(),
+        format.extension()
+    ))
+}
+
+/// Characters allowed unescaped in package URL components
+fn purl_encode(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b".-_~".contains(&byte) {
+            res.push(byte as char);
+        } else {
+            res.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    res
+}
+
+fn purl(package: &Package, os: Option<&OsRelease>) -> String {
+    let distro = os
+        .map(|os| os.id.as_str())
+        .unwrap_or(package.manager.default_distro());
+    //rpm epoch is a qualifier, not part of the version
+    let (epoch, version) = match package.version.split_once(':') {
+        Some((epoch, version)) if package.manager == PackageManager::Rpm => (Some(epoch), version),
+        _ => (None, package.version.as_str()),
+    };
+    let mut qualifiers = Vec::new();
+    if let Some(arch) = &package.arch {
+        qualifiers.push(format!("arch={}", purl_encode(arch)));
+    }
+    if let Some(epoch) = epoch {
+        qualifiers.push(format!("epoch={}", purl_encode(epoch)));
+    }
+    let mut purl = format!(
+        "pkg:{}/{}/{}@{}",
+        package.manager.purl_type(),
+        purl_encode(distro),
+        purl_encode(&package.name),
+        purl_encode(version)
+    );
+    if !qualifiers.is_empty() {
+        purl.push('?');
+        purl.push_str(&qualifiers.join("&"));
+    }
+    purl
+}
+
+/// Deterministic UUID made from the hash of the content, same image gives the same document
+fn content_uuid(content: &[u8]) -> String {
+    let mut bytes: [u8; 16] = Sha256::digest(content)[..16].try_into().unwrap();
+    bytes[6] = (bytes[6] & 0x0f) | 0x50;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = hex::encode(bytes);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+/// SBOM document in given format, `name` identifies the image
+pub fn sbom_document(
+    inventory: &PackageInventory,
+    format: SbomFormat,
+    name: &str,
+    created: DateTime<Utc>,
+) -> Value {
+    let os = inventory.os.as_ref();
+    let purls: Vec<String> = inventory.packages.iter().map(|p| purl(p, os)).collect();
+    let uuid = content_uuid(format!("{name}\n{}", purls.join("\n")).as_bytes());
+    let created = created.format("%Y-%m-%dT%H:%M:%SZ").to_string();
+    let tool = format!("gvmkit-build-{}", env!("CARGO_PKG_VERSION"));
+    match format {
+        SbomFormat::Spdx => {
+            let packages: Vec<Value> = inventory
+                .packages
+                .iter()
+                .zip(&purls)
+                .enumerate()
+                .map(|(no, (package, purl))| {
+                    json!({
+                        "name": package.name,
+                        "SPDXID": format!("SPDXRef-Package-{}-{}", package.manager.name(), no),
+                        "versionInfo": package.version,
+                        "downloadLocation": "NOASSERTION",
+                        "licenseConcluded": "NOASSERTION",
+                        "licenseDeclared": "NOASSERTION",
+                        "copyrightText": "NOASSERTION",
+                        "externalRefs": [{
+                            "referenceCategory": "PACKAGE-MANAGER",
+                            "referenceType": "purl",
+                            "referenceLocator": purl,
+                        }],
+                    })
+                })
+                .collect();
+            json!({
+                "spdxVersion": "SPDX-2.3",
+                "dataLicense": "CC0-1.0",
+                "SPDXID": "SPDXRef-DOCUMENT",
+                "name": name,
+                "documentNamespace": format!("https://golem.network/spdx/{}-{}", purl_encode(name), uuid),
+                "creationInfo": {
+                    "created": created,
+                    "creators": [format!("Tool: {tool}")],
+                },
+                "packages": packages,
+            })
+        }
+        SbomFormat::CycloneDx => {
+            let mut components: Vec<Value> = Vec::new();
+            if let Some(os) = os {
+                let mut component = json!({
+                    "type": "operating-system",
+                    "name": os.id,
+                });
+                if let Some(version) = &os.version_id {
+                    component["version"] = json!(version);
+                }
+                if let Some(description) = &os.pretty_name {
+                    component["description"] = json!(description);
+                }
+                components.push(component);
+            }
+            components.extend(
+                inventory
+                    .packages
+                    .iter()
+                    .zip(&purls)
+                    .map(|(package, purl)| {
+                        let mut component = json!({
+                            "type": "library",
+                            "bom-ref": purl,
+                            "name": package.name,
+                            "version": package.version,
+                            "purl": purl,
+                        });
+                        if let Some(license) = &package.license {
+                            component["licenses"] = json!([{ "license": { "name": license } }]);
+                        }
+                        component
+                    }),
+            );
+            json!({
+                "bomFormat": "CycloneDX",
+                "specVersion": "1.5",
+                "serialNumber": format!("urn:uuid:{uuid}"),
+                "version": 1,
+                "metadata": {
+                    "timestamp": created,
+                    "tools": [{
+                        "vendor": "Golem Factory",
+                        "name": "gvmkit-build",
+                        "version": env!("CARGO_PKG_VERSION"),
+                    }],
+                    "component": {
+                        "type": "container",
+                        "name": name,
+                    },
+                },
+                "components": components,
+            })
+        }
+    }
+}
+
+#[test]
+fn test_package_inventory() {
+    use crate::rootfs::{EntryKind, EntryMeta, RootfsEntry};
+    use chrono::TimeZone;
+
+    let dpkg_status = "Package: libc6\nStatus: install ok installed\nArchitecture: amd64\nVersion: 2.36-9+deb12u3\nDescription: GNU C Library\n shared libraries\n\nPackage: removed\nStatus: deinstall ok config-files\nVersion: 1.0\n\nPackage: bash\nStatus: install ok installed\nArchitecture: amd64\nVersion: 5.2.15-2+b2\n";
+    let apk_installed = "C:Q1abc=\nP:musl\nV:1.2.4-r2\nA:x86_64\nL:MIT\n\nP:busybox\nV:1.36.1-r5\nA:x86_64\nL:GPL-2.0-only\n";
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut tree = RootfsTree::new(temp_dir.path()).unwrap();
+    let add_file = |tree: &mut RootfsTree, path: &str, content: &str| {
+        let (source, size) = tree.stage_content(content.as_bytes()).unwrap();
+        tree.insert(
+            PathBuf::from(path),
+            RootfsEntry {
+                kind: EntryKind::File { source, size },
+                meta: EntryMeta::default(),
+            },
+        )
+        .unwrap();
+    };
+    add_file(&mut tree, DPKG_STATUS, dpkg_status);
+    add_file(&mut tree, APK_INSTALLED, apk_installed);
+    add_file(
+        &mut tree,
+        "/var/lib/dpkg/status.d/base",
+        "Package: tzdata\nVersion: 2024a-0+deb12u1\nArchitecture: all\n",
+    );
+    add_file(
+        &mut tree,
+        "/usr/lib/os-release",
+        "PRETTY_NAME=\"Debian GNU/Linux 12 (bookworm)\"\nID=debian\nVERSION_ID=\"12\"\n",
+    );
+    tree.insert(
+        PathBuf::from("/etc/os-release"),
+        RootfsEntry {
+            kind: EntryKind::Symlink(PathBuf::from("../usr/lib/os-release")),
+            meta: EntryMeta::default(),
+        },
+    )
+    .unwrap();
+
+    let inventory = collect_packages(&tree).unwrap();
+    assert_eq!(inventory.os.as_ref().unwrap().id, "debian");
+    assert_eq!(inventory.count(PackageManager::Dpkg), 3);
+    assert_eq!(inventory.count(PackageManager::Apk), 2);
+    assert!(inventory.warnings.is_empty());
+    let names: Vec<&str> = inventory.packages.iter().map(|p| p.name.as_str()).collect();
+    assert_eq!(names, ["busybox", "musl", "bash", "libc6", "tzdata"]);
+    assert_eq!(
+        purl(&inventory.packages[3], inventory.os.as_ref()),
+        "pkg:deb/debian/libc6@2.36-9%2Bdeb12u3?arch=amd64"
+    );
+
+    let created = Utc.timestamp_opt(1700000000, 0).unwrap();
+    let spdx = sbom_document(&inventory, SbomFormat::Spdx, "golem/test:v1", created);
+    assert_eq!(spdx["packages"].as_array().unwrap().len(), 5);
+    assert_eq!(spdx["creationInfo"]["created"], "2023-11-14T22:13:20Z");
+    let cdx = sbom_document(&inventory, SbomFormat::CycloneDx, "golem/test:v1", created);
+    assert_eq!(cdx["components"][0]["type"], "operating-system");
+    assert_eq!(
+        cdx["components"][2]["licenses"][0]["license"]["name"],
+        "MIT"
+    );
+    //same content gives the same document
+    assert_eq!(
+        cdx,
+        sbom_document(&inventory, SbomFormat::CycloneDx, "golem/test:v1", created)
+    );
+}
@@ -0,0 +1,546 @@
+//! Read-only access to rpm database in sqlite format (rpmdb.sqlite, used since rpm 4.16).
+//!
+//! Only what is needed to list packages is implemented: table b-tree traversal,
+//! overflow pages and record decoding of sqlite, then rpm header blobs from `Packages` table.
+//! Committed frames of write-ahead log (rpmdb.sqlite-wal) are applied on top of the database,
+//! as rpm keeps the database in WAL mode and recent transactions may not be checkpointed yet.
+
+use anyhow::anyhow;
+
+use super::{Package, PackageManager};
+
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+const WAL_MAGIC_LE: u32 = 0x377f0682;
+const WAL_MAGIC_BE: u32 = 0x377f0683;
+const WAL_HEADER_SIZE: usize = 32;
+const WAL_FRAME_HEADER_SIZE: usize = 24;
+const PACKAGES_TABLE: &str = "Packages";
+
+const RPMTAG_NAME: i32 = 1000;
+const RPMTAG_VERSION: i32 = 1001;
+const RPMTAG_RELEASE: i32 = 1002;
+const RPMTAG_EPOCH: i32 = 1003;
+const RPMTAG_LICENSE: i32 = 1014;
+const RPMTAG_ARCH: i32 = 1022;
+
+const RPM_INT32_TYPE: u32 = 4;
+const RPM_STRING_TYPE: u32 = 6;
+const RPM_STRING_ARRAY_TYPE: u32 = 8;
+const RPM_I18NSTRING_TYPE: u32 = 9;
+
+#[derive(Debug, Clone, PartialEq)]
+enum SqlValue {
+    Null,
+    Integer(i64),
+    Float(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+struct SqliteFile<'a> {
+    data: &'a [u8],
+    page_size: usize,
+    usable_size: usize,
+}
+
+/// Reads sqlite varint, returns value and its length
+fn read_varint(data: &[u8]) -> anyhow::Result<(u64, usize)> {
+    let mut value = 0_u64;
+    for i in 0..9 {
+        let byte = *data.get(i).ok_or_else(|| anyhow!("Truncated varint"))?;
+        if i == 8 {
+            return Ok(((value << 8) | byte as u64, 9));
+        }
+        value = (value << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    unreachable!()
+}
+
+fn be_u16(data: &[u8], offset: usize) -> anyhow::Result<usize> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+        .ok_or_else(|| anyhow!("Truncated page"))
+}
+
+fn be_u32(data: &[u8], offset: usize) -> anyhow::Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or_else(|| anyhow!("Truncated page"))
+}
+
+impl<'a> SqliteFile<'a> {
+    fn open(data: &'a [u8]) -> anyhow::Result<Self> {
+        if data.len() < 100 || data.get(..16) != Some(SQLITE_MAGIC) {
+            return Err(anyhow!("Not a sqlite database"));
+        }
+        let page_size = db_page_size(data)?;
+        let reserved = *data
+            .get(20)
+            .ok_or_else(|| anyhow!("Truncated sqlite header"))?;
+        let usable_size = page_size - reserved as usize;
+        //minimum required by sqlite file format, keeps local payload computations positive
+        if usable_size < 480 {
+            return Err(anyhow!("Invalid sqlite usable page size {usable_size}"));
+        }
+        Ok(SqliteFile {
+            data,
+            page_size,
+            usable_size,
+        })
+    }
+
+    fn page(&self, page_no: u32) -> anyhow::Result<&'a [u8]> {
+        let start = (page_no as usize)
+            .checked_sub(1)
+            .ok_or_else(|| anyhow!("Invalid page number 0"))?
+            * self.page_size;
+        self.data
+            .get(start..start + self.page_size)
+            .ok_or_else(|| anyhow!("Page {page_no} out of file"))
+    }
+
+    /// Payload of table leaf cell, joined with overflow pages
+    fn cell_payload(&self, page: &[u8], offset: usize) -> anyhow::Result<Vec<u8>> {
+        let cell = page
+            .get(offset..)
+            .ok_or_else(|| anyhow!("Cell out of page"))?;
+        let (payload_size, len) = read_varint(cell)?;
+        let (_rowid, rowid_len) = read_varint(cell.get(len..).unwrap_or_default())?;
+        let offset = offset + len + rowid_len;
+        //payload cannot be larger than the whole database, guards the allocation below
+        let payload_size = usize::try_from(payload_size)
+            .ok()
+            .filter(|size| *size <= self.data.len())
+            .ok_or_else(|| anyhow!("Invalid cell payload size {payload_size}"))?;
+
+        let max_local = self.usable_size - 35;
+        let local_size = if payload_size <= max_local {
+            payload_size
+        } else {
+            let min_local = (self.usable_size - 12) * 32 / 255 - 23;
+            let local = min_local + (payload_size - min_local) % (self.usable_size - 4);
+            if local <= max_local {
+                local
+            } else {
+                min_local
+            }
+        };
+        let mut payload = Vec::with_capacity(payload_size);
+        payload.extend_from_slice(
+            page.get(offset..offset + local_size)
+                .ok_or_else(|| anyhow!("Truncated cell"))?,
+        );
+        let mut overflow = if local_size < payload_size {
+            be_u32(page, offset + local_size)?
+        } else {
+            0
+        };
+        while overflow != 0 && payload.len() < payload_size {
+            let page = self.page(overflow)?;
+            overflow = be_u32(page, 0)?;
+            let take = (payload_size - payload.len()).min(self.usable_size - 4);
+            payload.extend_from_slice(
+                page.get(4..4 + take)
+                    .ok_or_else(|| anyhow!("Truncated overflow page"))?,
+            );
+        }
+        if payload.len() != payload_size {
+            return Err(anyhow!("Truncated overflow chain"));
+        }
+        Ok(payload)
+    }
+
+    /// Payloads of all rows of the table with b-tree rooted at `root_page`
+    fn table_rows(&self, root_page: u32) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut rows = Vec::new();
+        let mut stack = vec![root_page];
+        let mut visited = 0;
+        while let Some(page_no) = stack.pop() {
+            visited += 1;
+            if visited > self.data.len() / self.page_size {
+                return Err(anyhow!("Loop in sqlite b-tree"));
+            }
+            let page = self.page(page_no)?;
+            //first page starts with database header
+            let header = if page_no == 1 { 100 } else { 0 };
+            let page_type = *page.get(header).ok_or_else(|| anyhow!("Truncated page"))?;
+            let cell_count = be_u16(page, header + 3)?;
+            let cells_start = header + if page_type == 0x05 { 12 } else { 8 };
+            match page_type {
+                0x0d => {
+                    for i in 0..cell_count {
+                        let offset = be_u16(page, cells_start + i * 2)?;
+                        rows.push(self.cell_payload(page, offset)?);
+                    }
+                }
+                0x05 => {
+                    //rightmost child is visited last to keep rows in rowid order
+                    stack.push(be_u32(page, header + 8)?);
+                    for i in (0..cell_count).rev() {
+                        let offset = be_u16(page, cells_start + i * 2)?;
+                        stack.push(be_u32(page, offset)?);
+                    }
+                }
+                _ => return Err(anyhow!("Unexpected sqlite page type {page_type}")),
+            }
+        }
+        Ok(rows)
+    }
+}
+
+fn decode_record(payload: &[u8]) -> anyhow::Result<Vec<SqlValue>> {
+    let (header_size, mut pos) = read_varint(payload)?;
+    let header_size = usize::try_from(header_size)
+        .ok()
+        .filter(|size| *size <= payload.len())
+        .ok_or_else(|| anyhow!("Invalid record header size {header_size}"))?;
+    let mut types = Vec::new();
+    while pos < header_size {
+        let (serial_type, len) = read_varint(payload.get(pos..).unwrap_or_default())?;
+        types.push(serial_type);
+        pos += len;
+    }
+    let mut values = Vec::with_capacity(types.len());
+    let mut pos = header_size;
+    for serial_type in types {
+        let size = match serial_type {
+            0 | 8 | 9 => 0,
+            1..=4 => serial_type as usize,
+            5 => 6,
+            6 | 7 => 8,
+            n if n >= 12 => usize::try_from((n - 12) / 2)?,
+            n => return Err(anyhow!("Invalid serial type {n}")),
+        };
+        let end = pos
+            .checked_add(size)
+            .ok_or_else(|| anyhow!("Truncated record"))?;
+        let bytes = payload
+            .get(pos..end)
+            .ok_or_else(|| anyhow!("Truncated record"))?;
+        pos = end;
+        values.push(match serial_type {
+            0 => SqlValue::Null,
+            8 => SqlValue::Integer(0),
+            9 => SqlValue::Integer(1),
+            1..=6 => {
+                //sign extend big endian integer
+                let mut value = if bytes.first().is_some_and(|b| b & 0x80 != 0) {
+                    -1_i64
+                } else {
+                    0
+                };
+                for byte in bytes {
+                    value = (value << 8) | *byte as i64;
+                }
+                SqlValue::Integer(value)
+            }
+            7 => SqlValue::Float(f64::from_be_bytes(bytes.try_into()?)),
+            n if n % 2 == 0 => SqlValue::Blob(bytes.to_vec()),
+            _ => SqlValue::Text(String::from_utf8_lossy(bytes).to_string()),
+        });
+    }
+    Ok(values)
+}
+
+/// Header blob as stored in rpm database: index of tags followed by data
+struct RpmHeader<'a> {
+    index: &'a [u8],
+    data: &'a [u8],
+}
+
+impl<'a> RpmHeader<'a> {
+    fn parse(blob: &'a [u8]) -> anyhow::Result<Self> {
+        let index_count = be_u32(blob, 0)? as usize;
+        let data_size = be_u32(blob, 4)? as usize;
+        let index_end = index_count
+            .checked_mul(16)
+            .and_then(|len| len.checked_add(8))
+            .ok_or_else(|| anyhow!("Invalid rpm header"))?;
+        let data_end = index_end
+            .checked_add(data_size)
+            .filter(|end| *end <= blob.len())
+            .ok_or_else(|| anyhow!("Truncated rpm header"))?;
+        Ok(RpmHeader {
+            index: blob
+                .get(8..index_end)
+                .ok_or_else(|| anyhow!("Truncated rpm header"))?,
+            data: blob
+                .get(index_end..data_end)
+                .ok_or_else(|| anyhow!("Truncated rpm header"))?,
+        })
+    }
+
+    fn entry(&self, tag: i32) -> Option<(u32, usize)> {
+        self.index.chunks_exact(16).find_map(|entry| {
+            let entry_tag = i32::from_be_bytes(entry.get(0..4)?.try_into().ok()?);
+            if entry_tag != tag {
+                return None;
+            }
+            Some((
+                u32::from_be_bytes(entry.get(4..8)?.try_into().ok()?),
+                u32::from_be_bytes(entry.get(8..12)?.try_into().ok()?) as usize,
+            ))
+        })
+    }
+
+    fn string(&self, tag: i32) -> Option<String> {
+        let (data_type, offset) = self.entry(tag)?;
+        if !matches!(
+            data_type,
+            RPM_STRING_TYPE | RPM_STRING_ARRAY_TYPE | RPM_I18NSTRING_TYPE
+        ) {
+            return None;
+        }
+        //first string of arrays (e.g. default locale of i18n string)
+        let data = self.data.get(offset..)?;
+        let end = data.iter().position(|b| *b == 0)?;
+        Some(String::from_utf8_lossy(data.get(..end)?).to_string())
+    }
+
+    fn int32(&self, tag: i32) -> Option<u32> {
+        let (data_type, offset) = self.entry(tag)?;
+        if data_type != RPM_INT32_TYPE {
+            return None;
+        }
+        be_u32(self.data, offset).ok()
+    }
+}
+
+fn rpm_package(blob: &[u8]) -> anyhow::Result<Option<Package>> {
+    let header = RpmHeader::parse(blob)?;
+    let name = header
+        .string(RPMTAG_NAME)
+        .ok_or_else(|| anyhow!("rpm header without name"))?;
+    //imported signing keys are stored as pseudo packages
+    if name == "gpg-pubkey" {
+        return Ok(None);
+    }
+    let mut version = header.string(RPMTAG_VERSION).unwrap_or_default();
+    if let Some(release) = header.string(RPMTAG_RELEASE) {
+        version = format!("{version}-{release}");
+    }
+    if let Some(epoch) = header.int32(RPMTAG_EPOCH) {
+        version = format!("{epoch}:{version}");
+    }
+    Ok(Some(Package {
+        manager: PackageManager::Rpm,
+        name,
+        version,
+        arch: header.string(RPMTAG_ARCH),
+        license: header.string(RPMTAG_LICENSE),
+    }))
+}
+
+fn db_page_size(data: &[u8]) -> anyhow::Result<usize> {
+    let page_size = match be_u16(data, 16)? {
+        1 => 65536,
+        size => size,
+    };
+    if page_size < 512 || !page_size.is_power_of_two() {
+        return Err(anyhow!("Invalid sqlite page size {page_size}"));
+    }
+    Ok(page_size)
+}
+
+/// Cumulative checksum of write-ahead log, over pairs of 32-bit words
+fn wal_checksum(data: &[u8], big_endian: bool, mut sum: (u32, u32)) -> (u32, u32) {
+    let word = |b: &[u8]| {
+        let b = [b[0], b[1], b[2], b[3]];
+        if big_endian {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    };
+    for pair in data.chunks_exact(8) {
+        sum.0 = sum.0.wrapping_add(word(&pair[..4])).wrapping_add(sum.1);
+        sum.1 = sum.1.wrapping_add(word(&pair[4..])).wrapping_add(sum.0);
+    }
+    sum
+}
+
+/// Database content with committed frames of write-ahead log applied.
+///
+/// Frames after the last valid commit (torn or uncommitted writes, frames left
+/// from an older checkpoint generation) are ignored, like sqlite does on recovery.
+fn apply_wal(db: &[u8], wal: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut data = db.to_vec();
+    let Some(header) = wal.get(..WAL_HEADER_SIZE) else {
+        //empty log, e.g. after checkpoint
+        return Ok(data);
+    };
+    let big_endian = match be_u32(header, 0)? {
+        WAL_MAGIC_LE => false,
+        WAL_MAGIC_BE => true,
+        magic => return Err(anyhow!("Invalid sqlite WAL magic {magic:#x}")),
+    };
+    let page_size = be_u32(header, 8)? as usize;
+    let salt = (be_u32(header, 16)?, be_u32(header, 20)?);
+    let mut checksum = wal_checksum(&header[..24], big_endian, (0, 0));
+    if checksum != (be_u32(header, 24)?, be_u32(header, 28)?) {
+        return Err(anyhow!("Invalid sqlite WAL header checksum"));
+    }
+    if page_size != db_page_size(db)? {
+        return Err(anyhow!(
+            "sqlite WAL page size {page_size} differs from database"
+        ));
+    }
+
+    //each frame can add at most one page, guards resizing by crafted page numbers
+    let max_pages = (db.len() + wal.len()) / page_size;
+    let mut pending = Vec::new();
+    let mut offset = WAL_HEADER_SIZE;
+    while let Some(frame) = wal.get(offset..offset + WAL_FRAME_HEADER_SIZE + page_size) {
+        offset += frame.len();
+        let (frame_header, page) = frame.split_at(WAL_FRAME_HEADER_SIZE);
+        if (be_u32(frame_header, 8)?, be_u32(frame_header, 12)?) != salt {
+            break;
+        }
+        checksum = wal_checksum(&frame_header[..8], big_endian, checksum);
+        checksum = wal_checksum(page, big_endian, checksum);
+        if checksum != (be_u32(frame_header, 16)?, be_u32(frame_header, 20)?) {
+            break;
+        }
+        let page_no = be_u32(frame_header, 0)? as usize;
+        if page_no == 0 || page_no > max_pages {
+            return Err(anyhow!("Invalid page number {page_no} in sqlite WAL"));
+        }
+        pending.push((page_no, page));
+        //commit frame carries database size in pages
+        let db_pages = be_u32(frame_header, 4)? as usize;
+        if db_pages > max_pages {
+            return Err(anyhow!("Invalid database size {db_pages} in sqlite WAL"));
+        }
+        if db_pages != 0 {
+            for (page_no, page) in pending.drain(..) {
+                let start = (page_no - 1) * page_size;
+                if data.len() < start + page_size {
+                    data.resize(start + page_size, 0);
+                }
+                data[start..start + page_size].copy_from_slice(page);
+            }
+            data.resize(db_pages * page_size, 0);
+        }
+    }
+    Ok(data)
+}
+
+/// Installed packages from rpmdb.sqlite content and its write-ahead log, if present
+pub fn read_sqlite_packages(db: &[u8], wal: Option<&[u8]>) -> anyhow::Result<Vec<Package>> {
+    let db = match wal {
+        Some(wal) => std::borrow::Cow::Owned(apply_wal(db, wal)?),
+        None => std::borrow::Cow::Borrowed(db),
+    };
+    let file = SqliteFile::open(&db)?;
+    //schema table: type, name, tbl_name, rootpage, sql
+    let root_page = file
+        .table_rows(1)?
+        .iter()
+        .map(|row| decode_record(row))
+        .collect::<anyhow::Result<Vec<_>>>()?
+        .into_iter()
+        .find_map(|row| match (row.first(), row.get(1), row.get(3)) {
+            (
+                Some(SqlValue::Text(kind)),
+                Some(SqlValue::Text(name)),
+                Some(SqlValue::Integer(root_page)),
+            ) if kind == "table" && name == PACKAGES_TABLE => Some(*root_page as u32),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow!("Table {PACKAGES_TABLE} not found"))?;
+
+    let mut packages = Vec::new();
+    for row in file.table_rows(root_page)? {
+        //hnum is alias of rowid, stored as null
+        if let Some(SqlValue::Blob(blob)) = decode_record(&row)?.get(1) {
+            packages.extend(rpm_package(blob)?);
+        }
+    }
+    Ok(packages)
+}
+
+#[test]
+fn test_rpm_header() {
+    let mut index = Vec::new();
+    let mut data = Vec::new();
+    for (tag, data_type, value) in [
+        (RPMTAG_NAME, RPM_STRING_TYPE, &b"bash\0"[..]),
+        (RPMTAG_VERSION, RPM_STRING_TYPE, b"5.1.8\0"),
+        (RPMTAG_RELEASE, RPM_STRING_TYPE, b"6.el9\0\0\0"),
+        (RPMTAG_EPOCH, RPM_INT32_TYPE, &1_u32.to_be_bytes()),
+        (RPMTAG_LICENSE, RPM_I18NSTRING_TYPE, b"GPLv3+\0"),
+        (RPMTAG_ARCH, RPM_STRING_TYPE, b"x86_64\0"),
+    ] {
+        index.extend_from_slice(&tag.to_be_bytes());
+        index.extend_from_slice(&data_type.to_be_bytes());
+        index.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        index.extend_from_slice(&1_u32.to_be_bytes());
+        data.extend_from_slice(value);
+    }
+    let mut blob = Vec::new();
+    blob.extend_from_slice(&6_u32.to_be_bytes());
+    blob.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    blob.extend_from_slice(&index);
+    blob.extend_from_slice(&data);
+
+    let package = rpm_package(&blob).unwrap().unwrap();
+    assert_eq!(package.name, "bash");
+    assert_eq!(package.version, "1:5.1.8-6.el9");
+    assert_eq!(package.arch.as_deref(), Some("x86_64"));
+    assert_eq!(package.license.as_deref(), Some("GPLv3+"));
+    assert!(rpm_package(&blob[..blob.len() - 1]).is_err());
+    assert!(read_sqlite_packages(&blob, None).is_err());
+}
+
+#[test]
+fn test_rpm_sqlite_fixture() {
+    //created by sqlite3 with rpm schema in WAL mode (page size 1024), bash header spans
+    //overflow pages; glibc was removed and openssl-libs installed in not checkpointed transaction
+    let db = include_bytes!("testdata/rpmdb.sqlite");
+    let wal = include_bytes!("testdata/rpmdb.sqlite-wal");
+    let versions = |packages: Vec<Package>| {
+        packages
+            .into_iter()
+            .map(|p| format!("{} {}", p.name, p.version))
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(
+        versions(read_sqlite_packages(db, None).unwrap()),
+        ["bash 5.1.8-6.el9", "glibc 2.34-60.el9"]
+    );
+    let packages = read_sqlite_packages(db, Some(wal)).unwrap();
+    assert_eq!(packages[0].license.as_deref(), Some("GPLv3+"));
+    assert_eq!(
+        versions(packages),
+        ["bash 5.1.8-6.el9", "openssl-libs 1:3.0.7-27.el9"]
+    );
+
+    //torn frame after the commit is ignored
+    let mut torn = wal.to_vec();
+    torn.extend_from_slice(&wal[32..wal.len() / 2]);
+    assert_eq!(read_sqlite_packages(db, Some(&torn)).unwrap().len(), 2);
+    //frames with broken checksum are not applied
+    let mut corrupted = wal.to_vec();
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 0xff;
+    assert_eq!(
+        versions(read_sqlite_packages(db, Some(&corrupted)).unwrap()),
+        ["bash 5.1.8-6.el9", "glibc 2.34-60.el9"]
+    );
+    assert!(read_sqlite_packages(db, Some(&wal[..40])).is_ok());
+
+    //no truncation of the database may panic
+    for len in (0..db.len()).step_by(7) {
+        let _ = read_sqlite_packages(&db[..len], None);
+        let _ = read_sqlite_packages(db, Some(&wal[..len.min(wal.len())]));
+    }
+    let mut garbage = db.to_vec();
+    for i in (100..garbage.len()).step_by(13) {
+        garbage[i] = 0xff;
+    }
+    let _ = read_sqlite_packages(&garbage, None);
+}
@@ -0,0 +1,293 @@
+//! Ed25519 signatures of image descriptors.
+//!
+//! The signature covers the descriptor hash, which in turn covers hashes of all chunks
+//! and the image link, so it proves who published exactly this image.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use keyring::{Entry, Error};
+use serde::{Deserialize, Serialize};
+
+use crate::chunks::FileChunkDesc;
+
+const SIGNING_KEY_SERVICE_FIELD: &str = "gvmkit-build-rs-signing";
+//public keys of trusted publishers, hex encoded one per line
+const TRUSTED_KEYS_SERVICE_FIELD: &str = "gvmkit-build-rs-trusted";
+const TRUSTED_KEYS_USER_FIELD: &str = "trusted-keys";
+pub const DEFAULT_KEY_NAME: &str = "default";
+const SIGNATURE_ALGORITHM: &str = "ed25519";
+
+/// Signature file saved next to the image
+pub fn signature_path(image_path: &Path) -> PathBuf {
+    PathBuf::from(image_path.display().to_string() + ".descr.sig")
+}
+
+pub fn generate_signing_key() -> SigningKey {
+    SigningKey::generate(&mut rand::rngs::OsRng)
+}
+
+/// Secret key is stored as hex encoded 32 byte seed
+pub fn encode_signing_key(key: &SigningKey) -> String {
+    hex::encode(key.to_bytes())
+}
+
+pub fn parse_signing_key(value: &str) -> anyhow::Result<SigningKey> {
+    let bytes: [u8; 32] = hex::decode(value.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("Invalid signing key, expected 64 hex characters"))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+pub fn parse_public_key(value: &str) -> anyhow::Result<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(value.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("Invalid public key, expected 64 hex characters"))?;
+    Ok(VerifyingKey::from_bytes(&bytes)?)
+}
+
+/// Reads signing key from file, e.g. exported for CI
+pub fn read_signing_key_file(path: &Path) -> anyhow::Result<SigningKey> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Failed to read key file {}: {}", path.display(), e))?;
+    parse_signing_key(&content)
+}
+
+/// Writes secret key to file readable only by the owner
+pub fn write_signing_key_file(path: &Path, key: &SigningKey) -> anyhow::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .map_err(|e| anyhow!("Failed to create key file {}: {}", path.display(), e))?;
+    std::io::Write::write_all(&mut file, encode_signing_key(key).as_bytes())?;
+    Ok(())
+}
+
+pub fn save_signing_key(name: &str, key: &SigningKey) -> anyhow::Result<()> {
+    let entry = Entry::new(SIGNING_KEY_SERVICE_FIELD, name)?;
+    entry.set_password(&encode_signing_key(key))?;
+    Ok(())
+}
+
+/// Signing key stored in the keyring, None if there is no key with this name
+pub fn get_signing_key(name: &str) -> anyhow::Result<Option<SigningKey>> {
+    let entry = Entry::new(SIGNING_KEY_SERVICE_FIELD, name)?;
+    match entry.get_password() {
+        Ok(secret) => Ok(Some(parse_signing_key(&secret)?)),
+        Err(Error::NoEntry) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn load_signing_key(name: &str) -> anyhow::Result<SigningKey> {
+    get_signing_key(name)?.ok_or_else(|| {
+        anyhow!("Signing key {name} not found, create one with: gvmkit-build key generate --name {name}")
+    })
+}
+
+/// Returns false if there was no key with this name
+pub fn remove_signing_key(name: &str) -> anyhow::Result<bool> {
+    let entry = Entry::new(SIGNING_KEY_SERVICE_FIELD, name)?;
+    match entry.delete_password() {
+        Ok(_) => Ok(true),
+        Err(Error::NoEntry) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn parse_trusted_keys(content: &str) -> anyhow::Result<Vec<VerifyingKey>> {
+    content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_public_key)
+        .collect()
+}
+
+/// Public keys of trusted publishers stored in the keyring
+pub fn trusted_keys() -> anyhow::Result<Vec<VerifyingKey>> {
+    let entry = Entry::new(TRUSTED_KEYS_SERVICE_FIELD, TRUSTED_KEYS_USER_FIELD)?;
+    match entry.get_password() {
+        Ok(content) => parse_trusted_keys(&content),
+        Err(Error::NoEntry) => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn save_trusted_keys(keys: &[VerifyingKey]) -> anyhow::Result<()> {
+    let entry = Entry::new(TRUSTED_KEYS_SERVICE_FIELD, TRUSTED_KEYS_USER_FIELD)?;
+    if keys.is_empty() {
+        return match entry.delete_password() {
+            Ok(_) | Err(Error::NoEntry) => Ok(()),
+            Err(e) => Err(e.into()),
+        };
+    }
+    let content: Vec<String> = keys.iter().map(|k| hex::encode(k.as_bytes())).collect();
+    entry.set_password(&content.join("\n"))?;
+    Ok(())
+}
+
+/// Returns false if the key was already trusted
+pub fn trust_key(key: &VerifyingKey) -> anyhow::Result<bool> {
+    let mut keys = trusted_keys()?;
+    if keys.contains(key) {
+        return Ok(false);
+    }
+    keys.push(*key);
+    save_trusted_keys(&keys)?;
+    Ok(true)
+}
+
+/// Returns false if the key was not trusted
+pub fn untrust_key(key: &VerifyingKey) -> anyhow::Result<bool> {
+    let mut keys = trusted_keys()?;
+    let len = keys.len();
+    keys.retain(|k| k != key);
+    if keys.len() == len {
+        return Ok(false);
+    }
+    save_trusted_keys(&keys)?;
+    Ok(true)
+}
+
+/// Detached signature of the descriptor, all values hex encoded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageSignature {
+    pub algorithm: String,
+    pub public_key: String,
+    pub descriptor_hash: String,
+    pub signature: String,
+}
+
+impl ImageSignature {
+    pub fn sign(descr: &FileChunkDesc, key: &SigningKey) -> Self {
+        ImageSignature {
+            algorithm: SIGNATURE_ALGORITHM.to_string(),
+            public_key: hex::encode(key.verifying_key().as_bytes()),
+            descriptor_hash: descr.get_descr_hash_str(),
+            signature: hex::encode(key.sign(&descr.descr_hash).to_bytes()),
+        }
+    }
+
+    pub fn public_key(&self) -> anyhow::Result<VerifyingKey> {
+        parse_public_key(&self.public_key)
+    }
+
+    /// Checks if the signature is valid for the descriptor, returns key of the signer.
+    /// The caller decides if the key is trusted.
+    pub fn verify(&self, descr: &FileChunkDesc) -> anyhow::Result<VerifyingKey> {
+        if self.algorithm != SIGNATURE_ALGORITHM {
+            return Err(anyhow!(
+                "Unsupported signature algorithm {}",
+                self.algorithm
+            ));
+        }
+        if self.descriptor_hash != descr.get_descr_hash_str() {
+            return Err(anyhow!(
+                "Signature is for descriptor {}, not {}",
+                self.descriptor_hash,
+                descr.get_descr_hash_str()
+            ));
+        }
+        let public_key = self.public_key()?;
+        let signature: [u8; 64] = hex::decode(&self.signature)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| anyhow!("Invalid signature encoding"))?;
+        public_key
+            .verify(&descr.descr_hash, &Signature::from_bytes(&signature))
+            .map_err(|_| anyhow!("Invalid signature of descriptor {}", self.descriptor_hash))?;
+        Ok(public_key)
+    }
+
+    /// Checks the signature and that it was made with one of the trusted keys
+    pub fn verify_trusted(
+        &self,
+        descr: &FileChunkDesc,
+        trusted: &[VerifyingKey],
+    ) -> anyhow::Result<VerifyingKey> {
+        let public_key = self.verify(descr)?;
+        if !trusted.contains(&public_key) {
+            return Err(anyhow!(
+                "Image signed with untrusted key {}",
+                self.public_key
+            ));
+        }
+        Ok(public_key)
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path)
+            .map_err(|e| anyhow!("Failed to read signature {}: {}", path.display(), e))?;
+        serde_json::from_slice(&bytes)
+            .map_err(|e| anyhow!("Invalid signature file {}: {}", path.display(), e))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_descriptor_signature() {
+    use crate::chunks::create_descriptor_from_reader;
+
+    let data = vec![7_u8; 10000];
+    let descr = create_descriptor_from_reader(&data[..], data.len() as u64, 1000)
+        .await
+        .unwrap();
+    let key = generate_signing_key();
+    let signature = ImageSignature::sign(&descr, &key);
+    assert_eq!(signature.verify(&descr).unwrap(), key.verifying_key());
+
+    //key survives export and import
+    let imported = parse_signing_key(&encode_signing_key(&key)).unwrap();
+    assert_eq!(imported.verifying_key(), key.verifying_key());
+    assert!(parse_signing_key("abcd").is_err());
+
+    //signature is written next to the image
+    let temp_dir = tempfile::tempdir().unwrap();
+    let path = signature_path(&temp_dir.path().join("image.gvmi"));
+    assert!(path.ends_with("image.gvmi.descr.sig"));
+    signature.save(&path).unwrap();
+    assert_eq!(ImageSignature::load(&path).unwrap(), signature);
+
+    //other descriptor, forged signature or replaced public key are rejected
+    let other = create_descriptor_from_reader(&data[..], data.len() as u64, 2000)
+        .await
+        .unwrap();
+    assert!(signature.verify(&other).is_err());
+    let forged = ImageSignature {
+        descriptor_hash: other.get_descr_hash_str(),
+        ..signature.clone()
+    };
+    assert!(forged.verify(&other).is_err());
+    let other_key = generate_signing_key();
+    let replaced_key = ImageSignature {
+        public_key: hex::encode(other_key.verifying_key().as_bytes()),
+        ..signature.clone()
+    };
+    assert!(replaced_key.verify(&descr).is_err());
+
+    //valid signature is accepted only with trusted key
+    let trusted = parse_trusted_keys(&format!(
+        "{}\n\n{}\n",
+        hex::encode(other_key.verifying_key().as_bytes()),
+        signature.public_key
+    ))
+    .unwrap();
+    assert_eq!(trusted.len(), 2);
+    assert!(signature.verify_trusted(&descr, &trusted).is_ok());
+    assert!(signature.verify_trusted(&descr, &trusted[..1]).is_err());
+    assert!(signature.verify_trusted(&descr, &[]).is_err());
+    assert!(ImageSignature::sign(&other, &other_key)
+        .verify(&other)
+        .is_ok());
+}
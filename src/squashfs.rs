@@ -0,0 +1,254 @@
+use std::fs;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use anyhow::anyhow;
+use backhand::compression::{CompressionOptions, Compressor, Gzip, Lz4, Zstd};
+use backhand::{FilesystemCompressor, FilesystemWriter, NodeHeader};
+use indicatif::ProgressBar;
+
+use crate::rootfs::{EntryKind, EntryMeta, RootfsTree};
+
+pub const COMPRESSION_POSSIBLE_VALUES: &[&str] = &["lzo", "gzip", "lz4", "zstd", "xz"];
+
+/// Options used when writing squashfs image
+#[derive(Debug, Clone)]
+pub struct SquashfsOptions {
+    pub compression_method: String,
+    pub compression_level: Option<u32>,
+    /// Modification time stored in superblock, current time if not set
+    pub mod_time: Option<u32>,
+}
+
+impl Default for SquashfsOptions {
+    fn default() -> Self {
+        SquashfsOptions {
+            compression_method: "lzo".to_string(),
+            compression_level: None,
+            mod_time: None,
+        }
+    }
+}
+
+impl SquashfsOptions {
+    fn compressor(&self) -> anyhow::Result<FilesystemCompressor> {
+        let level = self.compression_level;
+        let (id, options) = match self.compression_method.as_str() {
+            //backhand writes lzo1x_1 only, there are no levels to choose from
+            "lzo" | "lz4" | "xz" if level.is_some() => {
+                return Err(anyhow!(
+                    "Compression method {} does not support compression level",
+                    self.compression_method
+                ));
+            }
+            "lzo" => (Compressor::Lzo, None),
+            "gzip" => {
+                let compression_level = level.unwrap_or(9);
+                if !(1..=9).contains(&compression_level) {
+                    return Err(anyhow!(
+                        "Invalid gzip compression level {}, possible values 1-9",
+                        compression_level
+                    ));
+                }
+                (
+                    Compressor::Gzip,
+                    Some(CompressionOptions::Gzip(Gzip {
+                        compression_level,
+                        window_size: 15,
+                        strategies: 0,
+                    })),
+                )
+            }
+            "zstd" => {
+                let compression_level = level.unwrap_or(15);
+                if !(1..=22).contains(&compression_level) {
+                    return Err(anyhow!(
+                        "Invalid zstd compression level {}, possible values 1-22",
+                        compression_level
+                    ));
+                }
+                (
+                    Compressor::Zstd,
+                    Some(CompressionOptions::Zstd(Zstd { compression_level })),
+                )
+            }
+            //lz4 without high compression mode, not available in backhand
+            "lz4" => (
+                Compressor::Lz4,
+                Some(CompressionOptions::Lz4(Lz4 {
+                    version: 1,
+                    flags: 0,
+                })),
+            ),
+            "xz" => (Compressor::Xz, None),
+            other => {
+                return Err(anyhow!(
+                    "Not supported compression method: {}, possible values {}",
+                    other,
+                    COMPRESSION_POSSIBLE_VALUES.join(", ")
+                ));
+            }
+        };
+        FilesystemCompressor::new(id, options)
+            .map_err(|e| anyhow!("Invalid compression options: {}", e))
+    }
+}
+
+fn node_header(meta: &EntryMeta) -> NodeHeader {
+    NodeHeader::new(meta.mode, meta.uid, meta.gid, meta.mtime)
+}
+
+/// Counts bytes written to show progress of squashfs creation
+struct ProgressWriter<W> {
+    inner: W,
+    pb: ProgressBar,
+}
+
+impl<W: Write> Write for ProgressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.pb.inc(written as u64);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for ProgressWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Writes squashfs image of the tree to `output` (file is truncated).
+/// Returns size of written image in bytes.
+pub fn write_squashfs(
+    tree: &RootfsTree,
+    output: &Path,
+    options: &SquashfsOptions,
+    pb: &ProgressBar,
+) -> anyhow::Result<u64> {
+    let mut writer = FilesystemWriter::default();
+    writer.set_compressor(options.compressor()?);
+    match options.mod_time {
+        Some(mod_time) => writer.set_time(mod_time),
+        None => writer.set_current_time(),
+    }
+    let root = tree.root_meta();
+    writer.set_root_mode(root.mode);
+    writer.set_root_uid(root.uid);
+    writer.set_root_gid(root.gid);
+
+    for (path, entry) in tree.entries() {
+        let header = node_header(&entry.meta);
+        match &entry.kind {
+            EntryKind::Dir => writer.push_dir(path, header),
+            EntryKind::File { source, .. } => {
+                writer.push_file_from_path(source.clone(), path, header)
+            }
+            EntryKind::Symlink(link) => writer.push_symlink(link.clone(), path, header),
+            EntryKind::CharDevice(device) => writer.push_char_device(*device, path, header),
+            EntryKind::BlockDevice(device) => writer.push_block_device(*device, path, header),
+            EntryKind::Fifo => writer.push_fifo(path, header),
+        }
+        .map_err(|e| anyhow!("Failed to add {} to image: {}", path.display(), e))?;
+    }
+
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output)?;
+    let mut out = ProgressWriter {
+        inner: BufWriter::new(file),
+        pb: pb.clone(),
+    };
+    let (_superblock, bytes_written) = writer
+        .write(&mut out)
+        .map_err(|e| anyhow!("Failed to write squashfs image: {}", e))?;
+    out.flush()?;
+    Ok(bytes_written)
+}
+
+#[test]
+fn test_write_squashfs() {
+    use crate::rootfs::RootfsEntry;
+    use backhand::{FilesystemReader, InnerNode};
+    use std::io::Read;
+    use std::path::PathBuf;
+
+    let mut rng = fastrand::Rng::new();
+    rng.seed(1234);
+    let content: Vec<u8> = std::iter::repeat_with(|| rng.u8(..)).take(300000).collect();
+
+    let temp_dir = tempfile::tempdir().unwrap();
+    let mut tree = RootfsTree::new(temp_dir.path()).unwrap();
+    let (source, size) = tree.stage_content(&content[..]).unwrap();
+    let meta = EntryMeta {
+        mode: 0o640,
+        uid: 1000,
+        gid: 100,
+        mtime: 1700000000,
+    };
+    tree.insert(
+        PathBuf::from("/data/file.bin"),
+        RootfsEntry {
+            kind: EntryKind::File { source, size },
+            meta,
+        },
+    )
+    .unwrap();
+    tree.insert(
+        PathBuf::from("/link"),
+        RootfsEntry {
+            kind: EntryKind::Symlink(PathBuf::from("data/file.bin")),
+            meta: EntryMeta::default(),
+        },
+    )
+    .unwrap();
+
+    for method in COMPRESSION_POSSIBLE_VALUES {
+        let output = temp_dir.path().join(format!("image-{method}.squashfs"));
+        let options = SquashfsOptions {
+            compression_method: method.to_string(),
+            ..Default::default()
+        };
+        let written = write_squashfs(&tree, &output, &options, &ProgressBar::hidden()).unwrap();
+        assert_eq!(written, fs::metadata(&output).unwrap().len());
+
+        let reader = FilesystemReader::from_reader(std::io::BufReader::new(
+            fs::File::open(&output).unwrap(),
+        ))
+        .unwrap();
+        let mut found_file = false;
+        for node in reader.files() {
+            if node.fullpath == Path::new("/data/file.bin") {
+                let InnerNode::File(file) = &node.inner else {
+                    panic!("Expected file");
+                };
+                let mut read_back = Vec::new();
+                reader
+                    .file(file)
+                    .reader()
+                    .read_to_end(&mut read_back)
+                    .unwrap();
+                assert_eq!(read_back, content);
+                assert_eq!(node.header.permissions, 0o640);
+                assert_eq!(node.header.uid, 1000);
+                found_file = true;
+            }
+        }
+        assert!(found_file, "file not found in {method} image");
+    }
+
+    for method in ["xz", "lzo"] {
+        let invalid = SquashfsOptions {
+            compression_method: method.to_string(),
+            compression_level: Some(3),
+            ..Default::default()
+        };
+        assert!(invalid.compressor().is_err());
+    }
+}
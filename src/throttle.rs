@@ -0,0 +1,287 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::anyhow;
+use chrono::Timelike;
+use humansize::DECIMAL;
+use tokio::time::Instant;
+
+/// Parses transfer rate in bytes per second, e.g. `500KB`, `2MiB/s`, `20Mbit`
+pub fn parse_rate(value: &str) -> anyhow::Result<u64> {
+    let value = value.trim();
+    let value = value.strip_suffix("/s").unwrap_or(value);
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid rate: {value}, expected e.g. 500KB, 2MB, 20Mbit"))?;
+    let multiplier = match unit.trim() {
+        "" | "B" => 1.0,
+        "k" | "K" | "kB" | "KB" => 1e3,
+        "KiB" => 1024.0,
+        "M" | "MB" => 1e6,
+        "MiB" => 1024.0 * 1024.0,
+        "G" | "GB" => 1e9,
+        "GiB" => 1024.0 * 1024.0 * 1024.0,
+        "kbit" | "Kbit" => 1e3 / 8.0,
+        "Mbit" => 1e6 / 8.0,
+        "Gbit" => 1e9 / 8.0,
+        unit => return Err(anyhow!("Unknown rate unit: {unit}")),
+    };
+    let rate = (number * multiplier) as u64;
+    if rate == 0 {
+        return Err(anyhow!("Rate has to be greater than zero"));
+    }
+    Ok(rate)
+}
+
+fn parse_time_of_day(value: &str) -> anyhow::Result<u32> {
+    let (hours, minutes) = value
+        .trim()
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Invalid time: {value}, expected HH:MM"))?;
+    let hours: u32 = hours
+        .parse()
+        .map_err(|_| anyhow!("Invalid time: {value}"))?;
+    let minutes: u32 = minutes
+        .parse()
+        .map_err(|_| anyhow!("Invalid time: {value}"))?;
+    //24:00 is allowed as end of the day
+    if hours > 24 || minutes > 59 || (hours == 24 && minutes > 0) {
+        return Err(anyhow!("Invalid time: {value}"));
+    }
+    Ok(hours * 60 + minutes)
+}
+
+/// Upload limit used between `start` and `end` (minutes of the day, local time)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleRule {
+    pub start: u32,
+    pub end: u32,
+    /// None means unlimited
+    pub rate: Option<u64>,
+}
+
+impl ScheduleRule {
+    fn contains(&self, minute: u32) -> bool {
+        if self.start < self.end {
+            minute >= self.start && minute < self.end
+        } else {
+            //range going past midnight, e.g. 22:00-06:00
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+/// Time-of-day upload limits, e.g. `09:00-17:00=1MB,17:00-09:00=unlimited`.
+/// First matching rule wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UploadSchedule {
+    pub rules: Vec<ScheduleRule>,
+}
+
+impl UploadSchedule {
+    pub fn parse(value: &str) -> anyhow::Result<Self> {
+        let mut rules = Vec::new();
+        for rule in value.split(',').filter(|rule| !rule.trim().is_empty()) {
+            let (range, rate) = rule.split_once('=').ok_or_else(|| {
+                anyhow!("Invalid schedule rule: {rule}, expected HH:MM-HH:MM=RATE")
+            })?;
+            let (start, end) = range
+                .split_once('-')
+                .ok_or_else(|| anyhow!("Invalid schedule range: {range}, expected HH:MM-HH:MM"))?;
+            let rate = match rate.trim() {
+                "unlimited" => None,
+                rate => Some(parse_rate(rate)?),
+            };
+            rules.push(ScheduleRule {
+                start: parse_time_of_day(start)?,
+                end: parse_time_of_day(end)?,
+                rate,
+            });
+        }
+        if rules.is_empty() {
+            return Err(anyhow!("Upload schedule is empty"));
+        }
+        Ok(UploadSchedule { rules })
+    }
+
+    /// Limit for given minute of the day, None if no rule matches
+    pub fn rule_at(&self, minute: u32) -> Option<&ScheduleRule> {
+        self.rules.iter().find(|rule| rule.contains(minute))
+    }
+}
+
+fn format_rate(rate: Option<u64>) -> String {
+    match rate {
+        Some(rate) => format!("{}/s", humansize::format_size(rate, DECIMAL)),
+        None => "unlimited".to_string(),
+    }
+}
+
+impl fmt::Display for UploadSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rules: Vec<String> = self
+            .rules
+            .iter()
+            .map(|rule| {
+                format!(
+                    "{:02}:{:02}-{:02}:{:02} {}",
+                    rule.start / 60,
+                    rule.start % 60,
+                    rule.end / 60,
+                    rule.end % 60,
+                    format_rate(rule.rate)
+                )
+            })
+            .collect();
+        write!(f, "{}", rules.join(", "))
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Option<u64>,
+    tokens: f64,
+    last: Instant,
+}
+
+/// Token bucket shared by all upload streams, clones use the same bucket.
+/// Allows bursts of up to one second of traffic.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    max_rate: Option<u64>,
+    schedule: Option<UploadSchedule>,
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    /// Limiter with constant rate (bytes per second), None means unlimited
+    pub fn new(max_rate: Option<u64>) -> Self {
+        RateLimiter {
+            max_rate,
+            schedule: None,
+            bucket: Arc::new(Mutex::new(Bucket {
+                rate: max_rate,
+                tokens: 0.0,
+                last: Instant::now(),
+            })),
+        }
+    }
+
+    /// Schedule takes precedence over constant rate in hours it covers
+    pub fn with_schedule(mut self, schedule: UploadSchedule) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Rate at given minute of the day
+    pub fn rate_at(&self, minute: u32) -> Option<u64> {
+        match self.schedule.as_ref().and_then(|s| s.rule_at(minute)) {
+            Some(rule) => rule.rate,
+            None => self.max_rate,
+        }
+    }
+
+    /// Rate in effect now (local time)
+    pub fn current_rate(&self) -> Option<u64> {
+        let now = chrono::Local::now();
+        self.rate_at(now.hour() * 60 + now.minute())
+    }
+
+    /// Waits until `bytes` can be sent without exceeding current rate
+    pub async fn acquire(&self, bytes: u64) {
+        let rate = self.current_rate();
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            if bucket.rate != rate {
+                //schedule switched to another limit, start with empty bucket
+                bucket.rate = rate;
+                bucket.tokens = 0.0;
+                bucket.last = now;
+            }
+            let Some(rate) = rate else {
+                return;
+            };
+            let rate = rate as f64;
+            let elapsed = now.duration_since(bucket.last).as_secs_f64();
+            bucket.last = now;
+            bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+            //tokens can go below zero, next callers wait for the debt to be paid
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens < 0.0 {
+                Duration::from_secs_f64(-bucket.tokens / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+impl fmt::Display for RateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.schedule {
+            Some(schedule) => write!(
+                f,
+                "{}, other hours {}",
+                schedule,
+                format_rate(self.max_rate)
+            ),
+            None => write!(f, "{}", format_rate(self.max_rate)),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_rate_limiter() {
+    assert_eq!(parse_rate("500KB").unwrap(), 500_000);
+    assert_eq!(parse_rate("2MiB/s").unwrap(), 2 * 1024 * 1024);
+    assert_eq!(parse_rate("1.5MB").unwrap(), 1_500_000);
+    assert_eq!(parse_rate("80Mbit").unwrap(), 10_000_000);
+    assert!(parse_rate("fast").is_err());
+    assert!(parse_rate("0").is_err());
+
+    let schedule = UploadSchedule::parse("09:00-17:00=1MB, 22:00-06:00=unlimited").unwrap();
+    let limiter = RateLimiter::new(Some(5_000_000)).with_schedule(schedule);
+    assert_eq!(limiter.rate_at(9 * 60), Some(1_000_000));
+    assert_eq!(limiter.rate_at(17 * 60), Some(5_000_000));
+    assert_eq!(limiter.rate_at(23 * 60), None);
+    assert_eq!(limiter.rate_at(5 * 60 + 59), None);
+    assert_eq!(
+        limiter.to_string(),
+        "09:00-17:00 1 MB/s, 22:00-06:00 unlimited, other hours 5 MB/s"
+    );
+    assert!(UploadSchedule::parse("09:00-25:00=1MB").is_err());
+    assert!(UploadSchedule::parse("09:00=1MB").is_err());
+
+    //rate is shared by all clones of the limiter
+    let limiter = RateLimiter::new(Some(10_000_000));
+    let start = Instant::now();
+    let tasks: Vec<_> = (0..4)
+        .map(|_| {
+            let limiter = limiter.clone();
+            tokio::spawn(async move {
+                for _ in 0..5 {
+                    limiter.acquire(100_000).await;
+                }
+            })
+        })
+        .collect();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    //2 MB at 10 MB/s
+    assert!(start.elapsed() >= Duration::from_millis(190));
+
+    let unlimited = RateLimiter::new(None);
+    let start = Instant::now();
+    unlimited.acquire(1_000_000_000).await;
+    assert!(start.elapsed() < Duration::from_millis(50));
+}
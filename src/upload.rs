@@ -1,23 +1,30 @@
-use std::collections::VecDeque;
-use std::env;
+use std::collections::{HashSet, VecDeque};
 
 use anyhow::anyhow;
+use async_trait::async_trait;
 use futures_util::{stream, StreamExt};
 use humansize::DECIMAL;
 use indicatif::{MultiProgress, ProgressBar};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use once_cell::sync::Lazy;
-use reqwest::{multipart, Body};
+use reqwest::{multipart, Body, Method};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sha2::{Digest, Sha256};
 use tokio::io::AsyncReadExt;
 
-use crate::chunks::{FileChunk, FileChunkDesc};
+use crate::backend::RegistryBackend;
+use crate::chunks::{ChunkHash, FileChunk, FileChunkDesc};
+use crate::client::RegistryClient;
+use crate::delta::{reuse_base_chunks, ChunkReuse, DeltaBase};
 use crate::image::ImageName;
+use crate::journal::{default_journal_dir, format_chunk_list, UploadJournal};
 use crate::progress::{create_chunk_pb, ProgressBarType};
+use crate::retry::{RetryPolicy, UploadError};
+use crate::signing::ImageSignature;
 use crate::wrapper::stream_file_with_progress;
 
 async fn load_bytes_and_sha(descr_path: &Path) -> anyhow::Result<(Vec<u8>, String)> {
@@ -28,15 +35,7 @@ async fn load_bytes_and_sha(descr_path: &Path) -> anyhow::Result<(Vec<u8>, Strin
     Ok((file_descr_bytes, descr_sha256))
 }
 
-fn get_repository_url() -> String {
-    let repo_url = env::var("REGISTRY_URL").unwrap_or("https://registry.golem.network".to_string());
-    let repo_url = repo_url.trim_end_matches('/').to_string();
-    repo_url
-}
-
-pub static REGISTRY_URL: Lazy<String> = Lazy::new(get_repository_url);
-
-#[derive(Debug, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Deserialize)]
 pub struct ValidateUploadResponse {
     pub descriptor: String,
     pub version: Option<u64>,
@@ -44,47 +43,10 @@ pub struct ValidateUploadResponse {
     pub chunks: Option<Vec<u64>>,
 }
 
-pub async fn check_login(user_name: &str, pat: &str) -> anyhow::Result<bool> {
-    println!(" * Checking credentials for {}...", user_name);
-    let repo_url = REGISTRY_URL.as_str();
-
-    let check_login_endpoint = format!("{repo_url}/auth/pat/login");
-    //println!("Validating image at: {}", validate_endpoint);
-
-    let post_data = json!(
-        {
-            "username": user_name,
-            "password": pat,
-        }
-    );
-    let client = reqwest::Client::new();
-    let response = client
-        .post(check_login_endpoint)
-        .json(&post_data)
-        .send()
-        .await
-        .map_err(|e| anyhow::anyhow!("Repository status check failed: {}", e))?;
-
-    let response_status = response.status();
-    match response_status {
-        reqwest::StatusCode::OK => {
-            println!(" -- successfully logged in");
-            Ok(true)
-        }
-        reqwest::StatusCode::UNAUTHORIZED => {
-            let text = response.text().await.unwrap_or_default();
-            println!(" -- failed to log in: {}", text);
-            Ok(false)
-        }
-        _ => {
-            let text = response.text().await.unwrap_or_default();
-            Err(anyhow::anyhow!(
-                "Other error when checking login, status: {}, err: {}",
-                response_status,
-                text
-            ))
-        }
-    }
+#[derive(Debug, Clone, serde::Deserialize)]
+struct ReuseChunksResponse {
+    //chunks copied from base descriptor
+    reused: Vec<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -95,84 +57,140 @@ pub struct AttachInfo {
     pub tag: String,
 }
 
-pub async fn attach_to_repo(
-    descr_sha256: &str,
-    image_name: &ImageName,
-    login: &str,
-    pat: &str,
-    check: bool,
-) -> anyhow::Result<AttachInfo> {
-    let Some(image_user_name) = image_name.user.clone() else {
-        return Err(anyhow::anyhow!("Image name must contain user"));
-    };
-    let repo_url = REGISTRY_URL.as_str();
-
-    let add_tag_endpoint = format!("{repo_url}/v1/image/descr/attach/{descr_sha256}");
-    let form = multipart::Form::new();
-    let form = form.text("tag", image_name.tag.clone());
-    let form = form.text("username", image_user_name.clone());
-    let form = form.text("repository", image_name.repository.clone());
-    let form = form.text("login", login.to_string());
-    let form = form.text("token", pat.to_string());
-    let form = if check {
-        form.text("check", "true")
-    } else {
-        form
-    };
+/// Options used when uploading image chunks
+#[derive(Debug, Clone)]
+pub struct PushOptions {
+    pub upload_workers: usize,
+    /// Continue upload recorded in local journal instead of starting a new one
+    pub resume: bool,
+    pub journal_dir: PathBuf,
+    /// Delay between checks of upload status while registry is syncing chunks
+    pub validate_interval: Duration,
+    /// Previous version of the image, chunks shared with it are reused instead of uploaded
+    pub delta_base: Option<DeltaBase>,
+    /// Signature of the descriptor uploaded together with it
+    pub signature: Option<ImageSignature>,
+}
 
-    if check {
-        println!(
-            " * Checking if image can be added to repository: {}",
-            image_name.to_normalized_name()
-        );
-    } else {
-        println!(
-            " * Adding image to repository: {}",
-            image_name.to_normalized_name()
-        );
+impl Default for PushOptions {
+    fn default() -> Self {
+        PushOptions {
+            upload_workers: 4,
+            resume: false,
+            journal_dir: default_journal_dir(),
+            validate_interval: Duration::from_secs(5),
+            delta_base: None,
+            signature: None,
+        }
     }
-    let client = reqwest::Client::new();
-    let response = client
-        .post(add_tag_endpoint)
-        .multipart(form)
-        .send()
-        .await
-        .map_err(|e| anyhow::anyhow!("Repository status check failed: {}", e))?;
+}
 
-    if response.status() != 200 {
-        return match response.text().await {
-            Ok(text) => Err(anyhow::anyhow!(
-                "Not possible to add to repository: {}",
-                text
-            )),
-            Err(e) => Err(anyhow::anyhow!("Not possible to add to repository: {}", e)),
-        };
+/// Progress bars shared by chunk upload workers
+#[derive(Clone)]
+pub struct UploadProgress {
+    pub mc: MultiProgress,
+    pub pb_chunks: ProgressBar,
+    pub pb_details: ProgressBar,
+    pub pb_total: ProgressBar,
+}
+
+/// Removes progress of unfinished chunk from total progress bars, also when upload is cancelled by timeout
+struct ChunkProgressGuard {
+    mc: MultiProgress,
+    pb_chunk: ProgressBar,
+    pb_details: ProgressBar,
+    pb_total: ProgressBar,
+    finished: bool,
+}
+
+impl Drop for ChunkProgressGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            let sent = self.pb_chunk.position();
+            self.pb_details
+                .set_position(self.pb_details.position().saturating_sub(sent));
+            self.pb_total
+                .set_position(self.pb_total.position().saturating_sub(sent));
+        }
+        self.mc.remove(&self.pb_chunk);
     }
-    let text = response.text().await?;
-    if check {
-        println!(" -- checked successfully");
-    } else {
-        println!(" -- success: {}", text);
+}
+
+/// Uploads chunk repeating failed attempts according to retry policy, every attempt is recorded in journal
+async fn upload_chunk_with_retries(
+    upload: impl Fn() -> tokio::task::JoinHandle<Result<(), UploadError>>,
+    chunk_no: u64,
+    policy: RetryPolicy,
+    journal: Arc<Mutex<UploadJournal>>,
+    cancelled: Arc<AtomicBool>,
+) -> Result<(), UploadError> {
+    let mut retry = 0;
+    loop {
+        if cancelled.load(Ordering::Relaxed) {
+            return Err(UploadError::fatal("upload cancelled"));
+        }
+        journal.lock().unwrap().start_attempt(chunk_no);
+        let attempt = upload();
+        let abort_handle = attempt.abort_handle();
+        let res = match policy.chunk_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, attempt).await {
+                Ok(res) => res,
+                Err(_) => {
+                    abort_handle.abort();
+                    Ok(Err(UploadError::retryable(format!(
+                        "Chunk upload timed out after {}",
+                        humantime::format_duration(timeout)
+                    ))))
+                }
+            },
+            None => attempt.await,
+        };
+        let err = match res {
+            Ok(Ok(())) => {
+                journal.lock().unwrap().record_success(chunk_no);
+                return Ok(());
+            }
+            Ok(Err(err)) => err,
+            Err(join_err) => UploadError::fatal(format!("Upload task failed: {join_err}")),
+        };
+        journal
+            .lock()
+            .unwrap()
+            .record_failure(chunk_no, &err.to_string());
+        if !err.is_retryable() || retry >= policy.max_retries {
+            return Err(err);
+        }
+        retry += 1;
+        let delay = policy.delay(retry, err.retry_after());
+        log::warn!(
+            "Chunk {} upload failed: {}, retry {}/{} in {}",
+            chunk_no,
+            err,
+            retry,
+            policy.max_retries,
+            humantime::format_duration(Duration::from_millis(delay.as_millis() as u64))
+        );
+        tokio::time::sleep(delay).await;
     }
-    Ok(AttachInfo {
-        url: repo_url.to_string(),
-        repo: image_name.repository.clone(),
-        tag: image_name.tag.clone(),
-        user: image_user_name,
-    })
 }
 
 //returns if full upload is needed
-pub async fn upload_descriptor(descr_path: &Path) -> anyhow::Result<bool> {
-    let repo_url = REGISTRY_URL.as_str();
+pub async fn upload_descriptor<B: RegistryBackend>(
+    registry: &B,
+    descr_path: &Path,
+) -> anyhow::Result<bool> {
     let (_, descr_sha256) = load_bytes_and_sha(descr_path).await?;
-    let vu = validate_upload(&descr_sha256).await?;
+    let vu = registry.validate_upload(&descr_sha256).await?;
     if vu.descriptor != "ok" {
         //upload descriptor if not found
-        push_descr(descr_path).await?;
+        registry.push_descr(descr_path).await?;
     } else {
         println!(" -- descriptor already uploaded");
-        println!(" -- download link: {}/download/{}", repo_url, descr_sha256);
+        println!(
+            " -- download link: {}/download/{}",
+            registry.base_url(),
+            descr_sha256
+        );
     }
     if let Some(status) = vu.status {
         if status == "full" {
@@ -184,36 +202,94 @@ pub async fn upload_descriptor(descr_path: &Path) -> anyhow::Result<bool> {
     Ok(true)
 }
 
-pub async fn full_upload(
+pub async fn full_upload<B: RegistryBackend>(
+    registry: &B,
     path: &Path,
     descr: &FileChunkDesc,
-    upload_workers: usize,
+    options: &PushOptions,
 ) -> anyhow::Result<()> {
-    let vu = validate_upload(&descr.get_descr_hash_str()).await?;
+    let vu = registry
+        .validate_upload(&descr.get_descr_hash_str())
+        .await?;
     if vu.descriptor != "ok" {
         return Err(anyhow!("Failed to register descriptor in repository"));
     }
 
     if let Some(status) = &vu.status {
         if status != "full" {
-            push_chunks(path, descr, vu.chunks, upload_workers).await?;
+            let journal = UploadJournal::open(&options.journal_dir, path, descr, options.resume)?;
+            if options.resume {
+                println!(
+                    " -- resuming upload from journal {}",
+                    journal.path().display()
+                );
+                println!(
+                    " -- chunks uploaded before: {}/{}",
+                    journal.uploaded_chunks().len(),
+                    descr.chunks.len()
+                );
+                let failed = journal.failed_chunks();
+                if !failed.is_empty() {
+                    println!(" -- chunks to retry: {}", format_chunk_list(&failed));
+                }
+            }
+            let journal = Arc::new(Mutex::new(journal));
+            let reused_chunks = match &options.delta_base {
+                Some(delta_base) => {
+                    reuse_base_chunks(registry, descr, delta_base, &journal)
+                        .await?
+                        .accepted
+                }
+                None => Vec::new(),
+            };
+            push_chunks(
+                registry,
+                path,
+                descr,
+                vu.chunks,
+                &reused_chunks,
+                options.upload_workers,
+                journal.clone(),
+            )
+            .await?;
             //Golem Registry is using NFS - wait for files to sync on server side.
             //Two seconds should be enough
             let mut tries = 1;
             loop {
-                let vu = validate_upload(&descr.get_descr_hash_str()).await?;
+                let vu = registry
+                    .validate_upload(&descr.get_descr_hash_str())
+                    .await?;
                 if vu.status.unwrap_or_default() != "full" {
                     tries += 1;
                     if tries > 6 {
-                        break Err(anyhow!("Failed to validate image upload"));
+                        //chunks still missing on server side have to be uploaded again on resume
+                        let mut journal = journal.lock().unwrap();
+                        for (chunk_no, uploaded) in vu.chunks.unwrap_or_default().iter().enumerate()
+                        {
+                            if *uploaded == 0 {
+                                journal
+                                    .record_failure(chunk_no as u64, "not confirmed by registry");
+                            }
+                        }
+                        break Err(anyhow!(
+                            "Failed to validate image upload, run push with --resume to continue"
+                        ));
                     }
                     println!(
-                        "Image not validated, trying again in 5 seconds {}/{}",
-                        tries, 6
+                        "Image not validated, trying again in {} {}/{}",
+                        humantime::format_duration(options.validate_interval),
+                        tries,
+                        6
                     );
-                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    tokio::time::sleep(options.validate_interval).await;
                 } else {
                     println!(" -- image validated successfully");
+                    let mut journal = journal.lock().unwrap();
+                    let retried = journal.retried_chunks();
+                    if !retried.is_empty() {
+                        println!(" -- chunks retried: {}", format_chunk_list(&retried));
+                    }
+                    journal.remove()?;
                     break Ok(());
                 }
             }
@@ -226,147 +302,71 @@ pub async fn full_upload(
     }
 }
 
-pub async fn validate_upload(descr_sha256: &str) -> anyhow::Result<ValidateUploadResponse> {
-    let repo_url = REGISTRY_URL.as_str();
-
-    let validate_endpoint = format!("{repo_url}/v1/image/descr/{descr_sha256}");
-    //println!("Validating image at: {}", validate_endpoint);
-    let client = reqwest::Client::new();
-    let response = client
-        .get(validate_endpoint)
-        .send()
-        .await
-        .map_err(|e| anyhow::anyhow!("Repository status check failed: {}", e))?;
-
-    let response = response.json::<ValidateUploadResponse>().await?;
-    Ok(response)
-}
-
-pub async fn push_descr(file_path: &Path) -> anyhow::Result<()> {
-    let repo_url = REGISTRY_URL.as_str();
-    println!(" * Uploading image descriptor to: {}", repo_url);
-    let (_, descr_sha256) = load_bytes_and_sha(file_path).await?;
-
-    let descr_endpoint = format!("{repo_url}/v1/image/push/descr");
-    let client = reqwest::Client::new();
-    let form = multipart::Form::new();
-    let pb = create_chunk_pb(1, ProgressBarType::DescriptorUpload);
-
-    let file_stream =
-        stream_file_with_progress(file_path, None, Some(pb.clone()), None, None).await?;
-    let body = Body::wrap_stream(file_stream);
-    let some_file = multipart::Part::stream(body)
-        .file_name("descriptor.txt")
-        .mime_str("application/octet-stream")?;
-    let form = form.part("file", some_file);
-
-    let res = client
-        .post(descr_endpoint)
-        .multipart(form)
-        .send()
-        .await
-        .map_err(|e| anyhow::anyhow!("Image upload error: {}", e));
-
-    pb.finish_and_clear();
-    match res {
-        Ok(res) => {
-            if res.status().is_success() {
-                println!(" -- descriptor uploaded successfully");
-                println!(" -- download link: {}/download/{}", repo_url, descr_sha256);
-            } else {
-                return Err(anyhow::anyhow!(
-                    "Image upload failed with code {}: {}",
-                    res.status().as_u16(),
-                    res.text().await.unwrap_or_default()
-                ));
-            }
-            Ok(())
-        }
-        Err(e) => {
-            println!("Image upload failed: {}", e);
-            Err(e)
-        }
+/// Uploads descriptor and image chunks (if not already present in registry)
+/// and attaches the image to the given repository tag.
+/// Returns information about the tag the image was attached to.
+pub async fn push_image<B: RegistryBackend>(
+    registry: &B,
+    path: &Path,
+    descr: &FileChunkDesc,
+    descr_path: &Path,
+    push_image_name: Option<&ImageName>,
+    options: &PushOptions,
+) -> anyhow::Result<Option<AttachInfo>> {
+    println!("Uploading image to golem registry: {}", registry.base_url());
+    if let Some(signature) = &options.signature {
+        signature.verify(descr)?;
     }
-}
-
-pub async fn upload_single_chunk(
-    file_path: PathBuf,
-    chunk: FileChunk,
-    //file_descr: FileChunkDesc,
-    descr_sha256: String,
-    mc: MultiProgress,
-    pb_chunks: ProgressBar,
-    pb_details: ProgressBar,
-    pb_total: ProgressBar,
-) -> anyhow::Result<()> {
-    let repo_url = REGISTRY_URL.as_str();
-    //println!("Uploading image to: {}", repo_url);
-    let descr_endpoint = format!("{repo_url}/v1/image/push/chunk");
-    //println!("Uploading image to: {}", descr_endpoint);
-    let client = reqwest::Client::new();
-    let form = multipart::Form::new();
-    let form = form.text("descr-sha256", descr_sha256.clone());
-    let form = form.text("chunk-no", chunk.chunk_no.to_string());
-    let form = form.text("chunk-sha256", hex::encode(chunk.sha256));
-    let form = form.text("chunk-pos", chunk.pos.to_string());
-    let form = form.text("chunk-len", chunk.len.to_string());
-    let pb_chunk = create_chunk_pb(chunk.len, ProgressBarType::SingleChunk);
-    if !pb_chunk.is_hidden() {
-        mc.add(pb_chunk.clone());
+    let full_upload_needed = upload_descriptor(registry, descr_path).await?;
+
+    if full_upload_needed {
+        if let Some(push_image_name) = push_image_name {
+            //check if we can attach to the repo before uploading the file
+            let _repo_info = registry
+                .attach_to_repo(&descr.get_descr_hash_str(), push_image_name, true)
+                .await?;
+        };
+        full_upload(registry, path, descr, options).await?;
     }
-    pb_chunk.set_message(format!("Chunk {}", chunk.chunk_no + 1,));
-
-    let chunk_stream = stream_file_with_progress(
-        &file_path,
-        Some(std::ops::Range {
-            start: chunk.pos as usize,
-            end: (chunk.pos + chunk.len) as usize,
-        }),
-        Some(pb_chunk.clone()),
-        Some(pb_details.clone()),
-        Some(pb_total.clone()),
-    )
-    .await?;
-    let body = Body::wrap_stream(chunk_stream);
-    let some_file = multipart::Part::stream(body)
-        .file_name("descriptor.txt")
-        .mime_str("application/octet-stream")?;
-
-    let form = form.part("file", some_file);
-
-    let res = client
-        .post(descr_endpoint)
-        .multipart(form)
-        .send()
-        .await
-        .map_err(|e| anyhow::anyhow!("Image upload error: {}", e));
 
-    mc.remove(&pb_chunk);
-    match res {
-        Ok(res) => {
-            if res.status().is_success() {
-                pb_chunks.inc(1);
-                Ok(())
-            } else {
-                let status = res.status();
-                let error_msg = res.text().await.unwrap_or_default();
+    let attach_info = if let Some(push_image_name) = push_image_name {
+        //attach to repo after upload
+        Some(
+            registry
+                .attach_to_repo(&descr.get_descr_hash_str(), push_image_name, false)
+                .await?,
+        )
+    } else {
+        None
+    };
 
-                Err(anyhow::anyhow!(
-                    "Image upload failed with code {}, msg: {}",
-                    status.as_u16(),
-                    error_msg
-                ))
-            }
+    //signature is uploaded only for complete image
+    if let Some(signature) = &options.signature {
+        if registry
+            .push_signature(&descr.get_descr_hash_str(), signature)
+            .await?
+        {
+            println!(
+                " -- signature uploaded, public key: {}",
+                signature.public_key
+            );
+        } else {
+            println!(" -- registry does not support signatures, signature not uploaded");
         }
-        Err(e) => Err(e),
     }
+    Ok(attach_info)
 }
 
-pub async fn push_chunks(
+/// Uploads chunks missing in the registry. `uploaded_chunks` is the status reported by the registry,
+/// the journal is used only without it. `reused_chunks` were copied by the registry after the status was read.
+pub async fn push_chunks<B: RegistryBackend>(
+    registry: &B,
     file_path: &Path,
     file_descr: &FileChunkDesc,
     uploaded_chunks: Option<Vec<u64>>,
+    reused_chunks: &[u64],
     upload_workers: usize,
+    journal: Arc<Mutex<UploadJournal>>,
 ) -> anyhow::Result<()> {
     let descr_sha256 = file_descr.get_descr_hash_str();
     {
@@ -398,26 +398,28 @@ pub async fn push_chunks(
         mc.add(pb_chunks.clone());
     }
 
-    let chunks_to_upload = if let Some(uploaded_chunks) = uploaded_chunks {
-        let mut chunks = Vec::<FileChunk>::new();
-        for f in &file_descr.chunks {
-            let is_uploaded = *uploaded_chunks
-                .get(f.chunk_no as usize)
-                .ok_or(anyhow!("Chunk number {} is out of bounds", f.chunk_no))?;
-            if is_uploaded == 1 {
-                pb_chunks.inc(1);
-                pb_details.inc(f.len);
-                pb_total.inc(f.len);
-                log::debug!("Chunk {} already uploaded, skipping", f.chunk_no);
-                continue;
-            } else {
-                chunks.push(f.clone());
+    let reused_chunks: HashSet<u64> = reused_chunks.iter().copied().collect();
+    let mut chunks_to_upload = Vec::<FileChunk>::new();
+    for f in &file_descr.chunks {
+        //registry status wins over the journal, chunks lost on server side are uploaded again
+        let is_uploaded = match &uploaded_chunks {
+            Some(uploaded_chunks) => {
+                *uploaded_chunks
+                    .get(f.chunk_no as usize)
+                    .ok_or(anyhow!("Chunk number {} is out of bounds", f.chunk_no))?
+                    == 1
             }
+            None => journal.lock().unwrap().is_uploaded(f.chunk_no),
+        };
+        if is_uploaded || reused_chunks.contains(&f.chunk_no) {
+            pb_chunks.inc(1);
+            pb_details.inc(f.len);
+            pb_total.inc(f.len);
+            log::debug!("Chunk {} already uploaded, skipping", f.chunk_no);
+        } else {
+            chunks_to_upload.push(f.clone());
         }
-        chunks
-    } else {
-        file_descr.chunks.clone()
-    };
+    }
 
     pb_chunks.set_message("Chunked upload");
     pb_total.set_message("Total upload");
@@ -438,12 +440,16 @@ pub async fn push_chunks(
                 }
 
                 if ticks.len() > 1 {
-                    let speed =
-                        (ticks[0] - ticks[ticks.len() - 1]) as f64 / (ticks.len() - 1) as f64;
-                    let total_speed = (pb_details.position() - total_start_pos) as f64
+                    //position can go back when failed chunk is retried
+                    let speed = ticks[0].saturating_sub(ticks[ticks.len() - 1]) as f64
+                        / (ticks.len() - 1) as f64;
+                    let total_speed = pb_details.position().saturating_sub(total_start_pos) as f64
                         / total_start.elapsed().as_secs_f64();
                     let eta_str = if speed > 100.0 {
-                        let sec_left = (pb_details.length().unwrap_or(1) - pb_details.position())
+                        let sec_left = pb_details
+                            .length()
+                            .unwrap_or(1)
+                            .saturating_sub(pb_details.position())
                             as f64
                             / speed;
                         if sec_left > 0.0 {
@@ -481,26 +487,59 @@ pub async fn push_chunks(
         }
     });
 
+    //fatal error (e.g. rejected credentials) stops all workers, retryable ones affect single chunk
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let mut fatal_error = None;
     let mut futures = stream::iter(chunks_to_upload.iter().map(|chunk| {
-        tokio::spawn(upload_single_chunk(
-            PathBuf::from(file_path),
-            chunk.clone(),
-            descr_sha256.clone(),
-            mc.clone(),
-            pb_chunks.clone(),
-            pb_details.clone(),
-            pb_total.clone(),
-        ))
+        let policy = registry.retry_policy().clone();
+        let registry = registry.clone();
+        let file_path = PathBuf::from(file_path);
+        let chunk = chunk.clone();
+        let chunk_hash = file_descr.chunk_hash;
+        let descr_sha256 = descr_sha256.clone();
+        let progress = UploadProgress {
+            mc: mc.clone(),
+            pb_chunks: pb_chunks.clone(),
+            pb_details: pb_details.clone(),
+            pb_total: pb_total.clone(),
+        };
+        let chunk_no = chunk.chunk_no;
+        let upload = move || {
+            let registry = registry.clone();
+            let file_path = file_path.clone();
+            let chunk = chunk.clone();
+            let descr_sha256 = descr_sha256.clone();
+            let progress = progress.clone();
+            tokio::spawn(async move {
+                registry
+                    .push_chunk(file_path, chunk, chunk_hash, descr_sha256, progress)
+                    .await
+            })
+        };
+        tokio::spawn({
+            let journal = journal.clone();
+            let cancelled = cancelled.clone();
+            async move {
+                let res =
+                    upload_chunk_with_retries(upload, chunk_no, policy, journal, cancelled).await;
+                (chunk_no, res)
+            }
+        })
     }))
     .buffer_unordered(upload_workers);
+    //keep uploading other chunks when one fails, failed ones are left for --resume
+    let mut failed_chunks = Vec::new();
     while let Some(fut) = futures.next().await {
         match fut {
-            Ok(join_res) => match join_res {
-                Ok(_res) => {}
-                Err(e) => {
-                    return Err(e);
+            Ok((_chunk_no, Ok(()))) => {}
+            Ok((chunk_no, Err(e))) => {
+                log::error!("Chunk {} upload failed: {}", chunk_no, e);
+                if !e.is_retryable() && fatal_error.is_none() {
+                    cancelled.store(true, Ordering::Relaxed);
+                    fatal_error = Some(e);
                 }
-            },
+                failed_chunks.push(chunk_no);
+            }
             Err(e) => {
                 log::error!("Image upload failed: {:?}", e);
                 return Err(anyhow!("Image upload failed: {:?}", e));
@@ -516,6 +555,360 @@ pub async fn push_chunks(
     mc.remove(&pb_details);
     mc.remove(&pb_total);
 
+    if let Some(err) = fatal_error {
+        return Err(anyhow!("Image upload failed: {}", err));
+    }
+    if !failed_chunks.is_empty() {
+        failed_chunks.sort();
+        return Err(anyhow!(
+            "Upload of {} chunks failed ({}), run push with --resume to continue",
+            failed_chunks.len(),
+            format_chunk_list(&failed_chunks)
+        ));
+    }
     println!(" -- chunked upload finished successfully");
     Ok(())
 }
+
+#[async_trait]
+impl RegistryBackend for RegistryClient {
+    fn base_url(&self) -> &str {
+        RegistryClient::base_url(self)
+    }
+
+    fn retry_policy(&self) -> &RetryPolicy {
+        RegistryClient::retry_policy(self)
+    }
+
+    fn with_credentials(self, user_name: &str, pat: &str) -> Self {
+        RegistryClient::with_credentials(self, user_name, pat)
+    }
+
+    async fn check_login(&self, user_name: &str, pat: &str) -> anyhow::Result<bool> {
+        println!(" * Checking credentials for {}...", user_name);
+
+        let post_data = json!(
+            {
+                "username": user_name,
+                "password": pat,
+            }
+        );
+        let response = self
+            .request(Method::POST, "/auth/pat/login")
+            .json(&post_data)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("Repository status check failed: {}", e))?;
+
+        let response_status = response.status();
+        match response_status {
+            reqwest::StatusCode::OK => {
+                println!(" -- successfully logged in");
+                Ok(true)
+            }
+            reqwest::StatusCode::UNAUTHORIZED => {
+                let text = response.text().await.unwrap_or_default();
+                println!(" -- failed to log in: {}", text);
+                Ok(false)
+            }
+            _ => {
+                let text = response.text().await.unwrap_or_default();
+                Err(anyhow::anyhow!(
+                    "Other error when checking login, status: {}, err: {}",
+                    response_status,
+                    text
+                ))
+            }
+        }
+    }
+
+    async fn attach_to_repo(
+        &self,
+        descr_sha256: &str,
+        image_name: &ImageName,
+        check: bool,
+    ) -> anyhow::Result<AttachInfo> {
+        let Some(image_user_name) = image_name.user.clone() else {
+            return Err(anyhow::anyhow!("Image name must contain user"));
+        };
+        let (login, pat) = self
+            .credentials()
+            .map(|c| (c.user_name.clone(), c.token.clone()))
+            .unwrap_or_default();
+
+        let form = multipart::Form::new();
+        let form = form.text("tag", image_name.tag.clone());
+        let form = form.text("username", image_user_name.clone());
+        let form = form.text("repository", image_name.repository.clone());
+        let form = form.text("login", login);
+        let form = form.text("token", pat);
+        let form = if check {
+            form.text("check", "true")
+        } else {
+            form
+        };
+
+        if check {
+            println!(
+                " * Checking if image can be added to repository: {}",
+                image_name.to_normalized_name()
+            );
+        } else {
+            println!(
+                " * Adding image to repository: {}",
+                image_name.to_normalized_name()
+            );
+        }
+        let response = self
+            .request(
+                Method::POST,
+                &format!("/v1/image/descr/attach/{descr_sha256}"),
+            )
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("Repository status check failed: {}", e))?;
+
+        if response.status() != 200 {
+            return match response.text().await {
+                Ok(text) => Err(anyhow::anyhow!(
+                    "Not possible to add to repository: {}",
+                    text
+                )),
+                Err(e) => Err(anyhow::anyhow!("Not possible to add to repository: {}", e)),
+            };
+        }
+        let text = response.text().await?;
+        if check {
+            println!(" -- checked successfully");
+        } else {
+            println!(" -- success: {}", text);
+        }
+        Ok(AttachInfo {
+            url: RegistryClient::base_url(self).to_string(),
+            repo: image_name.repository.clone(),
+            tag: image_name.tag.clone(),
+            user: image_user_name,
+        })
+    }
+
+    async fn validate_upload(&self, descr_sha256: &str) -> anyhow::Result<ValidateUploadResponse> {
+        let response = self
+            .request(Method::GET, &format!("/v1/image/descr/{descr_sha256}"))
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("Repository status check failed: {}", e))?;
+
+        let response = response.json::<ValidateUploadResponse>().await?;
+        Ok(response)
+    }
+
+    async fn push_descr(&self, file_path: &Path) -> anyhow::Result<()> {
+        println!(
+            " * Uploading image descriptor to: {}",
+            RegistryClient::base_url(self)
+        );
+        let (_, descr_sha256) = load_bytes_and_sha(file_path).await?;
+
+        let form = multipart::Form::new();
+        let pb = create_chunk_pb(1, ProgressBarType::DescriptorUpload);
+
+        let file_stream =
+            stream_file_with_progress(file_path, None, Some(pb.clone()), None, None, None).await?;
+        let body = Body::wrap_stream(file_stream);
+        let some_file = multipart::Part::stream(body)
+            .file_name("descriptor.txt")
+            .mime_str("application/octet-stream")?;
+        let form = form.part("file", some_file);
+
+        let res = self
+            .request(Method::POST, "/v1/image/push/descr")
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|e| anyhow::anyhow!("Image upload error: {}", e));
+
+        pb.finish_and_clear();
+        match res {
+            Ok(res) => {
+                if res.status().is_success() {
+                    println!(" -- descriptor uploaded successfully");
+                    println!(
+                        " -- download link: {}",
+                        self.url(&format!("/download/{descr_sha256}"))
+                    );
+                } else {
+                    return Err(anyhow::anyhow!(
+                        "Image upload failed with code {}: {}",
+                        res.status().as_u16(),
+                        res.text().await.unwrap_or_default()
+                    ));
+                }
+                Ok(())
+            }
+            Err(e) => {
+                println!("Image upload failed: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    async fn push_signature(
+        &self,
+        descr_sha256: &str,
+        signature: &ImageSignature,
+    ) -> anyhow::Result<bool> {
+        let response = self
+            .request(
+                Method::POST,
+                &format!("/v1/image/push/signature/{descr_sha256}"),
+            )
+            .json(signature)
+            .send()
+            .await
+            .map_err(|e| anyhow!("Signature upload error: {}", e))?;
+        match response.status() {
+            //older registries do not have the endpoint
+            reqwest::StatusCode::NOT_FOUND
+            | reqwest::StatusCode::METHOD_NOT_ALLOWED
+            | reqwest::StatusCode::NOT_IMPLEMENTED => Ok(false),
+            status if status.is_success() => Ok(true),
+            status => Err(anyhow!(
+                "Signature upload failed with code {}: {}",
+                status.as_u16(),
+                response.text().await.unwrap_or_default()
+            )),
+        }
+    }
+
+    async fn tagged_descriptor(&self, image_name: &ImageName) -> anyhow::Result<FileChunkDesc> {
+        let descr_sha256 = self.resolve_tag(image_name).await?;
+        Ok(self.download_descriptor(&descr_sha256).await?.0)
+    }
+
+    async fn reuse_chunks(
+        &self,
+        descr_sha256: &str,
+        base_descr_sha256: &str,
+        chunks: &[ChunkReuse],
+    ) -> anyhow::Result<Option<Vec<u64>>> {
+        let response = self
+            .request(Method::POST, "/v1/image/push/reuse-chunks")
+            .json(&json!({
+                "descriptor": descr_sha256,
+                "base": base_descr_sha256,
+                "chunks": chunks,
+            }))
+            .send()
+            .await
+            .map_err(|e| anyhow!("Chunk reuse request failed: {}", e))?;
+        match response.status() {
+            //older registries do not have the endpoint
+            reqwest::StatusCode::NOT_FOUND
+            | reqwest::StatusCode::METHOD_NOT_ALLOWED
+            | reqwest::StatusCode::NOT_IMPLEMENTED => Ok(None),
+            status if status.is_success() => {
+                Ok(Some(response.json::<ReuseChunksResponse>().await?.reused))
+            }
+            status => Err(anyhow!(
+                "Chunk reuse failed with code {}: {}",
+                status.as_u16(),
+                response.text().await.unwrap_or_default()
+            )),
+        }
+    }
+
+    async fn push_chunk(
+        &self,
+        file_path: PathBuf,
+        chunk: FileChunk,
+        chunk_hash: ChunkHash,
+        descr_sha256: String,
+        progress: UploadProgress,
+    ) -> Result<(), UploadError> {
+        let UploadProgress {
+            mc,
+            pb_chunks,
+            pb_details,
+            pb_total,
+        } = progress;
+        let form = multipart::Form::new();
+        let form = form.text("descr-sha256", descr_sha256.clone());
+        let form = form.text("chunk-no", chunk.chunk_no.to_string());
+        //chunk-sha256 is the only hash field older registries understand
+        let form = match chunk_hash {
+            ChunkHash::Sha256 => form.text("chunk-sha256", hex::encode(chunk.hash)),
+            ChunkHash::Blake3 => form,
+        };
+        let form = form.text("chunk-hash-alg", chunk_hash.name());
+        let form = form.text("chunk-hash", hex::encode(chunk.hash));
+        let form = form.text("chunk-pos", chunk.pos.to_string());
+        let form = form.text("chunk-len", chunk.len.to_string());
+        let pb_chunk = create_chunk_pb(chunk.len, ProgressBarType::SingleChunk);
+        if !pb_chunk.is_hidden() {
+            mc.add(pb_chunk.clone());
+        }
+        pb_chunk.set_message(format!("Chunk {}", chunk.chunk_no + 1,));
+        let mut progress_guard = ChunkProgressGuard {
+            mc,
+            pb_chunk: pb_chunk.clone(),
+            pb_details: pb_details.clone(),
+            pb_total: pb_total.clone(),
+            finished: false,
+        };
+
+        let chunk_stream = stream_file_with_progress(
+            &file_path,
+            Some(std::ops::Range {
+                start: chunk.pos as usize,
+                end: (chunk.pos + chunk.len) as usize,
+            }),
+            Some(pb_chunk.clone()),
+            Some(pb_details.clone()),
+            Some(pb_total.clone()),
+            self.upload_limiter().cloned(),
+        )
+        .await
+        .map_err(|e| UploadError::fatal(e.to_string()))?;
+        let body = Body::wrap_stream(chunk_stream);
+        let some_file = multipart::Part::stream(body)
+            .file_name("descriptor.txt")
+            .mime_str("application/octet-stream")
+            .map_err(|e| UploadError::fatal(e.to_string()))?;
+
+        let form = form.part("file", some_file);
+
+        let res = self
+            .transfer(Method::POST, "/v1/image/push/chunk")
+            .multipart(form)
+            .send()
+            .await
+            .map_err(|e| {
+                let err = UploadError::from_reqwest(&e);
+                UploadError {
+                    message: format!("Image upload error: {}", e),
+                    ..err
+                }
+            })?;
+
+        if res.status().is_success() {
+            progress_guard.finished = true;
+            pb_chunks.inc(1);
+            Ok(())
+        } else {
+            let status = res.status();
+            let headers = res.headers().clone();
+            let error_msg = res.text().await.unwrap_or_default();
+
+            Err(UploadError::from_status(
+                status,
+                &headers,
+                format!(
+                    "Image upload failed with code {}, msg: {}",
+                    status.as_u16(),
+                    error_msg
+                ),
+            ))
+        }
+    }
+}
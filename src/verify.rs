@@ -0,0 +1,235 @@
+use std::path::Path;
+
+use serde::Serialize;
+use sha2::Digest;
+use sha3::Sha3_224;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+use crate::chunks::FileChunkDesc;
+use crate::metadata::read_metadata_footer;
+use crate::progress::{create_chunk_pb, ProgressBarType};
+
+/// What the image is checked against
+pub enum VerifyExpectation {
+    /// Full descriptor, every chunk is checked
+    Descriptor(FileChunkDesc),
+    /// Image link (hex sha3-224 of the whole file) only
+    ImageLink(String),
+}
+
+/// Range of bytes `[start, end)` not matching the descriptor, adjacent chunks are merged
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CorruptedRange {
+    pub first_chunk: u64,
+    pub last_chunk: u64,
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VerifyReport {
+    pub file_size: u64,
+    pub expected_size: Option<u64>,
+    pub expected_link: String,
+    pub actual_link: String,
+    pub chunks_checked: usize,
+    pub corrupted_ranges: Vec<CorruptedRange>,
+    pub metadata_crc_valid: bool,
+    pub metadata_error: Option<String>,
+}
+
+impl VerifyReport {
+    pub fn link_valid(&self) -> bool {
+        self.expected_link == self.actual_link
+    }
+
+    pub fn size_valid(&self) -> bool {
+        self.expected_size
+            .map(|size| size == self.file_size)
+            .unwrap_or(true)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.link_valid()
+            && self.size_valid()
+            && self.corrupted_ranges.is_empty()
+            && self.metadata_crc_valid
+    }
+}
+
+fn add_corrupted_chunk(ranges: &mut Vec<CorruptedRange>, chunk_no: u64, start: u64, end: u64) {
+    match ranges.last_mut() {
+        Some(last) if last.last_chunk + 1 == chunk_no => {
+            last.last_chunk = chunk_no;
+            last.end = end;
+        }
+        _ => ranges.push(CorruptedRange {
+            first_chunk: chunk_no,
+            last_chunk: chunk_no,
+            start,
+            end,
+        }),
+    }
+}
+
+/// Re-hashes content of the image and compares it with expected sha3 and chunk hashes.
+/// Metadata is not checked here, see [`verify_image`].
+pub async fn verify_content<AsyncReader>(
+    mut reader: AsyncReader,
+    file_size: u64,
+    expected: &VerifyExpectation,
+) -> anyhow::Result<VerifyReport>
+where
+    AsyncReader: tokio::io::AsyncRead + Unpin,
+{
+    let pb = create_chunk_pb(file_size, ProgressBarType::CreateDescriptor);
+    pb.set_message("Verifying file");
+
+    let mut sha3 = Sha3_224::new();
+    let mut corrupted_ranges = Vec::new();
+    let mut chunks_checked = 0;
+    let mut offset = 0;
+    let (expected_size, expected_link) = match expected {
+        VerifyExpectation::Descriptor(descr) => {
+            let mut buffer = Vec::new();
+            for chunk in &descr.chunks {
+                //chunks past the end of file are reported as corrupted
+                let available = file_size.saturating_sub(chunk.pos).min(chunk.len);
+                buffer.resize(available as usize, 0);
+                reader.read_exact(&mut buffer).await?;
+                sha3.update(&buffer);
+                offset += available;
+                pb.inc(available);
+                chunks_checked += 1;
+                if available != chunk.len || descr.chunk_hash.digest(&buffer) != chunk.hash {
+                    add_corrupted_chunk(
+                        &mut corrupted_ranges,
+                        chunk.chunk_no,
+                        chunk.pos,
+                        chunk.pos + chunk.len,
+                    );
+                }
+            }
+            (Some(descr.size), descr.get_sha3_str())
+        }
+        VerifyExpectation::ImageLink(link) => (None, link.to_lowercase()),
+    };
+    //rest of the file (whole file for image link, bytes past descriptor size otherwise)
+    let mut buffer = vec![0; 1024 * 1024];
+    while offset < file_size {
+        let read = reader.read(&mut buffer).await?;
+        if read == 0 {
+            return Err(anyhow::anyhow!(
+                "Unexpected end of file at {}, expected {} bytes",
+                offset,
+                file_size
+            ));
+        }
+        sha3.update(&buffer[..read]);
+        offset += read as u64;
+        pb.inc(read as u64);
+    }
+    pb.finish_and_clear();
+
+    Ok(VerifyReport {
+        file_size,
+        expected_size,
+        expected_link,
+        actual_link: hex::encode(sha3.finalize()),
+        chunks_checked,
+        corrupted_ranges,
+        metadata_crc_valid: false,
+        metadata_error: None,
+    })
+}
+
+/// Checks gvmi file against descriptor or image link and validates metadata footer CRC
+pub async fn verify_image(
+    path: &Path,
+    expected: &VerifyExpectation,
+) -> anyhow::Result<VerifyReport> {
+    let file = File::open(path).await?;
+    let file_size = file.metadata().await?.len();
+    let mut report = verify_content(file, file_size, expected).await?;
+    match read_metadata_footer(path) {
+        Ok(footer) => {
+            report.metadata_crc_valid = footer.crc_valid();
+            if !footer.crc_valid() {
+                report.metadata_error = Some(format!(
+                    "CRC mismatch, stored {:08x} computed {:08x}",
+                    footer.crc_stored, footer.crc_computed
+                ));
+            }
+        }
+        Err(err) => report.metadata_error = Some(err.to_string()),
+    }
+    Ok(report)
+}
+
+#[tokio::test]
+async fn test_verify_content() {
+    use crate::chunks::create_descriptor_from_reader;
+    use std::iter::repeat_with;
+
+    let mut rng = fastrand::Rng::new();
+    rng.seed(1234);
+    let bytes: Vec<u8> = repeat_with(|| rng.u8(..)).take(10500).collect();
+    let descr = create_descriptor_from_reader(&bytes[..], bytes.len() as u64, 1000)
+        .await
+        .unwrap();
+    let link = descr.get_sha3_str();
+    let expected = VerifyExpectation::Descriptor(descr);
+
+    let report = verify_content(&bytes[..], bytes.len() as u64, &expected)
+        .await
+        .unwrap();
+    assert!(report.link_valid());
+    assert!(report.corrupted_ranges.is_empty());
+    assert_eq!(report.chunks_checked, 11);
+
+    let mut damaged = bytes.clone();
+    damaged[2500] ^= 1;
+    damaged[3999] ^= 1;
+    damaged[10400] ^= 1;
+    let report = verify_content(&damaged[..], damaged.len() as u64, &expected)
+        .await
+        .unwrap();
+    assert!(!report.link_valid());
+    assert_eq!(
+        report.corrupted_ranges,
+        vec![
+            CorruptedRange {
+                first_chunk: 2,
+                last_chunk: 3,
+                start: 2000,
+                end: 4000
+            },
+            CorruptedRange {
+                first_chunk: 10,
+                last_chunk: 10,
+                start: 10000,
+                end: 10500
+            }
+        ]
+    );
+
+    //truncated file
+    let report = verify_content(&bytes[..9000], 9000, &expected)
+        .await
+        .unwrap();
+    assert!(!report.size_valid());
+    assert_eq!(report.corrupted_ranges[0].start, 9000);
+    assert_eq!(report.corrupted_ranges[0].first_chunk, 9);
+
+    let report = verify_content(
+        &bytes[..],
+        bytes.len() as u64,
+        &VerifyExpectation::ImageLink(link.to_uppercase()),
+    )
+    .await
+    .unwrap();
+    assert!(report.link_valid());
+    assert_eq!(report.chunks_checked, 0);
+}
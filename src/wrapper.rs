@@ -5,6 +5,8 @@ use std::io::SeekFrom;
 use std::path::Path;
 
 use std::sync::{Arc, Mutex};
+
+use crate::throttle::RateLimiter;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
 
@@ -17,6 +19,13 @@ struct ProgressContextInner {
 pub struct ProgressContext {
     inner: Arc<Mutex<ProgressContextInner>>,
 }
+
+impl Default for ProgressContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ProgressContext {
     pub fn new() -> Self {
         ProgressContext {
@@ -33,6 +42,29 @@ impl ProgressContext {
     }
 }
 
+/// Reader wrapper advancing progress bar by number of bytes read
+pub struct ProgressReader<R> {
+    inner: R,
+    pb: indicatif::ProgressBar,
+}
+
+impl<R> ProgressReader<R> {
+    pub fn new(inner: R, pb: &indicatif::ProgressBar) -> Self {
+        ProgressReader {
+            inner,
+            pb: pb.clone(),
+        }
+    }
+}
+
+impl<R: std::io::Read> std::io::Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.pb.inc(read as u64);
+        Ok(read)
+    }
+}
+
 pub fn stream_with_progress(
     stream_in: impl Stream<Item = Result<Bytes, bollard::errors::Error>> + std::marker::Unpin,
     pb: &indicatif::ProgressBar,
@@ -80,12 +112,15 @@ pub fn stream_with_progress(
     })
 }
 
+/// Streams file (or its range) in 100kB pieces advancing progress bars,
+/// with `limiter` every piece waits for its share of upload bandwidth
 pub async fn stream_file_with_progress(
     file_in: &Path,
     chunk: Option<std::ops::Range<usize>>,
     pb_file: Option<indicatif::ProgressBar>,
     pb_global1: Option<indicatif::ProgressBar>,
     pb_global2: Option<indicatif::ProgressBar>,
+    limiter: Option<RateLimiter>,
 ) -> anyhow::Result<impl Stream<Item = Result<Bytes, anyhow::Error>>> {
     let mut file = File::open(file_in).await?;
     let file_size = file.metadata().await?.len();
@@ -107,6 +142,7 @@ pub async fn stream_file_with_progress(
         let pb_file = pb_file.clone();
         let pb_global1 = pb_global1.clone();
         let pb_global2 = pb_global2.clone();
+        let limiter = limiter.clone();
         async move {
             if bytes_to_read == 0 {
                 if let Some(pb) = pb_file {
@@ -116,6 +152,9 @@ pub async fn stream_file_with_progress(
             }
             //println!("Bytes to read: {}", bytes_to_read);
             let current_read_size = std::cmp::min(bytes_to_read, 100000);
+            if let Some(limiter) = limiter {
+                limiter.acquire(current_read_size as u64).await;
+            }
             let mut buf = vec![0u8; current_read_size];
             let bytes_read = file.read_exact(&mut buf).await.unwrap();
             let bytes = Bytes::from(buf);